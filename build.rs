@@ -0,0 +1,10 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "grpc")]
+    {
+        // A vendored protoc keeps the build free of system dependencies.
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("protoc is vendored");
+        std::env::set_var("PROTOC", protoc);
+        tonic_build::compile_protos("proto/cloud_zip.proto").expect("the proto file compiles");
+    }
+}
@@ -0,0 +1,1973 @@
+use aws_sdk_s3::Client;
+use futures::stream::{self, BoxStream, StreamExt};
+use regex::bytes::Regex;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::future::Future;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use tokio::io::AsyncReadExt;
+use tokio::sync::{OnceCell, Semaphore};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, debug_span, info, warn, Instrument, Span};
+
+use crate::backend::{
+    group_ranges, CoalescingReader, GzipIndex, GzipReader, LocalBackend, MemoryBackend,
+    RangeReader, S3Backend, SliceReader,
+};
+use crate::central_directory::{
+    build_index, check_entries, check_local_header, extend_index, scan_local_headers,
+};
+use crate::codepage::NameEncoding;
+use crate::content_type;
+use crate::digest::{self, HashAlgorithm};
+use crate::error::{CloudZipError, Result};
+use crate::exif::{self, Exif, ExifSegment};
+use crate::extract::{
+    extract_symlink, extract_to_path, link_duplicate, open_entry_reader, range_entry_reader,
+    resume_to_path, skipping_entry_reader, verify_entry, write_to_path, DecodeContext,
+    DownloadOptions, EntryReader, FileAttributes,
+};
+use crate::grep::{self, GrepContext, GrepMatches};
+use crate::limits::{Budget, ExtractLimits};
+use crate::location::{ArchiveLocation, BackendOptions};
+use crate::manifest::ManifestEntry;
+use crate::metadata::{
+    self, ArchiveFingerprint, Encryption, EntryIndex, FileMetadata, IndexFormat,
+};
+use crate::metrics::{self, EntryRecord, MetricsRecorder};
+use crate::output::{safe_components, windows_safe, ConflictPolicy, PathLayout};
+use crate::preview::{self, Preview, PreviewFormat};
+use crate::progress::{Progress, ProgressCallback, ProgressTracker};
+use crate::resume::JobState;
+use crate::s3_output::{self, ExtractedObjects, S3Destination};
+use crate::selection::EntrySelector;
+use crate::sync::{self, LocalFile, SyncOptions, SyncPlan};
+
+/// Number of entries fetched and decompressed at once by the batch extraction methods.
+pub const DEFAULT_CONCURRENCY: usize = 8;
+
+/// Deflated entries at least this large are read at random through checkpoints in their
+/// deflate stream by [`CloudZip::entry_reader`], rather than decompressed into memory.
+pub const CHECKPOINTED_ENTRY_LEN: u64 = 64 * 1024 * 1024;
+
+/// A zip archive plus the index used to extract single entries from it.
+pub struct CloudZip {
+    reader: Arc<dyn RangeReader>,
+    /// Local index file; `None` when the index only lives in memory.
+    metadata_path: Option<PathBuf>,
+    /// The index as last loaded from or saved to the metadata path, or downloaded from a sidecar.
+    entries: Mutex<Option<Arc<EntryIndex>>>,
+    /// Set once the archive is known to be the one the index was built from.
+    verified: OnceCell<()>,
+    /// Readers of large deflated entries by name, which keep the checkpoints they found.
+    inflated: Mutex<HashMap<String, Arc<GzipReader>>>,
+    index_format: IndexFormat,
+    #[cfg(feature = "zstd")]
+    compress_index: bool,
+    concurrency: usize,
+    download: DownloadOptions,
+    layout: PathLayout,
+    on_conflict: ConflictPolicy,
+    limits: ExtractLimits,
+    preserve_times: bool,
+    preserve_permissions: bool,
+    symlinks: bool,
+    link_duplicates: bool,
+    windows_names: bool,
+    resume_state: Option<PathBuf>,
+    cancel: CancellationToken,
+    password: Option<Vec<u8>>,
+    check_headers: bool,
+    strict: bool,
+    name_encoding: NameEncoding,
+    progress: Option<ProgressCallback>,
+    metrics: Option<Arc<MetricsRecorder>>,
+}
+
+impl CloudZip {
+    /// Opens an archive stored behind any [`RangeReader`] backend.
+    pub fn open(reader: impl RangeReader + 'static, metadata_path: impl Into<PathBuf>) -> Self {
+        Self::with_reader(Arc::new(reader), metadata_path)
+    }
+
+    /// Opens an archive from a shared backend handle.
+    pub fn with_reader(reader: Arc<dyn RangeReader>, metadata_path: impl Into<PathBuf>) -> Self {
+        Self::with_index(reader, Some(metadata_path.into()), None)
+    }
+
+    /// Opens an archive whose index is already loaded, without any index file.
+    pub fn from_index(reader: Arc<dyn RangeReader>, list: Vec<FileMetadata>) -> Self {
+        Self::with_index(reader, None, Some(EntryIndex::new(list)))
+    }
+
+    /// Opens an archive with an index loaded from elsewhere, such as a catalog; its fingerprint
+    /// is still checked before entries are read.
+    pub fn from_entry_index(reader: Arc<dyn RangeReader>, entries: EntryIndex) -> Self {
+        Self::with_index(reader, None, Some(entries))
+    }
+
+    /// Opens an archive without a local index file: the sidecar stored next to the archive is
+    /// used when there is one, otherwise the central directory is read from the archive itself.
+    pub async fn discover(reader: Arc<dyn RangeReader>) -> Result<Self> {
+        Self::discover_with_names(reader, NameEncoding::default()).await
+    }
+
+    /// Opens an archive like [`CloudZip::discover`], decoding the names of entries without
+    /// the UTF-8 flag as `names` says when the central directory is read.
+    pub async fn discover_with_names(
+        reader: Arc<dyn RangeReader>,
+        names: NameEncoding,
+    ) -> Result<Self> {
+        let entries = match reader.read_sidecar().await? {
+            Some(sidecar) => {
+                debug!(size = sidecar.len(), "Using the index sidecar");
+                metadata::decode_metadata(&sidecar)?
+            }
+            None => {
+                debug!("No index sidecar, indexing the archive");
+                let list = build_index(reader.as_ref(), names).await?;
+                EntryIndex::new(list).with_fingerprint(Some(fingerprint(reader.as_ref()).await?))
+            }
+        };
+        Ok(Self::with_index(reader, None, Some(entries)).with_name_encoding(names))
+    }
+
+    fn with_index(
+        reader: Arc<dyn RangeReader>,
+        metadata_path: Option<PathBuf>,
+        entries: Option<EntryIndex>,
+    ) -> Self {
+        CloudZip {
+            reader,
+            metadata_path,
+            entries: Mutex::new(entries.map(Arc::new)),
+            verified: OnceCell::new(),
+            inflated: Mutex::new(HashMap::new()),
+            index_format: IndexFormat::default(),
+            #[cfg(feature = "zstd")]
+            compress_index: false,
+            concurrency: DEFAULT_CONCURRENCY,
+            download: DownloadOptions::default(),
+            layout: PathLayout::default(),
+            on_conflict: ConflictPolicy::default(),
+            limits: ExtractLimits::default(),
+            preserve_times: true,
+            preserve_permissions: true,
+            symlinks: false,
+            link_duplicates: false,
+            resume_state: None,
+            cancel: CancellationToken::new(),
+            windows_names: cfg!(windows),
+            password: None,
+            check_headers: false,
+            strict: false,
+            name_encoding: NameEncoding::default(),
+            progress: None,
+            metrics: None,
+        }
+    }
+
+    /// Opens an archive by location, picking the backend from its scheme.
+    pub async fn open_location(
+        location: &ArchiveLocation,
+        metadata_path: impl Into<PathBuf>,
+        options: &BackendOptions,
+    ) -> Result<Self> {
+        Ok(Self::with_reader(
+            location.open(options).await?,
+            metadata_path,
+        ))
+    }
+
+    pub fn open_local(
+        zip_path: impl Into<PathBuf>,
+        metadata_path: impl Into<PathBuf>,
+    ) -> Result<Self> {
+        Ok(Self::open(LocalBackend::open(zip_path)?, metadata_path))
+    }
+
+    pub fn open_s3(
+        client: Client,
+        bucket: impl Into<String>,
+        key: impl Into<String>,
+        metadata_path: impl Into<PathBuf>,
+    ) -> Self {
+        Self::open(S3Backend::new(client, bucket, key), metadata_path)
+    }
+
+    /// Sets the format [`CloudZip::index`] and [`CloudZip::upload_sidecar`] write the index in.
+    pub fn with_index_format(mut self, index_format: IndexFormat) -> Self {
+        self.index_format = index_format;
+        self
+    }
+
+    /// Compresses the index [`CloudZip::index`] and [`CloudZip::upload_sidecar`] write with zstd.
+    #[cfg(feature = "zstd")]
+    pub fn with_index_compression(mut self, compress: bool) -> Self {
+        self.compress_index = compress;
+        self
+    }
+
+    /// Sets how the names of entries without the UTF-8 flag are decoded when the archive is
+    /// indexed; see [`NameEncoding`].
+    pub fn with_name_encoding(mut self, name_encoding: NameEncoding) -> Self {
+        self.name_encoding = name_encoding;
+        self
+    }
+
+    /// Sets how many entries batch extraction fetches and decompresses in parallel.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Sets how large entries are split into concurrent ranged reads.
+    pub fn with_download_options(mut self, download: DownloadOptions) -> Self {
+        self.download = download;
+        self
+    }
+
+    /// Sets how entry names are mapped to paths below the output directory.
+    pub fn with_layout(mut self, layout: PathLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Sets what happens when an extracted file already exists.
+    pub fn with_conflict_policy(mut self, on_conflict: ConflictPolicy) -> Self {
+        self.on_conflict = on_conflict;
+        self
+    }
+
+    /// Sets the size and compression ratio limits enforced while extracting.
+    pub fn with_limits(mut self, limits: ExtractLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets whether extracted files get the modification time recorded in the archive.
+    pub fn with_preserve_times(mut self, preserve_times: bool) -> Self {
+        self.preserve_times = preserve_times;
+        self
+    }
+
+    /// Sets whether extracted files get the Unix permissions recorded in the archive.
+    pub fn with_preserve_permissions(mut self, preserve_permissions: bool) -> Self {
+        self.preserve_permissions = preserve_permissions;
+        self
+    }
+
+    /// Sets whether symlink entries are recreated as symlinks rather than as files holding
+    /// the link target. Links pointing outside of the output directory are refused.
+    pub fn with_symlinks(mut self, symlinks: bool) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    /// Sets whether [`CloudZip::extract_matching`] extracts files with the CRC-32 and size of
+    /// one extracted before them as hard links to it, after checking that their content is
+    /// the same, to save disk space. Linked files share the times and permissions of the
+    /// first.
+    pub fn with_link_duplicates(mut self, link_duplicates: bool) -> Self {
+        self.link_duplicates = link_duplicates;
+        self
+    }
+
+    /// Sets whether extracted names are rewritten into ones Windows can create, replacing
+    /// forbidden characters and renaming reserved device names; on by default on Windows, and
+    /// useful elsewhere when writing to a share Windows machines read. Each renamed entry is
+    /// logged.
+    pub fn with_windows_names(mut self, windows_names: bool) -> Self {
+        self.windows_names = windows_names;
+        self
+    }
+
+    /// Makes [`CloudZip::extract_matching`] record each entry it finishes in a job state file
+    /// at `path`, so that running the same extraction again after a crash or interruption
+    /// skips the entries already written and resumes stored entries from their `.part`
+    /// files. The file is removed once an extraction completes.
+    pub fn with_resume_state(mut self, path: impl Into<PathBuf>) -> Self {
+        self.resume_state = Some(path.into());
+        self
+    }
+
+    /// Stops downloads and decompressions in flight once `cancel` is cancelled: the operation
+    /// fails with [`CloudZipError::Cancelled`], removing its `.part` files except those kept
+    /// for [`CloudZip::with_resume_state`], whose job state then lists every finished entry.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Sets the password used to decrypt encrypted entries.
+    pub fn with_password(mut self, password: impl Into<Vec<u8>>) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    /// Checks the local header in front of each entry against the index before decoding it,
+    /// failing on any mismatch rather than decoding whatever bytes the offset points at. This
+    /// costs one small extra read per entry.
+    pub fn with_header_checks(mut self, check_headers: bool) -> Self {
+        self.check_headers = check_headers;
+        self
+    }
+
+    /// Refuses to read entries of an archive whose index holds duplicate names or entries with
+    /// overlapping data, the marks of archives crafted to confuse zip readers or to expand far
+    /// beyond their size; see [`EntryIndex::conflicts`].
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Reports the progress of extractions and verifications, counted per call: every batch
+    /// starts again from zero with its own totals.
+    pub fn with_progress(mut self, callback: impl Fn(&Progress) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Arc::new(callback));
+        self
+    }
+
+    /// Counts the requests, bytes and time of every entry extracted or verified into
+    /// `metrics`; the backend's reads are only counted when it was opened with the same
+    /// recorder in [`BackendOptions::metrics`].
+    pub fn with_metrics(mut self, metrics: Arc<MetricsRecorder>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Starts counting for an entry, when metrics are collected.
+    fn entry_metrics(&self, metadata: &FileMetadata) -> Option<Arc<EntryRecord>> {
+        let metrics = self.metrics.as_ref()?;
+        Some(metrics.entry(&metadata.file_name))
+    }
+
+    /// The span of extracting one entry, within the current one.
+    fn entry_span(metadata: &FileMetadata) -> Span {
+        debug_span!(
+            "extract",
+            entry = %metadata.file_name,
+            size = metadata.uncompressed_size,
+            compressed_size = metadata.compressed_size,
+        )
+    }
+
+    pub fn reader(&self) -> &Arc<dyn RangeReader> {
+        &self.reader
+    }
+
+    /// The index file, unless the index is only held in memory.
+    pub fn metadata_path(&self) -> Option<&Path> {
+        self.metadata_path.as_deref()
+    }
+
+    /// Builds the index of the archive and saves it to the metadata path, if there is one.
+    ///
+    /// Only the end of central directory records, the central directory and the local file
+    /// headers are read, so remote archives are indexed in place with a few ranged reads.
+    pub async fn index(&self) -> Result<Vec<FileMetadata>> {
+        // Reading the tail first lets backends answer the fingerprint from the same response.
+        let list = build_index(self.reader.as_ref(), self.name_encoding).await?;
+        let fingerprint = fingerprint(self.reader.as_ref()).await?;
+        self.save_index(list, fingerprint)
+    }
+
+    /// Builds a best-effort index of an archive whose central directory is missing or damaged
+    /// from its local headers, and saves it to the metadata path, if there is one.
+    ///
+    /// The archive is walked from the start, reading one small range per entry as long as the
+    /// local headers record their sizes; entries that cannot be recovered are left out.
+    pub async fn recover_index(&self) -> Result<Vec<FileMetadata>> {
+        let list = scan_local_headers(self.reader.as_ref(), self.name_encoding).await?;
+        let fingerprint = fingerprint(self.reader.as_ref()).await?;
+        self.save_index(list, fingerprint)
+    }
+
+    /// Brings `previous`, an index of an earlier version of the archive, up to date and saves
+    /// it to the metadata path, if there is one.
+    ///
+    /// An unchanged archive is not read at all. When entries were only appended, the central
+    /// directory still starts with those of `previous`, which are kept as they are: only the
+    /// local headers of the new entries are read. Anything else rebuilds the index like
+    /// [`CloudZip::index`].
+    pub async fn update_index(&self, previous: &EntryIndex) -> Result<IndexUpdate> {
+        let fingerprint = fingerprint(self.reader.as_ref()).await?;
+        let (list, reused) = match previous.fingerprint() {
+            Some(recorded) if recorded.check(&fingerprint).is_ok() => {
+                debug!("The archive has not changed since it was indexed");
+                (previous.as_slice().to_vec(), previous.len())
+            }
+            // Appending never makes an archive smaller.
+            Some(recorded) if recorded.size > fingerprint.size => (
+                build_index(self.reader.as_ref(), self.name_encoding).await?,
+                0,
+            ),
+            _ => {
+                extend_index(
+                    self.reader.as_ref(),
+                    previous.as_slice(),
+                    self.name_encoding,
+                )
+                .await?
+            }
+        };
+        let entries = self.save_index(list, fingerprint)?.len();
+        Ok(IndexUpdate { entries, reused })
+    }
+
+    /// Checks the index against the archive as it is now: its fingerprint, every entry against
+    /// the central directory, and the local headers of up to `headers` entries spread over the
+    /// archive, each read with a small ranged GET. Nothing is downloaded beyond that; see
+    /// [`CloudZip::verify`] for checking the data itself.
+    pub async fn check_index(&self, headers: usize) -> Result<IndexCheck> {
+        let entries = self.entries()?;
+        let changed = match entries.fingerprint() {
+            Some(recorded) => match recorded.check(&fingerprint(self.reader.as_ref()).await?) {
+                Ok(()) => None,
+                Err(CloudZipError::ArchiveChanged(change)) => Some(change),
+                Err(err) => return Err(err),
+            },
+            None => None,
+        };
+        let (problems, headers_checked) = check_entries(
+            self.reader.as_ref(),
+            entries.as_slice(),
+            headers,
+            self.name_encoding,
+        )
+        .await?;
+        Ok(IndexCheck {
+            entries: entries.len(),
+            changed,
+            problems,
+            headers_checked,
+        })
+    }
+
+    /// Builds the index from a local copy of the archive and saves it to the metadata path, if
+    /// there is one.
+    pub async fn index_from(&self, zip_path: impl AsRef<Path>) -> Result<Vec<FileMetadata>> {
+        let local_copy = LocalBackend::open(zip_path.as_ref())?;
+        let fingerprint = fingerprint(self.reader.as_ref()).await?;
+        let local_size = local_copy.size().await?;
+        if local_size != fingerprint.size {
+            return Err(CloudZipError::invalid_archive(format!(
+                "Local copy {} is {} bytes but the archive is {} bytes",
+                zip_path.as_ref().display(),
+                local_size,
+                fingerprint.size
+            )));
+        }
+        let list = build_index(&local_copy, self.name_encoding).await?;
+        self.save_index(list, fingerprint)
+    }
+
+    fn save_index(
+        &self,
+        list: Vec<FileMetadata>,
+        fingerprint: ArchiveFingerprint,
+    ) -> Result<Vec<FileMetadata>> {
+        let entries = EntryIndex::new(list).with_fingerprint(Some(fingerprint));
+        warn_conflicts(&entries);
+        if let Some(path) = &self.metadata_path {
+            #[cfg(feature = "zstd")]
+            if self.compress_index {
+                metadata::write_compressed_metadata(path, &entries, self.index_format)?;
+            } else {
+                metadata::write_metadata(path, &entries, self.index_format)?;
+            }
+            #[cfg(not(feature = "zstd"))]
+            metadata::write_metadata(path, &entries, self.index_format)?;
+        }
+        let list = entries.as_slice().to_vec();
+        *self.lock_entries() = Some(Arc::new(entries));
+        Ok(list)
+    }
+
+    fn lock_entries(&self) -> MutexGuard<'_, Option<Arc<EntryIndex>>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The index, read from the metadata path the first time it is needed.
+    pub fn entries(&self) -> Result<Arc<EntryIndex>> {
+        let mut entries = self.lock_entries();
+        if let Some(entries) = &*entries {
+            return Ok(entries.clone());
+        }
+        let path = self
+            .metadata_path
+            .as_ref()
+            .expect("an archive without an index file is opened with its index");
+        let loaded = Arc::new(metadata::read_metadata(path)?);
+        *entries = Some(loaded.clone());
+        Ok(loaded)
+    }
+
+    /// The index, once the archive has been checked to be the one it was built from and, in
+    /// strict mode, to hold no conflicting entries.
+    ///
+    /// The check costs one request to the backend per `CloudZip`; indexes that predate
+    /// fingerprints are trusted as they are.
+    pub async fn verified_entries(&self) -> Result<Arc<EntryIndex>> {
+        let entries = self.entries()?;
+        self.verified
+            .get_or_try_init(|| async {
+                if let Some(recorded) = entries.fingerprint() {
+                    recorded.check(&fingerprint(self.reader.as_ref()).await?)?;
+                    debug!("The archive is the one the index was built from");
+                }
+                if self.strict {
+                    let conflicts = entries.conflicts();
+                    if let Some(first) = conflicts.first() {
+                        return Err(CloudZipError::ConflictingEntries(match conflicts.len() {
+                            1 => first.to_string(),
+                            count => format!("{}, and {} more", first, count - 1),
+                        }));
+                    }
+                }
+                Ok::<_, CloudZipError>(())
+            })
+            .await?;
+        Ok(entries)
+    }
+
+    /// Stores the index as the sidecar next to the archive, where [`CloudZip::discover`]
+    /// finds it.
+    pub async fn upload_sidecar(&self) -> Result<()> {
+        let entries = self.entries()?;
+        #[cfg(feature = "zstd")]
+        let encoded = if self.compress_index {
+            metadata::encode_compressed_metadata(&entries, self.index_format)?
+        } else {
+            metadata::encode_metadata(&entries, self.index_format)?
+        };
+        #[cfg(not(feature = "zstd"))]
+        let encoded = metadata::encode_metadata(&entries, self.index_format)?;
+        self.reader.write_sidecar(encoded).await
+    }
+
+    /// Returns every entry recorded in the index.
+    pub fn list(&self) -> Result<Vec<FileMetadata>> {
+        Ok(self.entries()?.as_slice().to_vec())
+    }
+
+    /// Returns the indexed entries picked by `selector`.
+    pub fn list_matching(&self, selector: &EntrySelector) -> Result<Vec<FileMetadata>> {
+        Ok(self
+            .entries()?
+            .select(selector)
+            .into_iter()
+            .cloned()
+            .collect())
+    }
+
+    /// Opens a single entry for reading its decompressed bytes, without writing anything to disk.
+    pub async fn open_entry(&self, file_name: &str) -> Result<EntryReader> {
+        let entries = self.verified_entries().await?;
+        let metadata = entries.find(file_name)?;
+        if metadata.is_directory {
+            return Err(CloudZipError::InvalidEntryPath {
+                file_name: file_name.to_string(),
+                reason: "it is a directory",
+            });
+        }
+        let context = self.decode_context([metadata]);
+        context.budget.check_planned([metadata])?;
+        Ok(open_entry_reader(
+            self.reader.clone(),
+            metadata.clone(),
+            self.download,
+            Arc::new(context),
+        ))
+    }
+
+    /// Detects the content type of an entry from its first [`content_type::SNIFF_LEN`]
+    /// bytes, with [`content_type::detect`]; only those are read or decoded.
+    pub async fn detect_type(&self, file_name: &str) -> Result<Option<&'static str>> {
+        let mut prefix = Vec::new();
+        self.open_entry_range(file_name, 0..content_type::SNIFF_LEN)
+            .await?
+            .read_to_end(&mut prefix)
+            .await?;
+        Ok(content_type::detect(&prefix))
+    }
+
+    /// Reads the EXIF metadata and embedded thumbnail of a JPEG entry from its first bytes,
+    /// [`exif::EXIF_PROBE_LEN`] of them and more when the EXIF segment goes further; `None`
+    /// when the entry is not a JPEG image with EXIF metadata.
+    pub async fn read_exif(&self, file_name: &str) -> Result<Option<Exif>> {
+        let mut len = exif::EXIF_PROBE_LEN;
+        loop {
+            let mut prefix = Vec::new();
+            self.open_entry_range(file_name, 0..len)
+                .await?
+                .read_to_end(&mut prefix)
+                .await?;
+            match exif::find_segment(&prefix) {
+                ExifSegment::Found(tiff) => return Ok(exif::parse(&prefix[tiff])),
+                ExifSegment::Truncated(end)
+                    if prefix.len() as u64 == len && end <= exif::MAX_EXIF_PROBE_LEN =>
+                {
+                    len = end.max(len * 2).min(exif::MAX_EXIF_PROBE_LEN);
+                }
+                ExifSegment::Truncated(_) | ExifSegment::Missing => return Ok(None),
+            }
+        }
+    }
+
+    /// Writes the thumbnail of `exif`, read by [`CloudZip::read_exif`] from the entry
+    /// `file_name`, to where [`CloudZip::extract_to`] would write the entry, and returns the
+    /// written path, or `None` when there is no thumbnail or an existing file was kept.
+    pub async fn extract_thumbnail_to(
+        &self,
+        file_name: &str,
+        exif: &Exif,
+        output_dir: impl AsRef<Path>,
+    ) -> Result<Option<PathBuf>> {
+        let Some(thumbnail) = &exif.thumbnail else {
+            return Ok(None);
+        };
+        let output_file_path = self
+            .output_path(output_dir.as_ref(), file_name, false)?
+            .ok_or_else(|| CloudZipError::InvalidEntryPath {
+                file_name: file_name.to_string(),
+                reason: "nothing is left of its name after applying the path layout",
+            })?;
+        let Some(output_file_path) = self
+            .on_conflict
+            .resolve(output_file_path, &mut HashSet::new())?
+        else {
+            return Ok(None);
+        };
+        write_to_path(thumbnail.as_slice(), output_file_path)
+            .await
+            .map(Some)
+    }
+
+    /// Reads the schema and first `rows` rows of a tabular entry, in `format` or else the one
+    /// its name or first bytes show. CSV and JSON Lines entries are read from their start up
+    /// to the last row; of a Parquet entry, the footer at its end, which gives its schema and
+    /// row count, then the pages of each column up to the last row.
+    pub async fn preview(
+        &self,
+        file_name: &str,
+        format: Option<PreviewFormat>,
+        rows: usize,
+    ) -> Result<Preview> {
+        let format = match format.or_else(|| PreviewFormat::from_name(file_name)) {
+            Some(format) => format,
+            None => match self.detect_type(file_name).await? {
+                Some("application/vnd.apache.parquet") => PreviewFormat::Parquet,
+                Some("text/plain") => PreviewFormat::Csv,
+                _ => {
+                    return Err(CloudZipError::InvalidRequest(format!(
+                        "{} is not a CSV, JSON Lines or Parquet entry",
+                        file_name
+                    )))
+                }
+            },
+        };
+        match format {
+            PreviewFormat::Csv => {
+                preview::preview_csv(self.open_entry(file_name).await?, rows).await
+            }
+            PreviewFormat::Jsonl => {
+                preview::preview_jsonl(self.open_entry(file_name).await?, rows).await
+            }
+            PreviewFormat::Parquet => {
+                let size = self
+                    .verified_entries()
+                    .await?
+                    .find(file_name)?
+                    .uncompressed_size;
+                let tail_start = size.saturating_sub(preview::PARQUET_TAIL_LEN);
+                let mut tail = Vec::new();
+                self.open_entry_range(file_name, tail_start..size)
+                    .await?
+                    .read_to_end(&mut tail)
+                    .await?;
+                let footer_start = preview::parquet_footer_start(&tail, tail_start)?;
+                let mut footer = Vec::new();
+                self.open_entry_range(file_name, footer_start..tail_start)
+                    .await?
+                    .read_to_end(&mut footer)
+                    .await?;
+                let footer = preview::read_parquet_footer(&footer)?;
+                let rows = footer
+                    .rows(rows, |range| async move {
+                        let mut bytes = Vec::new();
+                        self.open_entry_range(file_name, range)
+                            .await?
+                            .read_to_end(&mut bytes)
+                            .await?;
+                        Ok(bytes)
+                    })
+                    .await?;
+                Ok(Preview {
+                    rows,
+                    ..footer.preview
+                })
+            }
+        }
+    }
+
+    /// Opens bytes `range` of the decompressed content of an entry, clamped to its size,
+    /// decoding as little as the entry allows: a stored entry is read with one ranged read, a
+    /// deflated one from the checkpoint before the range once [`CloudZip::entry_checkpoints`]
+    /// or [`CloudZip::use_entry_checkpoints`] gave it checkpoints, and any other from its
+    /// start up to the end of the range. The CRC-32 is not checked, as the content is not
+    /// read whole.
+    pub async fn open_entry_range(
+        &self,
+        file_name: &str,
+        range: Range<u64>,
+    ) -> Result<EntryReader> {
+        let entries = self.verified_entries().await?;
+        let metadata = entries.find(file_name)?;
+        let end = range.end.min(metadata.uncompressed_size);
+        let start = range.start.min(end);
+        if metadata.is_random_access() && !metadata.is_directory {
+            if self.check_headers {
+                check_local_header(self.reader.as_ref(), metadata, self.name_encoding).await?;
+            }
+            return Ok(range_entry_reader(
+                self.reader.clone(),
+                metadata.file_offset + start,
+                end - start,
+            ));
+        }
+        if metadata.is_checkpointable() {
+            let inflated = self.inflated(metadata);
+            if inflated.has_index() {
+                debug!(entry = file_name, start, end, "Decoding from a checkpoint");
+                return Ok(range_entry_reader(inflated, start, end - start));
+            }
+        }
+        Ok(skipping_entry_reader(
+            self.open_entry(file_name).await?,
+            start,
+            end - start,
+        ))
+    }
+
+    /// Random access to the content of an entry, such as one that is itself a zip, without
+    /// writing it to disk: a stored entry is read in place from this archive, a deflated one
+    /// of at least [`CHECKPOINTED_ENTRY_LEN`] bytes through [`CloudZip::entry_checkpoints`],
+    /// and any other is decompressed into memory.
+    pub async fn entry_reader(&self, file_name: &str) -> Result<Arc<dyn RangeReader>> {
+        let entries = self.verified_entries().await?;
+        let metadata = entries.find(file_name)?;
+        if metadata.compression_method == 0
+            && metadata.encryption == Encryption::None
+            && !metadata.is_directory
+        {
+            debug!(entry = file_name, "Reading the entry in place");
+            return Ok(Arc::new(SliceReader::new(
+                self.reader.clone(),
+                metadata.file_offset,
+                metadata.compressed_size,
+            )));
+        }
+        if metadata.is_checkpointable() && metadata.uncompressed_size >= CHECKPOINTED_ENTRY_LEN {
+            return Ok(self.inflated(metadata));
+        }
+        let mut bytes = Vec::new();
+        self.open_entry(file_name)
+            .await?
+            .read_to_end(&mut bytes)
+            .await?;
+        debug!(
+            entry = file_name,
+            size = bytes.len(),
+            "Decompressed the entry into memory"
+        );
+        Ok(Arc::new(MemoryBackend::new(bytes)))
+    }
+
+    /// The checkpoints of a deflated entry, from which any part of its content is decoded
+    /// without decoding everything before it. The first call decodes the whole entry to find
+    /// them; they are kept for later calls and for [`CloudZip::entry_reader`].
+    pub async fn entry_checkpoints(&self, file_name: &str) -> Result<Arc<GzipIndex>> {
+        let entries = self.verified_entries().await?;
+        self.checkpointable(entries.find(file_name)?)?.index().await
+    }
+
+    /// Uses checkpoints of a deflated entry found earlier, such as those of
+    /// [`CloudZip::entry_checkpoints`] stored with [`GzipIndex::encode`]; returns whether they
+    /// were found in the entry as it is now, as otherwise they are ignored.
+    pub async fn use_entry_checkpoints(&self, file_name: &str, index: GzipIndex) -> Result<bool> {
+        let entries = self.verified_entries().await?;
+        self.checkpointable(entries.find(file_name)?)?
+            .use_index(index)
+            .await
+    }
+
+    fn checkpointable(&self, metadata: &FileMetadata) -> Result<Arc<GzipReader>> {
+        if !metadata.is_checkpointable() {
+            return Err(CloudZipError::InvalidRequest(format!(
+                "{} is not a deflated, unencrypted file",
+                metadata.file_name
+            )));
+        }
+        Ok(self.inflated(metadata))
+    }
+
+    /// The reader of a deflated entry, shared by every call so that the checkpoints it finds
+    /// are only found once.
+    fn inflated(&self, metadata: &FileMetadata) -> Arc<GzipReader> {
+        let mut inflated = self.inflated.lock().unwrap_or_else(PoisonError::into_inner);
+        inflated
+            .entry(metadata.file_name.clone())
+            .or_insert_with(|| {
+                let data = SliceReader::new(
+                    self.reader.clone(),
+                    metadata.file_offset,
+                    metadata.compressed_size,
+                );
+                Arc::new(GzipReader::deflate(Arc::new(data), metadata.crc32))
+            })
+            .clone()
+    }
+
+    /// Opens an entry that is itself a zip as an archive of its own, indexing it from its
+    /// bytes as [`CloudZip::entry_reader`] gives them.
+    pub async fn open_nested(&self, file_name: &str) -> Result<CloudZip> {
+        Self::discover_with_names(self.entry_reader(file_name).await?, self.name_encoding).await
+    }
+
+    /// Extracts a single entry below the current directory and returns the written path, or
+    /// `None` when an existing file was kept.
+    pub async fn extract(&self, file_name: &str) -> Result<Option<PathBuf>> {
+        self.extract_to(file_name, ".").await
+    }
+
+    /// Extracts a single entry below `output_dir`, laid out according to the configured
+    /// [`PathLayout`], and returns the written path, or `None` when an existing file was kept
+    /// according to the [`ConflictPolicy`].
+    ///
+    /// A directory, whether stored as its own entry or only implied by the names below it,
+    /// is extracted together with everything it contains.
+    pub async fn extract_to(
+        &self,
+        file_name: &str,
+        output_dir: impl AsRef<Path>,
+    ) -> Result<Option<PathBuf>> {
+        let output_dir = output_dir.as_ref();
+        let entries = self.verified_entries().await?;
+        let metadata = match entries.find(file_name) {
+            Ok(metadata) if !metadata.is_directory => metadata,
+            Ok(_) | Err(CloudZipError::EntryNotFound(_)) => {
+                return self
+                    .extract_directory(&entries, file_name, output_dir)
+                    .await
+                    .map(Some)
+            }
+            Err(err) => return Err(err),
+        };
+
+        let output_file_path =
+            self.output_path(output_dir, file_name, false)?
+                .ok_or_else(|| CloudZipError::InvalidEntryPath {
+                    file_name: file_name.to_string(),
+                    reason: "nothing is left of its name after applying the path layout",
+                })?;
+        let Some(output_file_path) = self
+            .on_conflict
+            .resolve(output_file_path, &mut HashSet::new())?
+        else {
+            return Ok(None);
+        };
+        let context = self.decode_context([metadata]);
+        context.budget.check_planned([metadata])?;
+
+        if self.symlinks && metadata.is_symlink() {
+            return extract_symlink(
+                self.reader.as_ref(),
+                metadata,
+                output_dir,
+                output_file_path,
+                &context,
+            )
+            .await
+            .map(Some);
+        }
+        extract_to_path(
+            self.reader.as_ref(),
+            metadata,
+            output_file_path,
+            self.download,
+            Arc::new(context),
+            self.file_attributes(metadata),
+        )
+        .await
+        .map(Some)
+    }
+
+    /// Extracts bytes `range` of the content of a single entry, as
+    /// [`CloudZip::open_entry_range`] reads them, to where [`CloudZip::extract_to`] would write
+    /// the whole entry, and returns the written path, or `None` when an existing file was kept.
+    pub async fn extract_range_to(
+        &self,
+        file_name: &str,
+        range: Range<u64>,
+        output_dir: impl AsRef<Path>,
+    ) -> Result<Option<PathBuf>> {
+        let output_file_path = self
+            .output_path(output_dir.as_ref(), file_name, false)?
+            .ok_or_else(|| CloudZipError::InvalidEntryPath {
+                file_name: file_name.to_string(),
+                reason: "nothing is left of its name after applying the path layout",
+            })?;
+        let Some(output_file_path) = self
+            .on_conflict
+            .resolve(output_file_path, &mut HashSet::new())?
+        else {
+            return Ok(None);
+        };
+        let content = self.open_entry_range(file_name, range).await?;
+        write_to_path(content, output_file_path).await.map(Some)
+    }
+
+    /// Extracts every entry whose name starts with `prefix` into `output_dir` and returns the
+    /// written paths.
+    pub async fn extract_prefix(
+        &self,
+        prefix: &str,
+        output_dir: impl AsRef<Path>,
+    ) -> Result<Vec<PathBuf>> {
+        self.extract_matching(&EntrySelector::Prefix(prefix.to_string()), output_dir)
+            .await
+    }
+
+    /// Extracts every entry picked by `selector` into `output_dir`, laid out according to the
+    /// configured [`PathLayout`], and returns the written paths.
+    ///
+    /// Entries with nothing left of their name after applying the layout are skipped, as are
+    /// existing files kept by the [`ConflictPolicy`].
+    pub async fn extract_matching(
+        &self,
+        selector: &EntrySelector,
+        output_dir: impl AsRef<Path>,
+    ) -> Result<Vec<PathBuf>> {
+        self.extract_selected(selector, output_dir.as_ref(), self.on_conflict)
+            .await
+    }
+
+    /// Compares the entries picked by `selector` with the files below `output_dir` where
+    /// [`CloudZip::extract_matching`] would write them, without changing anything. A file of
+    /// the entry's size is up to date when it has the entry's modification time, where times
+    /// are preserved, or else its CRC-32; every local path no selected entry is written to is
+    /// extra.
+    pub async fn plan_sync(
+        &self,
+        selector: &EntrySelector,
+        output_dir: impl AsRef<Path>,
+        options: SyncOptions,
+    ) -> Result<SyncPlan> {
+        Ok(self
+            .compare_local(selector, output_dir.as_ref(), options)
+            .await?
+            .plan)
+    }
+
+    /// Brings `output_dir` in line with the entries picked by `selector`, as
+    /// [`CloudZip::plan_sync`] plans it: missing and changed entries are extracted over what
+    /// is there, whatever the [`ConflictPolicy`], files with the right content get the entry's
+    /// modification time, and with [`SyncOptions::delete`], extra paths are removed. Returns
+    /// the plan carried out.
+    pub async fn sync_to(
+        &self,
+        selector: &EntrySelector,
+        output_dir: impl AsRef<Path>,
+        options: SyncOptions,
+    ) -> Result<SyncPlan> {
+        let output_dir = output_dir.as_ref();
+        let comparison = self.compare_local(selector, output_dir, options).await?;
+        for path in &comparison.other_kind {
+            sync::remove_path(path).await?;
+        }
+        let outdated: HashSet<String> = comparison
+            .plan
+            .missing
+            .iter()
+            .chain(&comparison.plan.changed)
+            .cloned()
+            .collect();
+        if !outdated.is_empty() {
+            self.extract_selected(
+                &EntrySelector::Names(outdated),
+                output_dir,
+                ConflictPolicy::Overwrite,
+            )
+            .await?;
+        }
+        for (path, attributes) in &comparison.touched {
+            attributes.apply(&File::open(path)?)?;
+        }
+        if options.delete {
+            for path in &comparison.plan.extra {
+                sync::remove_path(path).await?;
+                debug!(path = %path.display(), "Removed");
+            }
+        }
+        Ok(comparison.plan)
+    }
+
+    async fn compare_local(
+        &self,
+        selector: &EntrySelector,
+        output_dir: &Path,
+        options: SyncOptions,
+    ) -> Result<LocalComparison> {
+        let entries = self.verified_entries().await?;
+        let selected = entries.select(selector);
+        if selected.is_empty() {
+            return Err(CloudZipError::EntryNotFound(selector.describe()));
+        }
+        let mut planned = Vec::with_capacity(selected.len());
+        for metadata in selected {
+            if let Some(path) =
+                self.output_path(output_dir, &metadata.file_name, metadata.is_directory)?
+            {
+                planned.push((metadata, path));
+            }
+        }
+        let mut comparison = LocalComparison::default();
+        let expected = planned.iter().map(|(_, path)| path.clone()).collect();
+        let mut compared = stream::iter(planned)
+            .map(|(metadata, path)| async move {
+                let attributes = self.file_attributes(metadata);
+                let local = sync::compare(
+                    metadata,
+                    &path,
+                    self.symlinks && metadata.is_symlink(),
+                    attributes.modified,
+                    options.checksum,
+                )
+                .await;
+                (metadata, path, attributes, local)
+            })
+            .buffered(self.concurrency);
+        while let Some((metadata, path, attributes, local)) = compared.next().await {
+            let name = metadata.file_name.clone();
+            match local? {
+                LocalFile::Missing => comparison.plan.missing.push(name),
+                LocalFile::Differs => comparison.plan.changed.push(name),
+                LocalFile::OtherKind => {
+                    comparison.plan.changed.push(name);
+                    comparison.other_kind.push(path);
+                }
+                LocalFile::Current => comparison.plan.unchanged += 1,
+                LocalFile::Touched => {
+                    comparison.plan.unchanged += 1;
+                    comparison.touched.push((path, attributes));
+                }
+            }
+        }
+        comparison.plan.extra = sync::extra_paths(output_dir, &expected).await?;
+        Ok(comparison)
+    }
+
+    async fn extract_selected(
+        &self,
+        selector: &EntrySelector,
+        output_dir: &Path,
+        on_conflict: ConflictPolicy,
+    ) -> Result<Vec<PathBuf>> {
+        let entries = self.verified_entries().await?;
+        let selected = entries.select(selector);
+        if selected.is_empty() {
+            return Err(CloudZipError::EntryNotFound(selector.describe()));
+        }
+
+        let state = self
+            .resume_state
+            .as_deref()
+            .map(JobState::open)
+            .transpose()?
+            .map(Arc::new);
+        if let Some(state) = state.as_ref().filter(|state| state.finished_len() > 0) {
+            info!(
+                finished = state.finished_len(),
+                "Resuming an interrupted extraction"
+            );
+        }
+
+        // Every name is checked before anything is written, so one unsafe entry or conflict
+        // aborts the whole batch instead of leaving it half extracted.
+        let mut planned = Vec::with_capacity(selected.len());
+        let mut claimed = HashSet::new();
+        for metadata in selected {
+            let Some(output_path) =
+                self.output_path(output_dir, &metadata.file_name, metadata.is_directory)?
+            else {
+                continue;
+            };
+            if let Some(state) = &state {
+                if !metadata.is_directory && state.is_finished(metadata, &output_path) {
+                    debug!(entry = %metadata.file_name, "Already extracted");
+                    claimed.insert(output_path);
+                    continue;
+                }
+            }
+            let output_path = if metadata.is_directory {
+                Some(output_path)
+            } else {
+                on_conflict.resolve(output_path, &mut claimed)?
+            };
+            if let Some(output_path) = output_path {
+                planned.push((metadata, output_path));
+            }
+        }
+
+        let context = Arc::new(self.decode_context(planned.iter().map(|(metadata, _)| *metadata)));
+        context
+            .budget
+            .check_planned(planned.iter().map(|(metadata, _)| *metadata))?;
+
+        let reader = self.batch_reader(planned.iter().map(|(metadata, _)| *metadata));
+        let semaphore = Arc::new(Semaphore::new(self.concurrency));
+        let mut tasks = JoinSet::new();
+        let mut directories = Vec::new();
+        let mut symlinks = Vec::new();
+        let mut firsts = HashMap::new();
+        let mut duplicates = Vec::new();
+        for (index, (metadata, output_path)) in planned.into_iter().enumerate() {
+            if metadata.is_directory {
+                std::fs::create_dir_all(&output_path)?;
+                directories.push((output_path, self.file_attributes(metadata)));
+                continue;
+            }
+            if self.symlinks && metadata.is_symlink() {
+                symlinks.push((index, metadata, output_path));
+                continue;
+            }
+            if let Some(key) = metadata.content_key().filter(|_| self.link_duplicates) {
+                if let Some(&first) = firsts.get(&key) {
+                    duplicates.push((index, first, metadata, output_path));
+                    continue;
+                }
+                firsts.insert(key, index);
+            }
+            let reader = reader.clone();
+            let semaphore = semaphore.clone();
+            let metadata = metadata.clone();
+            let download = self.download;
+            let context = context.clone();
+            let attributes = self.file_attributes(&metadata);
+            let state = state.clone();
+            let entry = self.entry_metrics(&metadata);
+            let span = Self::entry_span(&metadata);
+            tasks.spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                let extracted = async {
+                    match &state {
+                        Some(state) => {
+                            let path = resume_to_path(
+                                reader.as_ref(),
+                                &metadata,
+                                output_path,
+                                download,
+                                context,
+                                attributes,
+                            )
+                            .await?;
+                            state.record(&metadata)?;
+                            Ok::<_, CloudZipError>(path)
+                        }
+                        None => {
+                            extract_to_path(
+                                reader.as_ref(),
+                                &metadata,
+                                output_path,
+                                download,
+                                context,
+                                attributes,
+                            )
+                            .await
+                        }
+                    }
+                };
+                let path = metrics::measured(entry, extracted.instrument(span)).await?;
+                Ok::<_, CloudZipError>((index, path))
+            });
+        }
+
+        // Dropping the set on the first failure aborts the extractions still in flight.
+        let mut written = Vec::with_capacity(tasks.len());
+        while let Some(joined) = tasks.join_next().await {
+            written.push(joined.map_err(std::io::Error::other)??);
+        }
+
+        // Duplicates are linked to the first of their kind once it has been written.
+        let firsts: HashMap<usize, PathBuf> = written.iter().cloned().collect();
+        for (index, first, metadata, output_path) in duplicates {
+            let reader = reader.clone();
+            let semaphore = semaphore.clone();
+            let metadata = metadata.clone();
+            let original = firsts[&first].clone();
+            let download = self.download;
+            let context = context.clone();
+            let attributes = self.file_attributes(&metadata);
+            let state = state.clone();
+            let entry = self.entry_metrics(&metadata);
+            let span = Self::entry_span(&metadata);
+            tasks.spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                let linked = link_duplicate(
+                    reader.as_ref(),
+                    &metadata,
+                    &original,
+                    output_path,
+                    download,
+                    context,
+                    attributes,
+                );
+                let path = metrics::measured(entry, linked.instrument(span)).await?;
+                if let Some(state) = &state {
+                    state.record(&metadata)?;
+                }
+                Ok::<_, CloudZipError>((index, path))
+            });
+        }
+        while let Some(joined) = tasks.join_next().await {
+            written.push(joined.map_err(std::io::Error::other)??);
+        }
+
+        // Links come after every file so none of them can redirect a later write.
+        for (index, metadata, output_path) in symlinks {
+            let path = extract_symlink(
+                self.reader.as_ref(),
+                metadata,
+                output_dir,
+                output_path,
+                &context,
+            )
+            .await?;
+            if let Some(state) = &state {
+                state.record(metadata)?;
+            }
+            written.push((index, path));
+        }
+        written.sort_unstable_by_key(|(index, _)| *index);
+
+        // Directory attributes are set last, since writing the files inside them bumps their
+        // times and a read-only mode would block those writes. Not every platform can open a
+        // directory for this, so failures are ignored.
+        for (path, attributes) in directories {
+            let _ = File::open(&path).and_then(|dir| attributes.apply(&dir));
+        }
+        if let Some(state) = state.and_then(Arc::into_inner) {
+            state.finish()?;
+        }
+
+        Ok(written.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// Uploads every file picked by `selector` to S3 below `destination`, laid out according
+    /// to the configured [`PathLayout`], without writing anything to local disk.
+    ///
+    /// Each entry is decompressed straight into an upload, multipart for entries larger than
+    /// one part, so memory holds a part per concurrent entry. Existing objects are
+    /// overwritten; directories and entries with nothing left of their name are skipped.
+    pub async fn extract_to_s3(
+        &self,
+        selector: &EntrySelector,
+        client: &Client,
+        destination: &S3Destination,
+    ) -> Result<ExtractedObjects> {
+        let entries = self.verified_entries().await?;
+        let selected = entries.select(selector);
+        if selected.is_empty() {
+            return Err(CloudZipError::EntryNotFound(selector.describe()));
+        }
+
+        let mut planned = Vec::with_capacity(selected.len());
+        for metadata in selected.into_iter().filter(|meta| !meta.is_directory) {
+            if let Some(path) = self.layout.relative_path(&metadata.file_name, false)? {
+                planned.push((metadata, destination.key(&path)));
+            }
+        }
+
+        let context = Arc::new(self.decode_context(planned.iter().map(|(metadata, _)| *metadata)));
+        context
+            .budget
+            .check_planned(planned.iter().map(|(metadata, _)| *metadata))?;
+
+        let reader = self.batch_reader(planned.iter().map(|(metadata, _)| *metadata));
+        let semaphore = Arc::new(Semaphore::new(self.concurrency));
+        let mut tasks = JoinSet::new();
+        for (index, (metadata, key)) in planned.into_iter().enumerate() {
+            let measured = self.entry_metrics(metadata);
+            let span = Self::entry_span(metadata);
+            let entry = span.in_scope(|| {
+                metrics::within_blocking(measured.clone(), || {
+                    open_entry_reader(
+                        reader.clone(),
+                        metadata.clone(),
+                        self.download,
+                        context.clone(),
+                    )
+                })
+            });
+            let semaphore = semaphore.clone();
+            let client = client.clone();
+            let bucket = destination.bucket.clone();
+            let size = metadata.uncompressed_size;
+            tasks.spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                let upload = s3_output::upload(&client, &bucket, &key, entry);
+                metrics::measured(measured, upload.instrument(span)).await?;
+                Ok::<_, CloudZipError>((index, format!("s3://{}/{}", bucket, key), size))
+            });
+        }
+
+        // Dropping the set on the first failure aborts the uploads still in flight.
+        let mut uploaded = Vec::with_capacity(tasks.len());
+        while let Some(joined) = tasks.join_next().await {
+            uploaded.push(joined.map_err(std::io::Error::other)??);
+        }
+        uploaded.sort_unstable_by_key(|(index, _, _)| *index);
+        Ok(ExtractedObjects {
+            bytes: uploaded.iter().map(|(_, _, size)| size).sum(),
+            entries: uploaded.into_iter().map(|(_, uri, _)| uri).collect(),
+        })
+    }
+
+    /// Extracts the entries listed in a manifest below `output_dir`, each to its own
+    /// destination when the manifest names one, and reports on every entry in manifest order.
+    ///
+    /// Destinations are checked like entry names: one that is absolute or climbs out of
+    /// `output_dir` fails its entry.
+    ///
+    /// Unlike [`extract_matching`](Self::extract_matching), a missing entry, a rejected
+    /// destination or a failed download does not stop the rest of the batch. The
+    /// [`ExtractLimits`] still apply to the whole batch, though: when the planned entries
+    /// exceed them, the manifest is rejected up front and nothing is extracted. `on_done` is
+    /// called as each entry finishes, in completion order.
+    pub async fn extract_manifest(
+        &self,
+        manifest: &[ManifestEntry],
+        output_dir: impl AsRef<Path>,
+        mut on_done: impl FnMut(&ExtractOutcome),
+    ) -> Result<Vec<ExtractOutcome>> {
+        let output_dir = output_dir.as_ref();
+        let entries = self.verified_entries().await?;
+        let mut outcomes: Vec<Option<ExtractOutcome>> = manifest.iter().map(|_| None).collect();
+        let mut finish = |index: usize, result: Result<Option<PathBuf>>| {
+            let outcome = ExtractOutcome {
+                file_name: manifest[index].name.clone(),
+                result,
+            };
+            on_done(&outcome);
+            outcomes[index] = Some(outcome);
+        };
+
+        let mut planned = Vec::with_capacity(manifest.len());
+        let mut claimed = HashSet::new();
+        for (index, item) in manifest.iter().enumerate() {
+            let metadata = match entries.find(&item.name) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    finish(index, Err(err));
+                    continue;
+                }
+            };
+            let output_path = match &item.destination {
+                Some(destination) => self.destination_path(output_dir, &item.name, destination),
+                None => self.output_path(output_dir, &item.name, metadata.is_directory),
+            };
+            let output_path = match output_path {
+                Ok(Some(path)) if metadata.is_directory => Ok(Some(path)),
+                Ok(Some(path)) => self.on_conflict.resolve(path, &mut claimed),
+                other => other,
+            };
+            match output_path {
+                Ok(Some(output_path)) => planned.push((index, metadata, output_path)),
+                other => finish(index, other),
+            }
+        }
+
+        let context =
+            Arc::new(self.decode_context(planned.iter().map(|(_, metadata, _)| *metadata)));
+        context
+            .budget
+            .check_planned(planned.iter().map(|(_, metadata, _)| *metadata))?;
+
+        let reader = self.batch_reader(planned.iter().map(|(_, metadata, _)| *metadata));
+        let semaphore = Arc::new(Semaphore::new(self.concurrency));
+        let mut tasks = JoinSet::new();
+        let mut symlinks = Vec::new();
+        for (index, metadata, output_path) in planned {
+            if metadata.is_directory {
+                let created = std::fs::create_dir_all(&output_path)
+                    .map(|()| Some(output_path))
+                    .map_err(CloudZipError::from);
+                finish(index, created);
+                continue;
+            }
+            if self.symlinks && metadata.is_symlink() {
+                symlinks.push((index, metadata, output_path));
+                continue;
+            }
+            let reader = reader.clone();
+            let semaphore = semaphore.clone();
+            let metadata = metadata.clone();
+            let download = self.download;
+            let context = context.clone();
+            let attributes = self.file_attributes(&metadata);
+            let entry = self.entry_metrics(&metadata);
+            let span = Self::entry_span(&metadata);
+            tasks.spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                let extracted = extract_to_path(
+                    reader.as_ref(),
+                    &metadata,
+                    output_path,
+                    download,
+                    context,
+                    attributes,
+                );
+                let result = metrics::measured(entry, extracted.instrument(span)).await;
+                (index, result.map(Some))
+            });
+        }
+
+        while let Some(joined) = tasks.join_next().await {
+            let (index, result) = joined.map_err(std::io::Error::other)?;
+            finish(index, result);
+        }
+        for (index, metadata, output_path) in symlinks {
+            let result = extract_symlink(
+                self.reader.as_ref(),
+                metadata,
+                output_dir,
+                output_path,
+                &context,
+            )
+            .await;
+            finish(index, result.map(Some));
+        }
+
+        Ok(outcomes
+            .into_iter()
+            .map(|outcome| outcome.expect("every manifest entry has an outcome"))
+            .collect())
+    }
+
+    /// Downloads and decodes every file entry matched by `selector` without writing anything,
+    /// checking each against its recorded CRC-32.
+    ///
+    /// A failing entry does not stop the others; the results come back in index order.
+    pub async fn verify(&self, selector: &EntrySelector) -> Result<Vec<EntryCheck>> {
+        let entries = self.verified_entries().await?;
+        let selected = entries.select(selector);
+        if selected.is_empty() {
+            return Err(CloudZipError::EntryNotFound(selector.describe()));
+        }
+
+        let context = Arc::new(self.decode_context(selected.iter().copied()));
+        let reader = self.batch_reader(selected.iter().copied());
+        let semaphore = Arc::new(Semaphore::new(self.concurrency));
+        let mut tasks = JoinSet::new();
+        for (index, metadata) in selected.into_iter().enumerate() {
+            if metadata.is_directory {
+                continue;
+            }
+            let reader = reader.clone();
+            let semaphore = semaphore.clone();
+            let metadata = metadata.clone();
+            let download = self.download;
+            let context = context.clone();
+            let entry = self.entry_metrics(&metadata);
+            let span = debug_span!("verify", entry = %metadata.file_name);
+            tasks.spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                let verified = verify_entry(reader.as_ref(), &metadata, download, context);
+                let result = metrics::measured(entry, verified.instrument(span)).await;
+                (
+                    index,
+                    EntryCheck {
+                        file_name: metadata.file_name,
+                        result,
+                    },
+                )
+            });
+        }
+
+        let mut checks = Vec::with_capacity(tasks.len());
+        while let Some(joined) = tasks.join_next().await {
+            checks.push(joined.map_err(std::io::Error::other)?);
+        }
+        checks.sort_unstable_by_key(|(index, _)| *index);
+        Ok(checks.into_iter().map(|(_, check)| check).collect())
+    }
+
+    /// Searches the decompressed content of the file entries picked by `selector` for lines
+    /// matching `regex`, up to the concurrency limit at a time. Results come in archive order,
+    /// each as soon as its entry and those before it have been searched.
+    pub async fn grep<'a>(
+        &'a self,
+        selector: &EntrySelector,
+        regex: &'a Regex,
+        context: GrepContext,
+    ) -> Result<BoxStream<'a, EntryMatches>> {
+        let results = self
+            .read_each(selector, move |content| {
+                grep::search(content, regex, context)
+            })
+            .await?;
+        Ok(results
+            .map(|(file_name, result)| EntryMatches { file_name, result })
+            .boxed())
+    }
+
+    /// Hashes the decompressed content of the file entries picked by `selector`, up to the
+    /// concurrency limit at a time, checking each CRC-32 on the way. Results come in archive
+    /// order.
+    pub async fn digest<'a>(
+        &'a self,
+        selector: &EntrySelector,
+        algorithm: HashAlgorithm,
+    ) -> Result<BoxStream<'a, EntryDigest>> {
+        let results = self
+            .read_each(selector, move |content| digest::digest(content, algorithm))
+            .await?;
+        Ok(results
+            .map(|(file_name, result)| EntryDigest { file_name, result })
+            .boxed())
+    }
+
+    /// Opens the file entries picked by `selector` and hands each to `read`, up to the
+    /// concurrency limit at a time, yielding what it returns in archive order.
+    async fn read_each<'a, T, F, Fut>(
+        &'a self,
+        selector: &EntrySelector,
+        read: F,
+    ) -> Result<BoxStream<'a, (String, Result<T>)>>
+    where
+        T: Send + 'a,
+        F: Fn(EntryReader) -> Fut + Send + Sync + 'a,
+        Fut: Future<Output = std::io::Result<T>> + Send + 'a,
+    {
+        let entries = self.verified_entries().await?;
+        let selected: Vec<String> = entries
+            .select(selector)
+            .into_iter()
+            .filter(|metadata| !metadata.is_directory)
+            .map(|metadata| metadata.file_name.clone())
+            .collect();
+        if selected.is_empty() {
+            return Err(CloudZipError::EntryNotFound(selector.describe()));
+        }
+        let read = Arc::new(read);
+        Ok(stream::iter(selected)
+            .map(move |file_name| {
+                let read = read.clone();
+                async move {
+                    let result = match self.open_entry(&file_name).await {
+                        Ok(content) => read(content).await.map_err(CloudZipError::from),
+                        Err(e) => Err(e),
+                    };
+                    (file_name, result)
+                }
+            })
+            .buffered(self.concurrency)
+            .boxed())
+    }
+
+    /// Counts the requests and bytes extracting the entries picked by `selector` would take
+    /// with the current download options, without downloading any of them.
+    ///
+    /// Requests made to find and check the index are not included; they have been made by the
+    /// time this returns.
+    pub async fn estimate(&self, selector: &EntrySelector) -> Result<TransferEstimate> {
+        let entries = self.verified_entries().await?;
+        let selected = entries.select(selector);
+        if selected.is_empty() {
+            return Err(CloudZipError::EntryNotFound(selector.describe()));
+        }
+
+        let mut estimate = TransferEstimate::default();
+        for metadata in selected.iter().filter(|metadata| !metadata.is_directory) {
+            estimate.entries += 1;
+            estimate.bytes_written += metadata.uncompressed_size;
+            if self.symlinks && metadata.is_symlink() {
+                estimate.requests += u64::from(metadata.compressed_size > 0);
+                estimate.bytes_downloaded += metadata.compressed_size;
+            }
+        }
+        let DownloadOptions {
+            part_size,
+            concurrency,
+            coalesce_gap,
+        } = self.download;
+        for group in group_ranges(
+            self.batch_ranges(selected.iter().copied()),
+            coalesce_gap,
+            part_size,
+        ) {
+            estimate.bytes_downloaded += group.len;
+            estimate.requests += match group.len {
+                0 => 0,
+                len if group.members == 1 && len > part_size && concurrency > 1 => {
+                    len.div_ceil(part_size)
+                }
+                _ => 1,
+            };
+        }
+        Ok(estimate)
+    }
+
+    /// The byte ranges a batch extracting `planned` reads through the batch reader, in order.
+    fn batch_ranges<'a>(
+        &self,
+        planned: impl IntoIterator<Item = &'a FileMetadata>,
+    ) -> Vec<(u64, u64)> {
+        planned
+            .into_iter()
+            .filter(|metadata| !(metadata.is_directory || self.symlinks && metadata.is_symlink()))
+            .map(|metadata| (metadata.file_offset, metadata.compressed_size))
+            .collect()
+    }
+
+    /// The reader for a batch extracting `planned` in this order, which fetches runs of small
+    /// neighbouring entries with one request each.
+    fn batch_reader<'a>(
+        &self,
+        planned: impl IntoIterator<Item = &'a FileMetadata>,
+    ) -> Arc<dyn RangeReader> {
+        CoalescingReader::wrap(
+            self.reader.clone(),
+            self.batch_ranges(planned),
+            self.download.coalesce_gap,
+            self.download.part_size,
+        )
+    }
+
+    /// The reader for copying the stored bytes of `planned` in this order, fetching runs of
+    /// neighbouring entries with one request each.
+    pub(crate) fn raw_reader<'a>(
+        &self,
+        planned: impl IntoIterator<Item = &'a FileMetadata>,
+    ) -> Arc<dyn RangeReader> {
+        CoalescingReader::wrap(
+            self.reader.clone(),
+            planned
+                .into_iter()
+                .filter(|metadata| !metadata.is_directory)
+                .map(|metadata| (metadata.file_offset, metadata.compressed_size)),
+            self.download.coalesce_gap,
+            self.download.part_size,
+        )
+    }
+
+    /// Where the configured layout writes the entry `file_name` below `output_dir`, with names
+    /// Windows would refuse rewritten when asked to.
+    fn output_path(
+        &self,
+        output_dir: &Path,
+        file_name: &str,
+        is_directory: bool,
+    ) -> Result<Option<PathBuf>> {
+        let Some(path) = self.layout.relative_path(file_name, is_directory)? else {
+            return Ok(None);
+        };
+        Ok(Some(self.below(output_dir, file_name, path)))
+    }
+
+    /// Where the manifest puts `file_name`, at `destination` below `output_dir`.
+    fn destination_path(
+        &self,
+        output_dir: &Path,
+        file_name: &str,
+        destination: &Path,
+    ) -> Result<Option<PathBuf>> {
+        let destination = destination.to_string_lossy();
+        let path: PathBuf = safe_components(&destination)?.into_iter().collect();
+        if path.as_os_str().is_empty() {
+            return Err(CloudZipError::InvalidEntryPath {
+                file_name: destination.into_owned(),
+                reason: "the destination names no file",
+            });
+        }
+        Ok(Some(self.below(output_dir, file_name, path)))
+    }
+
+    /// `path` below `output_dir`, renamed when Windows would not accept it.
+    fn below(&self, output_dir: &Path, file_name: &str, path: PathBuf) -> PathBuf {
+        if self.windows_names {
+            if let Some(renamed) = windows_safe(&path) {
+                warn!(
+                    entry = file_name,
+                    path = %renamed.display(),
+                    "Renamed the entry to a name Windows accepts"
+                );
+                return output_dir.join(renamed);
+            }
+        }
+        output_dir.join(path)
+    }
+
+    /// Everything decoding needs, with progress counted against the `planned` entries.
+    fn decode_context<'a>(
+        &self,
+        planned: impl IntoIterator<Item = &'a FileMetadata>,
+    ) -> DecodeContext {
+        DecodeContext {
+            budget: Budget::new(self.limits),
+            password: self.password.clone(),
+            check_headers: self.check_headers,
+            names: self.name_encoding,
+            progress: ProgressTracker::new(self.progress.clone(), planned),
+            cancel: self.cancel.clone(),
+        }
+    }
+
+    fn file_attributes(&self, metadata: &FileMetadata) -> FileAttributes {
+        FileAttributes {
+            modified: self
+                .preserve_times
+                .then(|| metadata.modified_time())
+                .flatten(),
+            permissions: self
+                .preserve_permissions
+                .then(|| metadata.permissions())
+                .flatten(),
+        }
+    }
+
+    async fn extract_directory(
+        &self,
+        entries: &EntryIndex,
+        file_name: &str,
+        output_dir: &Path,
+    ) -> Result<PathBuf> {
+        let directory = format!("{}/", file_name.trim_end_matches('/'));
+        if !entries
+            .as_slice()
+            .iter()
+            .any(|meta| meta.file_name.starts_with(&directory))
+        {
+            return Err(CloudZipError::EntryNotFound(file_name.to_string()));
+        }
+
+        self.extract_matching(&EntrySelector::Prefix(directory.clone()), output_dir)
+            .await?;
+        Ok(self
+            .output_path(output_dir, &directory, true)?
+            .unwrap_or_else(|| output_dir.to_path_buf()))
+    }
+}
+
+/// Outcome of extracting a single entry with [`CloudZip::extract_manifest`].
+#[derive(Debug)]
+pub struct ExtractOutcome {
+    pub file_name: String,
+    /// The written path, `None` when the entry was skipped, or why it could not be extracted.
+    pub result: Result<Option<PathBuf>>,
+}
+
+/// Where the local files [`CloudZip::sync_to`] looked at stand.
+#[derive(Default)]
+struct LocalComparison {
+    plan: SyncPlan,
+    /// Paths of changed entries taken by a directory where a file belongs, or the other way
+    /// around, removed before extracting.
+    other_kind: Vec<PathBuf>,
+    /// Files with the right content but another modification time.
+    touched: Vec<(PathBuf, FileAttributes)>,
+}
+
+/// What [`CloudZip::update_index`] did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IndexUpdate {
+    pub entries: usize,
+    /// Entries kept from the previous index instead of being read again; none when the index
+    /// was rebuilt.
+    pub reused: usize,
+}
+
+/// What [`CloudZip::check_index`] found.
+#[derive(Debug, Clone, Default)]
+pub struct IndexCheck {
+    pub entries: usize,
+    /// How the archive changed since the index was built, if it did.
+    pub changed: Option<String>,
+    /// Entries the index gets wrong, in archive order, then those whose local header disagrees.
+    pub problems: Vec<IndexProblem>,
+    pub headers_checked: usize,
+}
+
+impl IndexCheck {
+    /// Whether the index can still be used on the archive as it is.
+    pub fn is_sound(&self) -> bool {
+        self.changed.is_none() && self.problems.is_empty()
+    }
+}
+
+/// An entry [`CloudZip::check_index`] found wrong in the index.
+#[derive(Debug, Clone)]
+pub struct IndexProblem {
+    pub file_name: String,
+    pub reason: String,
+}
+
+/// What extracting a selection would transfer, as computed by [`CloudZip::estimate`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransferEstimate {
+    /// Files and symlinks; directories take no requests.
+    pub entries: usize,
+    /// Ranged GET requests, after coalescing neighbouring entries and splitting large ones.
+    pub requests: u64,
+    /// Bytes downloaded, including what coalesced requests read between entries.
+    pub bytes_downloaded: u64,
+    /// Decompressed bytes written out.
+    pub bytes_written: u64,
+}
+
+/// Outcome of verifying a single entry with [`CloudZip::verify`].
+#[derive(Debug)]
+pub struct EntryCheck {
+    pub file_name: String,
+    /// Number of bytes decoded, or why the entry could not be read back intact.
+    pub result: Result<u64>,
+}
+
+/// Outcome of searching a single entry with [`CloudZip::grep`].
+#[derive(Debug)]
+pub struct EntryMatches {
+    pub file_name: String,
+    pub result: Result<GrepMatches>,
+}
+
+/// Outcome of hashing a single entry with [`CloudZip::digest`].
+#[derive(Debug)]
+pub struct EntryDigest {
+    pub file_name: String,
+    pub result: Result<Vec<u8>>,
+}
+
+/// Reads what identifies the current version of the archive.
+async fn fingerprint(reader: &dyn RangeReader) -> Result<ArchiveFingerprint> {
+    Ok(ArchiveFingerprint {
+        size: reader.size().await?,
+        etag: reader.etag().await?,
+        version_id: reader.version_id().await?,
+    })
+}
+
+/// Conflicts logged when an archive is indexed; the rest are only counted.
+const REPORTED_CONFLICTS: usize = 10;
+
+/// Logs the conflicting entries of a freshly built index, which extraction in strict mode
+/// refuses.
+fn warn_conflicts(entries: &EntryIndex) {
+    let conflicts = entries.conflicts();
+    for conflict in conflicts.iter().take(REPORTED_CONFLICTS) {
+        warn!(%conflict, "Conflicting entries in the archive");
+    }
+    if conflicts.len() > REPORTED_CONFLICTS {
+        warn!(
+            more = conflicts.len() - REPORTED_CONFLICTS,
+            "More conflicting entries in the archive"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+
+    #[tokio::test]
+    async fn manifest_destinations_stay_inside_the_output_directory() {
+        let backend = MemoryBackend::zip([("a.txt", "a"), ("b.txt", "b")])
+            .await
+            .unwrap();
+        let archive = CloudZip::discover(Arc::new(backend)).await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let output_dir = dir.path().join("out");
+        let item = |name: &str, destination: &str| ManifestEntry {
+            name: name.to_string(),
+            destination: Some(destination.into()),
+        };
+        let manifest = [
+            item("a.txt", "../escaped.txt"),
+            item("a.txt", "/tmp/absolute.txt"),
+            item("b.txt", "kept/b.txt"),
+        ];
+        let outcomes = archive
+            .extract_manifest(&manifest, &output_dir, |_| {})
+            .await
+            .unwrap();
+        assert!(matches!(
+            outcomes[0].result,
+            Err(CloudZipError::InvalidEntryPath { .. })
+        ));
+        assert!(matches!(
+            outcomes[1].result,
+            Err(CloudZipError::InvalidEntryPath { .. })
+        ));
+        let written = outcomes[2].result.as_ref().unwrap().clone().unwrap();
+        assert_eq!(written, output_dir.join("kept").join("b.txt"));
+        assert_eq!(std::fs::read(written).unwrap(), b"b");
+        assert!(!dir.path().join("escaped.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn entries_renamed_for_windows_to_the_same_name_conflict() {
+        let backend = MemoryBackend::zip([("a?.txt", "first"), ("a*.txt", "second")])
+            .await
+            .unwrap();
+        let archive = CloudZip::discover(Arc::new(backend))
+            .await
+            .unwrap()
+            .with_windows_names(true);
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            archive
+                .extract_matching(&EntrySelector::All, dir.path().join("fail"))
+                .await,
+            Err(CloudZipError::OutputExists(_))
+        ));
+
+        let output_dir = dir.path().join("rename");
+        archive
+            .with_conflict_policy(ConflictPolicy::Rename)
+            .extract_matching(&EntrySelector::All, &output_dir)
+            .await
+            .unwrap();
+        let mut contents = [
+            std::fs::read_to_string(output_dir.join("a_.txt")).unwrap(),
+            std::fs::read_to_string(output_dir.join("a_ (1).txt")).unwrap(),
+        ];
+        contents.sort();
+        assert_eq!(contents, ["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn symlinks_with_oversized_data_are_rejected_before_reading() {
+        let backend = Arc::new(MemoryBackend::zip([("link", "target")]).await.unwrap());
+        let mut list = CloudZip::discover(backend.clone())
+            .await
+            .unwrap()
+            .list()
+            .unwrap();
+        list[0].unix_mode = Some(0o120777);
+        list[0].compressed_size = 1 << 40;
+        let archive = CloudZip::from_index(backend, list).with_symlinks(true);
+        let dir = tempfile::tempdir().unwrap();
+        let result = archive.extract_to("link", dir.path()).await;
+        assert!(matches!(
+            result,
+            Err(CloudZipError::InvalidEntryPath {
+                reason: "the symlink target is too long",
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,204 @@
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use sha2::Sha256;
+use std::time::SystemTime;
+use tokio::sync::OnceCell;
+
+use super::{response_stream, ByteStream, RangeReader};
+use crate::error::{CloudZipError, Result};
+
+const API_VERSION: &str = "2021-08-06";
+
+/// Characters left as-is in blob paths; everything else is percent-encoded.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+#[derive(Clone)]
+pub enum AzureCredential {
+    /// A shared access signature, with or without the leading `?`.
+    SasToken(String),
+    /// The base64 storage account key, used to sign requests with Shared Key.
+    AccountKey(String),
+    /// For containers with public read access.
+    Anonymous,
+}
+
+#[derive(Clone)]
+pub struct AzureConfig {
+    pub account: String,
+    pub credential: AzureCredential,
+    /// Overrides `https://<account>.blob.core.windows.net`, e.g. for Azurite.
+    pub endpoint: Option<String>,
+}
+
+/// A blob in Azure Blob Storage, read with ranged GETs.
+pub struct AzureBackend {
+    http: reqwest::Client,
+    config: AzureConfig,
+    container: String,
+    blob: String,
+    head: OnceCell<(u64, Option<String>)>,
+}
+
+impl AzureBackend {
+    pub fn new(config: AzureConfig, container: impl Into<String>, blob: impl Into<String>) -> Self {
+        AzureBackend {
+            http: reqwest::Client::new(),
+            config,
+            container: container.into(),
+            blob: blob.into(),
+            head: OnceCell::new(),
+        }
+    }
+
+    fn blob_url(&self) -> String {
+        let endpoint = match &self.config.endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!("https://{}.blob.core.windows.net", self.config.account),
+        };
+        format!(
+            "{}/{}/{}",
+            endpoint,
+            self.container,
+            utf8_percent_encode(&self.blob, PATH_SEGMENT)
+        )
+    }
+
+    /// Builds a request carrying the credential, signed with Shared Key when an account key is set.
+    fn request(&self, method: Method, ms_headers: &[(&str, String)]) -> Result<RequestBuilder> {
+        let url = self.blob_url();
+        let date = httpdate::fmt_http_date(SystemTime::now());
+
+        let mut headers = vec![
+            ("x-ms-date", date),
+            ("x-ms-version", API_VERSION.to_string()),
+        ];
+        headers.extend(ms_headers.iter().cloned());
+        headers.sort_by(|a, b| a.0.cmp(b.0));
+
+        let request_url = match &self.config.credential {
+            AzureCredential::SasToken(sas) => format!("{}?{}", url, sas.trim_start_matches('?')),
+            _ => url.clone(),
+        };
+        let mut request = self.http.request(method.clone(), request_url);
+        for (name, value) in &headers {
+            request = request.header(*name, value);
+        }
+
+        if let AzureCredential::AccountKey(key) = &self.config.credential {
+            let canonical_headers: String = headers
+                .iter()
+                .map(|(name, value)| format!("{}:{}\n", name, value))
+                .collect();
+            let path = url
+                .split_once("://")
+                .and_then(|(_, rest)| rest.find('/').map(|pos| &rest[pos..]))
+                .unwrap_or("/");
+            let string_to_sign = format!(
+                "{}\n\n\n\n\n\n\n\n\n\n\n\n{}/{}{}",
+                method, canonical_headers, self.config.account, path
+            );
+
+            let key = BASE64.decode(key).map_err(|err| {
+                CloudZipError::InvalidLocation(format!("Azure account key is not base64: {}", err))
+            })?;
+            let mut mac = Hmac::<Sha256>::new_from_slice(&key)
+                .map_err(|err| CloudZipError::InvalidLocation(err.to_string()))?;
+            mac.update(string_to_sign.as_bytes());
+            let signature = BASE64.encode(mac.finalize().into_bytes());
+            request = request.header(
+                "Authorization",
+                format!("SharedKey {}:{}", self.config.account, signature),
+            );
+        }
+
+        Ok(request)
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let resp = request.send().await.map_err(CloudZipError::http)?;
+        if resp.status().is_success() {
+            return Ok(resp);
+        }
+        let status = resp.status();
+        let body = if status == StatusCode::NOT_FOUND {
+            "blob not found".to_string()
+        } else {
+            resp.text().await.unwrap_or_default()
+        };
+        Err(CloudZipError::http_status(
+            &self.blob_url(),
+            status.as_u16(),
+            &body,
+        ))
+    }
+
+    async fn head(&self) -> Result<&(u64, Option<String>)> {
+        self.head
+            .get_or_try_init(|| async {
+                let resp = self.send(self.request(Method::HEAD, &[])?).await?;
+                let size = resp
+                    .headers()
+                    .get(reqwest::header::CONTENT_LENGTH)
+                    .and_then(|len| len.to_str().ok()?.parse::<u64>().ok())
+                    .ok_or_else(|| {
+                        CloudZipError::invalid_archive("Azure response is missing the blob size")
+                    })?;
+                Ok((size, etag_of(&resp)))
+            })
+            .await
+    }
+}
+
+fn etag_of(resp: &Response) -> Option<String> {
+    resp.headers()
+        .get(reqwest::header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(str::to_string)
+}
+
+#[async_trait]
+impl RangeReader for AzureBackend {
+    async fn read_range(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let range = format!("bytes={}-{}", offset, offset + len - 1);
+        let resp = self
+            .send(self.request(Method::GET, &[("x-ms-range", range)])?)
+            .await?;
+        let body = resp.bytes().await.map_err(CloudZipError::http)?;
+        Ok(body.to_vec())
+    }
+
+    fn stream_range(&self, offset: u64, len: u64) -> ByteStream<'_> {
+        if len == 0 {
+            return stream::empty().boxed();
+        }
+        let range = format!("bytes={}-{}", offset, offset + len - 1);
+        stream::once(async move {
+            self.send(self.request(Method::GET, &[("x-ms-range", range)])?)
+                .await
+        })
+        .map_ok(response_stream)
+        .try_flatten()
+        .boxed()
+    }
+
+    async fn size(&self) -> Result<u64> {
+        Ok(self.head().await?.0)
+    }
+
+    async fn etag(&self) -> Result<Option<String>> {
+        Ok(self.head().await?.1.clone())
+    }
+}
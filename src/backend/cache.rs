@@ -0,0 +1,419 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use md5::{Digest, Md5};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::SystemTime;
+use tokio::sync::OnceCell;
+use tracing::{debug, trace};
+
+use super::{ByteStream, RangeReader};
+use crate::error::{CloudZipError, Result};
+use crate::metrics::MetricsRecorder;
+
+/// How much of an archive [`CachingReader`] keeps, and where.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Bytes kept in memory for the lifetime of the reader; 0 disables the memory tier.
+    pub memory_bytes: u64,
+    /// Directory of the disk tier, which outlives the process and may be shared between
+    /// processes; `None` disables it.
+    pub disk_dir: Option<PathBuf>,
+    /// Size the disk tier is trimmed back to, least recently used files first.
+    pub disk_bytes: u64,
+    /// Longer ranges bypass the cache, so that one large entry cannot flush it.
+    pub max_range: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            memory_bytes: 64 * 1024 * 1024,
+            disk_dir: None,
+            disk_bytes: 1024 * 1024 * 1024,
+            max_range: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Serves repeated reads of the same byte range of another backend from memory or disk.
+///
+/// Ranges are cached exactly as requested, which is how the central directory and entries are
+/// read again on every extraction. Disk entries are keyed by the archive's ETag or version, so
+/// a replaced archive is never served from stale files; archives with neither are only cached
+/// in memory.
+pub struct CachingReader {
+    inner: Arc<dyn RangeReader>,
+    /// Names the archive in disk keys, e.g. its URI.
+    identity: String,
+    max_range: u64,
+    memory: Mutex<MemoryCache>,
+    disk: Option<DiskCache>,
+    /// Prefix of the disk keys of this version of the archive, once it is known.
+    disk_prefix: OnceCell<Option<String>>,
+    size: OnceCell<u64>,
+    metrics: Option<Arc<MetricsRecorder>>,
+}
+
+impl CachingReader {
+    pub fn new(
+        inner: Arc<dyn RangeReader>,
+        identity: impl Into<String>,
+        config: CacheConfig,
+    ) -> Self {
+        CachingReader {
+            inner,
+            identity: identity.into(),
+            max_range: config.max_range,
+            memory: Mutex::new(MemoryCache::new(config.memory_bytes)),
+            disk: config
+                .disk_dir
+                .filter(|_| config.disk_bytes > 0)
+                .map(|dir| DiskCache::new(dir, config.disk_bytes)),
+            disk_prefix: OnceCell::new(),
+            size: OnceCell::new(),
+            metrics: None,
+        }
+    }
+
+    /// Counts the hits and misses of every lookup into `metrics`.
+    pub fn with_metrics(mut self, metrics: Option<Arc<MetricsRecorder>>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    fn memory(&self) -> std::sync::MutexGuard<'_, MemoryCache> {
+        self.memory.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn disk_key(&self, offset: u64, len: u64) -> Result<Option<String>> {
+        if self.disk.is_none() {
+            return Ok(None);
+        }
+        let prefix = self
+            .disk_prefix
+            .get_or_try_init(|| async {
+                let etag = self.inner.etag().await?;
+                let version_id = self.inner.version_id().await?;
+                Ok::<_, CloudZipError>(match (etag, version_id) {
+                    (None, None) => None,
+                    (etag, version_id) => Some(format!(
+                        "{}\0{}\0{}",
+                        self.identity,
+                        etag.unwrap_or_default(),
+                        version_id.unwrap_or_default()
+                    )),
+                })
+            })
+            .await?;
+        Ok(prefix.as_ref().map(|prefix| {
+            let digest = Md5::digest(format!("{}\0{}\0{}", prefix, offset, len));
+            digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+        }))
+    }
+
+    async fn lookup(&self, offset: u64, len: u64) -> Result<Option<Bytes>> {
+        let found = self.find(offset, len).await?;
+        if let Some(metrics) = &self.metrics {
+            metrics.cache_lookup(found.is_some());
+        }
+        Ok(found)
+    }
+
+    async fn find(&self, offset: u64, len: u64) -> Result<Option<Bytes>> {
+        if let Some(bytes) = self.memory().get(offset, len) {
+            trace!(offset, len, "Range served from memory");
+            return Ok(Some(bytes));
+        }
+        let (Some(disk), Some(key)) = (&self.disk, self.disk_key(offset, len).await?) else {
+            return Ok(None);
+        };
+        let bytes = disk
+            .get(key)
+            .await
+            .filter(|bytes| bytes.len() as u64 == len);
+        if let Some(bytes) = &bytes {
+            trace!(offset, len, "Range served from disk");
+            self.memory().insert(offset, len, bytes.clone());
+        }
+        Ok(bytes)
+    }
+
+    async fn store(&self, offset: u64, len: u64, bytes: Bytes) -> Result<()> {
+        self.memory().insert(offset, len, bytes.clone());
+        if let (Some(disk), Some(key)) = (&self.disk, self.disk_key(offset, len).await?) {
+            disk.put(key, bytes).await;
+        }
+        Ok(())
+    }
+
+    /// Streams a range from the backend and caches it once it has arrived in full.
+    fn fill(&self, offset: u64, len: u64) -> ByteStream<'_> {
+        let chunks = self.inner.stream_range(offset, len);
+        stream::try_unfold(
+            (chunks, Vec::with_capacity(len as usize)),
+            move |(mut chunks, mut received)| async move {
+                match chunks.try_next().await? {
+                    Some(chunk) => {
+                        received.extend_from_slice(&chunk);
+                        Ok(Some((chunk, (chunks, received))))
+                    }
+                    None => {
+                        if received.len() as u64 == len {
+                            self.store(offset, len, received.into()).await?;
+                        }
+                        Ok(None)
+                    }
+                }
+            },
+        )
+        .boxed()
+    }
+}
+
+#[async_trait]
+impl RangeReader for CachingReader {
+    async fn read_range(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        if len > self.max_range {
+            return self.inner.read_range(offset, len).await;
+        }
+        if let Some(bytes) = self.lookup(offset, len).await? {
+            return Ok(bytes.into());
+        }
+        let bytes = self.inner.read_range(offset, len).await?;
+        if bytes.len() as u64 == len {
+            self.store(offset, len, Bytes::copy_from_slice(&bytes))
+                .await?;
+        }
+        Ok(bytes)
+    }
+
+    async fn size(&self) -> Result<u64> {
+        self.size
+            .get_or_try_init(|| self.inner.size())
+            .await
+            .copied()
+    }
+
+    async fn etag(&self) -> Result<Option<String>> {
+        self.inner.etag().await
+    }
+
+    async fn version_id(&self) -> Result<Option<String>> {
+        self.inner.version_id().await
+    }
+
+    fn stream_range(&self, offset: u64, len: u64) -> ByteStream<'_> {
+        if len > self.max_range {
+            return self.inner.stream_range(offset, len);
+        }
+        stream::once(async move {
+            let chunks: ByteStream<'_> = match self.lookup(offset, len).await? {
+                Some(bytes) => stream::once(async { Ok(bytes) }).boxed(),
+                None => self.fill(offset, len),
+            };
+            Ok::<_, CloudZipError>(chunks)
+        })
+        .try_flatten()
+        .boxed()
+    }
+
+    async fn read_tail(&self, len: u64) -> Result<(Vec<u8>, u64)> {
+        // The size is needed to turn the tail into a range; learn it from the tail read itself
+        // unless the disk tier has to look the archive up anyway.
+        let size = match self.size.get() {
+            Some(size) => Some(*size),
+            None if self.disk.is_some() => Some(self.size().await?),
+            None => None,
+        };
+        if let Some(size) = size {
+            let len = len.min(size);
+            if let Some(bytes) = self.lookup(size - len, len).await? {
+                return Ok((bytes.into(), size));
+            }
+        }
+        let (bytes, size) = self.inner.read_tail(len).await?;
+        let _ = self.size.set(size);
+        let len = bytes.len() as u64;
+        self.store(size - len, len, Bytes::copy_from_slice(&bytes))
+            .await?;
+        Ok((bytes, size))
+    }
+
+    async fn read_sidecar(&self) -> Result<Option<Vec<u8>>> {
+        self.inner.read_sidecar().await
+    }
+
+    async fn write_sidecar(&self, index: Vec<u8>) -> Result<()> {
+        self.inner.write_sidecar(index).await
+    }
+
+    fn disk_starts(&self) -> &[u64] {
+        self.inner.disk_starts()
+    }
+}
+
+/// Least recently used ranges, evicted once their total length exceeds the capacity.
+struct MemoryCache {
+    capacity: u64,
+    used: u64,
+    clock: u64,
+    ranges: HashMap<(u64, u64), (Bytes, u64)>,
+    /// Ranges by the clock value of their last use, oldest first.
+    order: BTreeMap<u64, (u64, u64)>,
+}
+
+impl MemoryCache {
+    fn new(capacity: u64) -> Self {
+        MemoryCache {
+            capacity,
+            used: 0,
+            clock: 0,
+            ranges: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, offset: u64, len: u64) -> Option<Bytes> {
+        self.clock += 1;
+        let (bytes, last_used) = self.ranges.get_mut(&(offset, len))?;
+        self.order.remove(last_used);
+        *last_used = self.clock;
+        self.order.insert(self.clock, (offset, len));
+        Some(bytes.clone())
+    }
+
+    fn insert(&mut self, offset: u64, len: u64, bytes: Bytes) {
+        if len > self.capacity {
+            return;
+        }
+        self.clock += 1;
+        if let Some((_, last_used)) = self.ranges.insert((offset, len), (bytes, self.clock)) {
+            self.order.remove(&last_used);
+            self.used -= len;
+        }
+        self.order.insert(self.clock, (offset, len));
+        self.used += len;
+        while self.used > self.capacity {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            self.ranges.remove(&key);
+            self.used -= key.1;
+        }
+    }
+}
+
+/// Ranges stored as files named after their key, trimmed by modification time, which reads
+/// refresh.
+struct DiskCache {
+    dir: PathBuf,
+    capacity: u64,
+    /// Bytes in the directory, counted on the first write and kept up to date from then on.
+    used: Mutex<Option<u64>>,
+}
+
+impl DiskCache {
+    fn new(dir: PathBuf, capacity: u64) -> Self {
+        DiskCache {
+            dir,
+            capacity,
+            used: Mutex::new(None),
+        }
+    }
+
+    fn path(dir: &Path, key: &str) -> PathBuf {
+        dir.join(&key[..2]).join(&key[2..])
+    }
+
+    async fn get(&self, key: String) -> Option<Bytes> {
+        let path = Self::path(&self.dir, &key);
+        tokio::task::spawn_blocking(move || {
+            let mut file = fs::File::options().read(true).write(true).open(path).ok()?;
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes).ok()?;
+            let _ = file.set_modified(SystemTime::now());
+            Some(Bytes::from(bytes))
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    async fn put(&self, key: String, bytes: Bytes) {
+        if bytes.len() as u64 > self.capacity {
+            return;
+        }
+        let dir = self.dir.clone();
+        let written = tokio::task::spawn_blocking(move || {
+            let path = Self::path(&dir, &key);
+            let partial = path.with_extension(format!("partial{}", std::process::id()));
+            fs::create_dir_all(path.parent().unwrap_or(&dir))?;
+            fs::write(&partial, &bytes)?;
+            fs::rename(&partial, &path).inspect_err(|_| {
+                let _ = fs::remove_file(&partial);
+            })?;
+            Ok::<_, std::io::Error>(bytes.len() as u64)
+        })
+        .await
+        .map_err(std::io::Error::other)
+        .and_then(|written| written);
+        let written = match written {
+            Ok(written) => written,
+            Err(err) => {
+                debug!(error = %err, dir = %self.dir.display(), "Could not write to the range cache");
+                return;
+            }
+        };
+
+        let over_capacity = {
+            let mut used = self.used.lock().unwrap_or_else(PoisonError::into_inner);
+            match used.as_mut() {
+                Some(used) => {
+                    *used += written;
+                    *used > self.capacity
+                }
+                None => true,
+            }
+        };
+        if over_capacity {
+            let dir = self.dir.clone();
+            let capacity = self.capacity;
+            if let Ok(remaining) = tokio::task::spawn_blocking(move || trim(&dir, capacity)).await {
+                *self.used.lock().unwrap_or_else(PoisonError::into_inner) = Some(remaining);
+            }
+        }
+    }
+}
+
+/// Removes the least recently used files until the directory holds at most `capacity` bytes,
+/// and returns what is left.
+fn trim(dir: &Path, capacity: u64) -> u64 {
+    let mut files = Vec::new();
+    for shard in fs::read_dir(dir).into_iter().flatten().flatten() {
+        for file in fs::read_dir(shard.path()).into_iter().flatten().flatten() {
+            let Ok(metadata) = file.metadata() else {
+                continue;
+            };
+            if metadata.is_file() {
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                files.push((modified, metadata.len(), file.path()));
+            }
+        }
+    }
+    files.sort();
+    let mut used: u64 = files.iter().map(|(_, len, _)| len).sum();
+    for (_, len, path) in files {
+        if used <= capacity {
+            break;
+        }
+        if fs::remove_file(&path).is_ok() {
+            used -= len;
+        }
+    }
+    used
+}
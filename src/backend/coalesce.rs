@@ -0,0 +1,200 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use super::{ByteStream, RangeReader};
+use crate::error::{CloudZipError, Result};
+
+/// A run of neighbouring ranges fetched with a single read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RangeGroup {
+    pub offset: u64,
+    pub len: u64,
+    /// Ranges served from this read.
+    pub members: usize,
+}
+
+/// Merges consecutive `(offset, len)` ranges that are at most `max_gap` bytes apart into
+/// groups of at most `max_len` bytes, keeping their order. Ranges out of order or larger than
+/// `max_len` stay on their own; `max_gap` 0 merges nothing.
+pub(crate) fn group_ranges(
+    ranges: impl IntoIterator<Item = (u64, u64)>,
+    max_gap: u64,
+    max_len: u64,
+) -> Vec<RangeGroup> {
+    let mut groups: Vec<RangeGroup> = Vec::new();
+    for (offset, len) in ranges {
+        if let Some(group) = groups.last_mut() {
+            let end = group.offset + group.len;
+            if max_gap > 0
+                && offset >= end
+                && offset - end <= max_gap
+                && offset + len - group.offset <= max_len
+            {
+                group.len = offset + len - group.offset;
+                group.members += 1;
+                continue;
+            }
+        }
+        groups.push(RangeGroup {
+            offset,
+            len,
+            members: 1,
+        });
+    }
+    groups
+}
+
+struct Group {
+    offset: u64,
+    len: u64,
+    state: Mutex<GroupState>,
+}
+
+struct GroupState {
+    bytes: Option<Bytes>,
+    /// Member reads still expected; the bytes are dropped after the last one.
+    remaining: usize,
+}
+
+/// Serves the entries of one batch from shared reads of the runs of small entries stored next
+/// to each other, so that a directory of tiny files costs a handful of requests instead of one
+/// per file.
+///
+/// A run is fetched when the first of its entries is read and released after the last one.
+/// Any other read, or a run member read again after that, goes to the inner backend.
+pub(crate) struct CoalescingReader {
+    inner: Arc<dyn RangeReader>,
+    groups: Vec<Group>,
+    /// Group of every coalesced `(offset, len)` range.
+    members: HashMap<(u64, u64), usize>,
+}
+
+impl CoalescingReader {
+    /// Wraps `inner` for a batch reading `ranges` in this order, or hands it back unchanged
+    /// when no two of them can share a read.
+    pub fn wrap(
+        inner: Arc<dyn RangeReader>,
+        ranges: impl IntoIterator<Item = (u64, u64)>,
+        max_gap: u64,
+        max_len: u64,
+    ) -> Arc<dyn RangeReader> {
+        let ranges: Vec<_> = ranges.into_iter().collect();
+        let mut groups = Vec::new();
+        let mut members = HashMap::new();
+        let mut ranges_left = ranges.iter();
+        for group in group_ranges(ranges.iter().copied(), max_gap, max_len) {
+            let group_ranges: Vec<_> = ranges_left.by_ref().take(group.members).collect();
+            if group.members < 2 {
+                continue;
+            }
+            for range in group_ranges {
+                members.insert(*range, groups.len());
+            }
+            groups.push(Group {
+                offset: group.offset,
+                len: group.len,
+                state: Mutex::new(GroupState {
+                    bytes: None,
+                    remaining: group.members,
+                }),
+            });
+        }
+        if groups.is_empty() {
+            return inner;
+        }
+        debug!(
+            ranges = members.len(),
+            requests = groups.len(),
+            "Coalesced neighbouring entries"
+        );
+        Arc::new(CoalescingReader {
+            inner,
+            groups,
+            members,
+        })
+    }
+
+    /// Slices a member range out of its group, fetching the group first if needed, or returns
+    /// `None` once the group has been released.
+    async fn read_member(&self, offset: u64, len: u64) -> Result<Option<Bytes>> {
+        let Some(&index) = self.members.get(&(offset, len)) else {
+            return Ok(None);
+        };
+        let group = &self.groups[index];
+        let mut state = group.state.lock().await;
+        if state.remaining == 0 {
+            return Ok(None);
+        }
+        let bytes = match &state.bytes {
+            Some(bytes) => bytes.clone(),
+            None => {
+                let bytes = Bytes::from(self.inner.read_range(group.offset, group.len).await?);
+                state.bytes.insert(bytes).clone()
+            }
+        };
+        state.remaining -= 1;
+        if state.remaining == 0 {
+            state.bytes = None;
+        }
+        let range = (offset - group.offset) as usize..(offset - group.offset + len) as usize;
+        Ok((range.end <= bytes.len()).then(|| bytes.slice(range)))
+    }
+}
+
+#[async_trait]
+impl RangeReader for CoalescingReader {
+    async fn read_range(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        match self.read_member(offset, len).await? {
+            Some(bytes) => Ok(bytes.into()),
+            None => self.inner.read_range(offset, len).await,
+        }
+    }
+
+    async fn size(&self) -> Result<u64> {
+        self.inner.size().await
+    }
+
+    async fn etag(&self) -> Result<Option<String>> {
+        self.inner.etag().await
+    }
+
+    async fn version_id(&self) -> Result<Option<String>> {
+        self.inner.version_id().await
+    }
+
+    fn stream_range(&self, offset: u64, len: u64) -> ByteStream<'_> {
+        if !self.members.contains_key(&(offset, len)) {
+            return self.inner.stream_range(offset, len);
+        }
+        stream::once(async move {
+            let chunks: ByteStream<'_> = match self.read_member(offset, len).await? {
+                Some(bytes) => stream::once(async { Ok(bytes) }).boxed(),
+                None => self.inner.stream_range(offset, len),
+            };
+            Ok::<_, CloudZipError>(chunks)
+        })
+        .try_flatten()
+        .boxed()
+    }
+
+    async fn read_tail(&self, len: u64) -> Result<(Vec<u8>, u64)> {
+        self.inner.read_tail(len).await
+    }
+
+    async fn read_sidecar(&self) -> Result<Option<Vec<u8>>> {
+        self.inner.read_sidecar().await
+    }
+
+    async fn write_sidecar(&self, index: Vec<u8>) -> Result<()> {
+        self.inner.write_sidecar(index).await
+    }
+
+    fn disk_starts(&self) -> &[u64] {
+        self.inner.disk_starts()
+    }
+}
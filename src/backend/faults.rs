@@ -0,0 +1,380 @@
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{ByteStream, RangeReader};
+use crate::error::{CloudZipError, Result};
+
+/// Which reads of a [`FaultyReader`] a fault hits, numbered from 1 in the order they start.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum FaultSchedule {
+    #[default]
+    Never,
+    /// These reads.
+    Reads(Vec<u64>),
+    /// Every `n`th read.
+    Every(u64),
+}
+
+impl FaultSchedule {
+    fn hits(&self, read: u64) -> bool {
+        match self {
+            FaultSchedule::Never => false,
+            FaultSchedule::Reads(reads) => reads.contains(&read),
+            FaultSchedule::Every(n) => read.is_multiple_of(*n),
+        }
+    }
+}
+
+/// What a [`FaultyReader`] does to the reads going through it.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    /// Added before every read.
+    pub latency: Duration,
+    /// Reads answered with an HTTP 500, which is worth retrying.
+    pub server_errors: FaultSchedule,
+    /// Reads cut off after half their bytes: a streamed range yields them and then fails
+    /// like a dropped connection, other reads come back short.
+    pub truncated: FaultSchedule,
+    /// Reads after which the ETag changes, as if the archive had been replaced.
+    pub etag_change_after: Option<u64>,
+}
+
+/// Injects [`Faults`] into the reads of another backend, to exercise retries, resumed
+/// downloads and the detection of replaced archives.
+///
+/// Faults hit reads by number rather than at random, so the same sequence of reads always
+/// meets the same faults. Ranged reads, streamed ranges, tails and sidecars all count as
+/// reads, retries included; sizes and ETags do not.
+pub struct FaultyReader {
+    inner: Arc<dyn RangeReader>,
+    faults: Faults,
+    reads: AtomicU64,
+}
+
+impl FaultyReader {
+    pub fn new(inner: Arc<dyn RangeReader>, faults: Faults) -> Self {
+        FaultyReader {
+            inner,
+            faults,
+            reads: AtomicU64::new(0),
+        }
+    }
+
+    /// Reads started so far.
+    pub fn reads(&self) -> u64 {
+        self.reads.load(Ordering::Relaxed)
+    }
+
+    /// Numbers the next read, after the latency, failing it when a server error is due.
+    async fn start(&self) -> Result<u64> {
+        if !self.faults.latency.is_zero() {
+            tokio::time::sleep(self.faults.latency).await;
+        }
+        let read = self.reads.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.faults.server_errors.hits(read) {
+            return Err(CloudZipError::Http {
+                message: format!("injected fault: HTTP 500 on read {}", read),
+                source: None,
+                transient: true,
+            });
+        }
+        Ok(read)
+    }
+
+    /// `bytes` without their second half, when read number `read` is truncated.
+    fn cut(&self, read: u64, mut bytes: Vec<u8>) -> Vec<u8> {
+        if self.faults.truncated.hits(read) {
+            bytes.truncate(bytes.len() / 2);
+        }
+        bytes
+    }
+}
+
+#[async_trait]
+impl RangeReader for FaultyReader {
+    async fn read_range(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let read = self.start().await?;
+        let bytes = self.inner.read_range(offset, len).await?;
+        Ok(self.cut(read, bytes))
+    }
+
+    async fn size(&self) -> Result<u64> {
+        self.inner.size().await
+    }
+
+    async fn etag(&self) -> Result<Option<String>> {
+        let etag = self.inner.etag().await?;
+        match self.faults.etag_change_after {
+            Some(after) if self.reads() >= after => Ok(Some(format!(
+                "{}-replaced",
+                etag.as_deref().unwrap_or_default()
+            ))),
+            _ => Ok(etag),
+        }
+    }
+
+    async fn version_id(&self) -> Result<Option<String>> {
+        self.inner.version_id().await
+    }
+
+    fn stream_range(&self, offset: u64, len: u64) -> ByteStream<'_> {
+        stream::once(self.start())
+            .map_ok(move |read| {
+                let chunks = self.inner.stream_range(offset, len);
+                match self.faults.truncated.hits(read) {
+                    true => cut_off(chunks, len / 2),
+                    false => chunks,
+                }
+            })
+            .try_flatten()
+            .boxed()
+    }
+
+    async fn read_tail(&self, len: u64) -> Result<(Vec<u8>, u64)> {
+        let read = self.start().await?;
+        let (bytes, size) = self.inner.read_tail(len).await?;
+        Ok((self.cut(read, bytes), size))
+    }
+
+    async fn read_sidecar(&self) -> Result<Option<Vec<u8>>> {
+        self.start().await?;
+        self.inner.read_sidecar().await
+    }
+
+    async fn write_sidecar(&self, index: Vec<u8>) -> Result<()> {
+        self.inner.write_sidecar(index).await
+    }
+
+    fn disk_starts(&self) -> &[u64] {
+        self.inner.disk_starts()
+    }
+}
+
+/// Passes on the first `keep` bytes of `chunks`, then fails as a dropped connection would.
+fn cut_off(chunks: ByteStream<'_>, keep: u64) -> ByteStream<'_> {
+    stream::unfold(Some((chunks, keep)), move |state| async move {
+        let (mut chunks, left) = state?;
+        if left == 0 {
+            let dropped = io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("injected fault: connection closed after {} bytes", keep),
+            );
+            return Some((Err(dropped.into()), None));
+        }
+        match chunks.next().await? {
+            Ok(mut chunk) => {
+                let taken = left.min(chunk.len() as u64);
+                chunk.truncate(taken as usize);
+                Some((Ok(chunk), Some((chunks, left - taken))))
+            }
+            Err(err) => Some((Err(err), None)),
+        }
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{MemoryBackend, RetryPolicy, RetryingReader};
+    use crate::error::CloudZipError;
+    use crate::extract::DownloadOptions;
+    use crate::metadata::EntryIndex;
+    use crate::selection::EntrySelector;
+    use crate::CloudZip;
+
+    fn retrying(inner: Arc<dyn RangeReader>) -> RetryingReader {
+        RetryingReader::new(
+            inner,
+            RetryPolicy {
+                max_attempts: 3,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1),
+            },
+        )
+    }
+
+    /// Three stored entries of 100 KB each, every one of its own bytes.
+    fn files() -> Vec<(String, Vec<u8>)> {
+        (0..3u8)
+            .map(|i| (format!("{}.bin", i), vec![i; 100 * 1024]))
+            .collect()
+    }
+
+    /// The index of `backend`, built without faults.
+    async fn index(backend: Arc<MemoryBackend>) -> EntryIndex {
+        let archive = CloudZip::discover(backend).await.unwrap();
+        (*archive.verified_entries().await.unwrap()).clone()
+    }
+
+    /// Extracts entries one at a time, each with a request of its own.
+    fn one_by_one(reader: Arc<dyn RangeReader>, entries: EntryIndex) -> CloudZip {
+        CloudZip::from_entry_index(reader, entries)
+            .with_concurrency(1)
+            .with_download_options(DownloadOptions {
+                coalesce_gap: 0,
+                ..DownloadOptions::default()
+            })
+    }
+
+    #[tokio::test]
+    async fn retries_recover_from_server_errors() {
+        let faulty = Arc::new(FaultyReader::new(
+            Arc::new(MemoryBackend::new(vec![7; 1000])),
+            Faults {
+                server_errors: FaultSchedule::Reads(vec![1, 2]),
+                ..Faults::default()
+            },
+        ));
+        let err = faulty.read_range(0, 10).await.unwrap_err();
+        assert!(err.is_transient());
+
+        let reader = retrying(faulty.clone());
+        assert_eq!(reader.read_range(0, 10).await.unwrap(), vec![7; 10]);
+        assert_eq!(faulty.reads(), 3);
+    }
+
+    #[tokio::test]
+    async fn retries_resume_dropped_streams() {
+        let bytes: Vec<u8> = (0..3_000_000u32).map(|i| i as u8).collect();
+        let faulty = Arc::new(FaultyReader::new(
+            Arc::new(MemoryBackend::new(bytes.clone())),
+            Faults {
+                truncated: FaultSchedule::Reads(vec![1]),
+                ..Faults::default()
+            },
+        ));
+        let reader = retrying(faulty.clone());
+        let streamed: Vec<u8> = reader
+            .stream_range(0, bytes.len() as u64)
+            .try_fold(Vec::new(), |mut streamed, chunk| async move {
+                streamed.extend_from_slice(&chunk);
+                Ok(streamed)
+            })
+            .await
+            .unwrap();
+        assert_eq!(streamed, bytes);
+        // The second read picked up where the dropped one stopped.
+        assert_eq!(faulty.reads(), 2);
+    }
+
+    #[tokio::test]
+    async fn extraction_survives_faults_when_retried() {
+        let backend = Arc::new(MemoryBackend::stored_zip(files()).await.unwrap());
+        let entries = index(backend.clone()).await;
+        let faults = Faults {
+            server_errors: FaultSchedule::Every(3),
+            truncated: FaultSchedule::Reads(vec![1]),
+            ..Faults::default()
+        };
+        let faulty: Arc<dyn RangeReader> =
+            Arc::new(FaultyReader::new(backend.clone(), faults.clone()));
+        let dir = tempfile::tempdir().unwrap();
+        assert!(one_by_one(faulty, entries.clone())
+            .extract_matching(&EntrySelector::All, dir.path())
+            .await
+            .is_err());
+
+        let faulty = Arc::new(FaultyReader::new(backend, faults));
+        let dir = tempfile::tempdir().unwrap();
+        one_by_one(Arc::new(retrying(faulty)), entries)
+            .extract_matching(&EntrySelector::All, dir.path())
+            .await
+            .unwrap();
+        for (name, content) in files() {
+            assert_eq!(std::fs::read(dir.path().join(name)).unwrap(), content);
+        }
+    }
+
+    #[tokio::test]
+    async fn interrupted_extractions_resume() {
+        let backend = Arc::new(MemoryBackend::stored_zip(files()).await.unwrap());
+        let entries = index(backend.clone()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let output_dir = dir.path().join("out");
+        let state = dir.path().join("job.jsonl");
+
+        let faulty = Arc::new(FaultyReader::new(
+            backend.clone(),
+            Faults {
+                server_errors: FaultSchedule::Reads(vec![2]),
+                ..Faults::default()
+            },
+        ));
+        assert!(one_by_one(faulty, entries.clone())
+            .with_resume_state(&state)
+            .extract_matching(&EntrySelector::All, &output_dir)
+            .await
+            .is_err());
+        assert!(state.exists());
+
+        let faulty = Arc::new(FaultyReader::new(backend, Faults::default()));
+        one_by_one(faulty.clone(), entries)
+            .with_resume_state(&state)
+            .extract_matching(&EntrySelector::All, &output_dir)
+            .await
+            .unwrap();
+        // Only the entry that failed is read again.
+        assert_eq!(faulty.reads(), 1);
+        assert!(!state.exists());
+        for (name, content) in files() {
+            assert_eq!(std::fs::read(output_dir.join(name)).unwrap(), content);
+        }
+    }
+
+    #[tokio::test]
+    async fn partial_files_longer_than_the_stored_data_are_replaced() {
+        let backend = Arc::new(MemoryBackend::stored_zip(files()).await.unwrap());
+        let mut list = index(backend.clone()).await.as_slice().to_vec();
+        // An index claiming more content than the entry stores.
+        list[0].uncompressed_size += 10;
+        let dir = tempfile::tempdir().unwrap();
+        let output_dir = dir.path().join("out");
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let part = vec![9; list[0].compressed_size as usize + 5];
+        std::fs::write(output_dir.join("0.bin.part"), part).unwrap();
+
+        one_by_one(backend, EntryIndex::new(list))
+            .with_resume_state(dir.path().join("job.jsonl"))
+            .extract_matching(&EntrySelector::Name("0.bin".to_string()), &output_dir)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read(output_dir.join("0.bin")).unwrap(),
+            files()[0].1
+        );
+    }
+
+    #[tokio::test]
+    async fn replaced_archives_are_noticed() {
+        let backend = Arc::new(
+            MemoryBackend::stored_zip(files())
+                .await
+                .unwrap()
+                .with_etag("v1"),
+        );
+        let entries = index(backend.clone()).await;
+        let faulty: Arc<dyn RangeReader> = Arc::new(FaultyReader::new(
+            backend,
+            Faults {
+                etag_change_after: Some(1),
+                ..Faults::default()
+            },
+        ));
+        let dir = tempfile::tempdir().unwrap();
+        CloudZip::from_entry_index(faulty.clone(), entries.clone())
+            .extract_to("0.bin", dir.path())
+            .await
+            .unwrap();
+        assert!(matches!(
+            CloudZip::from_entry_index(faulty, entries)
+                .extract_to("1.bin", dir.path())
+                .await,
+            Err(CloudZipError::ArchiveChanged(_))
+        ));
+    }
+}
@@ -0,0 +1,663 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::io::{self, BufReader, Read, Write};
+use std::sync::Arc;
+use tokio::sync::{mpsc, OnceCell};
+use tokio::task;
+use tracing::debug;
+
+use super::{ByteStream, RangeReader};
+use crate::error::{CloudZipError, Result};
+use crate::extract::{ChannelReader, ChannelWriter};
+use crate::inflate::{BitReader, Inflater};
+
+/// Decompressed bytes between checkpoints: reading any range decodes at most this much that
+/// it does not need, and the index holds one compressed 32 KiB window per span.
+const CHECKPOINT_SPAN: u64 = 4 * 1024 * 1024;
+/// First bytes of an encoded [`GzipIndex`].
+const GZIP_INDEX_MAGIC: &[u8; 8] = b"CZGZIDX1";
+/// First bytes of a sidecar holding the checkpoints ahead of the entry index.
+const BUNDLE_MAGIC: &[u8; 8] = b"CZGZSID1";
+/// Number of chunks buffered between the download, the decoder and the reader.
+const CHANNEL_CHUNKS: usize = 16;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const FLAG_HEADER_CRC: u8 = 0x02;
+const FLAG_EXTRA: u8 = 0x04;
+const FLAG_NAME: u8 = 0x08;
+const FLAG_COMMENT: u8 = 0x10;
+
+/// What the compressed file holds.
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    /// Gzip members, each checked against the CRC-32 and size in its trailer.
+    Gzip,
+    /// One raw deflate stream, such as the data of a zip entry, checked against the CRC-32
+    /// recorded for it when there is one.
+    Deflate { crc32: Option<u32> },
+}
+
+impl Format {
+    fn error(self, source: io::Error) -> CloudZipError {
+        let stream = match self {
+            Format::Gzip => "the gzip stream",
+            Format::Deflate { .. } => "the deflate stream",
+        };
+        CloudZipError::Decompression {
+            file_name: stream.to_string(),
+            source,
+        }
+    }
+}
+
+/// A point from which the gzip file can be decoded without reading what comes before it.
+#[derive(Debug, Clone)]
+struct Checkpoint {
+    /// Bit position in the compressed file of a deflate block or of the data of a member.
+    input: u64,
+    /// Offset of the first decompressed byte decoded from there.
+    output: u64,
+    /// The 32 KiB of output before it, deflate-compressed; empty at the start of a member.
+    window: Vec<u8>,
+}
+
+/// Checkpoints in a gzip file, zran-style, so that a range of its content is read by decoding
+/// from the one before it instead of from the start.
+#[derive(Debug, Clone)]
+pub struct GzipIndex {
+    /// Size and ETag of the compressed file the checkpoints were found in.
+    compressed_size: u64,
+    etag: Option<String>,
+    /// Size of the decompressed content.
+    size: u64,
+    checkpoints: Vec<Checkpoint>,
+}
+
+impl GzipIndex {
+    /// Size of the decompressed content.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn checkpoints(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let etag = self.etag.as_deref().unwrap_or_default().as_bytes();
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(GZIP_INDEX_MAGIC);
+        bytes.extend_from_slice(&self.compressed_size.to_le_bytes());
+        bytes.extend_from_slice(&self.size.to_le_bytes());
+        bytes.extend_from_slice(&(etag.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(etag);
+        bytes.extend_from_slice(&(self.checkpoints.len() as u64).to_le_bytes());
+        for checkpoint in &self.checkpoints {
+            bytes.extend_from_slice(&checkpoint.input.to_le_bytes());
+            bytes.extend_from_slice(&checkpoint.output.to_le_bytes());
+            bytes.extend_from_slice(&(checkpoint.window.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&checkpoint.window);
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut bytes = bytes
+            .strip_prefix(GZIP_INDEX_MAGIC)
+            .ok_or_else(|| CloudZipError::invalid_archive("Not a gzip index"))?;
+        let compressed_size = take_u64(&mut bytes)?;
+        let size = take_u64(&mut bytes)?;
+        let etag_len = take_u32(&mut bytes)? as usize;
+        let etag = String::from_utf8_lossy(take(&mut bytes, etag_len)?).into_owned();
+        let count = take_u64(&mut bytes)?;
+        let mut checkpoints = Vec::new();
+        for _ in 0..count {
+            let input = take_u64(&mut bytes)?;
+            let output = take_u64(&mut bytes)?;
+            let window_len = take_u32(&mut bytes)? as usize;
+            let window = take(&mut bytes, window_len)?.to_vec();
+            checkpoints.push(Checkpoint {
+                input,
+                output,
+                window,
+            });
+        }
+        if checkpoints.is_empty() {
+            return Err(CloudZipError::invalid_archive(
+                "The gzip index has no checkpoints",
+            ));
+        }
+        Ok(GzipIndex {
+            compressed_size,
+            etag: Some(etag).filter(|etag| !etag.is_empty()),
+            size,
+            checkpoints,
+        })
+    }
+
+    /// The last checkpoint at or before `offset`.
+    fn checkpoint_before(&self, offset: u64) -> &Checkpoint {
+        let after = self
+            .checkpoints
+            .partition_point(|checkpoint| checkpoint.output <= offset);
+        &self.checkpoints[after.saturating_sub(1)]
+    }
+
+    /// Where the compressed bytes needed to decode the content up to `end` stop.
+    fn input_end(&self, end: u64) -> u64 {
+        self.checkpoints
+            .iter()
+            .find(|checkpoint| checkpoint.output >= end)
+            .map_or(self.compressed_size, |checkpoint| {
+                checkpoint.input.div_ceil(8).min(self.compressed_size)
+            })
+    }
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if bytes.len() < len {
+        return Err(CloudZipError::invalid_archive(
+            "The gzip index is truncated",
+        ));
+    }
+    let (taken, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(taken)
+}
+
+fn take_u32(bytes: &mut &[u8]) -> Result<u32> {
+    Ok(u32::from_le_bytes(take(bytes, 4)?.try_into().unwrap()))
+}
+
+fn take_u64(bytes: &mut &[u8]) -> Result<u64> {
+    Ok(u64::from_le_bytes(take(bytes, 8)?.try_into().unwrap()))
+}
+
+fn compress_window(window: &[u8]) -> io::Result<Vec<u8>> {
+    if window.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(window)?;
+    encoder.finish()
+}
+
+fn decompress_window(window: &[u8]) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    DeflateDecoder::new(window).read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn byte<R: Read>(bits: &mut BitReader<R>) -> io::Result<u8> {
+    bits.byte()?
+        .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+}
+
+fn u32_le<R: Read>(bits: &mut BitReader<R>) -> io::Result<u32> {
+    let mut value = 0;
+    for shift in [0, 8, 16, 24] {
+        value |= (byte(bits)? as u32) << shift;
+    }
+    Ok(value)
+}
+
+/// Reads the header of the next gzip member; `false` at the end of the file, or at data
+/// after the last member that does not start another one, which gzip ignores too.
+fn member_header<R: Read>(bits: &mut BitReader<R>, first: bool) -> io::Result<bool> {
+    let magic = match bits.byte()? {
+        Some(magic) => magic,
+        None if first => return Err(io::ErrorKind::UnexpectedEof.into()),
+        None => return Ok(false),
+    };
+    if magic != GZIP_MAGIC[0] || byte(bits)? != GZIP_MAGIC[1] {
+        return if first {
+            Err(invalid("not a gzip file"))
+        } else {
+            Ok(false)
+        };
+    }
+    if byte(bits)? != 8 {
+        return Err(invalid("unknown gzip compression method"));
+    }
+    let flags = byte(bits)?;
+    // Modification time, extra flags and operating system.
+    for _ in 0..6 {
+        byte(bits)?;
+    }
+    if flags & FLAG_EXTRA != 0 {
+        let len = byte(bits)? as usize | (byte(bits)? as usize) << 8;
+        for _ in 0..len {
+            byte(bits)?;
+        }
+    }
+    for flag in [FLAG_NAME, FLAG_COMMENT] {
+        if flags & flag != 0 {
+            while byte(bits)? != 0 {}
+        }
+    }
+    if flags & FLAG_HEADER_CRC != 0 {
+        byte(bits)?;
+        byte(bits)?;
+    }
+    Ok(true)
+}
+
+/// Decodes one deflate stream from `bits` to its last block, adding a checkpoint to
+/// `checkpoints` every [`CHECKPOINT_SPAN`] bytes of output; `size` counts the output so far.
+/// Returns the CRC-32 and length of the stream's output.
+fn inflate_stream<R: Read>(
+    bits: &mut BitReader<R>,
+    checkpoints: &mut Vec<Checkpoint>,
+    size: &mut u64,
+) -> io::Result<(u32, u64)> {
+    let mut inflater = Inflater::new(&[]);
+    let mut crc = crc32fast::Hasher::new();
+    let mut len = 0u64;
+    loop {
+        if checkpoints
+            .last()
+            .is_none_or(|last| *size - last.output >= CHECKPOINT_SPAN)
+        {
+            checkpoints.push(Checkpoint {
+                input: bits.position(),
+                output: *size,
+                window: compress_window(inflater.window())?,
+            });
+        }
+        let last = inflater.block(bits)?;
+        let output = inflater.take();
+        crc.update(output);
+        len += output.len() as u64;
+        *size += output.len() as u64;
+        if last {
+            return Ok((crc.finalize(), len));
+        }
+    }
+}
+
+/// Decodes the whole file `input`, checking it, and records a checkpoint every
+/// [`CHECKPOINT_SPAN`] bytes of output.
+fn find_checkpoints(
+    input: impl Read,
+    format: Format,
+    compressed_size: u64,
+    etag: Option<String>,
+) -> io::Result<GzipIndex> {
+    let mut bits = BitReader::new(BufReader::new(input), 0, 0)?;
+    let mut checkpoints: Vec<Checkpoint> = Vec::new();
+    let mut size = 0u64;
+    match format {
+        Format::Gzip => {
+            let mut first = true;
+            while member_header(&mut bits, first)? {
+                first = false;
+                let (crc, member_size) = inflate_stream(&mut bits, &mut checkpoints, &mut size)?;
+                bits.align();
+                if u32_le(&mut bits)? != crc {
+                    return Err(invalid("CRC mismatch in a gzip member"));
+                }
+                if u32_le(&mut bits)? != member_size as u32 {
+                    return Err(invalid("size mismatch in a gzip member"));
+                }
+            }
+        }
+        Format::Deflate { crc32 } => {
+            let (crc, _) = inflate_stream(&mut bits, &mut checkpoints, &mut size)?;
+            if crc32.is_some_and(|crc32| crc32 != crc) {
+                return Err(invalid("CRC mismatch in the deflate stream"));
+            }
+        }
+    }
+    Ok(GzipIndex {
+        compressed_size,
+        etag,
+        size,
+        checkpoints,
+    })
+}
+
+/// Decodes from `checkpoint` the content between `offset` and `end` into `output`, reading
+/// the compressed file from the byte holding the checkpoint.
+fn decode_range(
+    input: impl Read,
+    format: Format,
+    checkpoint: &Checkpoint,
+    offset: u64,
+    end: u64,
+    output: &mut impl Write,
+) -> io::Result<()> {
+    let mut bits = BitReader::new(
+        BufReader::new(input),
+        checkpoint.input / 8,
+        (checkpoint.input % 8) as u32,
+    )?;
+    let mut inflater = Inflater::new(&decompress_window(&checkpoint.window)?);
+    let mut position = checkpoint.output;
+    while position < end {
+        let last = inflater.block(&mut bits)?;
+        let decoded = inflater.take();
+        let start = position;
+        position += decoded.len() as u64;
+        let from = offset.saturating_sub(start).min(decoded.len() as u64) as usize;
+        let to = (end - start).min(decoded.len() as u64) as usize;
+        if from < to {
+            output.write_all(&decoded[from..to])?;
+        }
+        if last && position < end {
+            if let Format::Deflate { .. } = format {
+                break;
+            }
+            bits.align();
+            // The CRC and size of the member, which was checked when it was indexed.
+            u32_le(&mut bits)?;
+            u32_le(&mut bits)?;
+            if !member_header(&mut bits, false)? {
+                break;
+            }
+            inflater.reset();
+        }
+    }
+    Ok(())
+}
+
+/// Sends the chunks of `chunks` to a blocking decoder until it stops listening.
+async fn feed(mut chunks: ByteStream<'_>, to: mpsc::Sender<io::Result<Bytes>>) {
+    while let Some(chunk) = chunks.next().await {
+        let failed = chunk.is_err();
+        if to.send(chunk.map_err(io::Error::other)).await.is_err() || failed {
+            break;
+        }
+    }
+}
+
+/// The decompressed content of a gzip file, such as a `.tar.gz`, or of a raw deflate stream,
+/// such as a deflated zip entry, with random access through a [`GzipIndex`].
+///
+/// The checkpoints are found by decoding the whole file the first time they are needed,
+/// unless the sidecar holds them: sidecars written through this reader keep them ahead of
+/// the entry index.
+pub struct GzipReader {
+    inner: Arc<dyn RangeReader>,
+    format: Format,
+    index: OnceCell<Arc<GzipIndex>>,
+}
+
+impl GzipReader {
+    pub fn new(inner: Arc<dyn RangeReader>) -> Self {
+        GzipReader {
+            inner,
+            format: Format::Gzip,
+            index: OnceCell::new(),
+        }
+    }
+
+    /// Reads `inner` as a raw deflate stream, checking its content against `crc32` when
+    /// decoding it whole.
+    pub fn deflate(inner: Arc<dyn RangeReader>, crc32: Option<u32>) -> Self {
+        GzipReader {
+            inner,
+            format: Format::Deflate { crc32 },
+            index: OnceCell::new(),
+        }
+    }
+
+    /// Uses checkpoints found earlier instead of decoding the file to find them.
+    pub fn with_index(self, index: GzipIndex) -> Self {
+        let _ = self.index.set(Arc::new(index));
+        self
+    }
+
+    /// The checkpoints of the file, decoding it whole to find them the first time.
+    pub async fn index(&self) -> Result<Arc<GzipIndex>> {
+        self.index
+            .get_or_try_init(|| self.find_checkpoints())
+            .await
+            .cloned()
+    }
+
+    /// Whether the checkpoints are known, so that reading any range decodes from the one
+    /// before it.
+    pub fn has_index(&self) -> bool {
+        self.index.initialized()
+    }
+
+    /// Uses checkpoints found earlier, such as those stored with [`GzipIndex::encode`], when
+    /// they were found in the file as it is now; returns whether they were. Does nothing once
+    /// the reader has checkpoints.
+    pub async fn use_index(&self, index: GzipIndex) -> Result<bool> {
+        if !self.is_current(&index).await? {
+            return Ok(false);
+        }
+        let _ = self.index.set(Arc::new(index));
+        Ok(true)
+    }
+
+    async fn find_checkpoints(&self) -> Result<Arc<GzipIndex>> {
+        let compressed_size = self.inner.size().await?;
+        let etag = self.inner.etag().await?;
+        let format = self.format;
+        let (sender, receiver) = mpsc::channel(CHANNEL_CHUNKS);
+        let decoder = task::spawn_blocking(move || {
+            find_checkpoints(ChannelReader::new(receiver), format, compressed_size, etag)
+        });
+        feed(self.inner.stream_range(0, compressed_size), sender).await;
+        let index = decoder
+            .await
+            .map_err(io::Error::other)?
+            .map_err(|err| format.error(err))?;
+        debug!(
+            size = index.size,
+            compressed_size,
+            checkpoints = index.checkpoints.len(),
+            "Found the checkpoints of the compressed stream"
+        );
+        Ok(Arc::new(index))
+    }
+
+    /// Whether `index` was found in the file as it is now.
+    async fn is_current(&self, index: &GzipIndex) -> Result<bool> {
+        if self.inner.size().await? != index.compressed_size {
+            return Ok(false);
+        }
+        Ok(match (&index.etag, self.inner.etag().await?) {
+            (Some(recorded), Some(current)) => *recorded == current,
+            _ => true,
+        })
+    }
+}
+
+#[async_trait]
+impl RangeReader for GzipReader {
+    async fn read_range(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let chunks: Vec<Bytes> = self.stream_range(offset, len).try_collect().await?;
+        Ok(chunks.concat())
+    }
+
+    /// Size of the decompressed content.
+    async fn size(&self) -> Result<u64> {
+        Ok(self.index().await?.size)
+    }
+
+    async fn etag(&self) -> Result<Option<String>> {
+        self.inner.etag().await
+    }
+
+    async fn version_id(&self) -> Result<Option<String>> {
+        self.inner.version_id().await
+    }
+
+    fn stream_range(&self, offset: u64, len: u64) -> ByteStream<'_> {
+        stream::once(async move {
+            let index = self.index().await?;
+            let end = offset.saturating_add(len).min(index.size);
+            if offset >= end {
+                return Ok::<_, CloudZipError>(stream::empty().boxed());
+            }
+            let checkpoint = index.checkpoint_before(offset).clone();
+            let start = checkpoint.input / 8;
+            let input_end = index.input_end(end);
+
+            let format = self.format;
+            let (input, input_receiver) = mpsc::channel(CHANNEL_CHUNKS);
+            let (output, output_receiver) = mpsc::channel(CHANNEL_CHUNKS);
+            task::spawn_blocking(move || {
+                let mut writer = ChannelWriter::new(output.clone());
+                let decoded = decode_range(
+                    ChannelReader::new(input_receiver),
+                    format,
+                    &checkpoint,
+                    offset,
+                    end,
+                    &mut writer,
+                );
+                if let Err(err) = decoded {
+                    let _ = output.blocking_send(Err(err));
+                }
+            });
+            let inner = self.inner.clone();
+            tokio::spawn(async move {
+                feed(inner.stream_range(start, input_end - start), input).await;
+            });
+            Ok(
+                stream::unfold(output_receiver, move |mut chunks| async move {
+                    let chunk = chunks.recv().await?;
+                    Some((chunk.map_err(|err| format.error(err)), chunks))
+                })
+                .boxed(),
+            )
+        })
+        .try_flatten()
+        .boxed()
+    }
+
+    /// The entry index of the sidecar, taking the checkpoints stored ahead of it when they
+    /// are still current.
+    async fn read_sidecar(&self) -> Result<Option<Vec<u8>>> {
+        let Some(sidecar) = self.inner.read_sidecar().await? else {
+            return Ok(None);
+        };
+        let Some(mut rest) = sidecar.strip_prefix(BUNDLE_MAGIC) else {
+            return Ok(Some(sidecar));
+        };
+        let len = take_u64(&mut rest)? as usize;
+        let index = GzipIndex::decode(take(&mut rest, len)?)?;
+        if !self.use_index(index).await? {
+            debug!("The checkpoints stored with the sidecar are stale");
+        }
+        Ok(Some(rest.to_vec()))
+    }
+
+    /// Stores the entry index with the checkpoints ahead of it.
+    async fn write_sidecar(&self, index: Vec<u8>) -> Result<()> {
+        let checkpoints = self.index().await?.encode();
+        let mut sidecar =
+            Vec::with_capacity(BUNDLE_MAGIC.len() + 8 + checkpoints.len() + index.len());
+        sidecar.extend_from_slice(BUNDLE_MAGIC);
+        sidecar.extend_from_slice(&(checkpoints.len() as u64).to_le_bytes());
+        sidecar.extend_from_slice(&checkpoints);
+        sidecar.extend_from_slice(&index);
+        self.inner.write_sidecar(sidecar).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    /// Text that spans several checkpoints yet compresses well.
+    fn text(len: usize) -> Vec<u8> {
+        let mut state = 7u32;
+        let mut text = Vec::with_capacity(len + 16);
+        while text.len() < len {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            text.extend_from_slice(format!("line {}\n", state % 100_000).as_bytes());
+        }
+        text.truncate(len);
+        text
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn reader(compressed: Vec<u8>) -> GzipReader {
+        GzipReader::new(Arc::new(MemoryBackend::new(compressed)))
+    }
+
+    #[tokio::test]
+    async fn reads_ranges_from_the_checkpoint_before_them() {
+        let data = text(10 * 1024 * 1024);
+        let compressed = gzip(&data);
+        let gzip = reader(compressed.clone());
+        let index = gzip.index().await.unwrap();
+        assert_eq!(index.size(), data.len() as u64);
+        assert!(index.checkpoints() >= 3);
+
+        for (offset, len) in [(0, 100), (5_000_000, 300_000), (9_000_000, 2_000_000)] {
+            let end = (offset + len).min(data.len());
+            let read = gzip.read_range(offset as u64, len as u64).await.unwrap();
+            assert_eq!(read, data[offset..end]);
+        }
+
+        // Checkpoints stored earlier serve a new reader without decoding the file again.
+        let reopened = reader(compressed);
+        assert!(reopened
+            .use_index(GzipIndex::decode(&index.encode()).unwrap())
+            .await
+            .unwrap());
+        let read = reopened.read_range(8_000_000, 1000).await.unwrap();
+        assert_eq!(read, data[8_000_000..8_001_000]);
+    }
+
+    #[tokio::test]
+    async fn reads_every_member() {
+        let (first, second) = (text(300_000), text(200_000));
+        let mut compressed = gzip(&first);
+        compressed.extend(gzip(&second));
+        let gzip = reader(compressed);
+        assert_eq!(gzip.size().await.unwrap(), 500_000);
+        let read = gzip.read_range(299_990, 20).await.unwrap();
+        assert_eq!(read[..10], first[299_990..]);
+        assert_eq!(read[10..], second[..10]);
+    }
+
+    #[tokio::test]
+    async fn reads_raw_deflate_streams() {
+        let data = text(1_000_000);
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let crc32 = crc32fast::hash(&data);
+
+        let deflate = GzipReader::deflate(
+            Arc::new(MemoryBackend::new(compressed.clone())),
+            Some(crc32),
+        );
+        let read = deflate.read_range(400_000, 1000).await.unwrap();
+        assert_eq!(read, data[400_000..401_000]);
+
+        let wrong = GzipReader::deflate(Arc::new(MemoryBackend::new(compressed)), Some(!crc32));
+        assert!(wrong.index().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_corrupted_trailers() {
+        let mut compressed = gzip(&text(100_000));
+        let crc = compressed.len() - 8;
+        compressed[crc] ^= 0xff;
+        assert!(reader(compressed).index().await.is_err());
+    }
+}
@@ -0,0 +1,137 @@
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use reqwest::header::{CONTENT_RANGE, ETAG, RANGE};
+use reqwest::{Response, StatusCode};
+use tokio::sync::OnceCell;
+
+use super::{response_stream, ByteStream, RangeReader};
+use crate::error::{CloudZipError, Result};
+
+/// Any HTTP(S) URL served by a server that honors `Range` requests.
+///
+/// The size is learned from the `Content-Range` of a ranged GET rather than a HEAD request,
+/// so presigned GET URLs work as well.
+pub struct HttpBackend {
+    http: reqwest::Client,
+    url: String,
+    head: OnceCell<(u64, Option<String>)>,
+}
+
+impl HttpBackend {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self::with_client(reqwest::Client::new(), url)
+    }
+
+    pub fn with_client(http: reqwest::Client, url: impl Into<String>) -> Self {
+        HttpBackend {
+            http,
+            url: url.into(),
+            head: OnceCell::new(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Sends a ranged GET and insists on a partial response.
+    async fn get_range(&self, range: String) -> Result<Response> {
+        let resp = self
+            .http
+            .get(&self.url)
+            .header(RANGE, range)
+            .send()
+            .await
+            .map_err(CloudZipError::http)?;
+
+        match resp.status() {
+            StatusCode::PARTIAL_CONTENT => Ok(resp),
+            status if status.is_success() => Err(CloudZipError::http_status(
+                &self.url,
+                status.as_u16(),
+                "server ignored the Range header",
+            )),
+            status => {
+                let body = resp.text().await.unwrap_or_default();
+                // What S3 answers once the expiry of a presigned URL has passed.
+                let body =
+                    if status == StatusCode::FORBIDDEN && body.contains("Request has expired") {
+                        "the presigned URL has expired, ask for a new one".to_string()
+                    } else {
+                        body
+                    };
+                Err(CloudZipError::http_status(
+                    &self.url,
+                    status.as_u16(),
+                    &body,
+                ))
+            }
+        }
+    }
+
+    fn parse_head(&self, resp: &Response) -> Result<(u64, Option<String>)> {
+        let size = resp
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|range| range.to_str().ok()?.rsplit('/').next()?.parse::<u64>().ok())
+            .ok_or_else(|| {
+                CloudZipError::invalid_archive("HTTP response is missing the total size")
+            })?;
+        let etag = resp
+            .headers()
+            .get(ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_string);
+        Ok((size, etag))
+    }
+
+    async fn head(&self) -> Result<&(u64, Option<String>)> {
+        self.head
+            .get_or_try_init(|| async {
+                let resp = self.get_range("bytes=0-0".to_string()).await?;
+                self.parse_head(&resp)
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl RangeReader for HttpBackend {
+    async fn read_range(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let resp = self
+            .get_range(format!("bytes={}-{}", offset, offset + len - 1))
+            .await?;
+        let body = resp.bytes().await.map_err(CloudZipError::http)?;
+        Ok(body.to_vec())
+    }
+
+    fn stream_range(&self, offset: u64, len: u64) -> ByteStream<'_> {
+        if len == 0 {
+            return stream::empty().boxed();
+        }
+        stream::once(self.get_range(format!("bytes={}-{}", offset, offset + len - 1)))
+            .map_ok(response_stream)
+            .try_flatten()
+            .boxed()
+    }
+
+    async fn size(&self) -> Result<u64> {
+        Ok(self.head().await?.0)
+    }
+
+    async fn etag(&self) -> Result<Option<String>> {
+        Ok(self.head().await?.1.clone())
+    }
+
+    async fn read_tail(&self, len: u64) -> Result<(Vec<u8>, u64)> {
+        let resp = self.get_range(format!("bytes=-{}", len)).await?;
+        let head = self.parse_head(&resp)?;
+        let size = head.0;
+        let _ = self.head.set(head);
+        let body = resp.bytes().await.map_err(CloudZipError::http)?;
+        Ok((body.to_vec(), size))
+    }
+}
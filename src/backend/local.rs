@@ -0,0 +1,121 @@
+use async_trait::async_trait;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use super::{RangeReader, SIDECAR_SUFFIX};
+use crate::error::Result;
+
+/// A zip file on the local filesystem.
+pub struct LocalBackend {
+    path: PathBuf,
+    file: Arc<File>,
+}
+
+impl LocalBackend {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let file = File::open(&path)?;
+        Ok(LocalBackend {
+            path,
+            file: Arc::new(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn sidecar_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(SIDECAR_SUFFIX);
+        path.into()
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+/// Reads `len` bytes at `offset`, or as many as the file holds there.
+fn read_fully_at(file: &File, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    let len = len.min(file.metadata()?.len().saturating_sub(offset));
+    let mut buf = vec![0u8; len as usize];
+    let mut filled = 0;
+    while filled < buf.len() {
+        match read_at(file, &mut buf[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    buf.truncate(filled);
+    Ok(buf)
+}
+
+#[async_trait]
+impl RangeReader for LocalBackend {
+    async fn read_range(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let file = self.file.clone();
+        let bytes = tokio::task::spawn_blocking(move || read_fully_at(&file, offset, len))
+            .await
+            .map_err(io::Error::other)??;
+        Ok(bytes)
+    }
+
+    async fn size(&self) -> Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    /// Local files have no ETag; modification time and size stand in for one.
+    async fn etag(&self) -> Result<Option<String>> {
+        let metadata = self.file.metadata()?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Some(format!(
+            "{:x}.{:x}-{:x}",
+            modified.as_secs(),
+            modified.subsec_nanos(),
+            metadata.len()
+        )))
+    }
+
+    async fn read_sidecar(&self) -> Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.sidecar_path()).await {
+            Ok(index) => Ok(Some(index)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn write_sidecar(&self, index: Vec<u8>) -> Result<()> {
+        tokio::fs::write(self.sidecar_path(), index).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_stop_at_the_end_of_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.zip");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let backend = LocalBackend::open(&path).unwrap();
+        assert_eq!(backend.read_range(5, u64::MAX).await.unwrap(), b"56789");
+        assert!(backend.read_range(20, 10).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,276 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use super::{split_range, ByteStream, RangeReader, STREAM_CHUNK_LEN};
+use crate::error::Result;
+use crate::metadata::ArchiveFingerprint;
+use crate::writer::{ArchiveSink, ArchiveWriter, EntryAttributes};
+
+/// A zip held in memory, such as an archive decompressed from an entry of another one, or one
+/// built by [`MemoryBackend::zip`] to exercise indexing and extraction without any storage
+/// service.
+///
+/// Its sidecar is kept in memory too, so indexes can be written and found again.
+pub struct MemoryBackend {
+    bytes: Bytes,
+    etag: Option<String>,
+    sidecar: Mutex<Option<Vec<u8>>>,
+}
+
+impl MemoryBackend {
+    pub fn new(bytes: impl Into<Bytes>) -> Self {
+        MemoryBackend {
+            bytes: bytes.into(),
+            etag: None,
+            sidecar: Mutex::new(None),
+        }
+    }
+
+    /// Writes a zip of `files`, each a name and its content, deflating the content; names
+    /// ending in `/` are directories.
+    pub async fn zip<N, C>(files: impl IntoIterator<Item = (N, C)>) -> Result<Self>
+    where
+        N: AsRef<str>,
+        C: AsRef<[u8]>,
+    {
+        Self::zip_with_level(files, 6).await
+    }
+
+    /// Like [`MemoryBackend::zip`], storing the content as it is, which leaves every entry
+    /// readable from any offset.
+    pub async fn stored_zip<N, C>(files: impl IntoIterator<Item = (N, C)>) -> Result<Self>
+    where
+        N: AsRef<str>,
+        C: AsRef<[u8]>,
+    {
+        Self::zip_with_level(files, 0).await
+    }
+
+    async fn zip_with_level<N, C>(
+        files: impl IntoIterator<Item = (N, C)>,
+        level: u32,
+    ) -> Result<Self>
+    where
+        N: AsRef<str>,
+        C: AsRef<[u8]>,
+    {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let mut writer = ArchiveWriter::new(MemorySink(written.clone())).with_level(level);
+        for (name, content) in files {
+            let name = name.as_ref();
+            if name.ends_with('/') {
+                writer
+                    .add_directory(name, EntryAttributes::default())
+                    .await?;
+            } else {
+                writer
+                    .add_file(name, content.as_ref(), EntryAttributes::default())
+                    .await?;
+            }
+        }
+        writer.finish().await?;
+        let bytes = std::mem::take(&mut *written.lock().unwrap_or_else(PoisonError::into_inner));
+        Ok(Self::new(bytes))
+    }
+
+    /// Reports `etag` as the version tag, so that a changed archive can be told apart from
+    /// the one an index was built from.
+    pub fn with_etag(mut self, etag: impl Into<String>) -> Self {
+        self.etag = Some(etag.into());
+        self
+    }
+
+    pub fn bytes(&self) -> &Bytes {
+        &self.bytes
+    }
+
+    fn slice(&self, offset: u64, len: u64) -> Bytes {
+        let size = self.bytes.len() as u64;
+        let start = offset.min(size) as usize;
+        let end = offset.saturating_add(len).min(size) as usize;
+        self.bytes.slice(start..end)
+    }
+}
+
+#[async_trait]
+impl RangeReader for MemoryBackend {
+    async fn read_range(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        Ok(self.slice(offset, len).to_vec())
+    }
+
+    async fn size(&self) -> Result<u64> {
+        Ok(self.bytes.len() as u64)
+    }
+
+    async fn etag(&self) -> Result<Option<String>> {
+        Ok(self.etag.clone())
+    }
+
+    /// Hands out the chunks without copying them.
+    fn stream_range(&self, offset: u64, len: u64) -> ByteStream<'_> {
+        stream::iter(split_range(offset, len, STREAM_CHUNK_LEN))
+            .map(move |(offset, len)| Ok(self.slice(offset, len)))
+            .boxed()
+    }
+
+    async fn read_sidecar(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .sidecar
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone())
+    }
+
+    async fn write_sidecar(&self, index: Vec<u8>) -> Result<()> {
+        *self.sidecar.lock().unwrap_or_else(PoisonError::into_inner) = Some(index);
+        Ok(())
+    }
+}
+
+/// Collects an archive being written into a buffer shared with whoever reads it afterwards.
+struct MemorySink(Arc<Mutex<Vec<u8>>>);
+
+#[async_trait]
+impl ArchiveSink for MemorySink {
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extend_from_slice(data);
+        Ok(())
+    }
+
+    async fn finish(self: Box<Self>) -> Result<ArchiveFingerprint> {
+        let size = self.0.lock().unwrap_or_else(PoisonError::into_inner).len() as u64;
+        Ok(ArchiveFingerprint {
+            size,
+            etag: None,
+            version_id: None,
+        })
+    }
+
+    async fn abort(self: Box<Self>) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::CloudZipError;
+    use crate::selection::EntrySelector;
+    use crate::CloudZip;
+
+    const FILES: [(&str, &str); 3] = [
+        ("docs/", ""),
+        ("docs/readme.txt", "hello, world"),
+        ("data.csv", "a,b\n1,2\n"),
+    ];
+
+    #[tokio::test]
+    async fn indexes_a_built_zip() {
+        let archive = CloudZip::discover(Arc::new(MemoryBackend::zip(FILES).await.unwrap()))
+            .await
+            .unwrap();
+        let list = archive.list().unwrap();
+        let names: Vec<&str> = list.iter().map(|entry| entry.file_name.as_str()).collect();
+        assert_eq!(names, ["docs/", "docs/readme.txt", "data.csv"]);
+        assert!(list[0].is_directory);
+        assert_eq!(list[1].uncompressed_size, 12);
+    }
+
+    #[tokio::test]
+    async fn extracts_deflated_and_stored_zips() {
+        for backend in [
+            MemoryBackend::zip(FILES).await.unwrap(),
+            MemoryBackend::stored_zip(FILES).await.unwrap(),
+        ] {
+            let archive = CloudZip::discover(Arc::new(backend)).await.unwrap();
+            let dir = tempfile::tempdir().unwrap();
+            archive
+                .extract_matching(&EntrySelector::All, dir.path())
+                .await
+                .unwrap();
+            for (name, content) in FILES.iter().filter(|(name, _)| !name.ends_with('/')) {
+                assert_eq!(
+                    std::fs::read(dir.path().join(name)).unwrap(),
+                    content.as_bytes()
+                );
+            }
+            assert!(dir.path().join("docs").is_dir());
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_crc_mismatches() {
+        let zip = MemoryBackend::stored_zip(FILES).await.unwrap();
+        let mut bytes = zip.bytes().to_vec();
+        let at = bytes
+            .windows(5)
+            .position(|window| window == b"hello")
+            .unwrap();
+        bytes[at] = b'j';
+        let archive = CloudZip::discover(Arc::new(MemoryBackend::new(bytes)))
+            .await
+            .unwrap();
+        let checks = archive.verify(&EntrySelector::All).await.unwrap();
+        let check = checks
+            .iter()
+            .find(|check| check.file_name == "docs/readme.txt")
+            .unwrap();
+        assert!(matches!(
+            check.result,
+            Err(CloudZipError::CrcMismatch { .. })
+        ));
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            archive.extract_to("docs/readme.txt", dir.path()).await,
+            Err(CloudZipError::CrcMismatch { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn round_trips_the_sidecar() {
+        let backend = Arc::new(MemoryBackend::zip(FILES).await.unwrap().with_etag("v1"));
+        assert!(backend.read_sidecar().await.unwrap().is_none());
+        let archive = CloudZip::discover(backend.clone()).await.unwrap();
+        archive.upload_sidecar().await.unwrap();
+        assert!(backend.read_sidecar().await.unwrap().is_some());
+
+        let reopened = CloudZip::discover(backend).await.unwrap();
+        let names = |archive: &CloudZip| -> Vec<String> {
+            archive
+                .list()
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.file_name)
+                .collect()
+        };
+        assert_eq!(names(&reopened), names(&archive));
+        let entries = reopened.verified_entries().await.unwrap();
+        assert_eq!(entries.fingerprint().unwrap().etag.as_deref(), Some("v1"));
+    }
+
+    #[tokio::test]
+    async fn notices_a_replaced_archive() {
+        let original = Arc::new(MemoryBackend::zip(FILES).await.unwrap().with_etag("v1"));
+        let entries = CloudZip::discover(original)
+            .await
+            .unwrap()
+            .verified_entries()
+            .await
+            .unwrap();
+        let replaced = MemoryBackend::zip(FILES).await.unwrap().with_etag("v2");
+        let archive = CloudZip::from_entry_index(Arc::new(replaced), (*entries).clone());
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            archive.extract_to("data.csv", dir.path()).await,
+            Err(CloudZipError::ArchiveChanged(_))
+        ));
+    }
+}
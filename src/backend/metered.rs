@@ -0,0 +1,82 @@
+use async_trait::async_trait;
+use futures::stream::{StreamExt, TryStreamExt};
+use std::sync::Arc;
+
+use super::{ByteStream, RangeReader};
+use crate::error::Result;
+use crate::metrics::MetricsRecorder;
+
+/// Counts the reads sent to another backend, the bytes they return and the failures into a
+/// [`MetricsRecorder`]. A streamed range is one read, however many chunks it comes in.
+pub struct MeteredReader {
+    inner: Arc<dyn RangeReader>,
+    metrics: Arc<MetricsRecorder>,
+}
+
+impl MeteredReader {
+    pub fn new(inner: Arc<dyn RangeReader>, metrics: Arc<MetricsRecorder>) -> Self {
+        MeteredReader { inner, metrics }
+    }
+
+    fn counted<T>(&self, result: Result<T>) -> Result<T> {
+        if result.is_err() {
+            self.metrics.failed();
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl RangeReader for MeteredReader {
+    async fn read_range(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        self.metrics.request();
+        let bytes = self.counted(self.inner.read_range(offset, len).await)?;
+        self.metrics.downloaded(bytes.len() as u64);
+        Ok(bytes)
+    }
+
+    async fn size(&self) -> Result<u64> {
+        self.inner.size().await
+    }
+
+    async fn etag(&self) -> Result<Option<String>> {
+        self.inner.etag().await
+    }
+
+    async fn version_id(&self) -> Result<Option<String>> {
+        self.inner.version_id().await
+    }
+
+    fn stream_range(&self, offset: u64, len: u64) -> ByteStream<'_> {
+        self.metrics.request();
+        self.inner
+            .stream_range(offset, len)
+            .inspect_ok(|chunk| self.metrics.downloaded(chunk.len() as u64))
+            .inspect_err(|_| self.metrics.failed())
+            .boxed()
+    }
+
+    async fn read_tail(&self, len: u64) -> Result<(Vec<u8>, u64)> {
+        self.metrics.request();
+        let (bytes, size) = self.counted(self.inner.read_tail(len).await)?;
+        self.metrics.downloaded(bytes.len() as u64);
+        Ok((bytes, size))
+    }
+
+    async fn read_sidecar(&self) -> Result<Option<Vec<u8>>> {
+        self.metrics.request();
+        let index = self.counted(self.inner.read_sidecar().await)?;
+        if let Some(index) = &index {
+            self.metrics.downloaded(index.len() as u64);
+        }
+        Ok(index)
+    }
+
+    async fn write_sidecar(&self, index: Vec<u8>) -> Result<()> {
+        self.inner.write_sidecar(index).await
+    }
+
+    fn disk_starts(&self) -> &[u64] {
+        self.inner.disk_starts()
+    }
+}
@@ -0,0 +1,136 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+
+use crate::error::{CloudZipError, Result};
+
+#[cfg(feature = "azure")]
+pub mod azure;
+pub mod cache;
+mod coalesce;
+pub mod faults;
+pub mod gzip;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod local;
+pub mod memory;
+pub mod metered;
+pub mod retry;
+pub mod s3;
+mod s3_http;
+pub mod slice;
+pub mod split;
+pub mod throttle;
+
+#[cfg(feature = "azure")]
+pub use azure::AzureBackend;
+pub use cache::{CacheConfig, CachingReader};
+pub(crate) use coalesce::{group_ranges, CoalescingReader};
+pub use faults::{FaultSchedule, Faults, FaultyReader};
+pub use gzip::{GzipIndex, GzipReader};
+#[cfg(feature = "http")]
+pub use http::HttpBackend;
+pub use local::LocalBackend;
+pub use memory::MemoryBackend;
+pub use metered::MeteredReader;
+pub use retry::{RetryPolicy, RetryingReader};
+pub use s3::S3Backend;
+pub use slice::SliceReader;
+pub use split::SplitReader;
+pub use throttle::{BandwidthLimit, ThrottledReader};
+
+/// Consecutive chunks of a byte range, as produced by [`RangeReader::stream_range`].
+pub type ByteStream<'a> = BoxStream<'a, Result<Bytes>>;
+
+/// Chunk size used by the default [`RangeReader::stream_range`].
+const STREAM_CHUNK_LEN: u64 = 1024 * 1024;
+
+/// Appended to the archive name to form the name of its index sidecar, e.g. `a.zip.czidx`.
+pub const SIDECAR_SUFFIX: &str = ".czidx";
+
+/// Splits `len` bytes starting at `offset` into `(offset, len)` parts of at most `part_len`.
+pub(crate) fn split_range(
+    offset: u64,
+    len: u64,
+    part_len: u64,
+) -> impl Iterator<Item = (u64, u64)> + Send {
+    let part_len = part_len.max(1);
+    (0..len.div_ceil(part_len)).map(move |i| {
+        let start = i * part_len;
+        (offset + start, part_len.min(len - start))
+    })
+}
+
+/// Random access to the bytes of an archive, wherever it is stored.
+///
+/// Indexing and extraction only ever go through this trait, so a new storage service
+/// needs nothing more than an implementation of it.
+#[async_trait]
+pub trait RangeReader: Send + Sync {
+    /// Reads `len` bytes starting at `offset`. Reads past the end of the archive are truncated.
+    async fn read_range(&self, offset: u64, len: u64) -> Result<Vec<u8>>;
+
+    /// Total size of the archive in bytes.
+    async fn size(&self) -> Result<u64>;
+
+    /// An opaque version tag that changes whenever the archive is replaced, if the backend has one.
+    async fn etag(&self) -> Result<Option<String>>;
+
+    /// The version of the archive in a versioned store such as an S3 bucket with versioning.
+    async fn version_id(&self) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Streams `len` bytes starting at `offset` without holding the whole range in memory.
+    ///
+    /// The default issues one ranged read per 1 MiB; backends whose responses can be
+    /// consumed incrementally should override this with a single streamed request.
+    fn stream_range(&self, offset: u64, len: u64) -> ByteStream<'_> {
+        stream::iter(split_range(offset, len, STREAM_CHUNK_LEN))
+            .then(move |(offset, len)| self.read_range(offset, len))
+            .map_ok(Bytes::from)
+            .boxed()
+    }
+
+    /// Reads the last `len` bytes of the archive and returns them with the archive size.
+    ///
+    /// Backends that can learn the size from the same request should override this.
+    async fn read_tail(&self, len: u64) -> Result<(Vec<u8>, u64)> {
+        let size = self.size().await?;
+        let len = len.min(size);
+        Ok((self.read_range(size - len, len).await?, size))
+    }
+
+    /// Reads the index sidecar stored next to the archive, or `None` when there is none.
+    ///
+    /// Backends without a place to keep one never find a sidecar.
+    async fn read_sidecar(&self) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Stores an encoded index next to the archive so that other machines can find it.
+    async fn write_sidecar(&self, _index: Vec<u8>) -> Result<()> {
+        Err(CloudZipError::InvalidLocation(
+            "This storage backend cannot hold an index sidecar".to_string(),
+        ))
+    }
+
+    /// Where each part starts, for a reader joining the parts of a split archive; empty for
+    /// an archive in one piece.
+    fn disk_starts(&self) -> &[u64] {
+        &[]
+    }
+}
+
+/// Streams the body of an HTTP response chunk by chunk.
+#[cfg(any(feature = "azure", feature = "http"))]
+pub(crate) fn response_stream(resp: reqwest::Response) -> ByteStream<'static> {
+    stream::try_unfold(resp, |mut resp| async move {
+        let chunk = resp
+            .chunk()
+            .await
+            .map_err(crate::error::CloudZipError::http)?;
+        Ok(chunk.map(|chunk| (chunk, resp)))
+    })
+    .boxed()
+}
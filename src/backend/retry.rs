@@ -0,0 +1,181 @@
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+use super::{ByteStream, RangeReader};
+use crate::error::{CloudZipError, Result};
+use crate::metrics::MetricsRecorder;
+
+/// How often and how patiently transient backend failures are retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts per request, the first one included; 1 disables retries.
+    pub max_attempts: u32,
+    /// Upper bound of the first backoff, doubled after every failed attempt.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Gives up on the first failure.
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Exponential backoff with full jitter, so that concurrent downloads hitting the same
+    /// failure do not all come back at the same moment.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .initial_backoff
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(self.max_backoff);
+        let random = RandomState::new().hash_one(SystemTime::now());
+        ceiling.mul_f64((random % 1024) as f64 / 1024.0)
+    }
+
+    fn should_retry(&self, err: &CloudZipError, attempt: u32) -> bool {
+        attempt < self.max_attempts && err.is_transient()
+    }
+}
+
+/// Retries the transient failures of another backend according to a [`RetryPolicy`].
+///
+/// Streamed ranges resume after the last byte received, so a connection dropped halfway
+/// through a large entry does not restart it.
+pub struct RetryingReader {
+    inner: Arc<dyn RangeReader>,
+    policy: RetryPolicy,
+    metrics: Option<Arc<MetricsRecorder>>,
+}
+
+impl RetryingReader {
+    pub fn new(inner: Arc<dyn RangeReader>, policy: RetryPolicy) -> Self {
+        RetryingReader {
+            inner,
+            policy,
+            metrics: None,
+        }
+    }
+
+    /// Counts every retry into `metrics`.
+    pub fn with_metrics(mut self, metrics: Option<Arc<MetricsRecorder>>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    fn retried(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.retried();
+        }
+    }
+
+    async fn retry<T, F, Fut>(&self, mut request: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match request().await {
+                Err(err) if self.policy.should_retry(&err, attempt) => {
+                    warn!(attempt, error = %err, "Retrying a failed request");
+                    self.retried();
+                    tokio::time::sleep(self.policy.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl RangeReader for RetryingReader {
+    async fn read_range(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        self.retry(|| self.inner.read_range(offset, len)).await
+    }
+
+    async fn size(&self) -> Result<u64> {
+        self.retry(|| self.inner.size()).await
+    }
+
+    async fn etag(&self) -> Result<Option<String>> {
+        self.retry(|| self.inner.etag()).await
+    }
+
+    async fn version_id(&self) -> Result<Option<String>> {
+        self.retry(|| self.inner.version_id()).await
+    }
+
+    fn stream_range(&self, offset: u64, len: u64) -> ByteStream<'_> {
+        let end = offset + len;
+        stream::try_unfold(
+            (offset, 1, None::<ByteStream<'_>>),
+            move |(mut position, mut attempt, mut current)| async move {
+                loop {
+                    if position >= end {
+                        return Ok(None);
+                    }
+                    let chunks = current
+                        .get_or_insert_with(|| self.inner.stream_range(position, end - position));
+                    let err = match chunks.next().await {
+                        Some(Ok(chunk)) => {
+                            position += chunk.len() as u64;
+                            // Progress was made, so the next failure starts a fresh series.
+                            return Ok(Some((chunk, (position, 1, current))));
+                        }
+                        Some(Err(err)) => err,
+                        None => CloudZipError::Io(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("range ended {} bytes early", end - position),
+                        )),
+                    };
+                    if !self.policy.should_retry(&err, attempt) {
+                        return Err(err);
+                    }
+                    warn!(attempt, position, error = %err, "Resuming an interrupted download");
+                    self.retried();
+                    tokio::time::sleep(self.policy.backoff(attempt)).await;
+                    attempt += 1;
+                    current = None;
+                }
+            },
+        )
+        .boxed()
+    }
+
+    async fn read_tail(&self, len: u64) -> Result<(Vec<u8>, u64)> {
+        self.retry(|| self.inner.read_tail(len)).await
+    }
+
+    async fn read_sidecar(&self) -> Result<Option<Vec<u8>>> {
+        self.retry(|| self.inner.read_sidecar()).await
+    }
+
+    async fn write_sidecar(&self, index: Vec<u8>) -> Result<()> {
+        self.retry(|| self.inner.write_sidecar(index.clone())).await
+    }
+
+    fn disk_starts(&self) -> &[u64] {
+        self.inner.disk_starts()
+    }
+}
@@ -0,0 +1,533 @@
+use async_trait::async_trait;
+use aws_config::provider_config::ProviderConfig;
+use aws_config::sts::AssumeRoleProvider;
+use aws_config::web_identity_token::{StaticConfiguration, WebIdentityTokenCredentialsProvider};
+use aws_config::{meta::region::RegionProviderChain, BehaviorVersion};
+use aws_sdk_s3::config::http::HttpResponse;
+use aws_sdk_s3::config::timeout::TimeoutConfig;
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::operation::get_object::{GetObjectError, GetObjectOutput};
+use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::primitives::ByteStream as S3ByteStream;
+use aws_sdk_s3::types::{RequestPayer, ServerSideEncryption};
+use aws_sdk_s3::{config::Region, Client};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use md5::{Digest, Md5};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tracing::{debug_span, Instrument, Span};
+
+use super::s3_http::http_client;
+use super::{ByteStream, RangeReader, SIDECAR_SUFFIX};
+use crate::error::{is_transient_status, CloudZipError, Result};
+
+/// An object in S3 or an S3-compatible store, read with ranged GETs.
+pub struct S3Backend {
+    client: Client,
+    bucket: String,
+    key: String,
+    version_id: Option<String>,
+    request_payer: Option<RequestPayer>,
+    encryption: S3Encryption,
+    head: OnceCell<ObjectHead>,
+}
+
+/// What a HEAD request tells about the object.
+struct ObjectHead {
+    size: u64,
+    etag: Option<String>,
+    version_id: Option<String>,
+}
+
+impl S3Backend {
+    pub fn new(client: Client, bucket: impl Into<String>, key: impl Into<String>) -> Self {
+        S3Backend {
+            client,
+            bucket: bucket.into(),
+            key: key.into(),
+            version_id: None,
+            request_payer: None,
+            encryption: S3Encryption::default(),
+            head: OnceCell::new(),
+        }
+    }
+
+    /// Pins reads to one version of the object in a bucket with versioning enabled.
+    pub fn with_version_id(mut self, version_id: Option<String>) -> Self {
+        self.version_id = version_id;
+        self
+    }
+
+    /// Accepts the charges for reading from a requester-pays bucket.
+    pub fn with_requester_pays(mut self, requester_pays: bool) -> Self {
+        self.request_payer = requester_pays.then_some(RequestPayer::Requester);
+        self
+    }
+
+    /// Sends the SSE-C key with every request, or checks that the object uses SSE-KMS.
+    pub fn with_encryption(mut self, encryption: S3Encryption) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// Creates a URL that lets anyone read the object with plain HTTP GETs, e.g. through
+    /// [`HttpBackend`](super::HttpBackend), until it expires. S3 allows at most seven days.
+    pub async fn presigned_url(&self, expires_in: Duration) -> Result<String> {
+        let presigning = PresigningConfig::expires_in(expires_in)
+            .map_err(|err| CloudZipError::InvalidLocation(err.to_string()))?;
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .set_version_id(self.version_id.clone())
+            .presigned(presigning)
+            .await
+            .map_err(|err| CloudZipError::s3(err, false))?;
+        Ok(request.uri().to_string())
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// The span of one GET of `range`, covering the request and, when the body is read
+    /// whole, the body too.
+    fn get_span(&self, range: &str) -> Span {
+        debug_span!("s3.get", otel.kind = "client", bucket = %self.bucket, key = %self.key, range)
+    }
+
+    async fn head(&self) -> Result<&ObjectHead> {
+        self.head
+            .get_or_try_init(|| async {
+                let resp = self
+                    .client
+                    .head_object()
+                    .bucket(&self.bucket)
+                    .key(&self.key)
+                    .set_version_id(self.version_id.clone())
+                    .set_request_payer(self.request_payer.clone())
+                    .set_sse_customer_algorithm(self.encryption.customer_algorithm())
+                    .set_sse_customer_key(self.encryption.customer_key())
+                    .set_sse_customer_key_md5(self.encryption.customer_key_md5())
+                    .send()
+                    .await
+                    .map_err(request_error)?;
+                self.encryption
+                    .check(resp.server_side_encryption(), resp.ssekms_key_id())?;
+                let size = resp.content_length().ok_or_else(|| {
+                    CloudZipError::invalid_archive("S3 response is missing the object size")
+                })?;
+                Ok(ObjectHead {
+                    size: size as u64,
+                    etag: resp.e_tag().map(str::to_string),
+                    version_id: resp.version_id().map(str::to_string),
+                })
+            })
+            .await
+    }
+
+    async fn get_range(&self, byte_range: String) -> Result<GetObjectOutput> {
+        self.client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .set_version_id(self.version_id.clone())
+            .set_request_payer(self.request_payer.clone())
+            .set_sse_customer_algorithm(self.encryption.customer_algorithm())
+            .set_sse_customer_key(self.encryption.customer_key())
+            .set_sse_customer_key_md5(self.encryption.customer_key_md5())
+            .range(byte_range)
+            .send()
+            .await
+            .map_err(request_error)
+    }
+
+    /// Reads the tail named by a suffix `range`, and the object size the response gives.
+    async fn read_suffix(&self, range: String) -> Result<(Vec<u8>, u64)> {
+        let resp = self.get_range(range).await?;
+
+        let object_size = resp
+            .content_range()
+            .and_then(|range| range.rsplit('/').next())
+            .and_then(|total| total.parse::<u64>().ok())
+            .or(resp.content_length().map(|len| len as u64))
+            .ok_or_else(|| {
+                CloudZipError::invalid_archive("S3 response is missing the object size")
+            })?;
+        self.encryption
+            .check(resp.server_side_encryption(), resp.ssekms_key_id())?;
+        let _ = self.head.set(ObjectHead {
+            size: object_size,
+            etag: resp.e_tag().map(str::to_string),
+            version_id: resp.version_id().map(str::to_string),
+        });
+
+        let body = resp.body.collect().await.map_err(body_error)?;
+        Ok((body.to_vec(), object_size))
+    }
+
+    async fn get_sidecar(&self, key: String) -> Result<Option<Vec<u8>>> {
+        let result = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .set_request_payer(self.request_payer.clone())
+            .set_sse_customer_algorithm(self.encryption.customer_algorithm())
+            .set_sse_customer_key(self.encryption.customer_key())
+            .set_sse_customer_key_md5(self.encryption.customer_key_md5())
+            .send()
+            .await;
+        let resp = match result {
+            Ok(resp) => resp,
+            Err(err)
+                if err
+                    .as_service_error()
+                    .is_some_and(GetObjectError::is_no_such_key) =>
+            {
+                return Ok(None)
+            }
+            // Without permission to list the bucket, which anonymous readers of a public bucket
+            // never have, S3 answers 403 instead of 404 for a missing key.
+            Err(SdkError::ServiceError(err)) if err.raw().status().as_u16() == 403 => {
+                return Ok(None)
+            }
+            Err(err) => return Err(request_error(err)),
+        };
+        let body = resp.body.collect().await.map_err(body_error)?;
+        Ok(Some(body.to_vec()))
+    }
+}
+
+#[async_trait]
+impl RangeReader for S3Backend {
+    async fn read_range(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let range = format!("bytes={}-{}", offset, offset + len - 1);
+        let span = self.get_span(&range);
+        async {
+            let resp = self.get_range(range).await?;
+            let body = resp.body.collect().await.map_err(body_error)?;
+            Ok(body.to_vec())
+        }
+        .instrument(span)
+        .await
+    }
+
+    fn stream_range(&self, offset: u64, len: u64) -> ByteStream<'_> {
+        if len == 0 {
+            return stream::empty().boxed();
+        }
+        let range = format!("bytes={}-{}", offset, offset + len - 1);
+        let span = self.get_span(&range);
+        stream::once(self.get_range(range).instrument(span))
+            .map_ok(|resp| {
+                stream::try_unfold(resp.body, |mut body| async move {
+                    let chunk = body.try_next().await.map_err(body_error)?;
+                    Ok(chunk.map(|chunk| (chunk, body)))
+                })
+            })
+            .try_flatten()
+            .boxed()
+    }
+
+    async fn size(&self) -> Result<u64> {
+        Ok(self.head().await?.size)
+    }
+
+    async fn etag(&self) -> Result<Option<String>> {
+        Ok(self.head().await?.etag.clone())
+    }
+
+    async fn version_id(&self) -> Result<Option<String>> {
+        Ok(self.head().await?.version_id.clone())
+    }
+
+    /// Uses a suffix range so the object size comes back with the tail in one request.
+    async fn read_tail(&self, len: u64) -> Result<(Vec<u8>, u64)> {
+        let range = format!("bytes=-{}", len);
+        let span = self.get_span(&range);
+        self.read_suffix(range).instrument(span).await
+    }
+
+    async fn read_sidecar(&self) -> Result<Option<Vec<u8>>> {
+        let key = format!("{}{}", self.key, SIDECAR_SUFFIX);
+        let span = debug_span!("s3.get", otel.kind = "client", bucket = %self.bucket, key = %key);
+        self.get_sidecar(key).instrument(span).await
+    }
+
+    async fn write_sidecar(&self, index: Vec<u8>) -> Result<()> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(format!("{}{}", self.key, SIDECAR_SUFFIX))
+            .set_request_payer(self.request_payer.clone())
+            .set_sse_customer_algorithm(self.encryption.customer_algorithm())
+            .set_sse_customer_key(self.encryption.customer_key())
+            .set_sse_customer_key_md5(self.encryption.customer_key_md5())
+            .set_server_side_encryption(self.encryption.kms().map(|_| ServerSideEncryption::AwsKms))
+            .set_ssekms_key_id(self.encryption.kms().flatten().map(str::to_string))
+            .body(S3ByteStream::from(index))
+            .send()
+            .await
+            .map_err(request_error)?;
+        Ok(())
+    }
+}
+
+/// Server-side encryption of the archive object. The sidecar index is stored the same way.
+#[derive(Debug, Clone, Default)]
+pub enum S3Encryption {
+    /// Whatever the bucket applies; nothing is sent or checked.
+    #[default]
+    Default,
+    /// SSE-C: the object is encrypted with a key that every request has to carry.
+    CustomerKey(CustomerKey),
+    /// SSE-KMS: reading fails unless the object is encrypted with KMS, and with this key when
+    /// one is given as a key ID or ARN.
+    Kms { key_id: Option<String> },
+}
+
+impl S3Encryption {
+    fn customer(&self) -> Option<&CustomerKey> {
+        match self {
+            S3Encryption::CustomerKey(key) => Some(key),
+            _ => None,
+        }
+    }
+
+    fn customer_algorithm(&self) -> Option<String> {
+        self.customer().map(|_| "AES256".to_string())
+    }
+
+    fn customer_key(&self) -> Option<String> {
+        self.customer().map(|key| key.key.clone())
+    }
+
+    fn customer_key_md5(&self) -> Option<String> {
+        self.customer().map(|key| key.key_md5.clone())
+    }
+
+    /// The expected KMS key, `Some(None)` when any key will do.
+    fn kms(&self) -> Option<Option<&str>> {
+        match self {
+            S3Encryption::Kms { key_id } => Some(key_id.as_deref()),
+            _ => None,
+        }
+    }
+
+    fn check(&self, sse: Option<&ServerSideEncryption>, kms_key_id: Option<&str>) -> Result<()> {
+        let Some(expected_key) = self.kms() else {
+            return Ok(());
+        };
+        if !matches!(
+            sse,
+            Some(ServerSideEncryption::AwsKms | ServerSideEncryption::AwsKmsDsse)
+        ) {
+            return Err(CloudZipError::UnexpectedEncryption(format!(
+                "expected SSE-KMS, found {}",
+                sse.map_or("no server-side encryption", ServerSideEncryption::as_str)
+            )));
+        }
+        match (expected_key, kms_key_id) {
+            // S3 reports the key ARN, which ends with `key/<key ID>`.
+            (Some(expected), Some(actual))
+                if actual != expected && !actual.ends_with(&format!("/{}", expected)) =>
+            {
+                Err(CloudZipError::UnexpectedEncryption(format!(
+                    "expected KMS key {}, found {}",
+                    expected, actual
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A 256-bit SSE-C key, kept base64 encoded along with its MD5 as S3 wants them.
+#[derive(Clone)]
+pub struct CustomerKey {
+    key: String,
+    key_md5: String,
+}
+
+impl CustomerKey {
+    /// Takes the key as base64, like the AWS CLI does.
+    pub fn from_base64(key: &str) -> Result<Self> {
+        let raw = BASE64.decode(key.trim()).map_err(|err| {
+            CloudZipError::InvalidLocation(format!("SSE-C key is not base64: {}", err))
+        })?;
+        if raw.len() != 32 {
+            return Err(CloudZipError::InvalidLocation(format!(
+                "SSE-C key must be 32 bytes, got {}",
+                raw.len()
+            )));
+        }
+        Ok(CustomerKey {
+            key: BASE64.encode(&raw),
+            key_md5: BASE64.encode(Md5::digest(&raw)),
+        })
+    }
+}
+
+/// Keeps the key itself out of logs and error messages.
+impl fmt::Debug for CustomerKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CustomerKey")
+            .field("key_md5", &self.key_md5)
+            .finish_non_exhaustive()
+    }
+}
+
+pub(crate) fn request_error<E>(err: SdkError<E, HttpResponse>) -> CloudZipError
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let transient = is_transient_request(&err);
+    CloudZipError::s3(err, transient)
+}
+
+/// Timeouts, connection failures, throttling and server errors are worth retrying; this holds
+/// for the requests of every AWS SDK client.
+pub(crate) fn is_transient_request<E>(err: &SdkError<E, HttpResponse>) -> bool {
+    match err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            true
+        }
+        SdkError::ServiceError(service) => is_transient_status(service.raw().status().as_u16()),
+        _ => false,
+    }
+}
+
+/// The connection dropping halfway through a response body.
+fn body_error<E>(err: E) -> CloudZipError
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    CloudZipError::s3(err, true)
+}
+
+/// Connection settings for the S3 client; anything left unset falls back to the AWS
+/// default provider chain (environment, profile, instance metadata).
+#[derive(Debug, Clone, Default)]
+pub struct S3Config {
+    /// Custom endpoint such as `http://127.0.0.1:9000` for MinIO.
+    pub endpoint_url: Option<String>,
+    pub region: Option<String>,
+    /// Address buckets as `endpoint/bucket/key` instead of `bucket.endpoint/key`.
+    pub force_path_style: bool,
+    /// Send unsigned requests without looking up any credentials, for public buckets.
+    pub no_sign_request: bool,
+    /// Accept the charges for reading from requester-pays buckets.
+    pub requester_pays: bool,
+    pub encryption: S3Encryption,
+    /// Named profile from the shared AWS config and credentials files.
+    pub profile: Option<String>,
+    /// Role to assume, e.g. one in the account that owns the bucket.
+    pub role_arn: Option<String>,
+    /// External ID required by the trust policy of the role.
+    pub external_id: Option<String>,
+    pub role_session_name: Option<String>,
+    /// OIDC token exchanged for credentials of `role_arn` (IRSA, GitHub Actions, ...) instead
+    /// of calling AssumeRole with the base credentials.
+    pub web_identity_token_file: Option<PathBuf>,
+    /// Gives up on opening a connection after this long; `None` keeps the SDK's 3.1 seconds.
+    pub connect_timeout: Option<Duration>,
+    /// Gives up on a request whose response has not started after this long; `None` waits
+    /// as long as the SDK does.
+    pub read_timeout: Option<Duration>,
+    /// Requests in flight at once, each of which holds a connection until its body is read;
+    /// further ones wait for a free slot. `None` leaves them unbounded.
+    pub max_connections: Option<usize>,
+    pub http_version: HttpVersion,
+}
+
+/// The HTTP versions the S3 client may speak.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// HTTP/2 where the endpoint offers it during the TLS handshake, HTTP/1.1 otherwise.
+    #[default]
+    Auto,
+    Http1,
+    /// HTTP/2 only, over plain HTTP too, for endpoints known to support it.
+    Http2,
+}
+
+const DEFAULT_REGION: &str = "us-east-1";
+const DEFAULT_SESSION_NAME: &str = "cloud_zip";
+
+pub async fn get_s3_client(config: &S3Config) -> Client {
+    let region_provider = RegionProviderChain::first_try(config.region.clone().map(Region::new))
+        .or_default_provider()
+        .or_else(Region::new(DEFAULT_REGION));
+    let mut loader = aws_config::defaults(BehaviorVersion::v2024_03_28()).region(region_provider);
+    if let Some(profile) = &config.profile {
+        loader = loader.profile_name(profile);
+    }
+    if config.no_sign_request {
+        loader = loader.no_credentials();
+    }
+    let shared_config = loader.load().await;
+
+    let mut s3_config =
+        aws_sdk_s3::config::Builder::from(&shared_config).force_path_style(config.force_path_style);
+    if let Some(endpoint_url) = &config.endpoint_url {
+        s3_config = s3_config.endpoint_url(endpoint_url);
+    }
+    if config.connect_timeout.is_some() || config.read_timeout.is_some() {
+        // Merged with the shared config, so the timeouts left unset keep their defaults.
+        let mut timeouts = TimeoutConfig::builder();
+        timeouts
+            .set_connect_timeout(config.connect_timeout)
+            .set_read_timeout(config.read_timeout);
+        s3_config = s3_config.timeout_config(timeouts.build());
+    }
+    if let Some(http_client) = http_client(config) {
+        s3_config = s3_config.http_client(http_client);
+    }
+    if let Some(role_arn) = config.role_arn.as_ref().filter(|_| !config.no_sign_request) {
+        let session_name = config
+            .role_session_name
+            .clone()
+            .unwrap_or_else(|| DEFAULT_SESSION_NAME.to_string());
+        s3_config = match &config.web_identity_token_file {
+            Some(token_file) => s3_config.credentials_provider(
+                WebIdentityTokenCredentialsProvider::builder()
+                    .configure(
+                        &ProviderConfig::default().with_region(shared_config.region().cloned()),
+                    )
+                    .static_configuration(StaticConfiguration {
+                        web_identity_token_file: token_file.clone(),
+                        role_arn: role_arn.clone(),
+                        session_name,
+                    })
+                    .build(),
+            ),
+            None => {
+                let mut assume_role = AssumeRoleProvider::builder(role_arn)
+                    .session_name(session_name)
+                    .configure(&shared_config);
+                if let Some(external_id) = &config.external_id {
+                    assume_role = assume_role.external_id(external_id);
+                }
+                s3_config.credentials_provider(assume_role.build().await)
+            }
+        };
+    }
+    aws_sdk_s3::Client::from_conf(s3_config.build())
+}
@@ -0,0 +1,109 @@
+use aws_sdk_s3::config::SharedHttpClient;
+use aws_smithy_runtime::client::http::hyper_014::HyperClientBuilder;
+use aws_smithy_runtime_api::client::http::{
+    http_client_fn, HttpClient, HttpConnector, HttpConnectorFuture, SharedHttpConnector,
+};
+use aws_smithy_runtime_api::client::orchestrator::HttpRequest;
+use aws_smithy_runtime_api::client::result::ConnectorError;
+use aws_smithy_types::body::{Error as BodyError, SdkBody};
+use bytes::Bytes;
+use http_body::{Body, Frame, SizeHint};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::s3::{HttpVersion, S3Config};
+
+/// The HTTP client of the S3 SDK, built for the connection settings of `config`, or `None`
+/// when it asks for nothing the SDK's default client does not already do.
+pub(super) fn http_client(config: &S3Config) -> Option<SharedHttpClient> {
+    if config.max_connections.is_none() && config.http_version == HttpVersion::Auto {
+        return None;
+    }
+    let mut hyper = hyper_014::Client::builder();
+    if let Some(max_connections) = config.max_connections {
+        hyper.pool_max_idle_per_host(max_connections);
+    }
+    let tls = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .https_or_http();
+    let tls = match config.http_version {
+        HttpVersion::Auto => tls.enable_http1().enable_http2().build(),
+        HttpVersion::Http1 => tls.enable_http1().build(),
+        HttpVersion::Http2 => {
+            hyper.http2_only(true);
+            tls.enable_http2().build()
+        }
+    };
+    let client = HyperClientBuilder::new().hyper_builder(hyper).build(tls);
+    Some(match config.max_connections {
+        Some(max_connections) => {
+            let permits = Arc::new(Semaphore::new(max_connections.max(1)));
+            http_client_fn(move |settings, components| {
+                SharedHttpConnector::new(LimitedConnector {
+                    inner: client.http_connector(settings, components),
+                    permits: permits.clone(),
+                })
+            })
+        }
+        None => client,
+    })
+}
+
+/// Holds every request back until one of `permits` is free, and keeps it until the response
+/// body has been read or dropped, which is as long as the request occupies a connection.
+///
+/// Requests wait here rather than in the connection pool, so the wait does not count against
+/// the connect timeout.
+#[derive(Debug)]
+struct LimitedConnector {
+    inner: SharedHttpConnector,
+    permits: Arc<Semaphore>,
+}
+
+impl HttpConnector for LimitedConnector {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        let inner = self.inner.clone();
+        let permits = self.permits.clone();
+        HttpConnectorFuture::new(async move {
+            let permit = permits
+                .acquire_owned()
+                .await
+                .map_err(|err| ConnectorError::other(err.into(), None))?;
+            let permit = Arc::new(permit);
+            let response = inner.call(request).await?;
+            Ok(response.map(move |body| {
+                SdkBody::from_body_1_x(PermitBody {
+                    inner: Box::pin(body),
+                    _permit: permit.clone(),
+                })
+            }))
+        })
+    }
+}
+
+struct PermitBody {
+    inner: Pin<Box<SdkBody>>,
+    _permit: Arc<OwnedSemaphorePermit>,
+}
+
+impl Body for PermitBody {
+    type Data = Bytes;
+    type Error = BodyError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BodyError>>> {
+        self.inner.as_mut().poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
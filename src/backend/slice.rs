@@ -0,0 +1,51 @@
+use async_trait::async_trait;
+use std::sync::Arc;
+
+use super::{ByteStream, RangeReader};
+use crate::error::Result;
+
+/// `len` bytes of another archive starting at `offset`, read in place: a zip stored without
+/// compression inside another one.
+pub struct SliceReader {
+    inner: Arc<dyn RangeReader>,
+    offset: u64,
+    len: u64,
+}
+
+impl SliceReader {
+    pub fn new(inner: Arc<dyn RangeReader>, offset: u64, len: u64) -> Self {
+        SliceReader { inner, offset, len }
+    }
+
+    /// The part of `len` bytes at `offset` inside the slice, as a range of the inner reader.
+    fn clamp(&self, offset: u64, len: u64) -> (u64, u64) {
+        let offset = offset.min(self.len);
+        (self.offset + offset, len.min(self.len - offset))
+    }
+}
+
+#[async_trait]
+impl RangeReader for SliceReader {
+    async fn read_range(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let (offset, len) = self.clamp(offset, len);
+        self.inner.read_range(offset, len).await
+    }
+
+    async fn size(&self) -> Result<u64> {
+        Ok(self.len)
+    }
+
+    /// The slice changes whenever the archive holding it does.
+    async fn etag(&self) -> Result<Option<String>> {
+        self.inner.etag().await
+    }
+
+    async fn version_id(&self) -> Result<Option<String>> {
+        self.inner.version_id().await
+    }
+
+    fn stream_range(&self, offset: u64, len: u64) -> ByteStream<'_> {
+        let (offset, len) = self.clamp(offset, len);
+        self.inner.stream_range(offset, len)
+    }
+}
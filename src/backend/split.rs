@@ -0,0 +1,117 @@
+use async_trait::async_trait;
+use futures::future::try_join_all;
+use futures::stream::{self, StreamExt};
+use std::sync::Arc;
+
+use super::{ByteStream, RangeReader};
+use crate::error::{CloudZipError, Result};
+
+/// The parts of a split archive (`a.z01`, `a.z02`, …, `a.zip`) read as one archive, each
+/// logical range turned into ranges of the parts it covers.
+pub struct SplitReader {
+    parts: Vec<Arc<dyn RangeReader>>,
+    /// Where each part starts in the joined archive.
+    starts: Vec<u64>,
+    size: u64,
+}
+
+impl SplitReader {
+    /// Joins `parts` in order; the last one holds the central directory and the sidecar.
+    pub async fn new(parts: Vec<Arc<dyn RangeReader>>) -> Result<Self> {
+        if parts.is_empty() {
+            return Err(CloudZipError::InvalidRequest(
+                "A split archive needs at least one part".to_string(),
+            ));
+        }
+        let sizes = try_join_all(parts.iter().map(|part| part.size())).await?;
+        let mut starts = Vec::with_capacity(parts.len());
+        let mut size = 0;
+        for part_size in sizes {
+            starts.push(size);
+            size += part_size;
+        }
+        Ok(SplitReader {
+            parts,
+            starts,
+            size,
+        })
+    }
+
+    /// The parts `len` bytes at `offset` cover, as `(part, offset in it, len)`.
+    fn pieces(&self, offset: u64, len: u64) -> Vec<(usize, u64, u64)> {
+        let end = (offset + len).min(self.size);
+        let mut pieces = Vec::new();
+        let mut position = offset;
+        // The last part starting at or before the offset, skipping empty ones.
+        let mut part = self.starts.partition_point(|&start| start <= offset);
+        part = part.saturating_sub(1);
+        while position < end && part < self.parts.len() {
+            let part_end = self.starts.get(part + 1).copied().unwrap_or(self.size);
+            if part_end > position {
+                let piece_end = end.min(part_end);
+                pieces.push((part, position - self.starts[part], piece_end - position));
+                position = piece_end;
+            }
+            part += 1;
+        }
+        pieces
+    }
+
+    fn last(&self) -> &dyn RangeReader {
+        &*self.parts[self.parts.len() - 1]
+    }
+
+    /// Joins the values every part has, or `None` when one of them has none.
+    fn joined(values: Vec<Option<String>>) -> Option<String> {
+        values
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.join(","))
+    }
+}
+
+#[async_trait]
+impl RangeReader for SplitReader {
+    async fn read_range(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let pieces = self.pieces(offset, len);
+        let reads = try_join_all(
+            pieces
+                .iter()
+                .map(|&(part, offset, len)| self.parts[part].read_range(offset, len)),
+        )
+        .await?;
+        Ok(reads.concat())
+    }
+
+    async fn size(&self) -> Result<u64> {
+        Ok(self.size)
+    }
+
+    async fn etag(&self) -> Result<Option<String>> {
+        let etags = try_join_all(self.parts.iter().map(|part| part.etag())).await?;
+        Ok(Self::joined(etags))
+    }
+
+    async fn version_id(&self) -> Result<Option<String>> {
+        let versions = try_join_all(self.parts.iter().map(|part| part.version_id())).await?;
+        Ok(Self::joined(versions))
+    }
+
+    fn stream_range(&self, offset: u64, len: u64) -> ByteStream<'_> {
+        stream::iter(self.pieces(offset, len))
+            .flat_map(move |(part, offset, len)| self.parts[part].stream_range(offset, len))
+            .boxed()
+    }
+
+    async fn read_sidecar(&self) -> Result<Option<Vec<u8>>> {
+        self.last().read_sidecar().await
+    }
+
+    async fn write_sidecar(&self, index: Vec<u8>) -> Result<()> {
+        self.last().write_sidecar(index).await
+    }
+
+    fn disk_starts(&self) -> &[u64] {
+        &self.starts
+    }
+}
@@ -0,0 +1,127 @@
+use async_trait::async_trait;
+use futures::stream::{StreamExt, TryStreamExt};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use super::{ByteStream, RangeReader};
+use crate::error::Result;
+
+/// A budget of bytes per second shared by every [`ThrottledReader`] holding it, so that
+/// concurrent downloads from any number of archives stay under one rate together.
+///
+/// Up to one second's worth of bytes may be read in a burst; reads beyond the budget are
+/// delayed until it has refilled.
+#[derive(Debug)]
+pub struct BandwidthLimit {
+    bytes_per_second: u64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that may be read right away; negative while earlier reads are still being paid
+    /// for.
+    available: f64,
+    refilled: Instant,
+}
+
+impl BandwidthLimit {
+    pub fn new(bytes_per_second: u64) -> Self {
+        let bytes_per_second = bytes_per_second.max(1);
+        BandwidthLimit {
+            bytes_per_second,
+            bucket: Mutex::new(Bucket {
+                available: bytes_per_second as f64,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    /// Takes `bytes` from the budget, waiting until it covers them. Callers are served in
+    /// the order they ask, however many bytes each takes.
+    pub async fn acquire(&self, bytes: u64) {
+        let rate = self.bytes_per_second as f64;
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+            bucket.available = (bucket.available + elapsed * rate).min(rate);
+            bucket.refilled = now;
+            bucket.available -= bytes as f64;
+            (bucket.available < 0.0).then(|| Duration::from_secs_f64(-bucket.available / rate))
+        };
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Holds the reads of another backend to a [`BandwidthLimit`].
+///
+/// Ranged reads take their length from the budget before they are sent; streamed ranges take
+/// each chunk as it arrives, which slows the connection itself down.
+pub struct ThrottledReader {
+    inner: Arc<dyn RangeReader>,
+    limit: Arc<BandwidthLimit>,
+}
+
+impl ThrottledReader {
+    pub fn new(inner: Arc<dyn RangeReader>, limit: Arc<BandwidthLimit>) -> Self {
+        ThrottledReader { inner, limit }
+    }
+}
+
+#[async_trait]
+impl RangeReader for ThrottledReader {
+    async fn read_range(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        self.limit.acquire(len).await;
+        self.inner.read_range(offset, len).await
+    }
+
+    async fn size(&self) -> Result<u64> {
+        self.inner.size().await
+    }
+
+    async fn etag(&self) -> Result<Option<String>> {
+        self.inner.etag().await
+    }
+
+    async fn version_id(&self) -> Result<Option<String>> {
+        self.inner.version_id().await
+    }
+
+    fn stream_range(&self, offset: u64, len: u64) -> ByteStream<'_> {
+        self.inner
+            .stream_range(offset, len)
+            .and_then(move |chunk| async move {
+                self.limit.acquire(chunk.len() as u64).await;
+                Ok(chunk)
+            })
+            .boxed()
+    }
+
+    async fn read_tail(&self, len: u64) -> Result<(Vec<u8>, u64)> {
+        self.limit.acquire(len).await;
+        self.inner.read_tail(len).await
+    }
+
+    async fn read_sidecar(&self) -> Result<Option<Vec<u8>>> {
+        let index = self.inner.read_sidecar().await?;
+        if let Some(index) = &index {
+            self.limit.acquire(index.len() as u64).await;
+        }
+        Ok(index)
+    }
+
+    async fn write_sidecar(&self, index: Vec<u8>) -> Result<()> {
+        self.inner.write_sidecar(index).await
+    }
+
+    fn disk_starts(&self) -> &[u64] {
+        self.inner.disk_starts()
+    }
+}
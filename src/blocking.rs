@@ -0,0 +1,205 @@
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::runtime::{Builder, Runtime};
+use tokio_util::io::SyncIoBridge;
+
+use crate::archive::{self, EntryCheck};
+use crate::backend::RangeReader;
+use crate::error::Result;
+use crate::extract;
+use crate::location::{ArchiveLocation, ArchiveUri, BackendOptions};
+use crate::metadata::{EntryIndex, FileMetadata};
+use crate::selection::EntrySelector;
+
+/// A [`crate::CloudZip`] for code that is not async, such as build scripts and simple tools,
+/// driving the async archive on a runtime of its own.
+///
+/// Every method blocks the calling thread until it is done, and panics when called from
+/// inside an async runtime, where the async [`crate::CloudZip`] should be used instead.
+/// Archives opened from one another, and the entries they open, share the runtime.
+pub struct CloudZip {
+    inner: archive::CloudZip,
+    runtime: Arc<Runtime>,
+}
+
+impl CloudZip {
+    /// Wraps an async archive, starting a runtime to drive it.
+    pub fn new(inner: archive::CloudZip) -> Result<Self> {
+        Ok(CloudZip {
+            inner,
+            runtime: Arc::new(runtime()?),
+        })
+    }
+
+    /// Opens an archive by location, like [`crate::CloudZip::open_location`].
+    pub fn open_location(
+        location: &ArchiveLocation,
+        metadata_path: impl Into<PathBuf>,
+        options: &BackendOptions,
+    ) -> Result<Self> {
+        let runtime = runtime()?;
+        let inner = runtime.block_on(archive::CloudZip::open_location(
+            location,
+            metadata_path,
+            options,
+        ))?;
+        Ok(CloudZip {
+            inner,
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    pub fn open_local(
+        zip_path: impl Into<PathBuf>,
+        metadata_path: impl Into<PathBuf>,
+    ) -> Result<Self> {
+        Self::new(archive::CloudZip::open_local(zip_path, metadata_path)?)
+    }
+
+    /// Opens an archive without a local index file, like [`crate::CloudZip::discover`].
+    pub fn discover(reader: Arc<dyn RangeReader>) -> Result<Self> {
+        let runtime = runtime()?;
+        let inner = runtime.block_on(archive::CloudZip::discover(reader))?;
+        Ok(CloudZip {
+            inner,
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    /// Opens the innermost archive `uri` names without a local index file.
+    pub fn discover_uri(uri: &ArchiveUri, options: &BackendOptions) -> Result<Self> {
+        let runtime = runtime()?;
+        let inner = runtime
+            .block_on(async { archive::CloudZip::discover(uri.open(options).await?).await })?;
+        Ok(CloudZip {
+            inner,
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    /// Applies the `with_*` settings of the async archive, e.g.
+    /// `zip.configure(|zip| zip.with_concurrency(4))`.
+    pub fn configure(
+        mut self,
+        settings: impl FnOnce(archive::CloudZip) -> archive::CloudZip,
+    ) -> Self {
+        self.inner = settings(self.inner);
+        self
+    }
+
+    /// The async archive, for what this one does not mirror.
+    pub fn inner(&self) -> &archive::CloudZip {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> archive::CloudZip {
+        self.inner
+    }
+
+    /// Runs `future` to completion on the runtime of the archive.
+    pub fn block_on<F: std::future::Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    /// See [`crate::CloudZip::index`].
+    pub fn index(&self) -> Result<Vec<FileMetadata>> {
+        self.block_on(self.inner.index())
+    }
+
+    /// See [`crate::CloudZip::verified_entries`].
+    pub fn entries(&self) -> Result<Arc<EntryIndex>> {
+        self.block_on(self.inner.verified_entries())
+    }
+
+    pub fn list(&self) -> Result<Vec<FileMetadata>> {
+        self.inner.list()
+    }
+
+    pub fn list_matching(&self, selector: &EntrySelector) -> Result<Vec<FileMetadata>> {
+        self.inner.list_matching(selector)
+    }
+
+    /// See [`crate::CloudZip::upload_sidecar`].
+    pub fn upload_sidecar(&self) -> Result<()> {
+        self.block_on(self.inner.upload_sidecar())
+    }
+
+    /// Opens a single entry for reading its decompressed bytes, like
+    /// [`crate::CloudZip::open_entry`].
+    pub fn open_entry(&self, file_name: &str) -> Result<EntryReader> {
+        let reader = self.block_on(self.inner.open_entry(file_name))?;
+        Ok(EntryReader {
+            inner: SyncIoBridge::new_with_handle(reader, self.runtime.handle().clone()),
+            _runtime: self.runtime.clone(),
+        })
+    }
+
+    /// Reads the whole decompressed content of a single entry.
+    pub fn read_entry(&self, file_name: &str) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        self.open_entry(file_name)?.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    /// Opens an entry that is itself a zip, like [`crate::CloudZip::open_nested`].
+    pub fn open_nested(&self, file_name: &str) -> Result<CloudZip> {
+        Ok(CloudZip {
+            inner: self.block_on(self.inner.open_nested(file_name))?,
+            runtime: self.runtime.clone(),
+        })
+    }
+
+    /// See [`crate::CloudZip::extract`].
+    pub fn extract(&self, file_name: &str) -> Result<Option<PathBuf>> {
+        self.block_on(self.inner.extract(file_name))
+    }
+
+    /// See [`crate::CloudZip::extract_to`].
+    pub fn extract_to(
+        &self,
+        file_name: &str,
+        output_dir: impl AsRef<Path>,
+    ) -> Result<Option<PathBuf>> {
+        self.block_on(self.inner.extract_to(file_name, output_dir))
+    }
+
+    /// See [`crate::CloudZip::extract_prefix`].
+    pub fn extract_prefix(
+        &self,
+        prefix: &str,
+        output_dir: impl AsRef<Path>,
+    ) -> Result<Vec<PathBuf>> {
+        self.block_on(self.inner.extract_prefix(prefix, output_dir))
+    }
+
+    /// See [`crate::CloudZip::extract_matching`].
+    pub fn extract_matching(
+        &self,
+        selector: &EntrySelector,
+        output_dir: impl AsRef<Path>,
+    ) -> Result<Vec<PathBuf>> {
+        self.block_on(self.inner.extract_matching(selector, output_dir))
+    }
+
+    /// See [`crate::CloudZip::verify`].
+    pub fn verify(&self, selector: &EntrySelector) -> Result<Vec<EntryCheck>> {
+        self.block_on(self.inner.verify(selector))
+    }
+}
+
+/// The decompressed bytes of an entry, read on the runtime of the archive it comes from.
+pub struct EntryReader {
+    inner: SyncIoBridge<extract::EntryReader>,
+    _runtime: Arc<Runtime>,
+}
+
+impl Read for EntryReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+fn runtime() -> Result<Runtime> {
+    Ok(Builder::new_multi_thread().enable_all().build()?)
+}
@@ -0,0 +1,421 @@
+use aws_sdk_s3::operation::get_object::GetObjectError;
+use aws_sdk_s3::Client;
+use futures::stream::{self, StreamExt};
+use globset::{GlobBuilder, GlobMatcher};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tracing::{debug, info, warn};
+
+use crate::archive::CloudZip;
+use crate::backend::s3::{get_s3_client, request_error};
+use crate::backend::SIDECAR_SUFFIX;
+use crate::error::{CloudZipError, Result};
+use crate::location::{ArchiveLocation, BackendOptions};
+use crate::metadata::{ArchiveFingerprint, EntryIndex, FileMetadata, IndexFormat};
+use crate::writer::open_sink;
+
+/// Layout version of catalog files; readers refuse newer ones.
+pub const CATALOG_VERSION: u32 = 1;
+
+/// The indexes of many archives in one file, for finding which archive holds an entry
+/// without opening any of them.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Catalog {
+    version: u32,
+    /// Sorted by URI.
+    pub archives: Vec<CatalogArchive>,
+}
+
+impl Default for Catalog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogArchive {
+    /// URI of the archive.
+    pub archive: String,
+    pub fingerprint: Option<ArchiveFingerprint>,
+    pub entries: Vec<FileMetadata>,
+}
+
+impl CatalogArchive {
+    /// The index of the archive, which still refuses to be used on a changed archive.
+    pub fn index(&self) -> EntryIndex {
+        EntryIndex::new(self.entries.clone()).with_fingerprint(self.fingerprint.clone())
+    }
+}
+
+/// What [`Catalog::update`] did.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CatalogUpdate {
+    /// Archives indexed because they were new or had changed.
+    pub indexed: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+    /// Archives that could not be indexed, and are left out.
+    pub failed: usize,
+}
+
+/// An entry found by [`Catalog::find`].
+#[derive(Debug, Clone, Copy)]
+pub struct CatalogMatch<'a> {
+    pub archive: &'a CatalogArchive,
+    pub entry: &'a FileMetadata,
+}
+
+/// Files sharing a CRC-32 and uncompressed size, found by [`Catalog::duplicates`].
+#[derive(Debug, Clone)]
+pub struct DuplicateGroup<'a> {
+    pub crc32: u32,
+    pub size: u64,
+    pub entries: Vec<CatalogMatch<'a>>,
+}
+
+impl DuplicateGroup<'_> {
+    /// Bytes taken by the copies after the first.
+    pub fn wasted(&self) -> u64 {
+        self.size * (self.entries.len() as u64 - 1)
+    }
+}
+
+impl Catalog {
+    pub fn new() -> Self {
+        Catalog {
+            version: CATALOG_VERSION,
+            archives: Vec::new(),
+        }
+    }
+
+    /// Reads the catalog at `location`, a local path or an S3 object; `None` when there is
+    /// none yet.
+    pub async fn load(
+        location: &ArchiveLocation,
+        options: &BackendOptions,
+    ) -> Result<Option<Self>> {
+        let bytes = match location {
+            ArchiveLocation::Local(path) => match tokio::fs::read(path).await {
+                Ok(bytes) => bytes,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(err.into()),
+            },
+            ArchiveLocation::S3 {
+                bucket,
+                key,
+                version_id,
+            } => {
+                let client = get_s3_client(&options.s3).await;
+                let result = client
+                    .get_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .set_version_id(version_id.clone())
+                    .send()
+                    .await;
+                let object = match result {
+                    Ok(object) => object,
+                    Err(err)
+                        if err
+                            .as_service_error()
+                            .is_some_and(GetObjectError::is_no_such_key) =>
+                    {
+                        return Ok(None)
+                    }
+                    Err(err) => return Err(request_error(err)),
+                };
+                object
+                    .body
+                    .collect()
+                    .await
+                    .map_err(|err| CloudZipError::s3(err, true))?
+                    .to_vec()
+            }
+            location => return Err(not_storable(location)),
+        };
+        Self::decode(&bytes).map(Some)
+    }
+
+    /// Writes the catalog at `location`, replacing what was there only once it is complete.
+    pub async fn save(
+        &self,
+        location: &ArchiveLocation,
+        options: &BackendOptions,
+        format: IndexFormat,
+    ) -> Result<()> {
+        if !matches!(
+            location,
+            ArchiveLocation::Local(_) | ArchiveLocation::S3 { .. }
+        ) {
+            return Err(not_storable(location));
+        }
+        let bytes = match format {
+            IndexFormat::Json => serde_json::to_vec(self)?,
+            // Only entry indexes are sharded.
+            IndexFormat::Cbor | IndexFormat::Sharded => serde_cbor::to_vec(self)?,
+        };
+        let mut sink = open_sink(location, options).await?;
+        if let Err(err) = sink.write(&bytes).await {
+            sink.abort().await;
+            return Err(err);
+        }
+        sink.finish().await?;
+        Ok(())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct VersionProbe {
+            version: u32,
+        }
+
+        let json = bytes.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'{');
+        let decode = |bytes| -> Result<VersionProbe> {
+            Ok(if json {
+                serde_json::from_slice(bytes)?
+            } else {
+                serde_cbor::from_slice(bytes)?
+            })
+        };
+        let VersionProbe { version } = decode(bytes)?;
+        if version > CATALOG_VERSION {
+            return Err(CloudZipError::UnsupportedIndexVersion {
+                found: version,
+                supported: CATALOG_VERSION,
+            });
+        }
+        Ok(if json {
+            serde_json::from_slice(bytes)?
+        } else {
+            serde_cbor::from_slice(bytes)?
+        })
+    }
+
+    /// Brings the catalog up to date with the `*.zip` objects under `prefix` in `bucket`,
+    /// indexing up to `concurrency` archives at a time.
+    ///
+    /// Only new archives and those whose size or ETag changed are read, through their sidecar
+    /// when it is current; archives that only grew keep the entries they had. Archives no longer listed are dropped from the catalog; those under
+    /// other prefixes are left alone.
+    pub async fn update(
+        &mut self,
+        bucket: &str,
+        prefix: &str,
+        options: &BackendOptions,
+        concurrency: usize,
+    ) -> Result<CatalogUpdate> {
+        let client = get_s3_client(&options.s3).await;
+        let listed = list_archives(&client, bucket, prefix).await?;
+        let uri_prefix = format!("s3://{}/{}", bucket, prefix);
+        let mut update = CatalogUpdate::default();
+
+        let mut known: HashMap<String, CatalogArchive> = std::mem::take(&mut self.archives)
+            .into_iter()
+            .map(|archive| (archive.archive.clone(), archive))
+            .collect();
+        let listed_uris: HashSet<String> = listed
+            .iter()
+            .map(|(location, _)| location.to_string())
+            .collect();
+        known.retain(|uri, _| {
+            let kept = !uri.starts_with(&uri_prefix) || listed_uris.contains(uri);
+            update.removed += usize::from(!kept);
+            kept
+        });
+
+        let mut stale = Vec::new();
+        for (location, listed) in listed {
+            let current = known.get(&location.to_string()).is_some_and(|archive| {
+                archive
+                    .fingerprint
+                    .as_ref()
+                    .is_some_and(|recorded| recorded.check(&listed).is_ok())
+            });
+            if current {
+                update.unchanged += 1;
+            } else {
+                let previous = known.remove(&location.to_string());
+                stale.push((location, previous));
+            }
+        }
+
+        let mut indexed = stream::iter(stale)
+            .map(|(location, previous)| async move {
+                let result = index_archive(&location, previous, options).await;
+                (location, result)
+            })
+            .buffer_unordered(concurrency.max(1));
+        while let Some((location, result)) = indexed.next().await {
+            let uri = location.to_string();
+            match result {
+                Ok(archive) => {
+                    debug!(archive = %uri, entries = archive.entries.len(), "Indexed");
+                    update.indexed += 1;
+                    known.insert(uri, archive);
+                }
+                Err(err) => {
+                    warn!(archive = %uri, error = %err, "Could not index the archive");
+                    update.failed += 1;
+                }
+            }
+        }
+
+        self.archives = known.into_values().collect();
+        self.archives
+            .sort_unstable_by(|a, b| a.archive.cmp(&b.archive));
+        info!(
+            prefix = %uri_prefix,
+            indexed = update.indexed,
+            unchanged = update.unchanged,
+            removed = update.removed,
+            failed = update.failed,
+            "Updated the catalog"
+        );
+        Ok(update)
+    }
+
+    /// The files, across all archives, whose name matches the glob `pattern`; a pattern
+    /// without `/` is matched against the last component of the names, like `find -name`.
+    pub fn find(&self, pattern: &str) -> Result<Vec<CatalogMatch<'_>>> {
+        let matcher = glob_matcher(pattern)?;
+        let whole_name = pattern.contains('/');
+        Ok(self
+            .archives
+            .iter()
+            .flat_map(|archive| {
+                archive
+                    .entries
+                    .iter()
+                    .map(move |entry| CatalogMatch { archive, entry })
+            })
+            .filter(|found| {
+                let name = &found.entry.file_name;
+                !found.entry.is_directory
+                    && if whole_name {
+                        matcher.is_match(name)
+                    } else {
+                        matcher.is_match(name.rsplit('/').next().unwrap_or(name))
+                    }
+            })
+            .collect())
+    }
+
+    /// The files, within an archive or across archives, sharing their CRC-32 and uncompressed
+    /// size with another, which makes them very likely to hold the same content; the groups
+    /// wasting the most space come first. Empty files and entries without a CRC-32, such as
+    /// those of tar archives, are left out.
+    pub fn duplicates(&self) -> Vec<DuplicateGroup<'_>> {
+        let mut groups: HashMap<(u32, u64), Vec<CatalogMatch<'_>>> = HashMap::new();
+        for archive in &self.archives {
+            for entry in &archive.entries {
+                if let Some(key) = entry.content_key() {
+                    groups
+                        .entry(key)
+                        .or_default()
+                        .push(CatalogMatch { archive, entry });
+                }
+            }
+        }
+        let mut groups: Vec<_> = groups
+            .into_iter()
+            .filter(|(_, entries)| entries.len() > 1)
+            .map(|((crc32, size), entries)| DuplicateGroup {
+                crc32,
+                size,
+                entries,
+            })
+            .collect();
+        groups.sort_by_key(|group| (std::cmp::Reverse(group.wasted()), group.crc32));
+        groups
+    }
+}
+
+fn glob_matcher(pattern: &str) -> Result<GlobMatcher> {
+    GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map(|glob| glob.compile_matcher())
+        .map_err(|err| CloudZipError::InvalidPattern(err.to_string()))
+}
+
+fn not_storable(location: &ArchiveLocation) -> CloudZipError {
+    CloudZipError::InvalidLocation(format!(
+        "catalogs are stored on disk or in S3, not at {}",
+        location
+    ))
+}
+
+/// The `*.zip` objects under `prefix`, with the size and ETag the listing reports.
+async fn list_archives(
+    client: &Client,
+    bucket: &str,
+    prefix: &str,
+) -> Result<Vec<(ArchiveLocation, ArchiveFingerprint)>> {
+    let mut archives = Vec::new();
+    let mut pages = client
+        .list_objects_v2()
+        .bucket(bucket)
+        .prefix(prefix)
+        .into_paginator()
+        .send();
+    while let Some(page) = pages.next().await {
+        for object in page.map_err(request_error)?.contents.unwrap_or_default() {
+            let Some(key) = object.key else {
+                continue;
+            };
+            if !key.to_ascii_lowercase().ends_with(".zip") || key.ends_with(SIDECAR_SUFFIX) {
+                continue;
+            }
+            archives.push((
+                ArchiveLocation::S3 {
+                    bucket: bucket.to_string(),
+                    key,
+                    version_id: None,
+                },
+                ArchiveFingerprint {
+                    size: object.size.unwrap_or_default().max(0) as u64,
+                    etag: object.e_tag,
+                    version_id: None,
+                },
+            ));
+        }
+    }
+    debug!(
+        bucket,
+        prefix,
+        archives = archives.len(),
+        "Listed the archives"
+    );
+    Ok(archives)
+}
+
+/// Indexes the archive through its sidecar when current, and otherwise by updating the entries
+/// `previous` recorded for it.
+async fn index_archive(
+    location: &ArchiveLocation,
+    previous: Option<CatalogArchive>,
+    options: &BackendOptions,
+) -> Result<CatalogArchive> {
+    let archive = CloudZip::discover(location.open(options).await?).await?;
+    let entries = match archive.verified_entries().await {
+        Ok(entries) if entries.fingerprint().is_some() => entries,
+        // A stale sidecar is no reason to skip the archive, and one without a fingerprint
+        // would have it indexed again on every update.
+        Ok(_) | Err(CloudZipError::ArchiveChanged(_)) => {
+            let previous = previous
+                .map(|previous| {
+                    EntryIndex::new(previous.entries).with_fingerprint(previous.fingerprint)
+                })
+                .unwrap_or_default();
+            let archive = CloudZip::from_index(location.open(options).await?, Vec::new());
+            archive.update_index(&previous).await?;
+            archive.entries()?
+        }
+        Err(err) => return Err(err),
+    };
+    Ok(CatalogArchive {
+        archive: location.to_string(),
+        fingerprint: entries.fingerprint().cloned(),
+        entries: entries.as_slice().to_vec(),
+    })
+}
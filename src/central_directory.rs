@@ -0,0 +1,1315 @@
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::collections::HashMap;
+use tracing::field::Empty;
+use tracing::{debug, debug_span, warn, Instrument};
+
+use crate::archive::IndexProblem;
+use crate::backend::RangeReader;
+use crate::codepage::NameEncoding;
+use crate::error::{CloudZipError, Result};
+use crate::metadata::{Encryption, FileMetadata};
+use crate::tar;
+
+const EOCD_SIGNATURE: u32 = 0x0605_4b50;
+const ZIP64_EOCD_SIGNATURE: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x0706_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x0807_4b50;
+
+const EOCD_LEN: usize = 22;
+const ZIP64_EOCD_LEN: usize = 56;
+const ZIP64_LOCATOR_LEN: usize = 20;
+const CENTRAL_HEADER_LEN: usize = 46;
+const LOCAL_HEADER_LEN: usize = 30;
+
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+const NTFS_EXTRA_ID: u16 = 0x000a;
+const EXTENDED_TIMESTAMP_EXTRA_ID: u16 = 0x5455;
+const AES_EXTRA_ID: u16 = 0x9901;
+const UNICODE_PATH_EXTRA_ID: u16 = 0x7075;
+
+/// General purpose flag bits.
+const FLAG_ENCRYPTED: u16 = 0x0001;
+const FLAG_DATA_DESCRIPTOR: u16 = 0x0008;
+const FLAG_UTF8: u16 = 0x0800;
+
+/// Placeholder compression method of WinZip AES entries; the real one is in the AES extra field.
+const AES_METHOD: u16 = 99;
+
+/// Seconds between 1601-01-01 (the NTFS epoch) and 1970-01-01.
+const NTFS_EPOCH_OFFSET: i64 = 11_644_473_600;
+
+/// The EOCD record is followed by at most a 64 KiB comment and preceded by the ZIP64 locator.
+const MAX_EOCD_SEARCH: u64 = (ZIP64_LOCATOR_LEN + EOCD_LEN) as u64 + u16::MAX as u64;
+
+/// Local headers closer together than this are fetched with a single ranged GET.
+const HEADER_WINDOW_GAP: u64 = 64 * 1024;
+const HEADER_WINDOW_MAX: u64 = 8 * 1024 * 1024;
+const HEADER_FETCH_CONCURRENCY: usize = 16;
+
+/// Bytes read at a time when scanning for local headers, and at each header found: enough
+/// for the names and extra fields of most entries.
+const SCAN_CHUNK: u64 = 4 * 1024 * 1024;
+const HEADER_PROBE: u64 = 1024;
+/// What a scan past damage looks for: the next entry, or the start of the central directory.
+const HEADER_SIGNATURES: [u32; 2] = [LOCAL_HEADER_SIGNATURE, CENTRAL_HEADER_SIGNATURE];
+/// Longest data descriptor: signature, CRC and two ZIP64 sizes.
+const MAX_DESCRIPTOR_LEN: u64 = 24;
+/// Longest local header: the fixed part, a name and an extra field of the largest size.
+const MAX_LOCAL_HEADER_LEN: u64 = LOCAL_HEADER_LEN as u64 + 2 * u16::MAX as u64;
+
+pub(crate) struct EndOfCentralDirectory {
+    pub entries: u64,
+    pub cd_size: u64,
+    /// Where the central directory starts; once read by [`read_central_directory`], in the
+    /// file rather than as recorded.
+    pub cd_offset: u64,
+    /// Offset of the EOCD record inside the buffer it was found in.
+    pub position: usize,
+    /// Bytes of other data before the archive, such as a self-extractor stub, which the
+    /// offsets the archive records do not count.
+    pub base: u64,
+    /// Number of the disk holding the record, the last one of a split archive.
+    pub disk: u32,
+    /// Disk on which the central directory starts.
+    pub cd_disk: u32,
+    /// Where each part of a split archive starts in the reader; empty for one in one piece.
+    pub disks: Vec<u64>,
+}
+
+impl EndOfCentralDirectory {
+    /// Any field saturated in the classic record means the real value lives in the ZIP64 record.
+    fn needs_zip64(&self) -> bool {
+        self.entries == u16::MAX as u64
+            || self.cd_size == u32::MAX as u64
+            || self.cd_offset == u32::MAX as u64
+            || self.disk == u16::MAX as u32
+            || self.cd_disk == u16::MAX as u32
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct CentralDirectoryEntry {
+    pub file_name: String,
+    /// Length of the name as stored, which decoding it may change.
+    pub name_len: usize,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    pub header_offset: u64,
+    /// Disk of a split archive on which the entry starts, which `header_offset` counts from.
+    pub disk: u32,
+    pub compression_method: u16,
+    pub crc32: u32,
+    pub version_made_by: u16,
+    pub external_attributes: u32,
+    /// Modification time in seconds since the Unix epoch.
+    pub modified: Option<i64>,
+    pub encryption: Encryption,
+}
+
+const DOS_DIRECTORY: u32 = 0x10;
+const UNIX_FILE_TYPE: u32 = 0o170000;
+const UNIX_DIRECTORY: u32 = 0o040000;
+
+/// Hosts in the "version made by" field whose external attributes carry a Unix mode.
+const UNIX_HOST: u8 = 3;
+const OSX_HOST: u8 = 19;
+
+impl CentralDirectoryEntry {
+    /// Some Windows tools omit the trailing slash and only set the directory attribute.
+    pub fn is_directory(&self) -> bool {
+        let unix_mode = self.external_attributes >> 16;
+        self.file_name.ends_with('/')
+            || (self.uncompressed_size == 0
+                && (self.external_attributes & DOS_DIRECTORY != 0
+                    || unix_mode & UNIX_FILE_TYPE == UNIX_DIRECTORY))
+    }
+
+    /// The Unix mode stored in the high half of the external attributes, if the archive was
+    /// written on a Unix-like system.
+    pub fn unix_mode(&self) -> Option<u32> {
+        let host = (self.version_made_by >> 8) as u8;
+        let mode = self.external_attributes >> 16;
+        (matches!(host, UNIX_HOST | OSX_HOST) && mode != 0).then_some(mode)
+    }
+
+    /// How `indexed`, the entry an index holds at the same position, fails to describe this
+    /// entry, or `None` when it has the same fields and its data where a local header at the
+    /// same offset can end.
+    fn mismatch(&self, indexed: &FileMetadata) -> Option<String> {
+        let header_len = indexed.file_offset.checked_sub(self.header_offset);
+        let fits = header_len.is_some_and(|len| {
+            len >= (LOCAL_HEADER_LEN + self.name_len) as u64
+                && len <= (LOCAL_HEADER_LEN + 2 * u16::MAX as usize) as u64
+        });
+        if indexed.file_name != self.file_name {
+            return Some(format!("the archive has {} here", self.file_name));
+        }
+        if !fits {
+            return Some(format!(
+                "its data offset {} cannot follow the local header at {}",
+                indexed.file_offset, self.header_offset
+            ));
+        }
+        let current = self.clone().into_metadata(indexed.file_offset);
+        if current.compressed_size != indexed.compressed_size
+            || current.uncompressed_size != indexed.uncompressed_size
+        {
+            Some(format!(
+                "the archive has it {} bytes compressed and {} uncompressed, the index {} and {}",
+                current.compressed_size,
+                current.uncompressed_size,
+                indexed.compressed_size,
+                indexed.uncompressed_size
+            ))
+        } else if current.crc32 != indexed.crc32 {
+            Some(format!(
+                "the archive records CRC-32 {}, the index {}",
+                describe_crc(current.crc32),
+                describe_crc(indexed.crc32)
+            ))
+        } else if current != *indexed {
+            Some("its compression, times or attributes differ from the archive".to_string())
+        } else {
+            None
+        }
+    }
+
+    pub fn into_metadata(self, file_offset: u64) -> FileMetadata {
+        FileMetadata {
+            unix_mode: self.unix_mode(),
+            is_directory: self.is_directory(),
+            file_name: self.file_name,
+            uncompressed_size: self.uncompressed_size,
+            compressed_size: self.compressed_size,
+            file_offset,
+            compression_method: self.compression_method,
+            // AE-2 entries store a zero CRC and rely on the authentication code instead.
+            crc32: match self.encryption {
+                Encryption::Aes {
+                    vendor_version: 2, ..
+                } => None,
+                _ => Some(self.crc32),
+            },
+            modified: self.modified,
+            encryption: self.encryption,
+        }
+    }
+}
+
+fn describe_crc(crc32: Option<u32>) -> String {
+    crc32.map_or_else(|| "none".to_string(), |crc32| format!("{:08x}", crc32))
+}
+
+fn invalid(msg: impl Into<String>) -> CloudZipError {
+    CloudZipError::invalid_archive(msg)
+}
+
+fn u16_at(buf: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([buf[pos], buf[pos + 1]])
+}
+
+fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]])
+}
+
+fn u64_at(buf: &[u8], pos: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[pos..pos + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Finds the EOCD record in the last bytes of an archive, scanning backwards over any comment.
+pub(crate) fn find_eocd(tail: &[u8]) -> Result<EndOfCentralDirectory> {
+    if tail.len() < EOCD_LEN {
+        return Err(invalid(
+            "Archive is too small to contain an end of central directory",
+        ));
+    }
+
+    (0..=tail.len() - EOCD_LEN)
+        .rev()
+        .find(|&pos| {
+            u32_at(tail, pos) == EOCD_SIGNATURE
+                && pos + EOCD_LEN + u16_at(tail, pos + 20) as usize == tail.len()
+        })
+        .map(|pos| EndOfCentralDirectory {
+            entries: u16_at(tail, pos + 10) as u64,
+            cd_size: u32_at(tail, pos + 12) as u64,
+            cd_offset: u32_at(tail, pos + 16) as u64,
+            position: pos,
+            base: 0,
+            disk: u16_at(tail, pos + 4) as u32,
+            cd_disk: u16_at(tail, pos + 6) as u32,
+            disks: Vec::new(),
+        })
+        .ok_or_else(|| invalid("End of central directory record not found"))
+}
+
+/// Reads the ZIP64 EOCD locator that directly precedes the classic EOCD record, if present:
+/// the disk holding the ZIP64 record and its offset there.
+fn find_zip64_locator(tail: &[u8], eocd_position: usize) -> Option<(u32, u64)> {
+    let pos = eocd_position.checked_sub(ZIP64_LOCATOR_LEN)?;
+    (u32_at(tail, pos) == ZIP64_LOCATOR_SIGNATURE)
+        .then(|| (u32_at(tail, pos + 4), u64_at(tail, pos + 8)))
+}
+
+/// The number of parts of the archive whose last part `reader` holds, from the disk number
+/// its EOCD records.
+pub(crate) async fn split_parts(reader: &dyn RangeReader) -> Result<u32> {
+    let (tail, _) = reader.read_tail(MAX_EOCD_SEARCH).await?;
+    let eocd = find_eocd(&tail)?;
+    if eocd.disk != u16::MAX as u32 {
+        return Ok(eocd.disk + 1);
+    }
+    // The ZIP64 locator records the total number of disks.
+    let pos = eocd
+        .position
+        .checked_sub(ZIP64_LOCATOR_LEN)
+        .filter(|&pos| u32_at(&tail, pos) == ZIP64_LOCATOR_SIGNATURE)
+        .ok_or_else(|| invalid("ZIP64 end of central directory locator not found"))?;
+    Ok(u32_at(&tail, pos + 16).max(1))
+}
+
+fn missing_parts() -> CloudZipError {
+    invalid(
+        "The archive is the last part of a split archive, whose other parts must be read with it",
+    )
+}
+
+/// Where `offset` on disk `disk` is in a reader whose parts start at `disks`.
+fn on_disk(disks: &[u64], disk: u32, offset: u64) -> Result<u64> {
+    if disks.is_empty() && disk == 0 {
+        return Ok(offset);
+    }
+    match disks.get(disk as usize) {
+        Some(start) => start
+            .checked_add(offset)
+            .ok_or_else(|| invalid("Offset lies outside of the archive")),
+        None if disks.is_empty() => Err(missing_parts()),
+        None => Err(invalid(format!(
+            "The split archive records disk {} but has only {} parts",
+            disk + 1,
+            disks.len()
+        ))),
+    }
+}
+
+fn parse_zip64_eocd(record: &[u8], position: usize) -> Result<EndOfCentralDirectory> {
+    if record.len() < ZIP64_EOCD_LEN || u32_at(record, 0) != ZIP64_EOCD_SIGNATURE {
+        return Err(invalid("Invalid ZIP64 end of central directory record"));
+    }
+    Ok(EndOfCentralDirectory {
+        entries: u64_at(record, 32),
+        cd_size: u64_at(record, 40),
+        cd_offset: u64_at(record, 48),
+        position,
+        base: 0,
+        disk: u32_at(record, 16),
+        cd_disk: u32_at(record, 20),
+        disks: Vec::new(),
+    })
+}
+
+/// Converts an MS-DOS date and time, which carry no time zone, to Unix seconds read as UTC.
+fn dos_to_unix(date: u16, time: u16) -> Option<i64> {
+    let year = 1980 + (date >> 9) as i64;
+    let month = ((date >> 5) & 0x0f) as i64;
+    let day = (date & 0x1f) as i64;
+    if !(1..=12).contains(&month) || day == 0 {
+        return None;
+    }
+    let hour = (time >> 11) as i64;
+    let minute = ((time >> 5) & 0x3f) as i64;
+    let second = ((time & 0x1f) * 2) as i64;
+
+    // Days since 1970-01-01 for a proleptic Gregorian date (Howard Hinnant's algorithm).
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    Some(days * 86_400 + hour * 3_600 + minute * 60 + second)
+}
+
+/// Reads the modification time from an NTFS extra field (100 ns ticks since 1601).
+fn ntfs_modified(data: &[u8]) -> Option<i64> {
+    let mut pos = 4;
+    while pos + 4 <= data.len() {
+        let tag = u16_at(data, pos);
+        let len = u16_at(data, pos + 2) as usize;
+        if tag == 0x0001 && len >= 8 && pos + 12 <= data.len() {
+            let ticks = u64_at(data, pos + 4) as i64;
+            return Some(ticks / 10_000_000 - NTFS_EPOCH_OFFSET);
+        }
+        pos += 4 + len;
+    }
+    None
+}
+
+/// Applies the extra fields of a central directory header: ZIP64 sizes and offsets, WinZip
+/// AES parameters, and the more precise timestamps written by Info-ZIP and Windows tools.
+fn apply_extra_fields(entry: &mut CentralDirectoryEntry, extra: &[u8]) -> Result<()> {
+    let mut pos = 0;
+    while pos + 4 <= extra.len() {
+        let id = u16_at(extra, pos);
+        let len = u16_at(extra, pos + 2) as usize;
+        let data = extra
+            .get(pos + 4..pos + 4 + len)
+            .ok_or_else(|| invalid("Extra field is truncated"))?;
+
+        match id {
+            ZIP64_EXTRA_ID => {
+                // Only fields saturated in the fixed header are present, in this order.
+                let mut at = 0;
+                let mut next = |field: &mut u64| -> Result<()> {
+                    if *field == u32::MAX as u64 {
+                        *field = data
+                            .get(at..at + 8)
+                            .map(|value| u64_at(value, 0))
+                            .ok_or_else(|| invalid("ZIP64 extra field is missing a value"))?;
+                        at += 8;
+                    }
+                    Ok(())
+                };
+                next(&mut entry.uncompressed_size)?;
+                next(&mut entry.compressed_size)?;
+                next(&mut entry.header_offset)?;
+                if entry.disk == u16::MAX as u32 {
+                    if let Some(disk) = data.get(at..at + 4) {
+                        entry.disk = u32_at(disk, 0);
+                    }
+                }
+            }
+            EXTENDED_TIMESTAMP_EXTRA_ID if data.len() >= 5 && data[0] & 1 != 0 => {
+                entry.modified = Some(u32_at(data, 1) as i32 as i64);
+            }
+            AES_EXTRA_ID if data.len() >= 7 && entry.compression_method == AES_METHOD => {
+                entry.encryption = Encryption::Aes {
+                    vendor_version: u16_at(data, 0),
+                    strength: data[4],
+                };
+                entry.compression_method = u16_at(data, 5);
+            }
+            NTFS_EXTRA_ID => {
+                if let Some(modified) = ntfs_modified(data) {
+                    entry.modified = Some(modified);
+                }
+            }
+            _ => {}
+        }
+        pos += 4 + len;
+    }
+    Ok(())
+}
+
+/// Decodes the raw `name` of an entry: from the Info-ZIP Unicode path extra field when it was
+/// written for this very name, which tools that rename entries without updating it would
+/// contradict; as UTF-8 when `flags` say so; and as `names` says otherwise.
+fn entry_name(name: &[u8], extra: &[u8], flags: u16, names: NameEncoding) -> String {
+    let mut pos = 0;
+    while pos + 4 <= extra.len() {
+        let id = u16_at(extra, pos);
+        let len = u16_at(extra, pos + 2) as usize;
+        let Some(data) = extra.get(pos + 4..pos + 4 + len) else {
+            break;
+        };
+        if id == UNICODE_PATH_EXTRA_ID
+            && data.len() > 5
+            && data[0] == 1
+            && u32_at(data, 1) == crc32fast::hash(name)
+        {
+            if let Ok(unicode) = std::str::from_utf8(&data[5..]) {
+                return unicode.to_string();
+            }
+        }
+        pos += 4 + len;
+    }
+    names.decode(name, flags & FLAG_UTF8 != 0)
+}
+
+/// Marks entries encrypted with the traditional PKWARE cipher, which have no extra field.
+fn apply_zip_crypto(entry: &mut CentralDirectoryEntry, flags: u16, dos_time: u16) {
+    if flags & FLAG_ENCRYPTED != 0 && entry.encryption == Encryption::None {
+        // Streamed entries only know their CRC afterwards, so the header checks the time.
+        let check_byte = if flags & FLAG_DATA_DESCRIPTOR != 0 {
+            (dos_time >> 8) as u8
+        } else {
+            (entry.crc32 >> 24) as u8
+        };
+        entry.encryption = Encryption::ZipCrypto { check_byte };
+    }
+}
+
+/// Parses the central directory `eocd` describes, giving header offsets in the file.
+pub(crate) fn parse_central_directory(
+    cd: &[u8],
+    eocd: &EndOfCentralDirectory,
+    names: NameEncoding,
+) -> Result<Vec<CentralDirectoryEntry>> {
+    // The count is only trusted as far as the central directory can hold that many headers.
+    let mut list =
+        Vec::with_capacity(eocd.entries.min((cd.len() / CENTRAL_HEADER_LEN) as u64) as usize);
+    let mut pos = 0;
+
+    for _ in 0..eocd.entries {
+        if pos + CENTRAL_HEADER_LEN > cd.len() || u32_at(cd, pos) != CENTRAL_HEADER_SIGNATURE {
+            return Err(invalid(format!(
+                "Invalid central directory header at offset {}",
+                pos
+            )));
+        }
+
+        let name_len = u16_at(cd, pos + 28) as usize;
+        let extra_len = u16_at(cd, pos + 30) as usize;
+        let comment_len = u16_at(cd, pos + 32) as usize;
+        let name_start = pos + CENTRAL_HEADER_LEN;
+        let record_end = name_start + name_len + extra_len + comment_len;
+        if record_end > cd.len() {
+            return Err(invalid("Central directory is truncated"));
+        }
+
+        let flags = u16_at(cd, pos + 8);
+        let dos_time = u16_at(cd, pos + 12);
+        let extra_start = name_start + name_len;
+        let extra = &cd[extra_start..extra_start + extra_len];
+        let mut entry = CentralDirectoryEntry {
+            file_name: entry_name(&cd[name_start..extra_start], extra, flags, names),
+            name_len,
+            compressed_size: u32_at(cd, pos + 20) as u64,
+            uncompressed_size: u32_at(cd, pos + 24) as u64,
+            header_offset: u32_at(cd, pos + 42) as u64,
+            disk: u16_at(cd, pos + 34) as u32,
+            compression_method: u16_at(cd, pos + 10),
+            crc32: u32_at(cd, pos + 16),
+            version_made_by: u16_at(cd, pos + 4),
+            external_attributes: u32_at(cd, pos + 38),
+            modified: dos_to_unix(u16_at(cd, pos + 14), dos_time),
+            encryption: Encryption::None,
+        };
+        apply_extra_fields(&mut entry, extra)?;
+        apply_zip_crypto(&mut entry, flags, dos_time);
+        entry.header_offset = on_disk(&eocd.disks, entry.disk, entry.header_offset)?
+            .checked_add(eocd.base)
+            .filter(|offset| {
+                offset
+                    .checked_add(LOCAL_HEADER_LEN as u64)
+                    .is_some_and(|end| end <= eocd.cd_offset)
+            })
+            .ok_or_else(|| invalid("Local header lies outside of the archive"))?;
+        list.push(entry);
+        pos = record_end;
+    }
+
+    Ok(list)
+}
+
+/// Returns the length of a local file header, i.e. the distance from its start to the entry data.
+pub(crate) fn local_header_len(header: &[u8]) -> Result<u64> {
+    if header.len() < LOCAL_HEADER_LEN || u32_at(header, 0) != LOCAL_HEADER_SIGNATURE {
+        return Err(invalid("Invalid local file header"));
+    }
+    Ok((LOCAL_HEADER_LEN + u16_at(header, 26) as usize + u16_at(header, 28) as usize) as u64)
+}
+
+/// Rebuilds the index of an archive whose central directory is missing or damaged, such as a
+/// truncated upload, by walking its local headers from the start.
+///
+/// Entries whose sizes the local header records are stepped over without reading their data.
+/// Past damage, and after streamed entries whose sizes only follow their data, the archive is
+/// searched for the next signature a chunk at a time. Entries cut short by the end of the
+/// archive, or whose size cannot be found, are left out.
+pub(crate) async fn scan_local_headers(
+    reader: &dyn RangeReader,
+    names: NameEncoding,
+) -> Result<Vec<FileMetadata>> {
+    let size = reader.size().await?;
+    let mut list = Vec::new();
+    let mut skipped = 0;
+    let mut next = Some(0u64);
+    while let Some(offset) =
+        next.filter(|&offset| offset.checked_add(4).is_some_and(|end| end <= size))
+    {
+        let mut header = reader
+            .read_range(offset, HEADER_PROBE.min(size - offset))
+            .await?;
+        match u32_at(&header, 0) {
+            LOCAL_HEADER_SIGNATURE => {}
+            CENTRAL_HEADER_SIGNATURE | EOCD_SIGNATURE | ZIP64_EOCD_SIGNATURE => break,
+            _ => {
+                next = find_signature(reader, offset + 1, size, &HEADER_SIGNATURES)
+                    .await?
+                    .map(|(at, _)| at);
+                continue;
+            }
+        }
+        // A header shorter than its fixed part can only be cut off by the end of the archive.
+        let Ok(header_len) = local_header_len(&header) else {
+            break;
+        };
+        if header_len > header.len() as u64 && offset + header_len <= size {
+            header = reader.read_range(offset, header_len).await?;
+        }
+        let Some((mut entry, flags)) = parse_local_header(&header, offset, names) else {
+            next = find_signature(reader, offset + 1, size, &HEADER_SIGNATURES)
+                .await?
+                .map(|(at, _)| at);
+            continue;
+        };
+        let data_start = offset + header_len;
+        let streamed = flags & FLAG_DATA_DESCRIPTOR != 0;
+
+        if !streamed || entry.compressed_size > 0 || entry.file_name.ends_with('/') {
+            let Some(data_end) = data_start
+                .checked_add(entry.compressed_size)
+                .filter(|&end| end <= size)
+            else {
+                warn!(
+                    entry = entry.file_name,
+                    "The entry is cut short by the end of the archive"
+                );
+                skipped += 1;
+                break;
+            };
+            next = Some(data_end);
+            let mut crc_known = true;
+            if streamed {
+                let after = reader
+                    .read_range(data_end, MAX_DESCRIPTOR_LEN.min(size - data_end))
+                    .await?;
+                match data_descriptor(&after, entry.compressed_size) {
+                    Some((crc32, _, len)) => {
+                        entry.crc32 = crc32;
+                        next = data_end.checked_add(len);
+                    }
+                    None => crc_known = false,
+                }
+            }
+            let mut meta = entry.into_metadata(data_start);
+            if !crc_known {
+                // Nothing to check the data against, rather than the zero the header holds.
+                meta.crc32 = None;
+            }
+            list.push(meta);
+            continue;
+        }
+
+        // The sizes follow the data, in a descriptor that starts with its signature or else
+        // ends right before the next header.
+        next = None;
+        let mut from = data_start;
+        let found = loop {
+            let signatures = [
+                DATA_DESCRIPTOR_SIGNATURE,
+                LOCAL_HEADER_SIGNATURE,
+                CENTRAL_HEADER_SIGNATURE,
+            ];
+            let Some((at, signature)) = find_signature(reader, from, size, &signatures).await?
+            else {
+                break None;
+            };
+            if signature == DATA_DESCRIPTOR_SIGNATURE {
+                let bytes = reader
+                    .read_range(at, MAX_DESCRIPTOR_LEN.min(size - at))
+                    .await?;
+                match data_descriptor(&bytes, at - data_start) {
+                    Some((crc32, uncompressed_size, len)) => {
+                        next = at.checked_add(len);
+                        break Some((crc32, at - data_start, uncompressed_size));
+                    }
+                    None => {
+                        from = at + 1;
+                        continue;
+                    }
+                }
+            }
+            next = Some(at);
+            let start = at.saturating_sub(MAX_DESCRIPTOR_LEN).max(data_start);
+            let before = reader.read_range(start, at - start).await?;
+            break [12, 20]
+                .into_iter()
+                .filter(|&len| len <= before.len())
+                .find_map(|len| {
+                    let compressed_size = at - data_start - len as u64;
+                    data_descriptor(&before[before.len() - len..], compressed_size)
+                        .filter(|&(_, _, found)| found == len as u64)
+                        .map(|(crc32, uncompressed_size, _)| {
+                            (crc32, compressed_size, uncompressed_size)
+                        })
+                });
+        };
+        match found {
+            Some((crc32, compressed_size, uncompressed_size)) => {
+                entry.crc32 = crc32;
+                entry.compressed_size = compressed_size;
+                entry.uncompressed_size = uncompressed_size;
+                list.push(entry.into_metadata(data_start));
+            }
+            None => {
+                let reason = if next.is_some() {
+                    "The size of the entry was not found"
+                } else {
+                    "The entry is cut short by the end of the archive"
+                };
+                warn!(entry = entry.file_name, "{}", reason);
+                skipped += 1;
+            }
+        }
+    }
+    debug!(
+        entries = list.len(),
+        skipped, size, "Scanned the local headers"
+    );
+    Ok(list)
+}
+
+/// Parses the local header at the start of `bytes`, found at `offset`, with its flags; `None`
+/// when it is not a plausible one, as signatures also turn up inside compressed data.
+fn parse_local_header(
+    bytes: &[u8],
+    offset: u64,
+    names: NameEncoding,
+) -> Option<(CentralDirectoryEntry, u16)> {
+    let name_len = u16_at(bytes, 26) as usize;
+    let extra_len = u16_at(bytes, 28) as usize;
+    let name = bytes.get(LOCAL_HEADER_LEN..LOCAL_HEADER_LEN + name_len)?;
+    let extra = bytes.get(LOCAL_HEADER_LEN + name_len..LOCAL_HEADER_LEN + name_len + extra_len)?;
+    // No zip tool needs a version above 6.3 to extract an entry.
+    if u16_at(bytes, 4) & 0xff > 63 || name.is_empty() || name.contains(&0) {
+        return None;
+    }
+    let flags = u16_at(bytes, 6);
+    let dos_time = u16_at(bytes, 10);
+    let mut entry = CentralDirectoryEntry {
+        file_name: entry_name(name, extra, flags, names),
+        name_len,
+        compressed_size: u32_at(bytes, 18) as u64,
+        uncompressed_size: u32_at(bytes, 22) as u64,
+        header_offset: offset,
+        disk: 0,
+        compression_method: u16_at(bytes, 8),
+        crc32: u32_at(bytes, 14),
+        version_made_by: 0,
+        external_attributes: 0,
+        modified: dos_to_unix(u16_at(bytes, 12), dos_time),
+        encryption: Encryption::None,
+    };
+    apply_extra_fields(&mut entry, extra).ok()?;
+    apply_zip_crypto(&mut entry, flags, dos_time);
+    Some((entry, flags))
+}
+
+/// Reads a data descriptor at the start of `bytes` recording `compressed_size`, with or
+/// without its signature and with 32 or 64-bit sizes: its CRC, uncompressed size and length.
+fn data_descriptor(bytes: &[u8], compressed_size: u64) -> Option<(u32, u64, u64)> {
+    let signed = bytes.len() >= 4 && u32_at(bytes, 0) == DATA_DESCRIPTOR_SIGNATURE;
+    let fields = if signed { &bytes[4..] } else { bytes };
+    let len = |fields_len: usize| (fields_len + if signed { 4 } else { 0 }) as u64;
+    if fields.len() >= 12 && u32_at(fields, 4) as u64 == compressed_size {
+        return Some((u32_at(fields, 0), u32_at(fields, 8) as u64, len(12)));
+    }
+    if fields.len() >= 20 && u64_at(fields, 4) == compressed_size {
+        return Some((u32_at(fields, 0), u64_at(fields, 12), len(20)));
+    }
+    None
+}
+
+/// The offset of the next of `signatures` from `from` on, and which one it is, reading the
+/// archive a chunk at a time.
+async fn find_signature(
+    reader: &dyn RangeReader,
+    from: u64,
+    size: u64,
+    signatures: &[u32],
+) -> Result<Option<(u64, u32)>> {
+    let mut start = from;
+    while start + 4 <= size {
+        let chunk = reader
+            .read_range(start, SCAN_CHUNK.min(size - start))
+            .await?;
+        let found = chunk
+            .windows(4)
+            .enumerate()
+            .map(|(position, window)| (position, u32_at(window, 0)))
+            .find(|(_, signature)| signatures.contains(signature));
+        if let Some((position, signature)) = found {
+            return Ok(Some((start + position as u64, signature)));
+        }
+        // Chunks overlap by three bytes so that no signature is split between two.
+        start += (chunk.len() as u64).saturating_sub(3).max(1);
+    }
+    Ok(None)
+}
+
+/// Checks the local header in front of the data of `metadata` against it: a header with the
+/// same name, or with the old name of an entry renamed in place, must end right where the
+/// data starts, with the same compression method and
+/// encryption and, unless they follow the data, the same sizes and CRC.
+///
+/// An offset into the data of another entry or onto the header of another, as overlapping-entry
+/// zip bombs use, or an archive changed in place fails here instead of being decoded.
+pub(crate) async fn check_local_header(
+    reader: &dyn RangeReader,
+    metadata: &FileMetadata,
+    names: NameEncoding,
+) -> Result<()> {
+    let mismatch = |reason: String| CloudZipError::HeaderMismatch {
+        file_name: metadata.file_name.clone(),
+        reason,
+    };
+    let mut probe = HEADER_PROBE.max((LOCAL_HEADER_LEN + metadata.file_name.len()) as u64);
+    let (header, flags) = loop {
+        let start = metadata.file_offset.saturating_sub(probe);
+        let window = reader
+            .read_range(start, metadata.file_offset - start)
+            .await?;
+        // The header closest to the data whose length ends it there.
+        let found = (0..window.len().saturating_sub(LOCAL_HEADER_LEN - 1))
+            .rev()
+            .find(|&position| {
+                local_header_len(&window[position..])
+                    .is_ok_and(|len| len == (window.len() - position) as u64)
+            });
+        if let Some(position) = found {
+            let offset = start + position as u64;
+            break parse_local_header(&window[position..], offset, names)
+                .ok_or_else(|| mismatch(format!("the local header at {} is not valid", offset)))?;
+        }
+        if start == 0 || probe >= MAX_LOCAL_HEADER_LEN {
+            return Err(mismatch(format!(
+                "no local header ends where its data starts, at {}",
+                metadata.file_offset
+            )));
+        }
+        probe = MAX_LOCAL_HEADER_LEN;
+    };
+
+    if header.file_name != metadata.file_name
+        && !same_data_as_renamed(reader, metadata, &header, flags).await?
+    {
+        return Err(mismatch(format!("the header names {}", header.file_name)));
+    }
+    if header.compression_method != metadata.compression_method
+        || (header.encryption == Encryption::None) != (metadata.encryption == Encryption::None)
+    {
+        return Err(mismatch(
+            "the header records another compression method or encryption".to_string(),
+        ));
+    }
+    if flags & FLAG_DATA_DESCRIPTOR == 0 {
+        if header.compressed_size != metadata.compressed_size
+            || header.uncompressed_size != metadata.uncompressed_size
+        {
+            return Err(mismatch(format!(
+                "the header records {} bytes compressed and {} uncompressed, the index {} and {}",
+                header.compressed_size,
+                header.uncompressed_size,
+                metadata.compressed_size,
+                metadata.uncompressed_size
+            )));
+        }
+        if metadata.crc32.is_some_and(|crc32| crc32 != header.crc32) {
+            return Err(mismatch(format!(
+                "the header records CRC-32 {:08x}, the index {}",
+                header.crc32,
+                describe_crc(metadata.crc32)
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Whether the header in front of the data of `metadata`, which names another entry, still
+/// records its data: renaming in place only rewrites the central directory, leaving the old
+/// name in the local header. The sizes and CRC must match the index, in the header or in the
+/// descriptor after the data, so the data of another entry is still told apart.
+async fn same_data_as_renamed(
+    reader: &dyn RangeReader,
+    metadata: &FileMetadata,
+    header: &CentralDirectoryEntry,
+    flags: u16,
+) -> Result<bool> {
+    let Some(crc32) = metadata.crc32 else {
+        return Ok(false);
+    };
+    if flags & FLAG_DATA_DESCRIPTOR == 0 {
+        return Ok(header.compressed_size == metadata.compressed_size
+            && header.uncompressed_size == metadata.uncompressed_size
+            && header.crc32 == crc32);
+    }
+    let size = reader.size().await?;
+    let Some(data_end) = metadata
+        .file_offset
+        .checked_add(metadata.compressed_size)
+        .filter(|&end| end < size)
+    else {
+        return Ok(false);
+    };
+    let after = reader
+        .read_range(data_end, MAX_DESCRIPTOR_LEN.min(size - data_end))
+        .await?;
+    Ok(
+        data_descriptor(&after, metadata.compressed_size).is_some_and(
+            |(found, uncompressed_size, _)| {
+                found == crc32 && uncompressed_size == metadata.uncompressed_size
+            },
+        ),
+    )
+}
+
+/// Compares `indexed`, the entries of an index, with the central directory, then reads the
+/// local headers of up to `headers` of the entries that match, spread over the archive, to
+/// confirm their data starts where the index says.
+pub(crate) async fn check_entries(
+    reader: &dyn RangeReader,
+    indexed: &[FileMetadata],
+    headers: usize,
+    names: NameEncoding,
+) -> Result<(Vec<IndexProblem>, usize)> {
+    let Some((eocd, cd)) = zip_or_tar(reader).await? else {
+        return Ok((tar::check_entries(reader, indexed, names).await?, 0));
+    };
+    let entries = parse_central_directory(&cd, &eocd, names)?;
+    let mut problems = Vec::new();
+    let mut matching = Vec::new();
+    for position in 0..entries.len().max(indexed.len()) {
+        let (file_name, reason) = match (entries.get(position), indexed.get(position)) {
+            (Some(entry), Some(meta)) => match entry.mismatch(meta) {
+                Some(reason) => (meta.file_name.clone(), reason),
+                None => {
+                    matching.push((entry, meta));
+                    continue;
+                }
+            },
+            (Some(entry), None) => (
+                entry.file_name.clone(),
+                "missing from the index".to_string(),
+            ),
+            (None, Some(meta)) => (meta.file_name.clone(), "not in the archive".to_string()),
+            (None, None) => unreachable!(),
+        };
+        problems.push(IndexProblem { file_name, reason });
+    }
+
+    let sampled: Vec<_> = if headers >= matching.len() {
+        matching
+    } else {
+        (0..headers)
+            .map(|i| matching[i * matching.len() / headers])
+            .collect()
+    };
+    let checked = sampled.len();
+    let header_problems: Vec<IndexProblem> = stream::iter(sampled)
+        .map(|(entry, meta)| async move {
+            let len = (LOCAL_HEADER_LEN + entry.name_len) as u64;
+            let header = reader.read_range(entry.header_offset, len).await?;
+            let reason = match local_header_len(&header) {
+                Err(_) => Some(format!("no local header at {}", entry.header_offset)),
+                Ok(len) if entry.header_offset.checked_add(len) != Some(meta.file_offset) => {
+                    Some(format!(
+                        "its data starts at {}, not at {}",
+                        entry.header_offset.saturating_add(len),
+                        meta.file_offset
+                    ))
+                }
+                Ok(_) => None,
+            };
+            Ok::<_, CloudZipError>(reason.map(|reason| IndexProblem {
+                file_name: meta.file_name.clone(),
+                reason,
+            }))
+        })
+        .buffered(HEADER_FETCH_CONCURRENCY)
+        .try_filter_map(|problem| async move { Ok(problem) })
+        .try_collect()
+        .await?;
+    debug!(
+        entries = entries.len(),
+        indexed = indexed.len(),
+        headers = checked,
+        "Checked the index against the archive"
+    );
+    problems.extend(header_problems);
+    Ok((problems, checked))
+}
+
+/// Groups local header offsets into byte windows so nearby headers share one request.
+fn header_windows(mut offsets: Vec<u64>) -> Vec<(u64, u64, Vec<u64>)> {
+    offsets.sort_unstable();
+    let mut windows: Vec<(u64, u64, Vec<u64>)> = Vec::new();
+
+    for offset in offsets {
+        let end = offset.saturating_add(LOCAL_HEADER_LEN as u64);
+        match windows.last_mut() {
+            Some((start, window_end, members))
+                if offset <= window_end.saturating_add(HEADER_WINDOW_GAP)
+                    && end - *start <= HEADER_WINDOW_MAX =>
+            {
+                *window_end = end;
+                members.push(offset);
+            }
+            _ => windows.push((offset, end, vec![offset])),
+        }
+    }
+
+    windows
+}
+
+/// Reads the end of central directory records and the central directory they point at.
+pub(crate) async fn read_central_directory(
+    reader: &dyn RangeReader,
+) -> Result<(EndOfCentralDirectory, Vec<u8>)> {
+    let (tail, archive_size) = reader.read_tail(MAX_EOCD_SEARCH).await?;
+    let tail_start = archive_size - tail.len() as u64;
+
+    // Small archives keep their whole central directory inside the tail that was just read.
+    let read_range = |offset: u64, len: u64| {
+        let tail = &tail;
+        async move {
+            if offset >= tail_start
+                && offset
+                    .checked_add(len)
+                    .is_some_and(|end| end <= archive_size)
+            {
+                let start = (offset - tail_start) as usize;
+                Ok(tail[start..start + len as usize].to_vec())
+            } else {
+                reader.read_range(offset, len).await
+            }
+        }
+    };
+
+    let disks = reader.disk_starts();
+    let mut eocd = find_eocd(&tail)?;
+    // The central directory ends where the record after it starts.
+    let mut cd_end = tail_start + eocd.position as u64;
+    if eocd.needs_zip64() {
+        let (disk, recorded) = find_zip64_locator(&tail, eocd.position)
+            .ok_or_else(|| invalid("ZIP64 end of central directory locator not found"))?;
+        let recorded = on_disk(disks, disk, recorded)?;
+        // Where a ZIP64 record without extensible data sits, right before its locator.
+        let expected = cd_end.checked_sub((ZIP64_LOCATOR_LEN + ZIP64_EOCD_LEN) as u64);
+        let mut zip64_offset = recorded;
+        let mut record = Vec::new();
+        let recorded_end = recorded.checked_add(ZIP64_EOCD_LEN as u64).ok_or_else(|| {
+            invalid("ZIP64 end of central directory record lies outside of the archive")
+        })?;
+        if recorded_end <= archive_size {
+            record = read_range(recorded, ZIP64_EOCD_LEN as u64).await?;
+        }
+        if !record.starts_with(&ZIP64_EOCD_SIGNATURE.to_le_bytes()) {
+            if let Some(expected) = expected.filter(|&expected| expected != recorded) {
+                record = read_range(expected, ZIP64_EOCD_LEN as u64).await?;
+                zip64_offset = expected;
+            }
+        }
+        eocd = parse_zip64_eocd(&record, eocd.position)?;
+        cd_end = zip64_offset;
+    }
+    if disks.is_empty() && eocd.disk > 0 {
+        return Err(missing_parts());
+    }
+    if !disks.is_empty() && disks.len() != eocd.disk as usize + 1 {
+        return Err(invalid(format!(
+            "The split archive has {} parts but {} were given",
+            eocd.disk + 1,
+            disks.len()
+        )));
+    }
+    eocd.cd_offset = on_disk(disks, eocd.cd_disk, eocd.cd_offset)?;
+    eocd.disks = disks.to_vec();
+
+    let recorded_cd_end = eocd
+        .cd_offset
+        .checked_add(eocd.cd_size)
+        .filter(|&end| end <= archive_size)
+        .ok_or_else(|| invalid("Central directory lies outside of the archive"))?;
+
+    let mut cd = read_range(eocd.cd_offset, eocd.cd_size).await?;
+    // Data prepended to an archive, such as a self-extractor stub, moves everything while the
+    // offsets it records still count from the start of the zip: the central directory is then
+    // found right before the records that follow it instead.
+    let starts_directory = |cd: &[u8]| cd.starts_with(&CENTRAL_HEADER_SIGNATURE.to_le_bytes());
+    let shift = cd_end
+        .checked_sub(recorded_cd_end)
+        .filter(|&shift| shift > 0);
+    if let Some(base) = shift.filter(|_| eocd.entries > 0 && !starts_directory(&cd)) {
+        let moved_offset = eocd
+            .cd_offset
+            .checked_add(base)
+            .ok_or_else(|| invalid("Central directory lies outside of the archive"))?;
+        let moved = read_range(moved_offset, eocd.cd_size).await?;
+        if starts_directory(&moved) {
+            debug!(base, "The archive has other data before its first entry");
+            cd = moved;
+            eocd.cd_offset = moved_offset;
+            eocd.base = base;
+        }
+    }
+    Ok((eocd, cd))
+}
+
+/// Reads the central directory like [`read_central_directory`], or returns `None` when the
+/// archive is a tar rather than a zip.
+async fn zip_or_tar(reader: &dyn RangeReader) -> Result<Option<(EndOfCentralDirectory, Vec<u8>)>> {
+    match read_central_directory(reader).await {
+        Ok(found) => Ok(Some(found)),
+        Err(err) => match tar::is_tar(reader).await {
+            Ok(true) => Ok(None),
+            _ => Err(err),
+        },
+    }
+}
+
+/// Builds the index of an archive, reading only the EOCD records, the central directory
+/// and the local headers; a tar archive is indexed from its headers instead.
+pub(crate) async fn build_index(
+    reader: &dyn RangeReader,
+    names: NameEncoding,
+) -> Result<Vec<FileMetadata>> {
+    Ok(extend_index(reader, &[], names).await?.0)
+}
+
+/// Builds the index like [`build_index`], keeping the entries of `previous` when the central
+/// directory still starts with all of them unchanged, as it does after entries were appended:
+/// only the local headers of the new entries are read then. Also returns how many entries
+/// were kept.
+pub(crate) async fn extend_index(
+    reader: &dyn RangeReader,
+    previous: &[FileMetadata],
+    names: NameEncoding,
+) -> Result<(Vec<FileMetadata>, usize)> {
+    let span = debug_span!("index", previous = previous.len(), entries = Empty);
+    let (entries, kept) = read_index(reader, previous, names)
+        .instrument(span.clone())
+        .await?;
+    span.record("entries", entries.len());
+    Ok((entries, kept))
+}
+
+async fn read_index(
+    reader: &dyn RangeReader,
+    previous: &[FileMetadata],
+    names: NameEncoding,
+) -> Result<(Vec<FileMetadata>, usize)> {
+    let Some((eocd, cd)) = zip_or_tar(reader).await? else {
+        return Ok((tar::build_index(reader, names).await?, 0));
+    };
+    let mut entries = parse_central_directory(&cd, &eocd, names)?;
+    debug!(
+        entries = entries.len(),
+        offset = eocd.cd_offset,
+        size = eocd.cd_size,
+        "Read the central directory"
+    );
+    let kept = if previous.len() <= entries.len()
+        && entries
+            .iter()
+            .zip(previous)
+            .all(|(entry, previous)| entry.mismatch(previous).is_none())
+    {
+        previous.len()
+    } else {
+        0
+    };
+    if !previous.is_empty() {
+        debug!(
+            kept,
+            of = previous.len(),
+            "Compared the central directory with the previous index"
+        );
+    }
+    let entries = entries.split_off(kept);
+    let windows = header_windows(entries.iter().map(|e| e.header_offset).collect());
+
+    let header_lens: Vec<(u64, u64)> = stream::iter(windows)
+        .map(|(start, end, members)| async move {
+            let bytes = reader.read_range(start, end - start).await?;
+            members
+                .into_iter()
+                .map(|offset| {
+                    let pos = (offset - start) as usize;
+                    let header = bytes.get(pos..).unwrap_or_default();
+                    Ok((offset, local_header_len(header)?))
+                })
+                .collect::<Result<Vec<_>>>()
+        })
+        .buffer_unordered(HEADER_FETCH_CONCURRENCY)
+        .try_concat()
+        .await?;
+    let header_lens: HashMap<u64, u64> = header_lens.into_iter().collect();
+
+    let list = previous[..kept]
+        .iter()
+        .cloned()
+        .chain(entries.into_iter().map(|entry| {
+            let file_offset = entry
+                .header_offset
+                .saturating_add(header_lens[&entry.header_offset]);
+            entry.into_metadata(file_offset)
+        }))
+        .collect();
+    Ok((list, kept))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+
+    const BIG: u64 = 5 << 30;
+
+    /// A ZIP64 archive holding one stored entry of [`BIG`] bytes, all of its sizes and
+    /// offsets in ZIP64 records, without the entry data: indexing never reads it. The ZIP64
+    /// end of central directory record claims `entries` entries.
+    fn zip64_archive(entries: u64) -> Vec<u8> {
+        let name = b"big.bin";
+        let mut zip = Vec::new();
+        zip.extend(LOCAL_HEADER_SIGNATURE.to_le_bytes());
+        zip.extend([45, 0, 0, 0, 0, 0, 0, 0, 0x21, 0]);
+        zip.extend(0u32.to_le_bytes());
+        zip.extend(u32::MAX.to_le_bytes());
+        zip.extend(u32::MAX.to_le_bytes());
+        zip.extend((name.len() as u16).to_le_bytes());
+        zip.extend(20u16.to_le_bytes());
+        zip.extend(name);
+        zip.extend(ZIP64_EXTRA_ID.to_le_bytes());
+        zip.extend(16u16.to_le_bytes());
+        zip.extend(BIG.to_le_bytes());
+        zip.extend(BIG.to_le_bytes());
+
+        let cd_offset = zip.len() as u64;
+        zip.extend(CENTRAL_HEADER_SIGNATURE.to_le_bytes());
+        zip.extend([45, 3, 45, 0, 0, 0, 0, 0, 0, 0, 0x21, 0]);
+        zip.extend(0u32.to_le_bytes());
+        zip.extend(u32::MAX.to_le_bytes());
+        zip.extend(u32::MAX.to_le_bytes());
+        zip.extend((name.len() as u16).to_le_bytes());
+        zip.extend(28u16.to_le_bytes());
+        zip.extend([0; 6]);
+        zip.extend((0o100644u32 << 16).to_le_bytes());
+        zip.extend(u32::MAX.to_le_bytes());
+        zip.extend(name);
+        zip.extend(ZIP64_EXTRA_ID.to_le_bytes());
+        zip.extend(24u16.to_le_bytes());
+        zip.extend(BIG.to_le_bytes());
+        zip.extend(BIG.to_le_bytes());
+        zip.extend(0u64.to_le_bytes());
+        let cd_size = zip.len() as u64 - cd_offset;
+
+        let zip64_offset = zip.len() as u64;
+        zip.extend(ZIP64_EOCD_SIGNATURE.to_le_bytes());
+        zip.extend((ZIP64_EOCD_LEN as u64 - 12).to_le_bytes());
+        zip.extend([45, 3, 45, 0]);
+        zip.extend([0; 8]);
+        zip.extend(entries.to_le_bytes());
+        zip.extend(entries.to_le_bytes());
+        zip.extend(cd_size.to_le_bytes());
+        zip.extend(cd_offset.to_le_bytes());
+
+        zip.extend(ZIP64_LOCATOR_SIGNATURE.to_le_bytes());
+        zip.extend(0u32.to_le_bytes());
+        zip.extend(zip64_offset.to_le_bytes());
+        zip.extend(1u32.to_le_bytes());
+
+        zip.extend(EOCD_SIGNATURE.to_le_bytes());
+        zip.extend([0; 4]);
+        zip.extend([0xff; 4]);
+        zip.extend([0xff; 8]);
+        zip.extend([0; 2]);
+        zip
+    }
+
+    #[tokio::test]
+    async fn indexes_zip64_entries() {
+        let backend = MemoryBackend::new(zip64_archive(1));
+        let list = build_index(&backend, NameEncoding::default())
+            .await
+            .unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].file_name, "big.bin");
+        assert_eq!(list[0].uncompressed_size, BIG);
+        assert_eq!(list[0].compressed_size, BIG);
+    }
+
+    #[tokio::test]
+    async fn rejects_entry_counts_the_central_directory_cannot_hold() {
+        let backend = MemoryBackend::new(zip64_archive(u64::MAX));
+        assert!(build_index(&backend, NameEncoding::default())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_offsets_that_overflow() {
+        let mut zip = zip64_archive(1);
+        // The ZIP64 record offset in the locator, then the central directory offset in the
+        // ZIP64 record.
+        let locator = zip.len() - EOCD_LEN - ZIP64_LOCATOR_LEN;
+        let original = zip[locator + 8..locator + 16].to_vec();
+        zip[locator + 8..locator + 16].copy_from_slice(&u64::MAX.to_le_bytes());
+        let backend = MemoryBackend::new(zip.clone());
+        assert!(build_index(&backend, NameEncoding::default())
+            .await
+            .is_err());
+
+        zip[locator + 8..locator + 16].copy_from_slice(&original);
+        let record = locator - ZIP64_EOCD_LEN;
+        zip[record + 48..record + 56].copy_from_slice(&u64::MAX.to_le_bytes());
+        let backend = MemoryBackend::new(zip);
+        assert!(build_index(&backend, NameEncoding::default())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn scans_archives_cut_short() {
+        let backend = MemoryBackend::stored_zip([("a.txt", "first"), ("b.txt", "second entry")])
+            .await
+            .unwrap();
+        let bytes = backend.bytes();
+        let data = bytes
+            .windows(12)
+            .position(|window| window == b"second entry")
+            .unwrap();
+        let truncated = MemoryBackend::new(bytes.slice(..data + 4));
+        let list = scan_local_headers(&truncated, NameEncoding::default())
+            .await
+            .unwrap();
+        let names: Vec<_> = list.iter().map(|meta| meta.file_name.as_str()).collect();
+        assert_eq!(names, ["a.txt"]);
+    }
+
+    #[tokio::test]
+    async fn scans_local_sizes_that_overflow() {
+        // The compressed size in the ZIP64 extra of the local header.
+        let mut zip = zip64_archive(1);
+        let size = LOCAL_HEADER_LEN + "big.bin".len() + 12;
+        zip[size..size + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        let list = scan_local_headers(&MemoryBackend::new(zip), NameEncoding::default())
+            .await
+            .unwrap();
+        assert!(list.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_header_offsets_past_the_central_directory() {
+        // The local header offset closes the ZIP64 extra of the only central header.
+        let mut zip = zip64_archive(1);
+        let offset = zip.len() - EOCD_LEN - ZIP64_LOCATOR_LEN - ZIP64_EOCD_LEN - 8;
+        for bad in [u64::MAX - 5, 1 << 40] {
+            zip[offset..offset + 8].copy_from_slice(&bad.to_le_bytes());
+            let backend = MemoryBackend::new(zip.clone());
+            let err = build_index(&backend, NameEncoding::default())
+                .await
+                .unwrap_err();
+            assert!(
+                err.to_string().contains("Local header lies outside"),
+                "{err}"
+            );
+        }
+    }
+}
@@ -0,0 +1,66 @@
+/// How entry names without the UTF-8 flag are decoded. Names with the flag are always read as
+/// UTF-8, and an Info-ZIP Unicode path extra field that still matches the name wins over both.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NameEncoding {
+    /// UTF-8 when the name is valid UTF-8, as many tools write it without setting the flag,
+    /// and IBM code page 437 otherwise.
+    #[default]
+    Auto,
+    /// IBM code page 437, which the zip specification prescribes and Windows tools write.
+    Cp437,
+    /// Windows-1252, which some Western European Windows tools write instead.
+    Windows1252,
+    /// UTF-8, replacing invalid sequences.
+    Utf8,
+}
+
+impl NameEncoding {
+    /// Decodes the raw name of an entry, which the UTF-8 flag says is UTF-8 when `utf8`.
+    pub(crate) fn decode(self, name: &[u8], utf8: bool) -> String {
+        if utf8 || name.is_ascii() {
+            return String::from_utf8_lossy(name).into_owned();
+        }
+        match self {
+            NameEncoding::Auto => match std::str::from_utf8(name) {
+                Ok(name) => name.to_string(),
+                Err(_) => decode_upper_half(name, &CP437),
+            },
+            NameEncoding::Cp437 => decode_upper_half(name, &CP437),
+            NameEncoding::Windows1252 => name
+                .iter()
+                .map(|&byte| match byte {
+                    0x80..=0x9f => WINDOWS_1252[byte as usize - 0x80],
+                    byte => byte as char,
+                })
+                .collect(),
+            NameEncoding::Utf8 => String::from_utf8_lossy(name).into_owned(),
+        }
+    }
+}
+
+fn decode_upper_half(name: &[u8], upper: &[char; 128]) -> String {
+    name.iter()
+        .map(|&byte| match byte {
+            0x80.. => upper[byte as usize - 0x80],
+            byte => byte as char,
+        })
+        .collect()
+}
+
+/// Bytes 0x80 to 0xff of code page 437; the lower half is ASCII.
+const CP437: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', 'É', 'æ', 'Æ',
+    'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', 'á', 'í', 'ó', 'ú', 'ñ', 'Ñ',
+    'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕',
+    '╣', '║', '╗', '╝', '╜', '╛', '┐', '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦',
+    '╠', '═', '╬', '╧', '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐',
+    '▀', 'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', '≡', '±',
+    '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// Bytes 0x80 to 0x9f of Windows-1252, with the five it leaves undefined kept as the C1
+/// controls; the rest is Latin-1.
+const WINDOWS_1252: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+];
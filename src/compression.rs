@@ -0,0 +1,89 @@
+use flate2::read::DeflateDecoder;
+use std::io::Read;
+
+use crate::error::{CloudZipError, Result};
+use crate::metadata::FileMetadata;
+
+pub const STORED: u16 = 0;
+pub const DEFLATED: u16 = 8;
+pub const BZIP2: u16 = 12;
+pub const LZMA: u16 = 14;
+pub const ZSTD: u16 = 93;
+pub const XZ: u16 = 95;
+
+/// Human readable name of a zip compression method.
+pub fn method_name(method: u16) -> &'static str {
+    match method {
+        STORED => "stored",
+        DEFLATED => "deflate",
+        BZIP2 => "bzip2",
+        LZMA => "lzma",
+        ZSTD => "zstd",
+        XZ => "xz",
+        _ => "unknown",
+    }
+}
+
+fn unsupported(method: u16) -> CloudZipError {
+    CloudZipError::UnsupportedCompression {
+        method,
+        name: method_name(method),
+    }
+}
+
+#[cfg(any(feature = "lzma", feature = "zstd"))]
+fn decompression_error(
+    metadata: &FileMetadata,
+) -> impl FnOnce(std::io::Error) -> CloudZipError + '_ {
+    |source| CloudZipError::Decompression {
+        file_name: metadata.file_name.clone(),
+        source,
+    }
+}
+
+/// Wraps the compressed bytes of an entry in the decoder for its compression method.
+pub(crate) fn decoder<'a>(
+    metadata: &FileMetadata,
+    compressed_data: impl Read + 'a,
+) -> Result<Box<dyn Read + 'a>> {
+    match metadata.compression_method {
+        STORED => Ok(Box::new(compressed_data)),
+        DEFLATED => Ok(Box::new(DeflateDecoder::new(compressed_data))),
+        #[cfg(feature = "bzip2")]
+        BZIP2 => Ok(Box::new(bzip2::read::BzDecoder::new(compressed_data))),
+        #[cfg(feature = "lzma")]
+        LZMA => lzma_decoder(metadata.uncompressed_size, compressed_data)
+            .map_err(decompression_error(metadata)),
+        #[cfg(feature = "lzma")]
+        XZ => Ok(Box::new(xz2::read::XzDecoder::new(compressed_data))),
+        #[cfg(feature = "zstd")]
+        ZSTD => Ok(Box::new(
+            zstd::stream::read::Decoder::new(compressed_data)
+                .map_err(decompression_error(metadata))?,
+        )),
+        other => Err(unsupported(other)),
+    }
+}
+
+/// Zip stores LZMA entries behind a small header of its own (version and properties size),
+/// so the properties are re-framed as a `.lzma` header carrying the known uncompressed size.
+#[cfg(feature = "lzma")]
+fn lzma_decoder<'a>(
+    uncompressed_size: u64,
+    mut compressed_data: impl Read + 'a,
+) -> std::io::Result<Box<dyn Read + 'a>> {
+    let mut zip_header = [0u8; 4];
+    compressed_data.read_exact(&mut zip_header)?;
+    let props_len = u16::from_le_bytes([zip_header[2], zip_header[3]]) as usize;
+
+    let mut alone_header = vec![0u8; props_len];
+    compressed_data.read_exact(&mut alone_header)?;
+    alone_header.extend_from_slice(&uncompressed_size.to_le_bytes());
+
+    let stream = xz2::stream::Stream::new_lzma_decoder(u64::MAX)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+    Ok(Box::new(xz2::read::XzDecoder::new_stream(
+        std::io::Cursor::new(alone_header).chain(compressed_data),
+        stream,
+    )))
+}
@@ -0,0 +1,134 @@
+/// Leading bytes of an entry read to detect its content type.
+pub const SNIFF_LEN: u64 = 512;
+
+/// Types that formats telling themselves apart only by name are built on: a `.docx` or
+/// `.jar` is a zip, an `.svg` may be plain XML, and text is any readable content.
+pub const GENERIC_TYPES: &[&str] = &["application/zip", "application/xml", "text/plain"];
+
+/// Signatures at fixed offsets, checked in order.
+const SIGNATURES: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (0, b"II*\0", "image/tiff"),
+    (0, b"MM\0*", "image/tiff"),
+    (0, b"\0\0\x01\0", "image/vnd.microsoft.icon"),
+    (0, b"8BPS", "image/vnd.adobe.photoshop"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"%!PS", "application/postscript"),
+    (0, b"{\\rtf", "application/rtf"),
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"PK\x05\x06", "application/zip"),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"BZh", "application/x-bzip2"),
+    (0, b"\xfd7zXZ\0", "application/x-xz"),
+    (0, b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (0, b"Rar!\x1a\x07", "application/vnd.rar"),
+    (257, b"ustar", "application/x-tar"),
+    (0, b"SQLite format 3\0", "application/vnd.sqlite3"),
+    (0, b"PAR1", "application/vnd.apache.parquet"),
+    (0, b"\x7fELF", "application/x-executable"),
+    (0, b"\0asm", "application/wasm"),
+    (0, b"\xca\xfe\xba\xbe", "application/java-vm"),
+    (0, b"wOFF", "font/woff"),
+    (0, b"wOF2", "font/woff2"),
+    (0, b"OTTO", "font/otf"),
+    (0, b"\0\x01\0\0\0", "font/ttf"),
+    (0, b"ID3", "audio/mpeg"),
+    (0, b"\xff\xfb", "audio/mpeg"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"fLaC", "audio/flac"),
+    (0, b"\x1aE\xdf\xa3", "video/x-matroska"),
+];
+
+/// Brands of ISO media files, from bytes 8 to 12 after the `ftyp` box type.
+const FTYP_BRANDS: &[(&[u8], &str)] = &[
+    (b"qt  ", "video/quicktime"),
+    (b"M4A ", "audio/mp4"),
+    (b"heic", "image/heic"),
+    (b"heix", "image/heic"),
+    (b"avif", "image/avif"),
+];
+
+/// RIFF forms, from bytes 8 to 12.
+const RIFF_FORMS: &[(&[u8], &str)] = &[
+    (b"WEBP", "image/webp"),
+    (b"WAVE", "audio/wav"),
+    (b"AVI ", "video/x-msvideo"),
+];
+
+/// The content type the first bytes of some content show, from the signatures of common
+/// binary formats and the start of HTML and XML documents, or `text/plain` for other text.
+/// `None` when nothing is recognised, and for empty content.
+pub fn detect(prefix: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| prefix.get(offset..offset + magic.len()) == Some(magic);
+    if let Some(&(_, _, content_type)) = SIGNATURES
+        .iter()
+        .find(|(offset, magic, _)| at(*offset, magic))
+    {
+        if content_type == "video/x-matroska" && contains(prefix, b"webm") {
+            return Some("video/webm");
+        }
+        return Some(content_type);
+    }
+    if at(4, b"ftyp") {
+        let brand = prefix.get(8..12).unwrap_or_default();
+        return Some(
+            FTYP_BRANDS
+                .iter()
+                .find(|(known, _)| *known == brand)
+                .map_or("video/mp4", |&(_, content_type)| content_type),
+        );
+    }
+    if at(0, b"RIFF") {
+        let form = prefix.get(8..12).unwrap_or_default();
+        if let Some(&(_, content_type)) = RIFF_FORMS.iter().find(|(known, _)| *known == form) {
+            return Some(content_type);
+        }
+    }
+    // Checked past the signature, as text may start with `BM` too.
+    if at(0, b"BM") && at(6, b"\0\0\0\0") {
+        return Some("image/bmp");
+    }
+    if !is_text(prefix) {
+        return None;
+    }
+    let start = prefix.strip_prefix(b"\xef\xbb\xbf").unwrap_or(prefix);
+    let start = start.trim_ascii_start().to_ascii_lowercase();
+    if start.starts_with(b"<!doctype html") || start.starts_with(b"<html") {
+        Some("text/html")
+    } else if start.starts_with(b"<svg")
+        || (start.starts_with(b"<?xml") && contains(&start, b"<svg"))
+    {
+        Some("image/svg+xml")
+    } else if start.starts_with(b"<?xml") {
+        Some("application/xml")
+    } else {
+        Some("text/plain")
+    }
+}
+
+/// Whether `prefix` is non-empty UTF-8, a character cut off at its end aside, without control
+/// characters other than whitespace and escape.
+fn is_text(prefix: &[u8]) -> bool {
+    let valid = match std::str::from_utf8(prefix) {
+        Ok(text) => text,
+        // At most 3 bytes of a character may have been cut off.
+        Err(err) if err.error_len().is_none() && prefix.len() - err.valid_up_to() < 4 => {
+            std::str::from_utf8(&prefix[..err.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return false,
+    };
+    !prefix.is_empty()
+        && !valid
+            .chars()
+            .any(|c| c.is_control() && !matches!(c, '\t' | '\n' | '\r' | '\x0c' | '\x1b'))
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
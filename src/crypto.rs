@@ -0,0 +1,279 @@
+use std::io::{self, Read};
+
+use crate::error::{CloudZipError, Result};
+use crate::metadata::{Encryption, FileMetadata};
+
+/// Wraps the stored bytes of an entry so that they come out decrypted, ready for the decoder.
+pub(crate) fn decrypting_reader<'a>(
+    metadata: &FileMetadata,
+    stored_data: impl Read + Send + 'a,
+    password: Option<&[u8]>,
+) -> Result<Box<dyn Read + Send + 'a>> {
+    if metadata.encryption == Encryption::None {
+        return Ok(Box::new(stored_data));
+    }
+    let password =
+        password.ok_or_else(|| CloudZipError::PasswordRequired(metadata.file_name.clone()))?;
+
+    match metadata.encryption {
+        Encryption::None => unreachable!("handled above"),
+        Encryption::ZipCrypto { check_byte } => Ok(Box::new(ZipCryptoReader::new(
+            metadata,
+            check_byte,
+            stored_data,
+            password,
+        )?)),
+        #[cfg(feature = "aes")]
+        Encryption::Aes { strength, .. } => Ok(Box::new(aes::AesReader::new(
+            metadata,
+            strength,
+            stored_data,
+            password,
+        )?)),
+        #[cfg(not(feature = "aes"))]
+        Encryption::Aes { .. } => {
+            let _ = password;
+            Err(CloudZipError::UnsupportedEncryption {
+                file_name: metadata.file_name.clone(),
+                name: "WinZip AES",
+            })
+        }
+    }
+}
+
+const ZIP_CRYPTO_HEADER_LEN: usize = 12;
+
+/// CRC-32 lookup table for the byte-wise key updates of ZipCrypto.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            c = if c & 1 != 0 {
+                (c >> 1) ^ 0xedb8_8320
+            } else {
+                c >> 1
+            };
+            bit += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+};
+
+/// The PKWARE traditional encryption keys.
+struct ZipCryptoKeys([u32; 3]);
+
+impl ZipCryptoKeys {
+    fn new(password: &[u8]) -> Self {
+        let mut keys = ZipCryptoKeys([0x1234_5678, 0x2345_6789, 0x3456_7890]);
+        for &byte in password {
+            keys.update(byte);
+        }
+        keys
+    }
+
+    fn crc(crc: u32, byte: u8) -> u32 {
+        CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    }
+
+    fn update(&mut self, byte: u8) {
+        let [k0, k1, k2] = &mut self.0;
+        *k0 = Self::crc(*k0, byte);
+        *k1 = k1
+            .wrapping_add(*k0 & 0xff)
+            .wrapping_mul(134_775_813)
+            .wrapping_add(1);
+        *k2 = Self::crc(*k2, (*k1 >> 24) as u8);
+    }
+
+    fn decrypt(&mut self, buf: &mut [u8]) {
+        for byte in buf {
+            let temp = (self.0[2] | 2) & 0xffff;
+            *byte ^= (temp.wrapping_mul(temp ^ 1) >> 8) as u8;
+            self.update(*byte);
+        }
+    }
+}
+
+/// Decrypts a ZipCrypto entry after consuming its 12-byte encryption header, which is
+/// counted in the compressed size but is not part of the compressed data.
+struct ZipCryptoReader<R> {
+    inner: R,
+    keys: ZipCryptoKeys,
+}
+
+impl<R: Read> ZipCryptoReader<R> {
+    fn new(metadata: &FileMetadata, check_byte: u8, mut inner: R, password: &[u8]) -> Result<Self> {
+        if metadata.compressed_size < ZIP_CRYPTO_HEADER_LEN as u64 {
+            return Err(CloudZipError::invalid_archive(format!(
+                "Encrypted entry {} is too short",
+                metadata.file_name
+            )));
+        }
+        let mut header = [0u8; ZIP_CRYPTO_HEADER_LEN];
+        inner
+            .read_exact(&mut header)
+            .map_err(|source| CloudZipError::Decompression {
+                file_name: metadata.file_name.clone(),
+                source,
+            })?;
+
+        let mut keys = ZipCryptoKeys::new(password);
+        keys.decrypt(&mut header);
+        // A one byte check lets about one wrong password in 256 through; the CRC catches those.
+        if header[ZIP_CRYPTO_HEADER_LEN - 1] != check_byte {
+            return Err(CloudZipError::WrongPassword(metadata.file_name.clone()));
+        }
+        Ok(ZipCryptoReader { inner, keys })
+    }
+}
+
+impl<R: Read> Read for ZipCryptoReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.keys.decrypt(&mut buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "aes")]
+mod aes {
+    use ::aes::{Aes128, Aes192, Aes256};
+    use ctr::cipher::{KeyIvInit, StreamCipher};
+    use hmac::{Hmac, Mac};
+    use sha1::Sha1;
+    use std::io::{self, Read};
+
+    use crate::error::{CloudZipError, Result};
+    use crate::metadata::FileMetadata;
+
+    const VERIFIER_LEN: usize = 2;
+    const AUTH_CODE_LEN: usize = 10;
+    const PBKDF2_ROUNDS: u32 = 1000;
+
+    /// WinZip counts CTR blocks little-endian, starting at one.
+    const INITIAL_COUNTER: [u8; 16] = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+    type Aes128Ctr = ctr::Ctr128LE<Aes128>;
+    type Aes192Ctr = ctr::Ctr128LE<Aes192>;
+    type Aes256Ctr = ctr::Ctr128LE<Aes256>;
+
+    /// Decrypts a WinZip AE-1/AE-2 entry: a salt and password verifier, the AES-CTR
+    /// ciphertext, then a 10-byte HMAC-SHA1 authentication code over the ciphertext.
+    pub(super) struct AesReader<R> {
+        inner: R,
+        cipher: Box<dyn StreamCipher + Send>,
+        mac: Option<Hmac<Sha1>>,
+        remaining: u64,
+    }
+
+    impl<R: Read> AesReader<R> {
+        pub fn new(
+            metadata: &FileMetadata,
+            strength: u8,
+            mut inner: R,
+            password: &[u8],
+        ) -> Result<Self> {
+            let key_len = match strength {
+                1 => 16,
+                2 => 24,
+                3 => 32,
+                _ => {
+                    return Err(CloudZipError::invalid_archive(format!(
+                        "Unknown AES strength {} for {}",
+                        strength, metadata.file_name
+                    )))
+                }
+            };
+            let salt_len = key_len / 2;
+
+            let mut header = vec![0u8; salt_len + VERIFIER_LEN];
+            inner
+                .read_exact(&mut header)
+                .map_err(|source| CloudZipError::Decompression {
+                    file_name: metadata.file_name.clone(),
+                    source,
+                })?;
+            let (salt, verifier) = header.split_at(salt_len);
+
+            let mut keys = vec![0u8; 2 * key_len + VERIFIER_LEN];
+            pbkdf2::pbkdf2_hmac::<Sha1>(password, salt, PBKDF2_ROUNDS, &mut keys);
+            if &keys[2 * key_len..] != verifier {
+                return Err(CloudZipError::WrongPassword(metadata.file_name.clone()));
+            }
+            let (key, mac_key) = (&keys[..key_len], &keys[key_len..2 * key_len]);
+
+            let cipher: Box<dyn StreamCipher + Send> = match key_len {
+                16 => Box::new(Aes128Ctr::new(key.into(), &INITIAL_COUNTER.into())),
+                24 => Box::new(Aes192Ctr::new(key.into(), &INITIAL_COUNTER.into())),
+                _ => Box::new(Aes256Ctr::new(key.into(), &INITIAL_COUNTER.into())),
+            };
+            let mac = Hmac::<Sha1>::new_from_slice(mac_key).expect("HMAC takes keys of any size");
+
+            let overhead = (salt_len + VERIFIER_LEN + AUTH_CODE_LEN) as u64;
+            let remaining = metadata
+                .compressed_size
+                .checked_sub(overhead)
+                .ok_or_else(|| {
+                    CloudZipError::invalid_archive(format!(
+                        "AES entry {} is too short",
+                        metadata.file_name
+                    ))
+                })?;
+
+            Ok(AesReader {
+                inner,
+                cipher,
+                mac: Some(mac),
+                remaining,
+            })
+        }
+
+        /// Checks the authentication code as soon as the last ciphertext byte has been read,
+        /// since decoders may stop reading before they see end of file.
+        fn verify(&mut self) -> io::Result<()> {
+            let Some(mac) = self.mac.take() else {
+                return Ok(());
+            };
+            let mut auth_code = [0u8; AUTH_CODE_LEN];
+            self.inner.read_exact(&mut auth_code)?;
+            if mac.finalize().into_bytes()[..AUTH_CODE_LEN] != auth_code {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "AES authentication code mismatch",
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    impl<R: Read> Read for AesReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.remaining == 0 {
+                self.verify()?;
+                return Ok(0);
+            }
+
+            let max = buf
+                .len()
+                .min(self.remaining.try_into().unwrap_or(usize::MAX));
+            let n = self.inner.read(&mut buf[..max])?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if let Some(mac) = &mut self.mac {
+                mac.update(&buf[..n]);
+            }
+            self.cipher.apply_keystream(&mut buf[..n]);
+            self.remaining -= n as u64;
+            if self.remaining == 0 {
+                self.verify()?;
+            }
+            Ok(n)
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+
+use crate::compression;
+use crate::error::Result;
+use crate::metadata::{Encryption, FileMetadata};
+use crate::writer::EntryAttributes;
+
+/// How an entry differs between two indexes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// An entry that is only in one of two indexes, or whose content differs between them.
+#[derive(Debug, Clone, Serialize)]
+pub struct EntryChange {
+    pub name: String,
+    pub change: Change,
+    pub old_size: Option<u64>,
+    pub new_size: Option<u64>,
+    pub old_crc32: Option<u32>,
+    pub new_crc32: Option<u32>,
+}
+
+/// Compares two lists of entries by name: entries only in `new` are added, those only in
+/// `old` removed, and those in both modified when their size differs, or their CRC-32 where
+/// both record one, as tar archives do not. Changes come in name order.
+pub fn diff(old: &[FileMetadata], new: &[FileMetadata]) -> Vec<EntryChange> {
+    let mut both: BTreeMap<&str, (Option<&FileMetadata>, Option<&FileMetadata>)> = BTreeMap::new();
+    for meta in old {
+        both.entry(&meta.file_name).or_default().0 = Some(meta);
+    }
+    for meta in new {
+        both.entry(&meta.file_name).or_default().1 = Some(meta);
+    }
+    both.into_iter()
+        .filter_map(|(name, (old, new))| {
+            let change = match (old, new) {
+                (None, _) => Change::Added,
+                (_, None) => Change::Removed,
+                (Some(old), Some(new)) => {
+                    let crc_differs =
+                        matches!((old.crc32, new.crc32), (Some(a), Some(b)) if a != b);
+                    if old.uncompressed_size == new.uncompressed_size && !crc_differs {
+                        return None;
+                    }
+                    Change::Modified
+                }
+            };
+            Some(EntryChange {
+                name: name.to_string(),
+                change,
+                old_size: old.map(|meta| meta.uncompressed_size),
+                new_size: new.map(|meta| meta.uncompressed_size),
+                old_crc32: old.and_then(|meta| meta.crc32),
+                new_crc32: new.and_then(|meta| meta.crc32),
+            })
+        })
+        .collect()
+}
+
+/// Lists the files, directories and symlinks below `root` as entries named the way
+/// [`crate::writer::add_path`] would name them, reading every file to take its CRC-32. A
+/// symlink's content is its target, as in an archive.
+pub async fn index_directory(root: &Path) -> Result<Vec<FileMetadata>> {
+    let mut entries = Vec::new();
+    let mut pending = vec![(root.to_path_buf(), String::new())];
+    let mut buf = vec![0u8; 64 * 1024];
+    while let Some((path, name)) = pending.pop() {
+        let metadata = tokio::fs::symlink_metadata(&path).await?;
+        let attributes = EntryAttributes::of(&metadata);
+        let entry = |file_name: String, size, crc32, is_directory| FileMetadata {
+            file_name,
+            uncompressed_size: size,
+            compressed_size: size,
+            is_directory,
+            file_offset: 0,
+            compression_method: compression::STORED,
+            crc32,
+            modified: attributes.modified,
+            unix_mode: attributes.unix_mode,
+            encryption: Encryption::None,
+        };
+        if metadata.is_dir() {
+            if !name.is_empty() {
+                entries.push(entry(format!("{}/", name), 0, Some(0), true));
+            }
+            let mut children = Vec::new();
+            let mut listing = tokio::fs::read_dir(&path).await?;
+            while let Some(child) = listing.next_entry().await? {
+                children.push(child.file_name());
+            }
+            // Popped in name order.
+            children.sort_unstable_by(|a, b| b.cmp(a));
+            for child in children {
+                let child_name = match name.as_str() {
+                    "" => child.to_string_lossy().into_owned(),
+                    name => format!("{}/{}", name, child.to_string_lossy()),
+                };
+                pending.push((path.join(&child), child_name));
+            }
+        } else if metadata.is_symlink() {
+            let target = tokio::fs::read_link(&path).await?;
+            let target = target.to_string_lossy();
+            let crc32 = crc32fast::hash(target.as_bytes());
+            entries.push(entry(name, target.len() as u64, Some(crc32), false));
+        } else {
+            let mut file = tokio::fs::File::open(&path).await?;
+            let mut hasher = crc32fast::Hasher::new();
+            let mut size = 0;
+            loop {
+                let read = file.read(&mut buf).await?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buf[..read]);
+                size += read as u64;
+            }
+            entries.push(entry(name, size, Some(hasher.finalize()), false));
+        }
+    }
+    Ok(entries)
+}
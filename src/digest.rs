@@ -0,0 +1,34 @@
+use md5::{Digest, Md5};
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Hash taken of the content of entries by [`crate::CloudZip::digest`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Md5,
+}
+
+/// Hashes all of `content`.
+pub(crate) async fn digest(
+    content: impl AsyncRead + Unpin,
+    algorithm: HashAlgorithm,
+) -> std::io::Result<Vec<u8>> {
+    match algorithm {
+        HashAlgorithm::Sha256 => hash::<Sha256>(content).await,
+        HashAlgorithm::Md5 => hash::<Md5>(content).await,
+    }
+}
+
+async fn hash<D: Digest>(mut content: impl AsyncRead + Unpin) -> std::io::Result<Vec<u8>> {
+    let mut hasher = D::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let read = content.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(hasher.finalize().to_vec());
+        }
+        hasher.update(&buf[..read]);
+    }
+}
@@ -0,0 +1,435 @@
+use aws_config::{meta::region::RegionProviderChain, BehaviorVersion};
+use aws_sdk_dynamodb::config::Region;
+use aws_sdk_dynamodb::primitives::Blob;
+use aws_sdk_dynamodb::types::{AttributeValue, DeleteRequest, PutRequest, WriteRequest};
+use aws_sdk_dynamodb::Client;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info};
+
+use crate::backend::s3::{is_transient_request, S3Config};
+use crate::error::{CloudZipError, Result};
+use crate::location::ArchiveLocation;
+use crate::metadata::{ArchiveFingerprint, EntryIndex, FileMetadata, INDEX_VERSION};
+use crate::selection::EntrySelector;
+
+/// Partition key of the table: the URI of the archive.
+const ARCHIVE_KEY: &str = "archive";
+/// Sort key of the table: [`HEADER_ENTRY`], or the generation and name of an entry.
+const ENTRY_KEY: &str = "entry";
+/// Sort key of the item describing the stored index; entry keys never start with `#`.
+const HEADER_ENTRY: &str = "#";
+/// BatchWriteItem takes at most this many requests.
+const WRITE_BATCH_LEN: usize = 25;
+const WRITE_CONCURRENCY: usize = 8;
+const GET_CONCURRENCY: usize = 16;
+
+/// Indexes kept in a DynamoDB table, one item per entry, so that every process reading an
+/// archive looks up the entries it needs instead of downloading a whole index.
+///
+/// The table has the string partition key `archive`, holding the archive URI, and the string
+/// sort key `entry`. Each stored index gets a new generation, which entry keys start with: the
+/// archive's header item switches to it only once all its entries are written, so readers never
+/// see half of an index, and the entries of the previous one are deleted afterwards.
+#[derive(Debug, Clone)]
+pub struct DynamoDbIndex {
+    client: Client,
+    table: String,
+}
+
+/// What the header item of an archive records.
+struct Header {
+    generation: String,
+    len: usize,
+    fingerprint: Option<ArchiveFingerprint>,
+}
+
+impl DynamoDbIndex {
+    pub fn with_client(client: Client, table: impl Into<String>) -> Self {
+        DynamoDbIndex {
+            client,
+            table: table.into(),
+        }
+    }
+
+    /// A client for the region and profile of the S3 settings; the role to assume is only
+    /// used for the archives. `AWS_ENDPOINT_URL_DYNAMODB` points it at another endpoint.
+    pub async fn new(config: &S3Config, table: impl Into<String>) -> Self {
+        let region_provider =
+            RegionProviderChain::first_try(config.region.clone().map(Region::new))
+                .or_default_provider();
+        let mut loader =
+            aws_config::defaults(BehaviorVersion::v2024_03_28()).region(region_provider);
+        if let Some(profile) = &config.profile {
+            loader = loader.profile_name(profile);
+        }
+        Self::with_client(Client::new(&loader.load().await), table)
+    }
+
+    /// Stores the index of `archive`, replacing the one stored before.
+    pub async fn store(&self, archive: &ArchiveLocation, index: &EntryIndex) -> Result<()> {
+        let uri = archive.to_string();
+        let previous = self.header(&uri).await?;
+        let generation = format!(
+            "{:x}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+        );
+
+        let mut puts = Vec::with_capacity(index.len());
+        for (position, meta) in index.as_slice().iter().enumerate() {
+            let item = HashMap::from([
+                (ARCHIVE_KEY.to_string(), AttributeValue::S(uri.clone())),
+                (
+                    ENTRY_KEY.to_string(),
+                    AttributeValue::S(entry_key(&generation, &meta.file_name)),
+                ),
+                (
+                    "position".to_string(),
+                    AttributeValue::N(position.to_string()),
+                ),
+                (
+                    "metadata".to_string(),
+                    AttributeValue::B(Blob::new(serde_cbor::to_vec(meta)?)),
+                ),
+            ]);
+            puts.push(
+                WriteRequest::builder()
+                    .put_request(
+                        PutRequest::builder()
+                            .set_item(Some(item))
+                            .build()
+                            .map_err(|err| CloudZipError::dynamodb(err, false))?,
+                    )
+                    .build(),
+            );
+        }
+        self.write_all(puts).await?;
+
+        let mut header = HashMap::from([
+            (ARCHIVE_KEY.to_string(), AttributeValue::S(uri.clone())),
+            (
+                ENTRY_KEY.to_string(),
+                AttributeValue::S(HEADER_ENTRY.to_string()),
+            ),
+            (
+                "version".to_string(),
+                AttributeValue::N(INDEX_VERSION.to_string()),
+            ),
+            (
+                "generator".to_string(),
+                AttributeValue::S(concat!("cloud_zip ", env!("CARGO_PKG_VERSION")).to_string()),
+            ),
+            (
+                "generation".to_string(),
+                AttributeValue::S(generation.clone()),
+            ),
+            (
+                "entries".to_string(),
+                AttributeValue::N(index.len().to_string()),
+            ),
+        ]);
+        if let Some(fingerprint) = index.fingerprint() {
+            header.insert(
+                "fingerprint".to_string(),
+                AttributeValue::B(Blob::new(serde_cbor::to_vec(fingerprint)?)),
+            );
+        }
+        self.client
+            .put_item()
+            .table_name(&self.table)
+            .set_item(Some(header))
+            .send()
+            .await
+            .map_err(request_error)?;
+
+        if let Some(previous) = previous {
+            self.delete_generation(&uri, &previous.generation).await?;
+        }
+        info!(
+            archive = %uri,
+            entries = index.len(),
+            table = %self.table,
+            "Stored the index in DynamoDB"
+        );
+        Ok(())
+    }
+
+    /// Deletes the stored index of `archive`, returning whether there was one.
+    pub async fn remove(&self, archive: &ArchiveLocation) -> Result<bool> {
+        let uri = archive.to_string();
+        let Some(header) = self.header(&uri).await? else {
+            return Ok(false);
+        };
+        self.client
+            .delete_item()
+            .table_name(&self.table)
+            .key(ARCHIVE_KEY, AttributeValue::S(uri.clone()))
+            .key(ENTRY_KEY, AttributeValue::S(HEADER_ENTRY.to_string()))
+            .send()
+            .await
+            .map_err(request_error)?;
+        self.delete_generation(&uri, &header.generation).await?;
+        Ok(true)
+    }
+
+    /// The entries of `archive` picked by `selector`, in archive order, as an index holding
+    /// only them and the fingerprint of the archive; `None` when no index of it is stored.
+    ///
+    /// Names and prefixes read only the matching items; globs and regexes read them all.
+    pub async fn select(
+        &self,
+        archive: &ArchiveLocation,
+        selector: &EntrySelector,
+    ) -> Result<Option<EntryIndex>> {
+        let uri = archive.to_string();
+        let Some(header) = self.header(&uri).await? else {
+            return Ok(None);
+        };
+        let mut rows: Vec<(u64, Vec<u8>)> = match selector {
+            EntrySelector::Name(name) => self
+                .get_entry(&uri, entry_key(&header.generation, name))
+                .await?
+                .into_iter()
+                .collect(),
+            EntrySelector::Names(names) => {
+                stream::iter(names)
+                    .map(|name| self.get_entry(&uri, entry_key(&header.generation, name)))
+                    .buffer_unordered(GET_CONCURRENCY)
+                    .try_filter_map(|row| async move { Ok(row) })
+                    .try_collect()
+                    .await?
+            }
+            EntrySelector::Prefix(prefix) => {
+                self.query(&uri, &entry_key(&header.generation, prefix), |_| true)
+                    .await?
+            }
+            EntrySelector::All | EntrySelector::Glob(_) | EntrySelector::Regex(_) => {
+                let skipped = entry_key(&header.generation, "").len();
+                self.query(&uri, &entry_key(&header.generation, ""), |key| {
+                    selector.matches(&key[skipped..])
+                })
+                .await?
+            }
+        };
+        rows.sort_unstable_by_key(|(position, _)| *position);
+
+        let entries = rows
+            .iter()
+            .map(|(_, bytes)| serde_cbor::from_slice(bytes))
+            .collect::<Result<Vec<FileMetadata>, _>>()?;
+        debug!(
+            selector = %selector.describe(),
+            entries = entries.len(),
+            of = header.len,
+            "Read entries from DynamoDB"
+        );
+        Ok(Some(
+            EntryIndex::new(entries).with_fingerprint(header.fingerprint),
+        ))
+    }
+
+    /// Every stored entry of `archive`, or `None` when no index of it is stored.
+    pub async fn load(&self, archive: &ArchiveLocation) -> Result<Option<EntryIndex>> {
+        self.select(archive, &EntrySelector::All).await
+    }
+
+    async fn header(&self, uri: &str) -> Result<Option<Header>> {
+        let item = self
+            .client
+            .get_item()
+            .table_name(&self.table)
+            .key(ARCHIVE_KEY, AttributeValue::S(uri.to_string()))
+            .key(ENTRY_KEY, AttributeValue::S(HEADER_ENTRY.to_string()))
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(request_error)?
+            .item;
+        let Some(item) = item else {
+            return Ok(None);
+        };
+        let version: u32 = number(uri, &item, "version")?;
+        if version > INDEX_VERSION {
+            return Err(CloudZipError::UnsupportedIndexVersion {
+                found: version,
+                supported: INDEX_VERSION,
+            });
+        }
+        let generation = match item.get("generation") {
+            Some(AttributeValue::S(generation)) => generation.clone(),
+            _ => return Err(malformed(uri, "generation")),
+        };
+        let fingerprint = match item.get("fingerprint") {
+            Some(AttributeValue::B(bytes)) => Some(serde_cbor::from_slice(bytes.as_ref())?),
+            _ => None,
+        };
+        Ok(Some(Header {
+            generation,
+            len: number(uri, &item, "entries")?,
+            fingerprint,
+        }))
+    }
+
+    async fn get_entry(&self, uri: &str, key: String) -> Result<Option<(u64, Vec<u8>)>> {
+        let item = self
+            .client
+            .get_item()
+            .table_name(&self.table)
+            .key(ARCHIVE_KEY, AttributeValue::S(uri.to_string()))
+            .key(ENTRY_KEY, AttributeValue::S(key))
+            .projection_expression("#position, metadata")
+            .expression_attribute_names("#position", "position")
+            .send()
+            .await
+            .map_err(request_error)?
+            .item;
+        item.map(|item| row(uri, &item)).transpose()
+    }
+
+    /// The items of `uri` whose key starts with `prefix` and is accepted by `wanted`.
+    async fn query(
+        &self,
+        uri: &str,
+        prefix: &str,
+        wanted: impl Fn(&str) -> bool,
+    ) -> Result<Vec<(u64, Vec<u8>)>> {
+        let mut items = self
+            .client
+            .query()
+            .table_name(&self.table)
+            .key_condition_expression("#archive = :archive AND begins_with(#entry, :prefix)")
+            .expression_attribute_names("#archive", ARCHIVE_KEY)
+            .expression_attribute_names("#entry", ENTRY_KEY)
+            .expression_attribute_values(":archive", AttributeValue::S(uri.to_string()))
+            .expression_attribute_values(":prefix", AttributeValue::S(prefix.to_string()))
+            .into_paginator()
+            .items()
+            .send();
+        let mut rows = Vec::new();
+        while let Some(item) = items.next().await {
+            let item = item.map_err(request_error)?;
+            let wanted = match item.get(ENTRY_KEY) {
+                Some(AttributeValue::S(key)) => wanted(key),
+                _ => false,
+            };
+            if wanted {
+                rows.push(row(uri, &item)?);
+            }
+        }
+        Ok(rows)
+    }
+
+    async fn delete_generation(&self, uri: &str, generation: &str) -> Result<()> {
+        let mut keys = self
+            .client
+            .query()
+            .table_name(&self.table)
+            .key_condition_expression("#archive = :archive AND begins_with(#entry, :prefix)")
+            .expression_attribute_names("#archive", ARCHIVE_KEY)
+            .expression_attribute_names("#entry", ENTRY_KEY)
+            .expression_attribute_values(":archive", AttributeValue::S(uri.to_string()))
+            .expression_attribute_values(":prefix", AttributeValue::S(entry_key(generation, "")))
+            .projection_expression("#archive, #entry")
+            .into_paginator()
+            .items()
+            .send();
+        let mut deletes = Vec::new();
+        while let Some(key) = keys.next().await {
+            let key = key.map_err(request_error)?;
+            deletes.push(
+                WriteRequest::builder()
+                    .delete_request(
+                        DeleteRequest::builder()
+                            .set_key(Some(key))
+                            .build()
+                            .map_err(|err| CloudZipError::dynamodb(err, false))?,
+                    )
+                    .build(),
+            );
+        }
+        debug!(
+            archive = uri,
+            generation,
+            entries = deletes.len(),
+            "Deleting stale entries"
+        );
+        self.write_all(deletes).await
+    }
+
+    /// Sends the requests in batches, again for those DynamoDB leaves unprocessed.
+    async fn write_all(&self, requests: Vec<WriteRequest>) -> Result<()> {
+        stream::iter(requests.chunks(WRITE_BATCH_LEN).map(<[_]>::to_vec))
+            .map(|batch| self.write_batch(batch))
+            .buffer_unordered(WRITE_CONCURRENCY)
+            .try_collect()
+            .await
+    }
+
+    async fn write_batch(&self, mut batch: Vec<WriteRequest>) -> Result<()> {
+        let mut delay = Duration::from_millis(50);
+        while !batch.is_empty() {
+            let output = self
+                .client
+                .batch_write_item()
+                .request_items(&self.table, batch)
+                .send()
+                .await
+                .map_err(request_error)?;
+            batch = output
+                .unprocessed_items
+                .and_then(|mut unprocessed| unprocessed.remove(&self.table))
+                .unwrap_or_default();
+            if !batch.is_empty() {
+                // Throttled: back off before trying the rest again.
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(Duration::from_secs(5));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn entry_key(generation: &str, file_name: &str) -> String {
+    format!("{}/{}", generation, file_name)
+}
+
+fn row(uri: &str, item: &HashMap<String, AttributeValue>) -> Result<(u64, Vec<u8>)> {
+    let metadata = match item.get("metadata") {
+        Some(AttributeValue::B(bytes)) => bytes.clone().into_inner(),
+        _ => return Err(malformed(uri, "metadata")),
+    };
+    Ok((number(uri, item, "position")?, metadata))
+}
+
+fn number<T: std::str::FromStr>(
+    uri: &str,
+    item: &HashMap<String, AttributeValue>,
+    attribute: &str,
+) -> Result<T> {
+    match item.get(attribute) {
+        Some(AttributeValue::N(number)) => number.parse().ok(),
+        _ => None,
+    }
+    .ok_or_else(|| malformed(uri, attribute))
+}
+
+fn malformed(uri: &str, attribute: &str) -> CloudZipError {
+    CloudZipError::InvalidRequest(format!(
+        "an index item of {} in DynamoDB has no valid {}",
+        uri, attribute
+    ))
+}
+
+fn request_error<E>(
+    err: aws_sdk_dynamodb::error::SdkError<E, aws_sdk_dynamodb::config::http::HttpResponse>,
+) -> CloudZipError
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let transient = is_transient_request(&err);
+    CloudZipError::dynamodb(err, transient)
+}
@@ -0,0 +1,164 @@
+use tracing::info;
+
+use crate::error::{CloudZipError, Result};
+use crate::location::{ArchiveLocation, BackendOptions};
+use crate::metadata::{EntryIndex, FileMetadata};
+use crate::writer::{ArchiveWriter, Retained};
+
+/// Deletions and renames applied to an archive by writing a new central directory after its
+/// data, which is copied inside S3 when possible rather than downloaded and uploaded again.
+///
+/// Names ending with `/` stand for a directory and everything below it. The data of deleted
+/// entries stays in the archive, unreachable; `create --from` writes a compact copy instead.
+/// Renamed entries keep their old name in their local header, which `unzip` warns about
+/// before extracting them under the new one and header checks accept.
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveEdit {
+    deletions: Vec<String>,
+    renames: Vec<(String, String)>,
+}
+
+impl ArchiveEdit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn delete(mut self, name: impl Into<String>) -> Self {
+        self.deletions.push(name.into());
+        self
+    }
+
+    /// Renames `from` to `to`; for a directory, the entries below it move along.
+    pub fn rename(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.renames.push((from.into(), to.into()));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deletions.is_empty() && self.renames.is_empty()
+    }
+
+    /// Applies the edit to the archive at `source`, writing the result at `destination`, which
+    /// may be the same location, and returns its index.
+    ///
+    /// Fails without writing anything when a deleted or renamed name matches no entry, or
+    /// when two entries would end up with the same name.
+    pub async fn apply(
+        &self,
+        source: &ArchiveLocation,
+        destination: &ArchiveLocation,
+        options: &BackendOptions,
+    ) -> Result<EntryIndex> {
+        let mut writer = ArchiveWriter::append(source, destination, options).await?;
+        let edited = self
+            .check(writer.entries())
+            .and_then(|()| writer.edit_existing(|metadata| self.retained(metadata)));
+        if let Err(err) = edited {
+            writer.abort().await;
+            return Err(err);
+        }
+        let index = writer.finish().await?;
+        info!(
+            deleted = self.deletions.len(),
+            renamed = self.renames.len(),
+            entries = index.len(),
+            archive = %destination,
+            "Edited the archive"
+        );
+        Ok(index)
+    }
+
+    /// Checks that every name given matches an entry.
+    fn check(&self, entries: &[FileMetadata]) -> Result<()> {
+        let names = self
+            .deletions
+            .iter()
+            .chain(self.renames.iter().map(|(from, _)| from));
+        for name in names {
+            if !entries
+                .iter()
+                .any(|metadata| covers(name, &metadata.file_name))
+            {
+                return Err(CloudZipError::EntryNotFound(name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn retained(&self, metadata: &FileMetadata) -> Retained {
+        let name = &metadata.file_name;
+        if self.deletions.iter().any(|deleted| covers(deleted, name)) {
+            return Retained::Drop;
+        }
+        // The first rename that applies wins.
+        for (from, to) in &self.renames {
+            if name == from {
+                return Retained::Rename(to.clone());
+            }
+            if from.ends_with('/') && name.starts_with(from.as_str()) {
+                let to = format!("{}/", to.trim_end_matches('/'));
+                return Retained::Rename(format!("{}{}", to, &name[from.len()..]));
+            }
+        }
+        Retained::Keep
+    }
+}
+
+/// Whether `pattern` names the entry `name`: the same name, or a directory containing it.
+fn covers(pattern: &str, name: &str) -> bool {
+    name == pattern || pattern.ends_with('/') && name.starts_with(pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{LocalBackend, MemoryBackend};
+    use crate::CloudZip;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn renamed_entries_pass_header_checks() {
+        let backend =
+            MemoryBackend::zip([("a.txt", "first"), ("dir/", ""), ("dir/b.txt", "second")])
+                .await
+                .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.zip");
+        std::fs::write(&path, backend.bytes()).unwrap();
+        let location = ArchiveLocation::Local(path.clone());
+        ArchiveEdit::new()
+            .rename("a.txt", "renamed.txt")
+            .rename("dir/", "moved/")
+            .apply(&location, &location, &BackendOptions::default())
+            .await
+            .unwrap();
+
+        let reader = Arc::new(LocalBackend::open(&path).unwrap());
+        let archive = CloudZip::discover(reader.clone())
+            .await
+            .unwrap()
+            .with_header_checks(true);
+        let output_dir = dir.path().join("out");
+        for (name, content) in [("renamed.txt", "first"), ("moved/b.txt", "second")] {
+            let written = archive
+                .extract_to(name, &output_dir)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(std::fs::read_to_string(written).unwrap(), content);
+        }
+
+        // The old name is only accepted while the data still matches the index.
+        let mut list = archive.list().unwrap();
+        for metadata in &mut list {
+            metadata.crc32 = metadata.crc32.map(|crc32| !crc32);
+        }
+        let archive = CloudZip::from_index(reader, list).with_header_checks(true);
+        assert!(matches!(
+            archive
+                .extract_to("renamed.txt", dir.path().join("again"))
+                .await,
+            Err(CloudZipError::HeaderMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,257 @@
+use std::io;
+use thiserror::Error;
+
+pub type Result<T, E = CloudZipError> = std::result::Result<T, E>;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Error)]
+pub enum CloudZipError {
+    #[error("S3 request failed: {message}")]
+    S3 {
+        message: String,
+        #[source]
+        source: BoxError,
+        /// Whether the same request may well succeed when retried.
+        transient: bool,
+    },
+
+    #[error("DynamoDB request failed: {message}")]
+    DynamoDb {
+        message: String,
+        #[source]
+        source: BoxError,
+        /// Whether the same request may well succeed when retried.
+        transient: bool,
+    },
+
+    #[error("HTTP request failed: {message}")]
+    Http {
+        message: String,
+        #[source]
+        source: Option<BoxError>,
+        /// Whether the same request may well succeed when retried.
+        transient: bool,
+    },
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("Failed to read or write the index: {0}")]
+    Metadata(#[from] serde_cbor::Error),
+
+    #[error("Failed to read or write the index: {0}")]
+    MetadataJson(#[from] serde_json::Error),
+
+    #[error("Failed to read or write the SQLite index: {0}")]
+    Sqlite(String),
+
+    #[error("Index format version {found} is newer than the supported version {supported}; upgrade cloud_zip to read it")]
+    UnsupportedIndexVersion { found: u32, supported: u32 },
+
+    #[error("The archive changed after it was indexed ({0}); index it again")]
+    ArchiveChanged(String),
+
+    #[error("The S3 object is not encrypted as expected: {0}")]
+    UnexpectedEncryption(String),
+
+    #[error("Entry not found in index: {0}")]
+    EntryNotFound(String),
+
+    #[error("Invalid entry pattern: {0}")]
+    InvalidPattern(String),
+
+    #[error("Invalid manifest: {0}")]
+    InvalidManifest(String),
+
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("Invalid archive location: {0}")]
+    InvalidLocation(String),
+
+    #[error("Cannot extract {file_name}: {reason}")]
+    InvalidEntryPath {
+        file_name: String,
+        reason: &'static str,
+    },
+
+    #[error("Cannot add {file_name} to the archive: {reason}")]
+    InvalidNewEntry {
+        file_name: String,
+        reason: &'static str,
+    },
+
+    #[error("Output file already exists: {}", .0.display())]
+    OutputExists(std::path::PathBuf),
+
+    #[error("Invalid zip archive: {0}")]
+    InvalidArchive(String),
+
+    #[error("Refusing to read an archive with conflicting entries: {0}")]
+    ConflictingEntries(String),
+
+    #[error("The local header of {file_name} does not match the index: {reason}")]
+    HeaderMismatch { file_name: String, reason: String },
+
+    #[error("Unsupported compression method {method} ({name})")]
+    UnsupportedCompression { method: u16, name: &'static str },
+
+    #[error("Entry {0} is encrypted; a password is required")]
+    PasswordRequired(String),
+
+    #[error("Wrong password for {0}")]
+    WrongPassword(String),
+
+    #[error("Unsupported encryption for {file_name} ({name})")]
+    UnsupportedEncryption {
+        file_name: String,
+        name: &'static str,
+    },
+
+    #[error("Failed to decompress {file_name}: {source}")]
+    Decompression {
+        file_name: String,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("Extraction of {file_name} aborted: {reason}")]
+    LimitExceeded { file_name: String, reason: String },
+
+    #[error("Cancelled")]
+    Cancelled,
+
+    #[error("CRC32 mismatch for {file_name}: expected {expected:08x}, got {actual:08x}")]
+    CrcMismatch {
+        file_name: String,
+        expected: u32,
+        actual: u32,
+    },
+}
+
+impl CloudZipError {
+    pub(crate) fn s3<E>(err: E, transient: bool) -> Self
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        CloudZipError::S3 {
+            message: chain_message(&err),
+            source: Box::new(err),
+            transient,
+        }
+    }
+
+    #[cfg(feature = "dynamodb")]
+    pub(crate) fn dynamodb<E>(err: E, transient: bool) -> Self
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        CloudZipError::DynamoDb {
+            message: chain_message(&err),
+            source: Box::new(err),
+            transient,
+        }
+    }
+
+    #[cfg(any(feature = "azure", feature = "http"))]
+    pub(crate) fn http(mut err: reqwest::Error) -> Self {
+        if let Some(url) = err.url_mut() {
+            url.set_query(None);
+        }
+        let transient = err.is_timeout()
+            || err.is_connect()
+            || err.is_request()
+            || err.is_body()
+            || err.is_decode()
+            || err
+                .status()
+                .is_some_and(|status| is_transient_status(status.as_u16()));
+        CloudZipError::Http {
+            message: chain_message(&err),
+            source: Some(Box::new(err)),
+            transient,
+        }
+    }
+
+    #[cfg(any(feature = "azure", feature = "http"))]
+    pub(crate) fn http_status(url: &str, status: u16, body: &str) -> Self {
+        CloudZipError::Http {
+            message: format!(
+                "{} returned HTTP {}: {}",
+                without_query(url),
+                status,
+                body.trim()
+            ),
+            source: None,
+            transient: is_transient_status(status),
+        }
+    }
+
+    /// Whether the failure may go away by itself, e.g. a timeout, a dropped connection or a
+    /// 5xx response, so that the operation is worth retrying.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            CloudZipError::S3 { transient, .. }
+            | CloudZipError::DynamoDb { transient, .. }
+            | CloudZipError::Http { transient, .. } => *transient,
+            CloudZipError::Io(err) => matches!(
+                err.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::Interrupted
+            ),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn invalid_archive(msg: impl Into<String>) -> Self {
+        CloudZipError::InvalidArchive(msg.into())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for CloudZipError {
+    fn from(err: rusqlite::Error) -> Self {
+        CloudZipError::Sqlite(err.to_string())
+    }
+}
+
+/// Lets errors cross `std::io` and `tokio::io` interfaces such as the entry reader.
+impl From<CloudZipError> for io::Error {
+    fn from(err: CloudZipError) -> Self {
+        match err {
+            CloudZipError::Io(err) => err,
+            err => io::Error::other(err),
+        }
+    }
+}
+
+/// Drops the query string, which holds the signature of presigned URLs and SAS tokens, from a
+/// URL shown in a message.
+pub(crate) fn without_query(url: &str) -> &str {
+    url.split_once('?').map_or(url, |(base, _)| base)
+}
+
+/// Server errors and throttling, as opposed to requests that are wrong in themselves.
+pub(crate) fn is_transient_status(status: u16) -> bool {
+    status >= 500 || status == 429
+}
+
+/// Joins an error with its chain of sources, skipping causes already included in the message.
+fn chain_message(err: &(dyn std::error::Error + 'static)) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(cause) = source {
+        let cause_message = cause.to_string();
+        if !message.contains(&cause_message) {
+            message.push_str(": ");
+            message.push_str(&cause_message);
+        }
+        source = cause.source();
+    }
+    message
+}
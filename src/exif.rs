@@ -0,0 +1,251 @@
+use serde::Serialize;
+use std::ops::Range;
+
+/// Leading bytes of a JPEG entry read first to find its EXIF segment, which cameras write
+/// right after the start of the image.
+pub const EXIF_PROBE_LEN: u64 = 64 * 1024;
+/// Most leading bytes read to reach the end of the EXIF segment.
+pub const MAX_EXIF_PROBE_LEN: u64 = 1024 * 1024;
+
+const TAG_MAKE: u16 = 0x010f;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_THUMBNAIL_OFFSET: u16 = 0x0201;
+const TAG_THUMBNAIL_LEN: u16 = 0x0202;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_PIXEL_WIDTH: u16 = 0xa002;
+const TAG_PIXEL_HEIGHT: u16 = 0xa003;
+
+const TYPE_ASCII: u16 = 2;
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+
+/// What the EXIF segment of a JPEG image records.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Exif {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    /// When the picture was taken, as `YYYY:MM:DD HH:MM:SS`, or else when the file was last
+    /// changed.
+    pub taken: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// 1 to 8, how the image is to be rotated or flipped for display.
+    pub orientation: Option<u16>,
+    /// The embedded JPEG thumbnail.
+    #[serde(skip)]
+    pub thumbnail: Option<Vec<u8>>,
+}
+
+/// Where the EXIF segment of a JPEG image is, as far as its first bytes tell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExifSegment {
+    /// The TIFF data of the segment, within the bytes looked at.
+    Found(Range<usize>),
+    /// More of the image, up to this length, is needed to tell.
+    Truncated(u64),
+    /// The image has no EXIF segment, or is not a JPEG image.
+    Missing,
+}
+
+/// Finds the EXIF segment among the markers at the start of a JPEG image.
+pub fn find_segment(prefix: &[u8]) -> ExifSegment {
+    if !prefix.starts_with(&[0xff, 0xd8]) {
+        return ExifSegment::Missing;
+    }
+    let mut pos = 2;
+    loop {
+        let Some(header) = prefix.get(pos..pos + 4) else {
+            return ExifSegment::Truncated(pos as u64 + 4);
+        };
+        let marker = header[1];
+        // Any number of 0xff may pad the space before a marker.
+        if header[0] == 0xff && marker == 0xff {
+            pos += 1;
+            continue;
+        }
+        // Markers are 0xff followed by anything but 0; the image data starts at SOS.
+        if header[0] != 0xff || marker == 0xda || marker == 0xd9 {
+            return ExifSegment::Missing;
+        }
+        // The length counts its own two bytes.
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        if len < 2 {
+            return ExifSegment::Missing;
+        }
+        let end = pos + 2 + len;
+        if marker == 0xe1 {
+            if end > prefix.len() {
+                return ExifSegment::Truncated(end as u64);
+            }
+            if end >= pos + 10 && prefix[pos + 4..end].starts_with(b"Exif\0\0") {
+                return ExifSegment::Found(pos + 10..end);
+            }
+        }
+        pos = end;
+    }
+}
+
+/// The TIFF structure of an EXIF segment.
+struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+/// One field of an IFD.
+struct Field {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    /// Offset of the value, or of the value itself when it fits in 4 bytes.
+    value_offset: usize,
+}
+
+impl Tiff<'_> {
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes: [u8; 2] = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    /// The fields of the IFD at `offset`, and the offset of the next IFD, 0 for none.
+    fn ifd(&self, offset: usize) -> Option<(Vec<Field>, usize)> {
+        let count = self.u16_at(offset)? as usize;
+        let fields = (0..count)
+            .filter_map(|i| {
+                let entry = offset + 2 + i * 12;
+                let kind = self.u16_at(entry + 2)?;
+                let count = self.u32_at(entry + 4)?;
+                let size = match kind {
+                    TYPE_SHORT => 2,
+                    TYPE_LONG => 4,
+                    _ => 1,
+                } * count as usize;
+                let value_offset = match size {
+                    0..=4 => entry + 8,
+                    _ => self.u32_at(entry + 8)? as usize,
+                };
+                Some(Field {
+                    tag: self.u16_at(entry)?,
+                    kind,
+                    count,
+                    value_offset,
+                })
+            })
+            .collect();
+        let next = self.u32_at(offset + 2 + count * 12).unwrap_or(0) as usize;
+        Some((fields, next))
+    }
+
+    fn number(&self, field: &Field) -> Option<u32> {
+        match field.kind {
+            TYPE_SHORT => self.u16_at(field.value_offset).map(u32::from),
+            TYPE_LONG => self.u32_at(field.value_offset),
+            _ => None,
+        }
+    }
+
+    fn text(&self, field: &Field) -> Option<String> {
+        if field.kind != TYPE_ASCII {
+            return None;
+        }
+        let bytes = self
+            .data
+            .get(field.value_offset..field.value_offset + field.count as usize)?;
+        let text = String::from_utf8_lossy(bytes);
+        let text = text.trim_end_matches('\0').trim();
+        (!text.is_empty()).then(|| text.to_string())
+    }
+}
+
+/// Parses the TIFF data of an EXIF segment, as [`find_segment`] finds it; `None` when it is
+/// not valid TIFF.
+pub fn parse(tiff: &[u8]) -> Option<Exif> {
+    let little_endian = match tiff.get(..4)? {
+        b"II*\0" => true,
+        b"MM\0*" => false,
+        _ => return None,
+    };
+    let tiff = Tiff {
+        data: tiff,
+        little_endian,
+    };
+    let mut exif = Exif::default();
+    let (fields, next) = tiff.ifd(tiff.u32_at(4)? as usize)?;
+    let mut exif_ifd = None;
+    for field in &fields {
+        match field.tag {
+            TAG_MAKE => exif.make = tiff.text(field),
+            TAG_MODEL => exif.model = tiff.text(field),
+            TAG_ORIENTATION => exif.orientation = tiff.number(field).map(|value| value as u16),
+            TAG_DATE_TIME => exif.taken = tiff.text(field),
+            TAG_EXIF_IFD => exif_ifd = tiff.number(field),
+            _ => {}
+        }
+    }
+    if let Some((fields, _)) = exif_ifd.and_then(|offset| tiff.ifd(offset as usize)) {
+        for field in &fields {
+            match field.tag {
+                TAG_DATE_TIME_ORIGINAL => exif.taken = tiff.text(field).or(exif.taken),
+                TAG_PIXEL_WIDTH => exif.width = tiff.number(field),
+                TAG_PIXEL_HEIGHT => exif.height = tiff.number(field),
+                _ => {}
+            }
+        }
+    }
+    // IFD1, the next one after IFD0, describes the thumbnail.
+    if let Some((fields, _)) = (next != 0).then(|| tiff.ifd(next)).flatten() {
+        let find = |tag| fields.iter().find(|field| field.tag == tag);
+        let offset = find(TAG_THUMBNAIL_OFFSET).and_then(|field| tiff.number(field));
+        let len = find(TAG_THUMBNAIL_LEN).and_then(|field| tiff.number(field));
+        if let (Some(offset), Some(len)) = (offset, len) {
+            exif.thumbnail = tiff
+                .data
+                .get(offset as usize..offset as usize + len as usize)
+                .filter(|thumbnail| thumbnail.starts_with(&[0xff, 0xd8]))
+                .map(<[u8]>::to_vec);
+        }
+    }
+    Some(exif)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_exif_segment() {
+        let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe0, 0x00, 0x04, 0x00, 0x00];
+        jpeg.extend([0xff, 0xe1, 0x00, 0x0c]);
+        jpeg.extend(b"Exif\0\0II*\0");
+        assert_eq!(find_segment(&jpeg), ExifSegment::Found(18..22));
+        assert_eq!(find_segment(&jpeg[..14]), ExifSegment::Truncated(22));
+    }
+
+    #[test]
+    fn rejects_segment_lengths_below_two() {
+        for len in [0, 1] {
+            for marker in [0xe0, 0xe1] {
+                let jpeg = [0xff, 0xd8, 0xff, marker, 0x00, len, 0xff, 0xd9];
+                assert_eq!(find_segment(&jpeg), ExifSegment::Missing);
+            }
+        }
+        // An APP1 segment too short to hold the EXIF header.
+        let jpeg = [0xff, 0xd8, 0xff, 0xe1, 0x00, 0x02, 0xff, 0xd9, 0x00, 0x00];
+        assert_eq!(find_segment(&jpeg), ExifSegment::Missing);
+    }
+}
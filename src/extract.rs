@@ -0,0 +1,865 @@
+use bytes::{Buf, Bytes};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::fs::{create_dir_all, File};
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio_util::sync::CancellationToken;
+use tracing::field::Empty;
+use tracing::{debug, debug_span, trace, Instrument, Span};
+
+use crate::backend::{split_range, ByteStream, RangeReader};
+use crate::central_directory::check_local_header;
+use crate::codepage::NameEncoding;
+use crate::compression;
+use crate::crypto::decrypting_reader;
+use crate::error::{CloudZipError, Result};
+use crate::limits::Budget;
+use crate::metadata::FileMetadata;
+use crate::metrics;
+use crate::output::target_stays_inside;
+use crate::progress::ProgressTracker;
+
+/// Largest link target accepted from a symlink entry.
+const MAX_SYMLINK_TARGET: u64 = 4096;
+/// Bytes a compressed or encrypted link target may take beyond its length.
+const SYMLINK_OVERHEAD: u64 = 64;
+
+/// Number of downloaded chunks buffered ahead of the decoder.
+const CHANNEL_CHUNKS: usize = 16;
+
+/// Controls how the compressed bytes of one large entry are split into concurrent ranged reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DownloadOptions {
+    /// Entries larger than this are fetched in parts of this many bytes.
+    pub part_size: u64,
+    /// Number of parts of a single entry in flight at once.
+    pub concurrency: usize,
+    /// Neighbouring entries of a batch whose data is at most this many bytes apart are fetched
+    /// with one request of up to `part_size` bytes; 0 fetches every entry on its own.
+    pub coalesce_gap: u64,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            part_size: 8 * 1024 * 1024,
+            concurrency: 4,
+            coalesce_gap: 64 * 1024,
+        }
+    }
+}
+
+/// Streams the compressed bytes of an entry, as concurrent parts when it exceeds the part size.
+///
+/// At most `concurrency` parts are held in memory at once.
+fn compressed_stream<'a>(
+    reader: &'a dyn RangeReader,
+    metadata: &FileMetadata,
+    options: DownloadOptions,
+) -> ByteStream<'a> {
+    let (offset, len) = (metadata.file_offset, metadata.compressed_size);
+    trace!(entry = %metadata.file_name, offset, len, "Fetching compressed data");
+    if len <= options.part_size || options.concurrency <= 1 {
+        return reader.stream_range(offset, len);
+    }
+
+    stream::iter(split_range(offset, len, options.part_size))
+        .map(move |(offset, len)| reader.read_range(offset, len))
+        .buffered(options.concurrency)
+        .map_ok(Bytes::from)
+        .boxed()
+}
+
+pub(crate) fn create_output_file(output_file_path: &Path) -> Result<File> {
+    if let Some(output_dir) = output_file_path.parent() {
+        if !output_dir.as_os_str().is_empty() && !output_dir.exists() {
+            create_dir_all(output_dir)?;
+        }
+    }
+
+    Ok(File::create(output_file_path)?)
+}
+
+/// Streams the compressed bytes of an entry through the decoder into `output_file_path`.
+///
+/// Data goes to `<output_file_path>.part` first, which is renamed into place only once the
+/// CRC has been checked, so an interrupted extraction never leaves a truncated file behind
+/// under the final name.
+pub(crate) async fn extract_to_path(
+    reader: &dyn RangeReader,
+    metadata: &FileMetadata,
+    output_file_path: PathBuf,
+    options: DownloadOptions,
+    context: Arc<DecodeContext>,
+    attributes: FileAttributes,
+) -> Result<PathBuf> {
+    let part = PartFile::new(&output_file_path);
+    let output_file = create_output_file(&part.path)?;
+    let (output_file, _) = decode_into(reader, metadata, options, context, output_file).await?;
+    attributes.apply(&output_file)?;
+    part.commit(&output_file_path)?;
+    debug!(entry = %metadata.file_name, path = %output_file_path.display(), "Extracted");
+    Ok(output_file_path)
+}
+
+/// Like [`extract_to_path`], for a batch that may be run again after an interruption: the
+/// `.part` file of a stored entry is kept when extraction stops half way, and the next run
+/// downloads only the rest of the entry, appending it. Other entries cannot be decoded from
+/// the middle and start over.
+pub(crate) async fn resume_to_path(
+    reader: &dyn RangeReader,
+    metadata: &FileMetadata,
+    output_file_path: PathBuf,
+    options: DownloadOptions,
+    context: Arc<DecodeContext>,
+    attributes: FileAttributes,
+) -> Result<PathBuf> {
+    if !metadata.is_random_access() {
+        return extract_to_path(
+            reader,
+            metadata,
+            output_file_path,
+            options,
+            context,
+            attributes,
+        )
+        .await;
+    }
+    let mut part = PartFile::new(&output_file_path);
+    part.keep = true;
+    // A stored entry holds as many bytes as it decompresses to, unless the index is wrong.
+    let done = match part.path.metadata() {
+        Ok(local) if local.len() <= metadata.compressed_size.min(metadata.uncompressed_size) => {
+            local.len()
+        }
+        _ => 0,
+    };
+    if done > 0 {
+        debug!(entry = %metadata.file_name, done, "Resuming a partial file");
+    }
+    let appended = match append_stored(reader, metadata, &part.path, done, options, &context).await
+    {
+        // Whatever was there did not come from this entry after all.
+        Err(CloudZipError::CrcMismatch { .. }) if done > 0 => {
+            debug!(entry = %metadata.file_name, "The partial file does not match, starting over");
+            append_stored(reader, metadata, &part.path, 0, options, &context).await
+        }
+        appended => appended,
+    };
+    context.progress.entry_done();
+    let output_file = match appended {
+        Ok(output_file) => output_file,
+        Err(err) => {
+            part.keep = !matches!(err, CloudZipError::CrcMismatch { .. })
+                && part.path.metadata().is_ok_and(|local| local.len() > 0);
+            return Err(err);
+        }
+    };
+    attributes.apply(&output_file)?;
+    part.commit(&output_file_path)?;
+    debug!(entry = %metadata.file_name, path = %output_file_path.display(), "Extracted");
+    Ok(output_file_path)
+}
+
+/// Downloads the content of a stored entry after its first `done` bytes, which `part_path`
+/// already holds, onto the end of that file, and checks the CRC of it all.
+async fn append_stored(
+    reader: &dyn RangeReader,
+    metadata: &FileMetadata,
+    part_path: &Path,
+    done: u64,
+    options: DownloadOptions,
+    context: &DecodeContext,
+) -> Result<File> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut output_file = if done > 0 {
+        let mut existing = File::open(part_path)?.take(done);
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = existing.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        std::fs::OpenOptions::new().append(true).open(part_path)?
+    } else {
+        create_output_file(part_path)?
+    };
+    if context.check_headers {
+        check_local_header(reader, metadata, context.names).await?;
+    }
+    let rest = FileMetadata {
+        file_offset: metadata.file_offset + done,
+        compressed_size: metadata.compressed_size - done,
+        ..metadata.clone()
+    };
+    let mut chunks = compressed_stream(reader, &rest, options);
+    let mut written = done;
+    while let Some(chunk) = next_chunk(&mut chunks, &context.cancel).await? {
+        let n = chunk.len() as u64;
+        context.progress.downloaded(n);
+        context.budget.consume(metadata, written, n)?;
+        hasher.update(&chunk);
+        output_file.write_all(&chunk)?;
+        written += n;
+        context.progress.written(n);
+    }
+    if let Some(expected) = metadata.crc32 {
+        let actual = hasher.finalize();
+        if actual != expected {
+            return Err(CloudZipError::CrcMismatch {
+                file_name: metadata.file_name.clone(),
+                expected,
+                actual,
+            });
+        }
+    }
+    Ok(output_file)
+}
+
+/// Extracts an entry likely to hold the same content as `original`, a file extracted
+/// before, as a hard link to it. The entry is still decoded and compared with the file, and
+/// written as usual when they differ or the filesystem refuses the link.
+pub(crate) async fn link_duplicate(
+    reader: &dyn RangeReader,
+    metadata: &FileMetadata,
+    original: &Path,
+    output_file_path: PathBuf,
+    options: DownloadOptions,
+    context: Arc<DecodeContext>,
+    attributes: FileAttributes,
+) -> Result<PathBuf> {
+    let comparison = Comparison {
+        file: io::BufReader::new(File::open(original)?),
+        expected: Vec::new(),
+        same: true,
+    };
+    let (mut comparison, _) =
+        decode_into(reader, metadata, options, context.clone(), comparison).await?;
+    if comparison.same && comparison.file.fill_buf()?.is_empty() {
+        let part = PartFile::new(&output_file_path);
+        let _ = std::fs::remove_file(&part.path);
+        match std::fs::hard_link(original, &part.path) {
+            Ok(()) => {
+                part.commit(&output_file_path)?;
+                debug!(entry = %metadata.file_name, path = %output_file_path.display(), original = %original.display(), "Linked a duplicate");
+                return Ok(output_file_path);
+            }
+            Err(err) => {
+                debug!(entry = %metadata.file_name, error = %err, "Could not hard-link a duplicate, writing it instead")
+            }
+        }
+    }
+    extract_to_path(
+        reader,
+        metadata,
+        output_file_path,
+        options,
+        context,
+        attributes,
+    )
+    .await
+}
+
+/// Compares what is written to it with the content of a file, read along.
+struct Comparison {
+    file: io::BufReader<File>,
+    expected: Vec<u8>,
+    /// Whether everything written so far matched the file.
+    same: bool,
+}
+
+impl Write for Comparison {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.same {
+            self.expected.resize(buf.len(), 0);
+            self.same = match self.file.read_exact(&mut self.expected) {
+                Ok(()) => self.expected == buf,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => false,
+                Err(err) => return Err(err),
+            };
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Downloads and decodes an entry without keeping its content, which checks the CRC and, for
+/// AES entries, the authentication code. Returns the decompressed size.
+pub(crate) async fn verify_entry(
+    reader: &dyn RangeReader,
+    metadata: &FileMetadata,
+    options: DownloadOptions,
+    context: Arc<DecodeContext>,
+) -> Result<u64> {
+    let (_, written) = decode_into(reader, metadata, options, context, io::sink()).await?;
+    Ok(written)
+}
+
+/// Streams the compressed bytes of an entry through the decoder into `output` and hands it
+/// back with the number of bytes written.
+///
+/// Decompression runs on a blocking thread fed through a bounded channel, so memory use does
+/// not depend on the size of the entry.
+async fn decode_into<W: Write + Send + 'static>(
+    reader: &dyn RangeReader,
+    metadata: &FileMetadata,
+    options: DownloadOptions,
+    context: Arc<DecodeContext>,
+    mut output: W,
+) -> Result<(W, u64)> {
+    let (tx, rx) = mpsc::channel(CHANNEL_CHUNKS);
+
+    let decompress = {
+        let metadata = metadata.clone();
+        let context = context.clone();
+        let entry = metrics::current_entry();
+        let span = decompress_span(&metadata);
+        task::spawn_blocking(move || {
+            let written = metrics::within_blocking(entry, || {
+                traced_decompress_into(
+                    &span,
+                    &metadata,
+                    ChannelReader::new(rx),
+                    &mut output,
+                    &context,
+                )
+            })?;
+            Ok((output, written))
+        })
+    };
+    let progress = &context.progress;
+    let (check_headers, names, cancel) = (context.check_headers, context.names, &context.cancel);
+    let feed = async move {
+        if check_headers {
+            check_local_header(reader, metadata, names).await?;
+        }
+        let mut chunks = compressed_stream(reader, metadata, options);
+        while let Some(chunk) = next_chunk(&mut chunks, cancel).await? {
+            progress.downloaded(chunk.len() as u64);
+            // The decoder hung up early; its own result says why.
+            if tx.send(Ok(chunk)).await.is_err() {
+                break;
+            }
+        }
+        Ok::<_, CloudZipError>(())
+    };
+
+    // A failed download also surfaces as a truncated stream in the decoder, so it wins.
+    let result = match tokio::join!(feed, decompress) {
+        (Err(err), _) => Err(err),
+        (Ok(()), Ok(decompressed)) => decompressed,
+        (Ok(()), Err(join_err)) => Err(io::Error::other(join_err).into()),
+    };
+    context.progress.entry_done();
+    result
+}
+
+/// Recreates a symlink entry at `output_path`, refusing targets that leave `output_root`.
+pub(crate) async fn extract_symlink(
+    reader: &dyn RangeReader,
+    metadata: &FileMetadata,
+    output_root: &Path,
+    output_path: PathBuf,
+    context: &DecodeContext,
+) -> Result<PathBuf> {
+    let rejected = |reason| CloudZipError::InvalidEntryPath {
+        file_name: metadata.file_name.clone(),
+        reason,
+    };
+    if metadata.uncompressed_size > MAX_SYMLINK_TARGET
+        || metadata.compressed_size > MAX_SYMLINK_TARGET + SYMLINK_OVERHEAD
+    {
+        return Err(rejected("the symlink target is too long"));
+    }
+
+    if context.check_headers {
+        check_local_header(reader, metadata, context.names).await?;
+    }
+    let compressed_data = reader
+        .read_range(metadata.file_offset, metadata.compressed_size)
+        .await?;
+    context.progress.downloaded(compressed_data.len() as u64);
+    let mut target = Vec::new();
+    decompress_into(metadata, compressed_data.as_slice(), &mut target, context)?;
+    context.progress.entry_done();
+    let target =
+        String::from_utf8(target).map_err(|_| rejected("the symlink target is not UTF-8"))?;
+
+    let link = output_path
+        .strip_prefix(output_root)
+        .unwrap_or(&output_path);
+    if !target_stays_inside(link, &target) {
+        return Err(rejected(
+            "the symlink points outside of the output directory",
+        ));
+    }
+
+    if let Some(parent) = output_path.parent() {
+        create_dir_all(parent)?;
+    }
+    // The conflict policy has already agreed to replace whatever is there.
+    if output_path.symlink_metadata().is_ok() {
+        std::fs::remove_file(&output_path)?;
+    }
+    create_symlink(&target, &output_path)?;
+    Ok(output_path)
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &str, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn create_symlink(target: &str, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn create_symlink(_target: &str, _link: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Attributes restored on an extracted file once its content is complete.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct FileAttributes {
+    pub modified: Option<SystemTime>,
+    pub permissions: Option<u32>,
+}
+
+impl FileAttributes {
+    pub fn apply(&self, file: &File) -> io::Result<()> {
+        if let Some(modified) = self.modified {
+            file.set_modified(modified)?;
+        }
+        #[cfg(unix)]
+        if let Some(mode) = self.permissions {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+}
+
+/// A temporary `.part` file that is removed unless committed, including when the extraction
+/// future is dropped half way through, unless it is kept to resume from.
+struct PartFile {
+    path: PathBuf,
+    committed: bool,
+    keep: bool,
+}
+
+impl PartFile {
+    fn new(output_file_path: &Path) -> Self {
+        let mut path = output_file_path.as_os_str().to_owned();
+        path.push(".part");
+        PartFile {
+            path: path.into(),
+            committed: false,
+            keep: false,
+        }
+    }
+
+    fn commit(mut self, output_file_path: &Path) -> Result<()> {
+        std::fs::rename(&self.path, output_file_path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        if !self.committed && !self.keep {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Starts downloading and decompressing an entry in the background and returns the reader
+/// its decompressed bytes come out of.
+pub(crate) fn open_entry_reader(
+    reader: Arc<dyn RangeReader>,
+    metadata: FileMetadata,
+    options: DownloadOptions,
+    context: Arc<DecodeContext>,
+) -> EntryReader {
+    let (compressed_tx, compressed_rx) = mpsc::channel(CHANNEL_CHUNKS);
+    let (tx, rx) = mpsc::channel(CHANNEL_CHUNKS);
+
+    let feed_metadata = metadata.clone();
+    let feed_context = context.clone();
+    let entry = metrics::current_entry();
+    let feed_entry = entry.clone();
+    let span = decompress_span(&metadata);
+    let feed = async move {
+        if feed_context.check_headers {
+            if let Err(err) =
+                check_local_header(reader.as_ref(), &feed_metadata, feed_context.names).await
+            {
+                let _ = compressed_tx.send(Err(err.into())).await;
+                return;
+            }
+        }
+        let mut chunks = compressed_stream(reader.as_ref(), &feed_metadata, options);
+        loop {
+            let chunk = match next_chunk(&mut chunks, &feed_context.cancel).await {
+                Ok(Some(chunk)) => {
+                    feed_context.progress.downloaded(chunk.len() as u64);
+                    Ok(chunk)
+                }
+                Ok(None) => break,
+                Err(err) => Err(err.into()),
+            };
+            let failed = chunk.is_err();
+            if compressed_tx.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    };
+    tokio::spawn(metrics::within(
+        feed_entry,
+        feed.instrument(Span::current()),
+    ));
+
+    task::spawn_blocking(move || {
+        let mut output = ChannelWriter { chunks: tx.clone() };
+        let compressed_data = ChannelReader::new(compressed_rx);
+        let result = metrics::within_blocking(entry, || {
+            traced_decompress_into(&span, &metadata, compressed_data, &mut output, &context)
+        });
+        context.progress.entry_done();
+        if let Err(err) = result {
+            let _ = tx.blocking_send(Err(err.into()));
+        }
+    });
+
+    EntryReader {
+        chunks: rx,
+        current: Bytes::new(),
+    }
+}
+
+/// Streams `len` bytes at `offset` of `reader` in the background, such as part of an entry
+/// read through [`CloudZip::entry_reader`](crate::CloudZip::entry_reader).
+pub(crate) fn range_entry_reader(
+    reader: Arc<dyn RangeReader>,
+    offset: u64,
+    len: u64,
+) -> EntryReader {
+    let (tx, rx) = mpsc::channel(CHANNEL_CHUNKS);
+    tokio::spawn(async move {
+        let mut chunks = reader.stream_range(offset, len);
+        while let Some(chunk) = chunks.next().await {
+            let failed = chunk.is_err();
+            if tx.send(chunk.map_err(io::Error::from)).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    EntryReader {
+        chunks: rx,
+        current: Bytes::new(),
+    }
+}
+
+/// Streams `len` bytes of `entry` after its first `skip`, which are decoded and dropped;
+/// decoding stops once the `len` bytes are read.
+pub(crate) fn skipping_entry_reader(mut entry: EntryReader, skip: u64, len: u64) -> EntryReader {
+    let (tx, rx) = mpsc::channel(CHANNEL_CHUNKS);
+    tokio::spawn(async move {
+        let (mut skip, mut left) = (skip, len);
+        while left > 0 {
+            let Some(chunk) = entry.chunks.recv().await else {
+                break;
+            };
+            let mut chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    let _ = tx.send(Err(err)).await;
+                    break;
+                }
+            };
+            if skip >= chunk.len() as u64 {
+                skip -= chunk.len() as u64;
+                continue;
+            }
+            chunk.advance(skip as usize);
+            skip = 0;
+            chunk.truncate(left.min(chunk.len() as u64) as usize);
+            left -= chunk.len() as u64;
+            if tx.send(Ok(chunk)).await.is_err() {
+                break;
+            }
+        }
+    });
+    EntryReader {
+        chunks: rx,
+        current: Bytes::new(),
+    }
+}
+
+/// Writes everything `content` yields to `output_file_path`, through a `.part` file renamed
+/// once it is complete.
+pub(crate) async fn write_to_path(
+    mut content: impl AsyncRead + Unpin,
+    output_file_path: PathBuf,
+) -> Result<PathBuf> {
+    if let Some(parent) = output_file_path.parent() {
+        create_dir_all(parent)?;
+    }
+    let part = PartFile::new(&output_file_path);
+    let mut file = tokio::fs::File::create(&part.path).await?;
+    tokio::io::copy(&mut content, &mut file).await?;
+    file.sync_all().await?;
+    drop(file);
+    part.commit(&output_file_path)?;
+    Ok(output_file_path)
+}
+
+/// What decoding entries needs beyond their metadata: the limits they are counted against, the
+/// password of encrypted entries, whether their local headers are checked first and how the
+/// names there are decoded, where progress is reported, and the token that stops downloads.
+pub(crate) struct DecodeContext {
+    pub budget: Budget,
+    pub password: Option<Vec<u8>>,
+    pub check_headers: bool,
+    pub names: NameEncoding,
+    pub progress: ProgressTracker,
+    pub cancel: CancellationToken,
+}
+
+/// The next chunk of `chunks`, or [`CloudZipError::Cancelled`] as soon as `cancel` is
+/// cancelled, which drops the request in flight.
+async fn next_chunk(
+    chunks: &mut ByteStream<'_>,
+    cancel: &CancellationToken,
+) -> Result<Option<Bytes>> {
+    tokio::select! {
+        biased;
+        _ = cancel.cancelled() => Err(CloudZipError::Cancelled),
+        chunk = chunks.try_next() => Ok(chunk?),
+    }
+}
+
+/// The span of decoding an entry, within the current one.
+fn decompress_span(metadata: &FileMetadata) -> Span {
+    debug_span!(
+        "decompress",
+        entry = %metadata.file_name,
+        method = metadata.compression_method,
+        written = Empty,
+        write_seconds = Empty,
+    )
+}
+
+/// Runs [`decompress_into`] in `span`, recording the bytes written and the time the writes
+/// took, which for files is the time spent on the disk.
+fn traced_decompress_into(
+    span: &Span,
+    metadata: &FileMetadata,
+    compressed_data: impl Read + Send,
+    output_file: &mut impl Write,
+    context: &DecodeContext,
+) -> Result<u64> {
+    span.in_scope(|| {
+        let mut output = TimedWriter {
+            inner: output_file,
+            writing: Duration::ZERO,
+        };
+        let written = decompress_into(metadata, compressed_data, &mut output, context);
+        span.record("write_seconds", output.writing.as_secs_f64());
+        if let Ok(written) = written {
+            span.record("written", written);
+        }
+        written
+    })
+}
+
+/// Adds up the time spent in the writes of another writer.
+struct TimedWriter<W> {
+    inner: W,
+    writing: Duration,
+}
+
+impl<W: Write> Write for TimedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        let written = self.inner.write(buf);
+        self.writing += started.elapsed();
+        written
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let started = Instant::now();
+        let flushed = self.inner.flush();
+        self.writing += started.elapsed();
+        flushed
+    }
+}
+
+pub(crate) fn decompress_into(
+    metadata: &FileMetadata,
+    compressed_data: impl Read + Send,
+    output_file: &mut impl Write,
+    context: &DecodeContext,
+) -> Result<u64> {
+    let stored_data = decrypting_reader(metadata, compressed_data, context.password.as_deref())?;
+    let decoder = compression::decoder(metadata, stored_data)?;
+    let mut reader = Crc32Reader::new(decoder);
+
+    // Read and write errors are kept apart so a full disk is not reported as a corrupt entry.
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut written = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(source) => {
+                return Err(CloudZipError::Decompression {
+                    file_name: metadata.file_name.clone(),
+                    source,
+                })
+            }
+        };
+        context.budget.consume(metadata, written, n as u64)?;
+        output_file.write_all(&buf[..n])?;
+        written += n as u64;
+        context.progress.written(n as u64);
+    }
+
+    if let Some(expected) = metadata.crc32 {
+        let actual = reader.finalize();
+        if actual != expected {
+            return Err(CloudZipError::CrcMismatch {
+                file_name: metadata.file_name.clone(),
+                expected,
+                actual,
+            });
+        }
+    }
+
+    Ok(written)
+}
+
+/// Computes the CRC32 of everything read through it.
+pub(crate) struct Crc32Reader<R> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read> Crc32Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Crc32Reader {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    pub fn finalize(self) -> u32 {
+        self.hasher.finalize()
+    }
+}
+
+impl<R: Read> Read for Crc32Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Blocking [`Read`] over chunks sent from an async task; a closed channel is end of file.
+pub(crate) struct ChannelReader {
+    chunks: mpsc::Receiver<io::Result<Bytes>>,
+    current: Bytes,
+}
+
+impl ChannelReader {
+    pub fn new(chunks: mpsc::Receiver<io::Result<Bytes>>) -> Self {
+        ChannelReader {
+            chunks,
+            current: Bytes::new(),
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => self.current = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len());
+        buf[..n].copy_from_slice(&self.current[..n]);
+        self.current.advance(n);
+        Ok(n)
+    }
+}
+
+/// Blocking [`Write`] that hands every buffer to an async consumer.
+pub(crate) struct ChannelWriter {
+    chunks: mpsc::Sender<io::Result<Bytes>>,
+}
+
+impl ChannelWriter {
+    pub fn new(chunks: mpsc::Sender<io::Result<Bytes>>) -> Self {
+        ChannelWriter { chunks }
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.chunks
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The decompressed bytes of a single entry, returned by
+/// [`CloudZip::open_entry`](crate::CloudZip::open_entry).
+///
+/// Download and decompression run in the background and stop when the reader is dropped.
+/// The CRC32 is checked at the end, so a corrupt entry fails on the last read.
+pub struct EntryReader {
+    chunks: mpsc::Receiver<io::Result<Bytes>>,
+    current: Bytes,
+}
+
+impl AsyncRead for EntryReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.current.is_empty() {
+            match ready!(self.chunks.poll_recv(cx)) {
+                Some(chunk) => self.current = chunk?,
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = buf.remaining().min(self.current.len());
+        buf.put_slice(&self.current[..n]);
+        self.current.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,99 @@
+use regex::bytes::Regex;
+use std::collections::VecDeque;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+
+/// Leading bytes of an entry looked at for a NUL to tell binary content from text, as grep
+/// does.
+const BINARY_PROBE_LEN: usize = 8 * 1024;
+
+/// Lines around each match to report as well, as grep's `-B` and `-A` do.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GrepContext {
+    pub before: usize,
+    pub after: usize,
+}
+
+/// A line of an entry that matched, or that is reported as context of a match.
+#[derive(Debug, Clone)]
+pub struct GrepLine {
+    /// 1-based line number.
+    pub number: u64,
+    /// The line without its newline.
+    pub text: Vec<u8>,
+    pub is_match: bool,
+    /// Whether lines were left out between the line reported before this one and it.
+    pub after_gap: bool,
+}
+
+/// What searching one entry found.
+#[derive(Debug, Clone, Default)]
+pub struct GrepMatches {
+    /// Number of matching lines. A binary entry stops at its first match, so it counts 1.
+    pub count: u64,
+    /// Whether the entry looks binary, in which case no lines are reported.
+    pub binary: bool,
+    pub lines: Vec<GrepLine>,
+}
+
+impl GrepMatches {
+    fn push(&mut self, number: u64, text: Vec<u8>, is_match: bool) {
+        let after_gap = self
+            .lines
+            .last()
+            .is_some_and(|last| last.number + 1 != number);
+        self.lines.push(GrepLine {
+            number,
+            text,
+            is_match,
+            after_gap,
+        });
+    }
+}
+
+/// Searches `content` line by line for `regex`, keeping the matching lines and `context`
+/// lines around them.
+pub(crate) async fn search(
+    content: impl AsyncRead + Unpin,
+    regex: &Regex,
+    context: GrepContext,
+) -> std::io::Result<GrepMatches> {
+    let mut content = BufReader::with_capacity(64 * 1024, content);
+    let mut matches = GrepMatches::default();
+    let probe = content.fill_buf().await?;
+    matches.binary = probe[..probe.len().min(BINARY_PROBE_LEN)].contains(&0);
+
+    let mut before: VecDeque<(u64, Vec<u8>)> = VecDeque::with_capacity(context.before);
+    let mut after_left = 0;
+    let mut line = Vec::new();
+    let mut number = 0;
+    loop {
+        line.clear();
+        if content.read_until(b'\n', &mut line).await? == 0 {
+            break;
+        }
+        number += 1;
+        let text = line.strip_suffix(b"\n").unwrap_or(&line);
+        if regex.is_match(text) {
+            matches.count += 1;
+            if matches.binary {
+                break;
+            }
+            for (number, text) in before.drain(..) {
+                matches.push(number, text, false);
+            }
+            matches.push(number, text.to_vec(), true);
+            after_left = context.after;
+        } else if matches.binary {
+            continue;
+        } else if after_left > 0 {
+            after_left -= 1;
+            matches.push(number, text.to_vec(), false);
+        } else if context.before > 0 {
+            if before.len() == context.before {
+                before.pop_front();
+            }
+            before.push_back((number, text.to_vec()));
+        }
+    }
+    Ok(matches)
+}
@@ -0,0 +1,232 @@
+// Handlers return tonic's `Status`, which is large but not worth boxing.
+#![allow(clippy::result_large_err)]
+
+use futures::stream::{self, Stream};
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+use tonic::{Request, Response, Status};
+use tracing::warn;
+
+use crate::archive::CloudZip;
+use crate::error::{CloudZipError, Result};
+use crate::location::{ArchiveLocation, BackendOptions};
+use crate::metadata::FileMetadata;
+use crate::selection::EntrySelector;
+
+/// Types generated from `proto/cloud_zip.proto`.
+pub mod proto {
+    tonic::include_proto!("cloud_zip.v1");
+}
+
+use proto::cloud_zip_server::CloudZipServer;
+use proto::{
+    extract_chunk, selector, Entry, ExtractChunk, ExtractRequest, IndexRequest, IndexResponse,
+    ListRequest, ListResponse, Selector,
+};
+
+/// Size of the `data` messages an entry is streamed in.
+const CHUNK_LEN: usize = 256 * 1024;
+
+/// The `cloud_zip.v1.CloudZip` service, opening the archive named by each request.
+///
+/// Archives on the local filesystem are refused unless allowed with
+/// [`CloudZipService::allow_local_files`], so that clients cannot read files of the host.
+pub struct CloudZipService {
+    backends: Arc<BackendOptions>,
+    allow_local_files: bool,
+}
+
+impl CloudZipService {
+    pub fn new(backends: BackendOptions) -> Self {
+        CloudZipService {
+            backends: Arc::new(backends),
+            allow_local_files: false,
+        }
+    }
+
+    pub fn allow_local_files(mut self, allow: bool) -> Self {
+        self.allow_local_files = allow;
+        self
+    }
+
+    /// Wraps the service for `tonic::transport::Server::add_service`.
+    pub fn into_server(self) -> CloudZipServer<Self> {
+        CloudZipServer::new(self)
+    }
+
+    fn location(&self, archive: &str) -> Result<ArchiveLocation, Status> {
+        let location: ArchiveLocation = archive.parse().map_err(status)?;
+        if matches!(location, ArchiveLocation::Local(_)) && !self.allow_local_files {
+            return Err(Status::permission_denied(format!(
+                "Local archives are not served: {}",
+                archive
+            )));
+        }
+        Ok(location)
+    }
+
+    /// Opens an archive, with the sidecar index when there is one.
+    async fn open(&self, archive: &str) -> Result<CloudZip, Status> {
+        let reader = self
+            .location(archive)?
+            .open(&self.backends)
+            .await
+            .map_err(status)?;
+        CloudZip::discover(reader).await.map_err(status)
+    }
+}
+
+fn status(err: CloudZipError) -> Status {
+    let message = err.to_string();
+    match err {
+        CloudZipError::EntryNotFound(_) => Status::not_found(message),
+        CloudZipError::InvalidPattern(_) | CloudZipError::InvalidLocation(_) => {
+            Status::invalid_argument(message)
+        }
+        CloudZipError::PasswordRequired(_) | CloudZipError::WrongPassword(_) => {
+            Status::permission_denied(message)
+        }
+        CloudZipError::S3 { .. } | CloudZipError::DynamoDb { .. } | CloudZipError::Http { .. } => {
+            Status::unavailable(message)
+        }
+        CloudZipError::ArchiveChanged(_) => Status::failed_precondition(message),
+        CloudZipError::Cancelled => Status::cancelled(message),
+        _ => {
+            warn!(error = %message, "Request failed");
+            Status::internal(message)
+        }
+    }
+}
+
+fn entry_selector(selector: Option<Selector>) -> Result<EntrySelector, Status> {
+    match selector.and_then(|selector| selector.kind) {
+        None => Ok(EntrySelector::All),
+        Some(selector::Kind::Name(name)) => Ok(EntrySelector::Name(name)),
+        Some(selector::Kind::Prefix(prefix)) => Ok(EntrySelector::Prefix(prefix)),
+        Some(selector::Kind::Glob(glob)) => EntrySelector::glob(&glob).map_err(status),
+        Some(selector::Kind::Regex(regex)) => EntrySelector::regex(&regex).map_err(status),
+    }
+}
+
+impl From<&FileMetadata> for Entry {
+    fn from(metadata: &FileMetadata) -> Self {
+        Entry {
+            name: metadata.file_name.clone(),
+            size: metadata.uncompressed_size,
+            compressed_size: metadata.compressed_size,
+            modified: metadata.modified,
+            is_directory: metadata.is_directory,
+            crc32: metadata.crc32,
+        }
+    }
+}
+
+/// Sends the selected files, each as its `entry` message followed by its `data` chunks.
+async fn send_entries(
+    archive: CloudZip,
+    selector: EntrySelector,
+    sender: &mpsc::Sender<Result<ExtractChunk, Status>>,
+) -> Result<(), Status> {
+    let entries = archive.verified_entries().await.map_err(status)?;
+    let selected = entries.select(&selector);
+    if selected.is_empty() {
+        return Err(status(CloudZipError::EntryNotFound(selector.describe())));
+    }
+    for metadata in selected.into_iter().filter(|meta| !meta.is_directory) {
+        let mut reader = archive
+            .open_entry(&metadata.file_name)
+            .await
+            .map_err(status)?;
+        let header = ExtractChunk {
+            kind: Some(extract_chunk::Kind::Entry(metadata.into())),
+        };
+        if sender.send(Ok(header)).await.is_err() {
+            // The client went away.
+            return Ok(());
+        }
+        loop {
+            let mut data = Vec::with_capacity(CHUNK_LEN);
+            (&mut reader)
+                .take(CHUNK_LEN as u64)
+                .read_to_end(&mut data)
+                .await
+                .map_err(|err| status(err.into()))?;
+            if data.is_empty() {
+                break;
+            }
+            let chunk = ExtractChunk {
+                kind: Some(extract_chunk::Kind::Data(data)),
+            };
+            if sender.send(Ok(chunk)).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+type ExtractStream = Pin<Box<dyn Stream<Item = Result<ExtractChunk, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl proto::cloud_zip_server::CloudZip for CloudZipService {
+    async fn index(
+        &self,
+        request: Request<IndexRequest>,
+    ) -> Result<Response<IndexResponse>, Status> {
+        let request = request.into_inner();
+        let reader = self
+            .location(&request.archive)?
+            .open(&self.backends)
+            .await
+            .map_err(status)?;
+        let archive = CloudZip::from_index(reader, Vec::new());
+        let entries = archive.index().await.map_err(status)?;
+        if request.sidecar {
+            archive.upload_sidecar().await.map_err(status)?;
+        }
+        Ok(Response::new(IndexResponse {
+            entries: entries.len() as u64,
+        }))
+    }
+
+    async fn list(&self, request: Request<ListRequest>) -> Result<Response<ListResponse>, Status> {
+        let request = request.into_inner();
+        let selector = entry_selector(request.selector)?;
+        let archive = self.open(&request.archive).await?;
+        let entries = archive.entries().map_err(status)?;
+        Ok(Response::new(ListResponse {
+            entries: entries
+                .select(&selector)
+                .into_iter()
+                .map(Entry::from)
+                .collect(),
+        }))
+    }
+
+    type ExtractStream = ExtractStream;
+
+    async fn extract(
+        &self,
+        request: Request<ExtractRequest>,
+    ) -> Result<Response<Self::ExtractStream>, Status> {
+        let request = request.into_inner();
+        let selector = entry_selector(request.selector)?;
+        let mut archive = self.open(&request.archive).await?;
+        if let Some(password) = request.password {
+            archive = archive.with_password(password);
+        }
+        // A couple of chunks in flight keep the archive reads ahead of the client.
+        let (sender, receiver) = mpsc::channel(4);
+        tokio::spawn(async move {
+            if let Err(err) = send_entries(archive, selector, &sender).await {
+                let _ = sender.send(Err(err)).await;
+            }
+        });
+        let chunks = stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|chunk| (chunk, receiver))
+        });
+        Ok(Response::new(Box::pin(chunks)))
+    }
+}
@@ -0,0 +1,507 @@
+use std::io::{self, Read};
+
+/// Distance back-references can reach, and so the history a decoder resumed mid-stream needs.
+pub(crate) const WINDOW_LEN: usize = 32 * 1024;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order in which the lengths of the code length code are stored.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+const END_OF_BLOCK: u16 = 256;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads bits least significant first, as deflate stores them, keeping count of where it is.
+pub(crate) struct BitReader<R> {
+    inner: R,
+    buffer: u64,
+    count: u32,
+    /// Bytes taken from `inner`, some of which may still be in `buffer`.
+    consumed: u64,
+}
+
+impl<R: Read> BitReader<R> {
+    /// Reads from `inner`, which starts at byte `start` of the stream, skipping its first
+    /// `skip` bits.
+    pub fn new(inner: R, start: u64, skip: u32) -> io::Result<Self> {
+        let mut reader = BitReader {
+            inner,
+            buffer: 0,
+            count: 0,
+            consumed: start,
+        };
+        reader.bits(skip)?;
+        Ok(reader)
+    }
+
+    /// Where the next bit is, counting from the start of the stream.
+    pub fn position(&self) -> u64 {
+        self.consumed * 8 - self.count as u64
+    }
+
+    /// Fills the buffer with as many whole bytes as fit, short only at the end of the input.
+    fn refill(&mut self) -> io::Result<()> {
+        let mut byte = [0u8];
+        while self.count <= 56 {
+            match self.inner.read(&mut byte) {
+                Ok(0) => break,
+                Ok(_) => {
+                    self.buffer |= (byte[0] as u64) << self.count;
+                    self.count += 8;
+                    self.consumed += 1;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn bits(&mut self, n: u32) -> io::Result<u32> {
+        if n == 0 {
+            return Ok(0);
+        }
+        if self.count < n {
+            self.refill()?;
+            if self.count < n {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        let value = (self.buffer & ((1u64 << n) - 1)) as u32;
+        self.buffer >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Skips to the next byte boundary.
+    pub fn align(&mut self) {
+        let partial = self.count % 8;
+        self.buffer >>= partial;
+        self.count -= partial;
+    }
+
+    /// Reads a whole byte once aligned, or `None` at the end of the input.
+    pub fn byte(&mut self) -> io::Result<Option<u8>> {
+        if self.count < 8 {
+            self.refill()?;
+            if self.count < 8 {
+                return Ok(None);
+            }
+        }
+        Ok(Some(self.bits(8)? as u8))
+    }
+
+    fn decode(&mut self, code: &Huffman) -> io::Result<u16> {
+        if self.count < code.bits {
+            self.refill()?;
+        }
+        let entry = code.table[(self.buffer & ((1u64 << code.bits) - 1)) as usize];
+        let len = entry & 0xf;
+        if len == 0 {
+            return Err(invalid("invalid Huffman code"));
+        }
+        if len > self.count {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.buffer >>= len;
+        self.count -= len;
+        Ok((entry >> 4) as u16)
+    }
+}
+
+/// A canonical Huffman code, looked up by its next `bits` input bits.
+struct Huffman {
+    /// The symbol shifted left by 4 and the code length, or 0 for unused codes.
+    table: Vec<u32>,
+    bits: u32,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> io::Result<Self> {
+        let bits = lengths.iter().copied().max().unwrap_or(0).max(1) as u32;
+        let mut count = [0u32; 16];
+        for &len in lengths {
+            count[len as usize] += 1;
+        }
+        count[0] = 0;
+        let mut next = [0u32; 16];
+        let mut code = 0;
+        for len in 1..16 {
+            code = (code + count[len - 1]) << 1;
+            next[len] = code;
+        }
+        let mut table = vec![0u32; 1 << bits];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len == 0 {
+                continue;
+            }
+            let len = len as u32;
+            let code = next[len as usize];
+            next[len as usize] += 1;
+            if code >= 1 << len {
+                return Err(invalid("over-subscribed Huffman code"));
+            }
+            let mut index = (code.reverse_bits() >> (32 - len)) as usize;
+            while index < table.len() {
+                table[index] = (symbol as u32) << 4 | len;
+                index += 1 << len;
+            }
+        }
+        Ok(Huffman { table, bits })
+    }
+
+    fn fixed() -> (Self, Self) {
+        let mut lengths = [8u8; 288];
+        lengths[144..256].fill(9);
+        lengths[256..280].fill(7);
+        let literals = Huffman::new(&lengths).expect("the fixed code is complete");
+        let distances = Huffman::new(&[5u8; 30]).expect("the fixed code is complete");
+        (literals, distances)
+    }
+}
+
+/// Decodes a raw deflate stream a block at a time, so that it can be stopped and resumed at
+/// any block boundary given the window before it.
+pub(crate) struct Inflater {
+    /// The last [`WINDOW_LEN`] bytes of earlier output, followed by the output not yet taken.
+    output: Vec<u8>,
+    /// Where the output not yet taken starts.
+    taken: usize,
+}
+
+impl Inflater {
+    /// Starts decoding after `window`, the output that came before.
+    pub fn new(window: &[u8]) -> Self {
+        let window = &window[window.len().saturating_sub(WINDOW_LEN)..];
+        Inflater {
+            output: window.to_vec(),
+            taken: window.len(),
+        }
+    }
+
+    /// Forgets earlier output, as at the start of a new gzip member.
+    pub fn reset(&mut self) {
+        self.output.clear();
+        self.taken = 0;
+    }
+
+    /// The last [`WINDOW_LEN`] bytes of output, which resuming right here needs.
+    pub fn window(&self) -> &[u8] {
+        &self.output[self.output.len().saturating_sub(WINDOW_LEN)..]
+    }
+
+    /// The output decoded since the last call.
+    pub fn take(&mut self) -> &[u8] {
+        let start = self.taken;
+        self.taken = self.output.len();
+        &self.output[start..]
+    }
+
+    /// Drops taken output beyond the window, which back-references can no longer reach.
+    fn trim(&mut self) {
+        let keep_from = self.taken.min(self.output.len().saturating_sub(WINDOW_LEN));
+        if keep_from > 0 {
+            self.output.drain(..keep_from);
+            self.taken -= keep_from;
+        }
+    }
+
+    /// Decodes the next block; returns whether it was the last of the stream.
+    pub fn block<R: Read>(&mut self, bits: &mut BitReader<R>) -> io::Result<bool> {
+        self.trim();
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => self.stored(bits)?,
+            1 => {
+                let (literals, distances) = Huffman::fixed();
+                self.compressed(bits, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(bits)?;
+                self.compressed(bits, &literals, &distances)?;
+            }
+            _ => return Err(invalid("invalid deflate block type")),
+        }
+        Ok(last)
+    }
+
+    fn stored<R: Read>(&mut self, bits: &mut BitReader<R>) -> io::Result<()> {
+        bits.align();
+        let len = bits.bits(16)?;
+        if bits.bits(16)? != !len & 0xffff {
+            return Err(invalid("stored block length does not match its complement"));
+        }
+        for _ in 0..len {
+            let byte = bits.byte()?.ok_or(io::ErrorKind::UnexpectedEof)?;
+            self.output.push(byte);
+        }
+        Ok(())
+    }
+
+    fn compressed<R: Read>(
+        &mut self,
+        bits: &mut BitReader<R>,
+        literals: &Huffman,
+        distances: &Huffman,
+    ) -> io::Result<()> {
+        loop {
+            let symbol = bits.decode(literals)?;
+            if symbol < END_OF_BLOCK {
+                self.output.push(symbol as u8);
+                continue;
+            }
+            if symbol == END_OF_BLOCK {
+                return Ok(());
+            }
+            let index = (symbol - 257) as usize;
+            if index >= LENGTH_BASE.len() {
+                return Err(invalid("invalid length symbol"));
+            }
+            let len = LENGTH_BASE[index] as usize + bits.bits(LENGTH_EXTRA[index] as u32)? as usize;
+            let index = bits.decode(distances)? as usize;
+            if index >= DISTANCE_BASE.len() {
+                return Err(invalid("invalid distance symbol"));
+            }
+            let distance =
+                DISTANCE_BASE[index] as usize + bits.bits(DISTANCE_EXTRA[index] as u32)? as usize;
+            if distance > self.output.len() {
+                return Err(invalid("distance reaches before the start of the stream"));
+            }
+            let start = self.output.len() - distance;
+            for i in 0..len {
+                let byte = self.output[start + i];
+                self.output.push(byte);
+            }
+        }
+    }
+}
+
+/// Reads the literal/length and distance codes of a dynamic block.
+fn dynamic_codes<R: Read>(bits: &mut BitReader<R>) -> io::Result<(Huffman, Huffman)> {
+    let literal_count = bits.bits(5)? as usize + 257;
+    let distance_count = bits.bits(5)? as usize + 1;
+    let code_length_count = bits.bits(4)? as usize + 4;
+    let mut code_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[symbol] = bits.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths)?;
+
+    let mut lengths = vec![0u8; literal_count + distance_count];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = bits.decode(&code_lengths)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths[..i]
+                    .last()
+                    .ok_or_else(|| invalid("repeated code length without a previous one"))?;
+                (previous, 3 + bits.bits(2)? as usize)
+            }
+            17 => (0, 3 + bits.bits(3)? as usize),
+            _ => (0, 11 + bits.bits(7)? as usize),
+        };
+        if i + repeat > lengths.len() {
+            return Err(invalid("too many code lengths"));
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+    if lengths[END_OF_BLOCK as usize] == 0 {
+        return Err(invalid("the block has no end-of-block code"));
+    }
+    Ok((
+        Huffman::new(&lengths[..literal_count])?,
+        Huffman::new(&lengths[literal_count..])?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    /// Words in an order that repeats rarely enough to take many dynamic blocks.
+    fn text(len: usize) -> Vec<u8> {
+        let words = [
+            "zip", "range", "bucket", "entry", "deflate", "window", "block", "s3",
+        ];
+        let mut state = 1u32;
+        let mut text = Vec::with_capacity(len + 8);
+        while text.len() < len {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            text.extend_from_slice(words[(state >> 16) as usize % words.len()].as_bytes());
+            text.push(b' ');
+        }
+        text.truncate(len);
+        text
+    }
+
+    fn deflate(data: &[u8], level: Compression) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), level);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn inflate(compressed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut bits = BitReader::new(compressed, 0, 0)?;
+        let mut inflater = Inflater::new(&[]);
+        let mut output = Vec::new();
+        loop {
+            let last = inflater.block(&mut bits)?;
+            output.extend_from_slice(inflater.take());
+            if last {
+                return Ok(output);
+            }
+        }
+    }
+
+    /// The type of the first block of a deflate stream.
+    fn first_block_type(compressed: &[u8]) -> u8 {
+        (compressed[0] >> 1) & 0b11
+    }
+
+    /// Writes bits least significant first, and Huffman codes most significant first.
+    #[derive(Default)]
+    struct BitWriter {
+        bytes: Vec<u8>,
+        count: u32,
+    }
+
+    impl BitWriter {
+        fn bits(&mut self, value: u32, n: u32) -> &mut Self {
+            for i in 0..n {
+                if self.count.is_multiple_of(8) {
+                    self.bytes.push(0);
+                }
+                let bit = (value >> i) as u8 & 1;
+                *self.bytes.last_mut().unwrap() |= bit << (self.count % 8);
+                self.count += 1;
+            }
+            self
+        }
+
+        fn code(&mut self, code: u32, n: u32) -> &mut Self {
+            for i in (0..n).rev() {
+                self.bits(code >> i, 1);
+            }
+            self
+        }
+    }
+
+    #[test]
+    fn inflates_stored_blocks() {
+        let data = text(100_000);
+        let compressed = deflate(&data, Compression::none());
+        assert_eq!(first_block_type(&compressed), 0);
+        assert_eq!(inflate(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn inflates_fixed_blocks() {
+        let data = b"a short line, a short line, a short line".to_vec();
+        let compressed = deflate(&data, Compression::default());
+        assert_eq!(first_block_type(&compressed), 1);
+        assert_eq!(inflate(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn inflates_dynamic_blocks() {
+        let data = text(1_000_000);
+        let compressed = deflate(&data, Compression::best());
+        assert_eq!(first_block_type(&compressed), 2);
+        assert_eq!(inflate(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn resumes_at_a_block_boundary() {
+        let data = text(1_000_000);
+        let compressed = deflate(&data, Compression::default());
+        let mut bits = BitReader::new(compressed.as_slice(), 0, 0).unwrap();
+        let mut inflater = Inflater::new(&[]);
+        assert!(!inflater.block(&mut bits).unwrap());
+        let decoded = inflater.take().len();
+        let position = bits.position();
+        let window = inflater.window().to_vec();
+
+        let start = position / 8;
+        let mut bits =
+            BitReader::new(&compressed[start as usize..], start, (position % 8) as u32).unwrap();
+        let mut inflater = Inflater::new(&window);
+        let mut rest = Vec::new();
+        loop {
+            let last = inflater.block(&mut bits).unwrap();
+            rest.extend_from_slice(inflater.take());
+            if last {
+                break;
+            }
+        }
+        assert_eq!(rest, data[decoded..]);
+    }
+
+    #[test]
+    fn rejects_over_subscribed_codes() {
+        // A dynamic block whose 19 code length codes are all one bit long.
+        let mut block = BitWriter::default();
+        block
+            .bits(1, 1)
+            .bits(2, 2)
+            .bits(0, 5)
+            .bits(0, 5)
+            .bits(15, 4);
+        for _ in 0..19 {
+            block.bits(1, 3);
+        }
+        let err = inflate(&block.bytes).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("over-subscribed"));
+    }
+
+    #[test]
+    fn rejects_distances_beyond_the_window() {
+        // A fixed block with a literal, then a copy of 3 bytes from 2 bytes back.
+        let mut block = BitWriter::default();
+        block
+            .bits(1, 1)
+            .bits(1, 2)
+            .code(0x30 + u32::from(b'a'), 8)
+            .code(1, 7)
+            .code(1, 5)
+            .code(0, 7);
+        let err = inflate(&block.bytes).unwrap_err();
+        assert!(err.to_string().contains("before the start of the stream"));
+
+        // The same copy is fine after a window holding enough output.
+        let mut bits = BitReader::new(block.bytes.as_slice(), 0, 0).unwrap();
+        let mut inflater = Inflater::new(b"xy");
+        inflater.block(&mut bits).unwrap();
+        assert_eq!(inflater.take(), b"ayay");
+    }
+
+    #[test]
+    fn rejects_invalid_block_types() {
+        let mut block = BitWriter::default();
+        block.bits(1, 1).bits(3, 2);
+        assert!(inflate(&block.bytes).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+use aws_sdk_s3::Client;
+use lambda_runtime::{service_fn, LambdaEvent};
+use percent_encoding::percent_decode_str;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+use tracing::info;
+
+use crate::archive::CloudZip;
+use crate::backend::s3::get_s3_client;
+use crate::backend::SIDECAR_SUFFIX;
+use crate::error::{CloudZipError, Result};
+use crate::location::{ArchiveLocation, BackendOptions};
+use crate::s3_output::S3Destination;
+use crate::selection::Selection;
+
+/// Runs the Lambda runtime loop, answering every invocation with [`LambdaHandler::handle`].
+pub async fn run(backends: BackendOptions) -> Result<(), lambda_runtime::Error> {
+    let handler = Arc::new(LambdaHandler::new(backends));
+    lambda_runtime::run(service_fn(move |event: LambdaEvent<Value>| {
+        let handler = handler.clone();
+        async move {
+            handler
+                .handle(event.payload)
+                .await
+                .map_err(lambda_runtime::Error::from)
+        }
+    }))
+    .await
+}
+
+/// The part of an S3 event notification naming the objects.
+#[derive(Debug, Deserialize)]
+pub struct S3Event {
+    #[serde(rename = "Records")]
+    pub records: Vec<S3EventRecord>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct S3EventRecord {
+    pub s3: S3Entity,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct S3Entity {
+    pub bucket: S3Bucket,
+    pub object: S3Object,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct S3Bucket {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct S3Object {
+    /// URL-encoded, with spaces as `+`.
+    pub key: String,
+    #[serde(rename = "versionId")]
+    pub version_id: Option<String>,
+}
+
+/// A request invoked directly, e.g. `{"operation": "list", "archive": "s3://b/a.zip"}`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum Request {
+    /// Indexes an archive, storing the index as its sidecar unless `sidecar` is false.
+    Index {
+        archive: String,
+        #[serde(default = "default_true")]
+        sidecar: bool,
+    },
+    /// Lists the selected entries.
+    List {
+        archive: String,
+        #[serde(flatten)]
+        select: Selection,
+    },
+    /// Copies the selected files to objects under `destination`, an `s3://bucket/prefix/`.
+    Extract {
+        archive: String,
+        #[serde(flatten)]
+        select: Selection,
+        destination: String,
+        password: Option<String>,
+    },
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// One element of a `list` response.
+#[derive(Serialize)]
+struct ListedEntry<'a> {
+    name: &'a str,
+    size: u64,
+    compressed_size: u64,
+    /// Seconds since the Unix epoch.
+    modified: Option<i64>,
+    is_directory: bool,
+}
+
+/// Answers invocations with the backends of the function's configuration.
+///
+/// S3 events index every archive they name and store the index as its sidecar, so that later
+/// requests read only the entries they need. Only S3 archives are accepted; a function has
+/// nothing of interest on its own disk.
+pub struct LambdaHandler {
+    backends: BackendOptions,
+    client: OnceCell<Client>,
+}
+
+impl LambdaHandler {
+    pub fn new(backends: BackendOptions) -> Self {
+        LambdaHandler {
+            backends,
+            client: OnceCell::new(),
+        }
+    }
+
+    async fn client(&self) -> &Client {
+        self.client
+            .get_or_init(|| get_s3_client(&self.backends.s3))
+            .await
+    }
+
+    /// Handles one invocation payload, an S3 event notification or a [`Request`] invoked
+    /// directly, returning the JSON response.
+    pub async fn handle(&self, payload: Value) -> Result<Value> {
+        if payload.get("Records").is_some() {
+            self.handle_event(parse_payload(payload)?).await
+        } else {
+            self.handle_request(parse_payload(payload)?).await
+        }
+    }
+
+    async fn handle_event(&self, event: S3Event) -> Result<Value> {
+        let mut indexed = Vec::new();
+        for record in event.records {
+            let key = event_key(&record.s3.object.key);
+            if key.ends_with(SIDECAR_SUFFIX) {
+                // Storing a sidecar notifies like any other upload.
+                continue;
+            }
+            let location = ArchiveLocation::S3 {
+                bucket: record.s3.bucket.name,
+                key,
+                version_id: record.s3.object.version_id,
+            };
+            let entries = self.index(&location, true).await?;
+            indexed.push(json!({ "archive": location.to_string(), "entries": entries }));
+        }
+        Ok(json!({ "indexed": indexed }))
+    }
+
+    async fn handle_request(&self, request: Request) -> Result<Value> {
+        match request {
+            Request::Index { archive, sidecar } => {
+                let location = s3_location(&archive)?;
+                let entries = self.index(&location, sidecar).await?;
+                Ok(json!({ "archive": location.to_string(), "entries": entries }))
+            }
+            Request::List { archive, select } => {
+                let selector = select.selector()?;
+                let archive = self.open(&archive).await?;
+                let entries = archive.entries()?;
+                let listed: Vec<_> = entries
+                    .select(&selector)
+                    .into_iter()
+                    .map(|meta| ListedEntry {
+                        name: &meta.file_name,
+                        size: meta.uncompressed_size,
+                        compressed_size: meta.compressed_size,
+                        modified: meta.modified,
+                        is_directory: meta.is_directory,
+                    })
+                    .collect();
+                Ok(json!({ "entries": listed }))
+            }
+            Request::Extract {
+                archive,
+                select,
+                destination,
+                password,
+            } => {
+                let selector = select.selector()?;
+                let destination: S3Destination = destination.parse()?;
+                let mut archive = self.open(&archive).await?;
+                if let Some(password) = password {
+                    archive = archive.with_password(password);
+                }
+                let extracted = archive
+                    .extract_to_s3(&selector, self.client().await, &destination)
+                    .await?;
+                Ok(serde_json::to_value(extracted)?)
+            }
+        }
+    }
+
+    async fn index(&self, location: &ArchiveLocation, sidecar: bool) -> Result<usize> {
+        let archive = CloudZip::from_index(location.open(&self.backends).await?, Vec::new());
+        let entries = archive.index().await?;
+        if sidecar {
+            archive.upload_sidecar().await?;
+        }
+        info!(archive = %location, entries = entries.len(), sidecar, "Indexed");
+        Ok(entries.len())
+    }
+
+    async fn open(&self, archive: &str) -> Result<CloudZip> {
+        CloudZip::discover(s3_location(archive)?.open(&self.backends).await?).await
+    }
+}
+
+fn parse_payload<T: DeserializeOwned>(payload: Value) -> Result<T> {
+    serde_json::from_value(payload).map_err(|err| CloudZipError::InvalidRequest(err.to_string()))
+}
+
+/// Decodes an object key as S3 event notifications write it.
+fn event_key(key: &str) -> String {
+    percent_decode_str(&key.replace('+', " "))
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+fn s3_location(archive: &str) -> Result<ArchiveLocation> {
+    match archive.parse()? {
+        location @ ArchiveLocation::S3 { .. } => Ok(location),
+        location => Err(CloudZipError::InvalidLocation(format!(
+            "only S3 archives are read from Lambda, not {}",
+            location
+        ))),
+    }
+}
@@ -0,0 +1,67 @@
+mod archive;
+pub mod backend;
+pub mod blocking;
+pub mod catalog;
+mod central_directory;
+mod codepage;
+pub mod compression;
+pub mod content_type;
+mod crypto;
+pub mod diff;
+pub mod digest;
+#[cfg(feature = "dynamodb")]
+pub mod dynamodb_index;
+pub mod edit;
+mod error;
+pub mod exif;
+mod extract;
+pub mod grep;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod inflate;
+#[cfg(feature = "lambda")]
+pub mod lambda;
+mod limits;
+pub mod location;
+pub mod manifest;
+pub mod metadata;
+mod metrics;
+#[cfg(feature = "mount")]
+pub mod mount;
+mod output;
+pub mod preview;
+mod progress;
+#[cfg(any(feature = "serve", feature = "worker"))]
+pub mod prometheus;
+mod resume;
+mod s3_output;
+mod selection;
+#[cfg(feature = "serve")]
+pub mod serve;
+pub mod sharded_index;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_index;
+pub mod sync;
+mod tar;
+#[cfg(feature = "worker")]
+pub mod worker;
+pub mod writer;
+
+pub use archive::{
+    CloudZip, EntryCheck, EntryDigest, EntryMatches, ExtractOutcome, IndexCheck, IndexProblem,
+    IndexUpdate, TransferEstimate, CHECKPOINTED_ENTRY_LEN, DEFAULT_CONCURRENCY,
+};
+pub use backend::{BandwidthLimit, CacheConfig, RangeReader, RetryPolicy};
+pub use codepage::NameEncoding;
+pub use error::{CloudZipError, Result};
+pub use extract::{DownloadOptions, EntryReader};
+pub use limits::ExtractLimits;
+pub use location::{ArchiveLocation, ArchiveUri, BackendOptions};
+pub use manifest::ManifestEntry;
+pub use metadata::{EntryConflict, EntryIndex, FileMetadata};
+pub use metrics::{EntryMetrics, Metrics, MetricsRecorder};
+pub use output::{ConflictPolicy, PathLayout};
+pub use progress::{Progress, ProgressCallback};
+pub use s3_output::{ExtractedObjects, S3Destination, S3Upload};
+pub use selection::{EntrySelector, Selection};
+pub use tokio_util::sync::CancellationToken;
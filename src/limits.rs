@@ -0,0 +1,94 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::error::{CloudZipError, Result};
+use crate::metadata::FileMetadata;
+
+/// Upper bounds on what an extraction may write, guarding against zip bombs.
+///
+/// Sizes recorded in the central directory can lie, so the limits are enforced on the bytes
+/// actually coming out of the decoder as well as checked up front against the index.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExtractLimits {
+    /// Largest decompressed size of a single entry, in bytes.
+    pub max_entry_size: Option<u64>,
+    /// Largest number of decompressed bytes written by one extraction call.
+    pub max_total_size: Option<u64>,
+    /// Largest ratio between the decompressed and compressed size of an entry.
+    pub max_ratio: Option<u64>,
+}
+
+/// Counts decompressed bytes against [`ExtractLimits`] across the entries of one extraction.
+#[derive(Debug)]
+pub(crate) struct Budget {
+    limits: ExtractLimits,
+    total: AtomicU64,
+}
+
+impl Budget {
+    pub fn new(limits: ExtractLimits) -> Self {
+        Budget {
+            limits,
+            total: AtomicU64::new(0),
+        }
+    }
+
+    /// Rejects entries whose recorded sizes already break a limit, before anything is fetched.
+    pub fn check_planned<'a>(
+        &self,
+        entries: impl IntoIterator<Item = &'a FileMetadata>,
+    ) -> Result<()> {
+        let mut total = 0u64;
+        for metadata in entries {
+            self.check_entry(metadata, metadata.uncompressed_size)?;
+            total = total.saturating_add(metadata.uncompressed_size);
+            if let Some(max) = self.limits.max_total_size.filter(|&max| total > max) {
+                return Err(exceeded(
+                    metadata,
+                    format!("the selected entries expand to more than {} bytes", max),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Accounts for `n` more decompressed bytes of an entry that has produced `written` so far.
+    pub fn consume(&self, metadata: &FileMetadata, written: u64, n: u64) -> Result<()> {
+        self.check_entry(metadata, written + n)?;
+        let total = self.total.fetch_add(n, Ordering::Relaxed) + n;
+        match self.limits.max_total_size {
+            Some(max) if total > max => Err(exceeded(
+                metadata,
+                format!("more than {} bytes decompressed in total", max),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_entry(&self, metadata: &FileMetadata, size: u64) -> Result<()> {
+        if let Some(max) = self.limits.max_entry_size.filter(|&max| size > max) {
+            return Err(exceeded(
+                metadata,
+                format!("entry expands to more than {} bytes", max),
+            ));
+        }
+        if let Some(ratio) = self.limits.max_ratio {
+            if size > metadata.compressed_size.max(1).saturating_mul(ratio) {
+                return Err(exceeded(
+                    metadata,
+                    format!(
+                        "compression ratio exceeds {}:1 ({} bytes from {})",
+                        ratio, size, metadata.compressed_size
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn exceeded(metadata: &FileMetadata, reason: String) -> CloudZipError {
+    CloudZipError::LimitExceeded {
+        file_name: metadata.file_name.clone(),
+        reason,
+    }
+}
@@ -0,0 +1,336 @@
+use futures::future::try_join_all;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::debug;
+
+use crate::archive::CloudZip;
+#[cfg(feature = "azure")]
+use crate::backend::azure::{AzureBackend, AzureConfig};
+#[cfg(feature = "http")]
+use crate::backend::HttpBackend;
+use crate::backend::{
+    s3::{get_s3_client, S3Config},
+    BandwidthLimit, CacheConfig, CachingReader, GzipReader, LocalBackend, MeteredReader,
+    RangeReader, RetryPolicy, RetryingReader, S3Backend, SplitReader, ThrottledReader,
+};
+use crate::central_directory::split_parts;
+use crate::error::{without_query, CloudZipError, Result};
+use crate::metrics::MetricsRecorder;
+
+/// Where an archive lives, parsed from a URI such as `s3://bucket/key.zip`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArchiveLocation {
+    /// `file:///path/to/a.zip` or a plain filesystem path.
+    Local(PathBuf),
+    /// `s3://bucket/key.zip`, or `s3://bucket/key.zip?versionId=...` for one version of it.
+    S3 {
+        bucket: String,
+        key: String,
+        version_id: Option<String>,
+    },
+    /// `http://...` or `https://...`, including presigned URLs.
+    Http(String),
+    /// `az://container/path/to/blob.zip`
+    Azure { container: String, blob: String },
+}
+
+/// An archive location with an optional entry inside it, written `archive!inner/path`.
+///
+/// Entries of archives nested inside it are written `outer.zip!inner.zip!inner/path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveUri {
+    pub location: ArchiveLocation,
+    /// Archives inside the one at `location` leading to the entry, outermost first.
+    pub nested: Vec<String>,
+    pub entry: Option<String>,
+}
+
+/// Settings needed to open the backends a location may resolve to.
+#[derive(Default)]
+pub struct BackendOptions {
+    pub s3: S3Config,
+    /// Applied to every remote backend; local files are read without retries.
+    pub retry: RetryPolicy,
+    /// Caches the ranges read from remote backends; `None` reads every range again.
+    pub cache: Option<CacheConfig>,
+    /// Shared by every remote backend opened with these options, so that all their downloads
+    /// together stay under it; `None` reads as fast as the network allows.
+    pub max_bandwidth: Option<Arc<BandwidthLimit>>,
+    /// Counts the reads of every backend opened with these options, local files included,
+    /// and the lookups of their caches.
+    pub metrics: Option<Arc<MetricsRecorder>>,
+    #[cfg(feature = "azure")]
+    pub azure: Option<AzureConfig>,
+}
+
+/// Marks the S3 object version at the end of an `s3://` URI.
+const VERSION_ID_QUERY: &str = "?versionId=";
+
+fn bucket_and_key(uri: &str, rest: &str) -> Result<(String, String)> {
+    match rest.split_once('/') {
+        Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => {
+            Ok((bucket.to_string(), key.to_string()))
+        }
+        _ => Err(CloudZipError::InvalidLocation(format!(
+            "{} must name both a container and an object",
+            uri
+        ))),
+    }
+}
+
+impl FromStr for ArchiveLocation {
+    type Err = CloudZipError;
+
+    fn from_str(uri: &str) -> Result<Self> {
+        // Single-letter "schemes" are Windows drive letters.
+        let scheme = match uri.split_once("://") {
+            Some((scheme, _)) if scheme.len() > 1 => scheme.to_ascii_lowercase(),
+            _ => return Ok(ArchiveLocation::Local(PathBuf::from(uri))),
+        };
+        let rest = &uri[scheme.len() + 3..];
+
+        match scheme.as_str() {
+            "file" => Ok(ArchiveLocation::Local(PathBuf::from(rest))),
+            "s3" => {
+                let (rest, version_id) = match rest.rsplit_once(VERSION_ID_QUERY) {
+                    Some((rest, version_id)) if !version_id.is_empty() => {
+                        (rest, Some(version_id.to_string()))
+                    }
+                    _ => (rest, None),
+                };
+                let (bucket, key) = bucket_and_key(uri, rest)?;
+                Ok(ArchiveLocation::S3 {
+                    bucket,
+                    key,
+                    version_id,
+                })
+            }
+            "http" | "https" => Ok(ArchiveLocation::Http(uri.to_string())),
+            "az" | "azure" => {
+                let (container, blob) = bucket_and_key(uri, rest)?;
+                Ok(ArchiveLocation::Azure { container, blob })
+            }
+            _ => Err(CloudZipError::InvalidLocation(format!(
+                "Unsupported scheme {}:// in {}",
+                scheme, uri
+            ))),
+        }
+    }
+}
+
+impl FromStr for ArchiveUri {
+    type Err = CloudZipError;
+
+    fn from_str(uri: &str) -> Result<Self> {
+        let mut parts = uri.split('!');
+        let location = parts.next().unwrap_or_default();
+        let mut nested: Vec<String> = parts.map(str::to_string).collect();
+        let entry = nested.pop().filter(|entry| !entry.is_empty());
+        if nested.iter().any(String::is_empty) {
+            return Err(CloudZipError::InvalidLocation(format!(
+                "{} has an empty archive name between two `!`",
+                uri
+            )));
+        }
+        Ok(ArchiveUri {
+            location: location.parse()?,
+            nested,
+            entry,
+        })
+    }
+}
+
+impl ArchiveUri {
+    /// Opens the innermost archive the URI names: the one at its location, or the last of
+    /// the archives nested inside it.
+    pub async fn open(&self, options: &BackendOptions) -> Result<Arc<dyn RangeReader>> {
+        self.open_nested(self.location.open(options).await?).await
+    }
+
+    /// Follows the nested archives of the URI from `reader`, the archive at its location.
+    /// Each is indexed from its bytes, as [`CloudZip::entry_reader`] gives them.
+    pub async fn open_nested(&self, reader: Arc<dyn RangeReader>) -> Result<Arc<dyn RangeReader>> {
+        let mut reader = reader;
+        for name in &self.nested {
+            reader = CloudZip::discover(reader).await?.entry_reader(name).await?;
+            if is_gzip(name) {
+                reader = Arc::new(GzipReader::new(reader));
+            }
+        }
+        Ok(reader)
+    }
+}
+
+impl fmt::Display for ArchiveLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveLocation::Local(path) => write!(f, "{}", path.display()),
+            ArchiveLocation::S3 {
+                bucket,
+                key,
+                version_id,
+            } => {
+                write!(f, "s3://{}/{}", bucket, key)?;
+                match version_id {
+                    Some(version_id) => write!(f, "{}{}", VERSION_ID_QUERY, version_id),
+                    None => Ok(()),
+                }
+            }
+            ArchiveLocation::Http(url) if url.contains('?') => {
+                write!(f, "{}?…", without_query(url))
+            }
+            ArchiveLocation::Http(url) => write!(f, "{}", url),
+            ArchiveLocation::Azure { container, blob } => write!(f, "az://{}/{}", container, blob),
+        }
+    }
+}
+
+/// Whether `name` is that of a gzip file, which is read decompressed.
+fn is_gzip(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.ends_with(".gz") || name.ends_with(".tgz")
+}
+
+#[cfg(not(all(feature = "http", feature = "azure")))]
+fn not_built(feature: &str) -> CloudZipError {
+    CloudZipError::InvalidLocation(format!(
+        "cloud_zip was built without the `{}` feature",
+        feature
+    ))
+}
+
+impl ArchiveLocation {
+    /// Creates the backend that serves ranged reads for this location. A `.gz` or `.tgz`
+    /// file is read through a [`GzipReader`], as its decompressed content.
+    pub async fn open(&self, options: &BackendOptions) -> Result<Arc<dyn RangeReader>> {
+        let backend = self.open_stored(options).await?;
+        if self.is_gzip() {
+            return Ok(Arc::new(GzipReader::new(backend)));
+        }
+        Ok(backend)
+    }
+
+    /// Creates the backend that serves the bytes stored at this location.
+    async fn open_stored(&self, options: &BackendOptions) -> Result<Arc<dyn RangeReader>> {
+        let backend = self.open_backend(options).await?;
+        let backend: Arc<dyn RangeReader> = match &options.metrics {
+            Some(metrics) => Arc::new(MeteredReader::new(backend, metrics.clone())),
+            None => backend,
+        };
+        if let ArchiveLocation::Local(_) = self {
+            return Ok(backend);
+        }
+        let backend: Arc<dyn RangeReader> = match &options.max_bandwidth {
+            Some(limit) => Arc::new(ThrottledReader::new(backend, limit.clone())),
+            None => backend,
+        };
+        let backend: Arc<dyn RangeReader> = Arc::new(
+            RetryingReader::new(backend, options.retry).with_metrics(options.metrics.clone()),
+        );
+        Ok(match &options.cache {
+            Some(config) => Arc::new(
+                CachingReader::new(backend, self.to_string(), config.clone())
+                    .with_metrics(options.metrics.clone()),
+            ),
+            None => backend,
+        })
+    }
+
+    fn is_gzip(&self) -> bool {
+        match self {
+            ArchiveLocation::Local(path) => is_gzip(&path.to_string_lossy()),
+            ArchiveLocation::S3 { key, .. } => is_gzip(key),
+            ArchiveLocation::Http(url) => is_gzip(without_query(url)),
+            ArchiveLocation::Azure { blob, .. } => is_gzip(blob),
+        }
+    }
+
+    /// Opens a split archive whose last part is at this location, `a.zip`, together with the
+    /// parts `a.z01`, `a.z02`, … next to it, as many as its EOCD record counts. An archive in
+    /// one piece is opened like [`ArchiveLocation::open`] does.
+    pub async fn open_split(&self, options: &BackendOptions) -> Result<Arc<dyn RangeReader>> {
+        let last = self.open(options).await?;
+        let parts = split_parts(&*last).await?;
+        if parts <= 1 {
+            return Ok(last);
+        }
+        let mut readers = try_join_all(
+            (1..parts).map(|number| async move { self.part(number)?.open(options).await }),
+        )
+        .await?;
+        readers.push(last);
+        debug!(archive = %self, parts, "Joined the parts of a split archive");
+        Ok(Arc::new(SplitReader::new(readers).await?))
+    }
+
+    /// Part `number` of the split archive whose last part is here: `a.z01` for `a.zip`.
+    fn part(&self, number: u32) -> Result<ArchiveLocation> {
+        let extension = format!("z{:02}", number);
+        let renamed = |name: &str| -> Result<String> {
+            let stem_end = name
+                .rfind('.')
+                .filter(|&dot| !name[dot..].contains('/'))
+                .ok_or_else(|| {
+                    CloudZipError::InvalidLocation(format!(
+                        "{} has no extension to name the parts of a split archive after",
+                        self
+                    ))
+                })?;
+            Ok(format!("{}.{}", &name[..stem_end], extension))
+        };
+        Ok(match self {
+            ArchiveLocation::Local(path) => ArchiveLocation::Local(path.with_extension(extension)),
+            ArchiveLocation::S3 { bucket, key, .. } => ArchiveLocation::S3 {
+                bucket: bucket.clone(),
+                key: renamed(key)?,
+                // Every part is an object of its own, with versions of its own.
+                version_id: None,
+            },
+            ArchiveLocation::Http(url) => {
+                let path = without_query(url);
+                ArchiveLocation::Http(format!("{}{}", renamed(path)?, &url[path.len()..]))
+            }
+            ArchiveLocation::Azure { container, blob } => ArchiveLocation::Azure {
+                container: container.clone(),
+                blob: renamed(blob)?,
+            },
+        })
+    }
+
+    async fn open_backend(&self, options: &BackendOptions) -> Result<Arc<dyn RangeReader>> {
+        match self {
+            ArchiveLocation::Local(path) => Ok(Arc::new(LocalBackend::open(path)?)),
+            ArchiveLocation::S3 {
+                bucket,
+                key,
+                version_id,
+            } => {
+                let client = get_s3_client(&options.s3).await;
+                Ok(Arc::new(
+                    S3Backend::new(client, bucket, key)
+                        .with_version_id(version_id.clone())
+                        .with_requester_pays(options.s3.requester_pays)
+                        .with_encryption(options.s3.encryption.clone()),
+                ))
+            }
+            #[cfg(feature = "http")]
+            ArchiveLocation::Http(url) => Ok(Arc::new(HttpBackend::new(url))),
+            #[cfg(not(feature = "http"))]
+            ArchiveLocation::Http(_) => Err(not_built("http")),
+            #[cfg(feature = "azure")]
+            ArchiveLocation::Azure { container, blob } => {
+                let config = options.azure.clone().ok_or_else(|| {
+                    CloudZipError::InvalidLocation(format!(
+                        "{} needs an Azure storage account to be configured",
+                        self
+                    ))
+                })?;
+                Ok(Arc::new(AzureBackend::new(config, container, blob)))
+            }
+            #[cfg(not(feature = "azure"))]
+            ArchiveLocation::Azure { .. } => Err(not_built("azure")),
+        }
+    }
+}
@@ -1,186 +1,4151 @@
-use zip::ZipArchive;
-use std::error::Error;
-use flate2::read::DeflateDecoder;
-use serde::{Deserialize, Serialize};
-use aws_sdk_s3::{Client, config::Region};
-use std::io::{self, Read, Seek, SeekFrom};
-use std::fs::{File, OpenOptions,create_dir_all};
-use aws_config::{meta::region::RegionProviderChain, BehaviorVersion};
-
-#[derive(Deserialize, Serialize, Debug)]
-struct FileMetadata {
-    file_name: String,
-    uncompressed_size: u64,
-    compressed_size: u64,
-    is_directory: bool,
-    file_offset: u64,
-}
-
-async fn download_bytes(
-    client: &Client,
-    bucket_name: &str,
-    object_key: &str,
-    byte_range: &str,
-) -> Result<Vec<u8>, Box<dyn Error>> {
-    let resp = client
-        .get_object()
-        .bucket(bucket_name)
-        .key(object_key)
-        .range(byte_range)
-        .send()
-        .await?;
+use clap::{
+    ArgAction, ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+};
+#[cfg(feature = "azure")]
+use cloud_zip::backend::azure::{AzureConfig, AzureCredential};
+#[cfg(feature = "dynamodb")]
+use cloud_zip::dynamodb_index::DynamoDbIndex;
+#[cfg(feature = "serve")]
+use cloud_zip::prometheus::PrometheusMetrics;
+#[cfg(feature = "sqlite")]
+use cloud_zip::sqlite_index::SqliteIndex;
+use cloud_zip::{
+    backend::s3::{get_s3_client, CustomerKey, HttpVersion, S3Backend, S3Config, S3Encryption},
+    backend::{GzipIndex, LocalBackend},
+    catalog::{Catalog, CatalogArchive, CatalogMatch, DuplicateGroup},
+    compression,
+    diff::{self, Change, EntryChange},
+    digest::HashAlgorithm,
+    edit::ArchiveEdit,
+    exif::Exif,
+    grep::{GrepContext, GrepMatches},
+    manifest::{parse_manifest, read_manifest, ManifestFormat},
+    metadata,
+    metadata::{Encryption, IndexFormat},
+    preview::{Preview, PreviewFormat},
+    sharded_index::ShardedIndex,
+    sync::{SyncOptions, SyncPlan},
+    writer::{self, ArchiveWriter},
+    ArchiveLocation, ArchiveUri, BackendOptions, BandwidthLimit, CacheConfig, CancellationToken,
+    CloudZip, CloudZipError, ConflictPolicy, DownloadOptions, EntryIndex, EntrySelector,
+    ExtractLimits, FileMetadata, IndexCheck, ManifestEntry, Metrics, MetricsRecorder, NameEncoding,
+    PathLayout, RangeReader, Result, RetryPolicy, S3Destination, TransferEstimate,
+    DEFAULT_CONCURRENCY,
+};
+use futures::stream::{self, StreamExt};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+#[cfg(feature = "otel")]
+use opentelemetry::trace::TracerProvider as _;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, LazyLock, Mutex, OnceLock, PoisonError};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, error, info, warn, Level};
+#[cfg(feature = "otel")]
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+#[derive(Parser)]
+#[command(
+    name = "cloud_zip",
+    version,
+    about = "Partial extraction of zip archives stored locally, in S3, Azure or behind HTTP"
+)]
+struct Cli {
+    #[command(flatten)]
+    log: LogArgs,
+    #[command(flatten)]
+    backends: BackendArgs,
+    /// Print the requests, bytes and retries of the command to stderr once it ends, with the
+    /// slowest entries
+    #[arg(long, global = true)]
+    stats: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Args)]
+struct LogArgs {
+    /// Log more: -v for debug details, -vv to trace every request
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Only log errors and draw no progress bar
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// How log lines are written to stderr
+    #[arg(long, value_enum, env = "CLOUD_ZIP_LOG_FORMAT", global = true, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// OpenTelemetry collector the spans of indexing, downloads, decompression and writes are
+    /// exported to, e.g. http://localhost:4317
+    #[cfg(feature = "otel")]
+    #[arg(
+        long,
+        value_name = "URL",
+        env = "OTEL_EXPORTER_OTLP_ENDPOINT",
+        global = true
+    )]
+    otlp_endpoint: Option<String>,
+    /// How spans are sent to the collector
+    #[cfg(feature = "otel")]
+    #[arg(long, value_enum, env = "OTEL_EXPORTER_OTLP_PROTOCOL", global = true, default_value_t = OtlpProtocol::Grpc)]
+    otlp_protocol: OtlpProtocol,
+}
+
+impl LogArgs {
+    /// Installs the global subscriber; `RUST_LOG` overrides the levels picked by the flags.
+    ///
+    /// Spans are only exported when a collector is given; they are recorded whatever the
+    /// log level, which only decides what is written to stderr.
+    fn init(&self) -> Result<LogGuard, CloudZipError> {
+        let level = match (self.quiet, self.verbose) {
+            (true, _) => "error",
+            (false, 0) => "info",
+            (false, 1) => "debug",
+            (false, _) => "trace",
+        };
+        let others = if self.quiet { "error" } else { "warn" };
+        let filter = EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| EnvFilter::new(format!("{},cloud_zip={}", others, level)));
+        let fmt = tracing_subscriber::fmt::layer().with_writer(|| LogLine(Vec::new()));
+        let fmt = match self.log_format {
+            LogFormat::Json => fmt.json().boxed(),
+            // People at a terminal know when things happen; log files need the time.
+            LogFormat::Text if std::io::stderr().is_terminal() => {
+                fmt.with_target(false).without_time().boxed()
+            }
+            LogFormat::Text => fmt.with_target(false).with_ansi(false).boxed(),
+        };
+        let registry = tracing_subscriber::registry().with(fmt.with_filter(filter));
+        #[cfg(feature = "otel")]
+        if let Some(endpoint) = &self.otlp_endpoint {
+            let provider = otlp_provider(endpoint, self.otlp_protocol)?;
+            let layer = tracing_opentelemetry::layer()
+                .with_tracer(provider.tracer("cloud_zip"))
+                .with_filter(Targets::new().with_target("cloud_zip", Level::DEBUG));
+            registry.with(layer).init();
+            return Ok(LogGuard {
+                traces: Some(provider),
+            });
+        }
+        registry.init();
+        Ok(LogGuard::default())
+    }
+}
+
+/// Keeps what [`LogArgs::init`] set up running until dropped.
+#[derive(Default)]
+struct LogGuard {
+    #[cfg(feature = "otel")]
+    traces: Option<opentelemetry_sdk::trace::TracerProvider>,
+}
+
+#[cfg(feature = "otel")]
+impl Drop for LogGuard {
+    /// Sends the spans still buffered.
+    fn drop(&mut self) {
+        if let Some(provider) = self.traces.take() {
+            if let Err(err) = provider.shutdown() {
+                eprintln!("Could not export the last spans: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+#[derive(Clone, Copy, ValueEnum)]
+enum OtlpProtocol {
+    Grpc,
+    #[value(name = "http/protobuf")]
+    HttpProtobuf,
+}
+
+/// Exports spans in batches to the collector at `endpoint`, as the service named by
+/// `OTEL_SERVICE_NAME`, or `cloud_zip`.
+#[cfg(feature = "otel")]
+fn otlp_provider(
+    endpoint: &str,
+    protocol: OtlpProtocol,
+) -> Result<opentelemetry_sdk::trace::TracerProvider, CloudZipError> {
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+
+    let exporter = match protocol {
+        OtlpProtocol::Grpc => SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build(),
+        // Like OTEL_EXPORTER_OTLP_ENDPOINT, the endpoint is the base URL of the collector.
+        OtlpProtocol::HttpProtobuf => SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+            .build(),
+    }
+    .map_err(|err| CloudZipError::InvalidRequest(format!("OTLP exporter: {}", err)))?;
+    let service = std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "cloud_zip".to_string());
+    Ok(opentelemetry_sdk::trace::TracerProvider::builder()
+        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+        .with_resource(opentelemetry_sdk::Resource::new([
+            opentelemetry::KeyValue::new("service.name", service),
+        ]))
+        .build())
+}
+
+/// Local headers `index check` reads by default, enough to catch offsets that are off
+/// throughout an index for a few dozen small requests.
+const DEFAULT_HEADER_CHECKS: usize = 32;
+
+/// Cancelled on Ctrl-C or `kill`, which stops the downloads and decompressions of archives
+/// configured by [`ReadArgs`].
+static CANCELLATION: LazyLock<CancellationToken> = LazyLock::new(CancellationToken::new);
+
+/// Set by `--stats`, and then shared by every backend and archive of the command.
+static METRICS: OnceLock<Arc<MetricsRecorder>> = OnceLock::new();
+
+/// Entries `--stats` lists, slowest first.
+const STATS_SLOWEST: usize = 10;
+
+/// How long an interrupted command has to stop by itself before it is dropped.
+const CANCEL_GRACE: Duration = Duration::from_secs(5);
+
+/// The progress bar currently drawn, which log lines have to clear out of the way.
+static PROGRESS_BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// One formatted log event, written to stderr in one go when dropped.
+struct LogLine(Vec<u8>);
+
+impl Write for LogLine {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LogLine {
+    fn drop(&mut self) {
+        let write = || {
+            let _ = std::io::stderr().write_all(&self.0);
+        };
+        let bar = PROGRESS_BAR
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        match bar {
+            Some(bar) => bar.suspend(write),
+            None => write(),
+        }
+    }
+}
+
+#[derive(Args)]
+struct BackendArgs {
+    #[command(flatten)]
+    s3: S3Args,
+    #[command(flatten)]
+    retry: RetryArgs,
+    #[command(flatten)]
+    cache: CacheArgs,
+    /// Cap on the bytes per second downloaded from remote archives, across all concurrent
+    /// requests, e.g. `50MB/s`
+    #[arg(long, value_name = "RATE", value_parser = parse_bandwidth, env = "CLOUD_ZIP_MAX_BANDWIDTH", global = true)]
+    max_bandwidth: Option<u64>,
+    #[cfg(feature = "azure")]
+    #[command(flatten)]
+    azure: AzureArgs,
+}
+
+impl BackendArgs {
+    fn options(&self) -> Result<BackendOptions> {
+        Ok(BackendOptions {
+            s3: self.s3.config()?,
+            retry: self.retry.policy(),
+            cache: self.cache.config(),
+            metrics: METRICS.get().cloned(),
+            max_bandwidth: self
+                .max_bandwidth
+                .map(|rate| Arc::new(BandwidthLimit::new(rate))),
+            #[cfg(feature = "azure")]
+            azure: self.azure.config()?,
+        })
+    }
+}
+
+#[derive(Args)]
+struct RetryArgs {
+    /// Attempts per remote request before giving up on timeouts, dropped connections and
+    /// 5xx responses; 1 disables retries
+    #[arg(long, env = "CLOUD_ZIP_MAX_ATTEMPTS", global = true, default_value_t = RetryPolicy::default().max_attempts)]
+    max_attempts: u32,
+    /// Backoff before the first retry in milliseconds, doubled with every further attempt
+    #[arg(long, value_name = "MS", global = true, default_value_t = RetryPolicy::default().initial_backoff.as_millis() as u64)]
+    retry_backoff: u64,
+}
+
+impl RetryArgs {
+    fn policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts.max(1),
+            initial_backoff: Duration::from_millis(self.retry_backoff),
+            ..RetryPolicy::default()
+        }
+    }
+}
+
+#[derive(Args)]
+struct CacheArgs {
+    /// Keep the ranges read from remote archives in this directory, so that later runs
+    /// against the same archive version read them locally
+    #[arg(long, value_name = "DIR", env = "CLOUD_ZIP_CACHE_DIR", global = true)]
+    cache_dir: Option<PathBuf>,
+    /// Size the cache directory is trimmed back to, least recently used ranges first
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, global = true, default_value = "1G")]
+    cache_size: u64,
+    /// Also keep up to this many bytes of ranges in memory while the command runs
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, global = true, default_value = "0")]
+    memory_cache: u64,
+}
+
+impl CacheArgs {
+    fn config(&self) -> Option<CacheConfig> {
+        if self.cache_dir.is_none() && self.memory_cache == 0 {
+            return None;
+        }
+        Some(CacheConfig {
+            memory_bytes: self.memory_cache,
+            disk_dir: self.cache_dir.clone(),
+            disk_bytes: self.cache_size,
+            ..CacheConfig::default()
+        })
+    }
+}
+
+#[derive(Args)]
+struct S3Args {
+    /// Custom S3 endpoint, e.g. http://127.0.0.1:9000 for MinIO
+    #[arg(long, env = "AWS_ENDPOINT_URL", global = true)]
+    endpoint_url: Option<String>,
+    /// AWS region of the bucket
+    #[arg(long, env = "AWS_REGION", global = true)]
+    region: Option<String>,
+    /// Use path-style bucket addressing (required by MinIO and most S3-compatible stores)
+    #[arg(long, env = "CLOUD_ZIP_FORCE_PATH_STYLE", global = true)]
+    force_path_style: bool,
+    /// Read public buckets anonymously, without credentials or request signing
+    #[arg(long, env = "CLOUD_ZIP_NO_SIGN_REQUEST", global = true)]
+    no_sign_request: bool,
+    /// Pay for the requests to a requester-pays bucket
+    #[arg(long, value_enum, global = true)]
+    request_payer: Option<RequestPayerArg>,
+    /// Base64 SSE-C key the archive was encrypted with; sent with every request
+    #[arg(long, env = "CLOUD_ZIP_SSE_C_KEY", global = true, hide_env_values = true, conflicts_with_all = ["sse_kms", "sse_kms_key_id"])]
+    sse_c_key: Option<String>,
+    /// Refuse to read archives that are not encrypted with SSE-KMS
+    #[arg(long, global = true)]
+    sse_kms: bool,
+    /// Refuse to read archives not encrypted with this KMS key (ID or ARN); implies --sse-kms
+    #[arg(long, global = true)]
+    sse_kms_key_id: Option<String>,
+    /// Named profile from ~/.aws/config and ~/.aws/credentials
+    #[arg(long, env = "AWS_PROFILE", global = true)]
+    profile: Option<String>,
+    /// Assume this IAM role, e.g. one in the account that owns the bucket
+    #[arg(long, global = true, conflicts_with = "no_sign_request")]
+    role_arn: Option<String>,
+    /// External ID demanded by the trust policy of --role-arn
+    #[arg(long, global = true, requires = "role_arn")]
+    external_id: Option<String>,
+    /// Session name recorded in CloudTrail for the assumed role
+    #[arg(long, global = true, requires = "role_arn")]
+    role_session_name: Option<String>,
+    /// Exchange this OIDC token file for credentials of --role-arn (IRSA and similar)
+    #[arg(
+        long,
+        global = true,
+        requires = "role_arn",
+        conflicts_with = "external_id"
+    )]
+    web_identity_token_file: Option<PathBuf>,
+    /// Give up on connecting to S3 after this many seconds
+    #[arg(long, value_name = "SECS", value_parser = parse_seconds, global = true)]
+    s3_connect_timeout: Option<Duration>,
+    /// Give up on an S3 request whose response has not started after this many seconds; 0
+    /// waits indefinitely
+    #[arg(long, value_name = "SECS", value_parser = parse_seconds, global = true, default_value = "30")]
+    s3_read_timeout: Duration,
+    /// Requests in flight to S3 at once, each holding a connection until its body is read
+    #[arg(long, value_name = "N", global = true)]
+    s3_max_connections: Option<usize>,
+    /// HTTP versions to speak to S3
+    #[arg(long, value_enum, global = true, default_value_t = HttpVersionArg::Auto)]
+    s3_http_version: HttpVersionArg,
+}
+
+impl S3Args {
+    fn config(&self) -> Result<S3Config> {
+        let encryption = match (&self.sse_c_key, &self.sse_kms_key_id) {
+            (Some(key), _) => S3Encryption::CustomerKey(CustomerKey::from_base64(key)?),
+            (None, Some(key_id)) => S3Encryption::Kms {
+                key_id: Some(key_id.clone()),
+            },
+            (None, None) if self.sse_kms => S3Encryption::Kms { key_id: None },
+            (None, None) => S3Encryption::Default,
+        };
+        Ok(S3Config {
+            endpoint_url: self.endpoint_url.clone(),
+            region: self.region.clone(),
+            force_path_style: self.force_path_style,
+            no_sign_request: self.no_sign_request,
+            requester_pays: self.request_payer.is_some(),
+            encryption,
+            profile: self.profile.clone(),
+            role_arn: self.role_arn.clone(),
+            external_id: self.external_id.clone(),
+            role_session_name: self.role_session_name.clone(),
+            web_identity_token_file: self.web_identity_token_file.clone(),
+            connect_timeout: self.s3_connect_timeout,
+            read_timeout: Some(self.s3_read_timeout).filter(|timeout| !timeout.is_zero()),
+            max_connections: self.s3_max_connections,
+            http_version: self.s3_http_version.into(),
+        })
+    }
+}
+
+#[cfg(feature = "azure")]
+#[derive(Args)]
+struct AzureArgs {
+    /// Azure storage account name
+    #[arg(long, env = "AZURE_STORAGE_ACCOUNT", global = true)]
+    azure_account: Option<String>,
+    /// Azure storage account key (Shared Key auth)
+    #[arg(long, env = "AZURE_STORAGE_KEY", global = true, hide_env_values = true)]
+    azure_key: Option<String>,
+    /// Shared access signature token
+    #[arg(
+        long,
+        env = "AZURE_STORAGE_SAS_TOKEN",
+        global = true,
+        hide_env_values = true
+    )]
+    azure_sas: Option<String>,
+    /// Custom blob endpoint, e.g. http://127.0.0.1:10000/devstoreaccount1 for Azurite
+    #[arg(long, env = "AZURE_STORAGE_ENDPOINT", global = true)]
+    azure_endpoint: Option<String>,
+}
+
+#[cfg(feature = "azure")]
+impl AzureArgs {
+    fn config(&self) -> Result<Option<AzureConfig>> {
+        let Some(account) = self.azure_account.clone() else {
+            return Ok(None);
+        };
+        let credential = match (&self.azure_sas, &self.azure_key) {
+            (Some(sas), _) => AzureCredential::SasToken(sas.clone()),
+            (None, Some(key)) => AzureCredential::AccountKey(key.clone()),
+            (None, None) => AzureCredential::Anonymous,
+        };
+        Ok(Some(AzureConfig {
+            account,
+            credential,
+            endpoint: self.azure_endpoint.clone(),
+        }))
+    }
+}
 
-    let body = resp.body.collect().await?;
-    Ok(body.to_vec())
+#[derive(Subcommand)]
+enum Command {
+    /// Build the central directory index of an archive, or check or repair a stored one
+    Index(IndexCommand),
+    /// Extract an entry, or every entry under a prefix, using a previously built index
+    Extract {
+        #[command(flatten)]
+        archive: ArchiveArgs,
+        /// Name of the entry inside the archive, unless given as `archive!entry`
+        #[arg(conflicts_with = "SelectArgs")]
+        entry: Option<String>,
+        #[command(flatten)]
+        select: SelectArgs,
+        /// Extract the entries listed in this file: a JSON array, `name,destination` CSV rows
+        /// or one name per line; `-` reads the list from stdin
+        #[arg(long, conflicts_with_all = ["entry", "SelectArgs", "bytes"])]
+        manifest: Option<PathBuf>,
+        /// Format of the manifest, instead of guessing it from the file extension
+        #[arg(long, value_enum, requires = "manifest")]
+        manifest_format: Option<ManifestKind>,
+        /// Write a JSON report on every manifest entry to this file; `-` writes to stdout
+        #[arg(long, requires = "manifest")]
+        report: Option<PathBuf>,
+        /// Print how many requests and bytes the extraction would take instead of running it
+        #[arg(long, conflicts_with_all = ["report", "bytes"])]
+        dry_run: bool,
+        /// Directory to write into
+        #[arg(short, long, env = "CLOUD_ZIP_OUTPUT_DIR", default_value = ".")]
+        output_dir: PathBuf,
+        /// Record the entries of a selection or directory as they are finished in this file;
+        /// running the same extraction again with it skips them and continues partial files
+        /// of stored entries. It is removed once the extraction completes
+        #[arg(long, value_name = "FILE", conflicts_with_all = ["manifest", "dest", "bytes", "dry_run"])]
+        resume: Option<PathBuf>,
+        /// Upload the entries below this s3://bucket/prefix/ instead of writing them to disk
+        #[arg(long, value_name = "S3_URI", conflicts_with_all = ["manifest", "output_dir", "bytes"])]
+        dest: Option<S3Destination>,
+        #[command(flatten)]
+        range: RangeArgs,
+        #[command(flatten)]
+        write: WriteArgs,
+        #[command(flatten)]
+        read: ReadArgs,
+    },
+    /// Extract only the entries missing from a local directory or differing from the files
+    /// there, optionally removing files the archive does not have
+    Sync {
+        #[command(flatten)]
+        archive: ArchiveArgs,
+        /// Directory to bring in line with the archive
+        output_dir: PathBuf,
+        #[command(flatten)]
+        select: SelectArgs,
+        /// Remove local files and directories no selected entry is extracted to
+        #[arg(long)]
+        delete: bool,
+        /// Compare the CRC-32 of every local file of the right size, not only of those whose
+        /// modification time differs from the entry's
+        #[arg(long)]
+        checksum: bool,
+        /// Print what would be extracted and removed without changing anything
+        #[arg(long)]
+        dry_run: bool,
+        #[command(flatten)]
+        write: WriteArgs,
+        #[command(flatten)]
+        read: ReadArgs,
+    },
+    /// Create an archive from local files or another archive's entries, storing its index as
+    /// the sidecar
+    Create {
+        /// Archive to write: a local path or s3://bucket/key
+        destination: ArchiveLocation,
+        #[command(flatten)]
+        add: AddArgs,
+        /// Do not store the index next to the new archive
+        #[arg(long, conflicts_with = "IndexArgs")]
+        no_sidecar: bool,
+        #[command(flatten)]
+        encoding: IndexArgs,
+    },
+    /// Add entries to an archive, writing it again with a new central directory and index
+    Append {
+        /// Archive to add to: a local path or s3://bucket/key
+        archive: ArchiveLocation,
+        #[command(flatten)]
+        add: AddArgs,
+        /// Write the result here instead of replacing the archive
+        #[arg(long, value_name = "ARCHIVE_URI")]
+        to: Option<ArchiveLocation>,
+        #[command(flatten)]
+        encoding: IndexArgs,
+    },
+    /// Delete or rename entries, writing only a new central directory and index after the
+    /// archive's data
+    Edit {
+        /// Archive to edit: a local path or s3://bucket/key
+        archive: ArchiveLocation,
+        /// Entry to delete; a name ending with `/` deletes everything below it
+        #[arg(long, value_name = "NAME", required_unless_present = "rename")]
+        delete: Vec<String>,
+        /// Rename an entry, or a directory ending with `/` along with everything below it
+        #[arg(long, value_name = "FROM=TO", value_parser = parse_rename)]
+        rename: Vec<(String, String)>,
+        /// Write the result here instead of replacing the archive
+        #[arg(long, value_name = "ARCHIVE_URI")]
+        to: Option<ArchiveLocation>,
+        #[command(flatten)]
+        encoding: IndexArgs,
+    },
+    /// Index every *.zip under S3 prefixes into a catalog, reading only the archives that
+    /// changed since the last run
+    Catalog {
+        /// Prefixes to index, as s3://bucket/prefix/
+        #[arg(required = true)]
+        prefixes: Vec<S3Destination>,
+        /// Catalog file to update: a local path or s3://bucket/key
+        #[arg(long, env = "CLOUD_ZIP_CATALOG")]
+        catalog: ArchiveLocation,
+        /// Number of archives indexed in parallel
+        #[arg(short = 'j', long, env = "CLOUD_ZIP_CONCURRENCY", default_value_t = DEFAULT_CONCURRENCY)]
+        concurrency: usize,
+        /// Encoding of the catalog
+        #[arg(long, value_enum, default_value_t = Format::Cbor)]
+        format: Format,
+    },
+    /// Find the archives of a catalog holding files matching a glob, and optionally extract them
+    Find {
+        /// Glob matched against file names, or against whole paths when it contains `/`
+        pattern: String,
+        /// Catalog file written by `catalog`
+        #[arg(long, env = "CLOUD_ZIP_CATALOG")]
+        catalog: ArchiveLocation,
+        /// Print a JSON array instead of a table
+        #[arg(long, conflicts_with = "extract")]
+        json: bool,
+        /// Extract the matching files instead of listing them
+        #[arg(short = 'x', long)]
+        extract: bool,
+        /// Directory the files are extracted into
+        #[arg(short, long, env = "CLOUD_ZIP_OUTPUT_DIR", default_value = ".")]
+        output_dir: PathBuf,
+        #[command(flatten)]
+        write: WriteArgs,
+        #[command(flatten)]
+        read: ReadArgs,
+    },
+    /// Write the decompressed contents of an entry to stdout
+    Cat {
+        #[command(flatten)]
+        archive: ArchiveArgs,
+        /// Name of the entry inside the archive, unless given as `archive!entry`
+        entry: Option<String>,
+        #[command(flatten)]
+        range: RangeArgs,
+        #[command(flatten)]
+        read: ReadArgs,
+    },
+    /// Print the first lines, or bytes, of an entry, downloading and decompressing no more of
+    /// it than they take
+    Head {
+        #[command(flatten)]
+        archive: ArchiveArgs,
+        /// Name of the entry inside the archive, unless given as `archive!entry`
+        entry: Option<String>,
+        #[command(flatten)]
+        count: PreviewArgs,
+        #[command(flatten)]
+        read: ReadArgs,
+    },
+    /// Print the last lines, or bytes, of an entry; of a stored entry, only the end is
+    /// downloaded
+    Tail {
+        #[command(flatten)]
+        archive: ArchiveArgs,
+        /// Name of the entry inside the archive, unless given as `archive!entry`
+        entry: Option<String>,
+        #[command(flatten)]
+        count: PreviewArgs,
+        #[command(flatten)]
+        read: ReadArgs,
+    },
+    /// Print the schema and first rows of a CSV, JSON Lines or Parquet entry; of a Parquet
+    /// entry, only the footer and the pages holding those rows are read
+    Preview {
+        #[command(flatten)]
+        archive: ArchiveArgs,
+        /// Name of the entry inside the archive, unless given as `archive!entry`
+        entry: Option<String>,
+        /// Number of rows to print
+        #[arg(short = 'n', long, default_value_t = 10)]
+        rows: usize,
+        /// Format of the entry, when its extension does not tell
+        #[arg(long, value_enum)]
+        format: Option<DataFormat>,
+        /// Print the preview as JSON
+        #[arg(long)]
+        json: bool,
+        #[command(flatten)]
+        read: ReadArgs,
+    },
+    /// Print the lines of entries matching a regular expression, prefixed with the entry
+    /// name, searching several entries at once as they are decompressed
+    Grep {
+        /// Regular expression searched for in each line
+        pattern: String,
+        #[command(flatten)]
+        archive: ArchiveArgs,
+        /// Only search entries matching this glob; without it, the `archive!entry` entry or
+        /// every entry is searched
+        glob: Option<String>,
+        /// Match regardless of case
+        #[arg(short = 'i', long)]
+        ignore_case: bool,
+        /// Prefix each line with its line number
+        #[arg(short = 'n', long)]
+        line_number: bool,
+        /// Lines to print after each match
+        #[arg(short = 'A', long, value_name = "NUM")]
+        after_context: Option<usize>,
+        /// Lines to print before each match
+        #[arg(short = 'B', long, value_name = "NUM")]
+        before_context: Option<usize>,
+        /// Lines to print before and after each match
+        #[arg(short = 'C', long, value_name = "NUM")]
+        context: Option<usize>,
+        /// Only print the names of entries with a match
+        #[arg(short = 'l', long)]
+        files_with_matches: bool,
+        /// Only print the number of matching lines of each entry with a match
+        #[arg(short = 'c', long, conflicts_with = "files_with_matches")]
+        count: bool,
+        /// Number of entries downloaded and searched in parallel
+        #[arg(short = 'j', long, env = "CLOUD_ZIP_CONCURRENCY", default_value_t = DEFAULT_CONCURRENCY)]
+        concurrency: usize,
+        #[command(flatten)]
+        read: ReadArgs,
+    },
+    /// Print the SHA-256 (or MD5) of the decompressed content of entries, in the format of
+    /// sha256sum (or md5sum), without writing them to disk
+    Hash {
+        #[command(flatten)]
+        archive: ArchiveArgs,
+        /// Only hash entries matching this glob; without it, the `archive!entry` entry or
+        /// every entry is hashed
+        glob: Option<String>,
+        /// Take SHA-256 hashes, the default
+        #[arg(long)]
+        sha256: bool,
+        /// Take MD5 hashes instead
+        #[arg(long, conflicts_with = "sha256")]
+        md5: bool,
+        /// Number of entries downloaded and hashed in parallel
+        #[arg(short = 'j', long, env = "CLOUD_ZIP_CONCURRENCY", default_value_t = DEFAULT_CONCURRENCY)]
+        concurrency: usize,
+        #[command(flatten)]
+        read: ReadArgs,
+    },
+    /// Compare the indexes of two archives, or an archive and a local directory: entries
+    /// added, removed, or whose size or CRC-32 changed
+    Diff {
+        /// Archive URI or local directory to compare from
+        old: ArchiveUri,
+        /// Archive URI or local directory to compare to
+        new: ArchiveUri,
+        #[command(flatten)]
+        select: SelectArgs,
+        /// How the names of entries without the UTF-8 flag are decoded when an archive is
+        /// indexed
+        #[arg(long, value_enum, default_value_t = NameCharset::Auto)]
+        name_encoding: NameCharset,
+        /// Print a JSON array of changes instead of one line each
+        #[arg(long)]
+        json: bool,
+    },
+    /// Sum the compressed and uncompressed sizes of the entries below each directory, from the
+    /// index alone
+    Du {
+        #[command(flatten)]
+        archive: ArchiveArgs,
+        #[command(flatten)]
+        select: SelectArgs,
+        /// Levels of directories listed; entries deeper down count towards their ancestors
+        #[arg(short, long, default_value_t = 1)]
+        depth: usize,
+        /// Order directories by uncompressed size, largest first, instead of by name
+        #[arg(long)]
+        by_size: bool,
+        /// Print sizes like 1.5 GiB instead of in bytes
+        #[arg(long)]
+        human: bool,
+        /// Print a JSON array instead of a table
+        #[arg(long, conflicts_with = "human")]
+        json: bool,
+    },
+    /// Report files sharing a CRC-32 and size, and so most likely their content, within or
+    /// across archives, from their indexes alone
+    Duplicates {
+        /// Archive URIs to compare the files of
+        #[arg(required_unless_present = "catalog", conflicts_with = "catalog")]
+        archives: Vec<ArchiveUri>,
+        /// Catalog file written by `catalog`, whose archives are compared instead
+        #[arg(long)]
+        catalog: Option<ArchiveLocation>,
+        /// How the names of entries without the UTF-8 flag are decoded when an archive is
+        /// indexed
+        #[arg(long, value_enum, default_value_t = NameCharset::Auto)]
+        name_encoding: NameCharset,
+        /// Print a JSON array of groups instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the EXIF metadata of JPEG entries, and optionally save their embedded
+    /// thumbnails, reading only the first bytes of each image
+    Exif {
+        #[command(flatten)]
+        archive: ArchiveArgs,
+        /// Only read entries matching this glob; without it, the `archive!entry` entry or
+        /// every .jpg and .jpeg entry is read
+        glob: Option<String>,
+        /// Save the thumbnails below this directory, each under the name of its image
+        #[arg(long, value_name = "DIR")]
+        thumbnails: Option<PathBuf>,
+        /// Print a JSON array instead of a table
+        #[arg(long)]
+        json: bool,
+        #[command(flatten)]
+        write: WriteArgs,
+        #[command(flatten)]
+        read: ReadArgs,
+    },
+    /// Show everything the index records about one entry
+    Stat {
+        #[command(flatten)]
+        archive: ArchiveArgs,
+        /// Name of the entry inside the archive, unless given as `archive!entry`
+        entry: Option<String>,
+    },
+    /// Download and decompress entries without writing them, checking every CRC-32
+    Verify {
+        #[command(flatten)]
+        archive: ArchiveArgs,
+        #[command(flatten)]
+        select: SelectArgs,
+        /// Number of entries downloaded and decompressed in parallel
+        #[arg(short = 'j', long, env = "CLOUD_ZIP_CONCURRENCY", default_value_t = DEFAULT_CONCURRENCY)]
+        concurrency: usize,
+        #[command(flatten)]
+        read: ReadArgs,
+    },
+    /// Mount the archive as a read-only filesystem until interrupted
+    #[cfg(feature = "mount")]
+    Mount {
+        #[command(flatten)]
+        archive: ArchiveArgs,
+        /// Empty directory to mount the archive on
+        mountpoint: PathBuf,
+        /// Let other users access the mount; needs `user_allow_other` in /etc/fuse.conf
+        #[arg(long)]
+        allow_other: bool,
+        #[command(flatten)]
+        read: ReadArgs,
+    },
+    /// Serve archive entries over HTTP until interrupted
+    #[cfg(feature = "serve")]
+    Serve {
+        /// Archives to serve, each as `name=URI`, or as a bare URI served under its file name
+        #[arg(required = true)]
+        archives: Vec<ServedArchive>,
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: std::net::SocketAddr,
+        /// Also answer `GET /metrics` with request, latency, backend and cache metrics for
+        /// Prometheus
+        #[arg(long)]
+        metrics: bool,
+        #[command(flatten)]
+        read: ReadArgs,
+    },
+    /// Serve indexing, listing and extraction over gRPC until interrupted
+    #[cfg(feature = "grpc")]
+    ServeGrpc {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:50051")]
+        listen: std::net::SocketAddr,
+        /// Let clients name archives on the local filesystem
+        #[arg(long)]
+        allow_local_files: bool,
+    },
+    /// Run as an AWS Lambda function, answering S3 events and direct invocations
+    #[cfg(feature = "lambda")]
+    Lambda,
+    /// Process extraction jobs from an SQS queue until interrupted
+    #[cfg(feature = "worker")]
+    Worker {
+        /// URL of the queue the jobs are received from
+        #[arg(long, env = "CLOUD_ZIP_QUEUE_URL")]
+        queue_url: String,
+        /// URL of a queue sent the result of every finished job
+        #[arg(long, env = "CLOUD_ZIP_RESULTS_QUEUE_URL")]
+        results_queue_url: Option<String>,
+        /// Number of jobs processed in parallel
+        #[arg(short = 'j', long, default_value_t = 4)]
+        concurrency: usize,
+        /// Seconds a received job stays hidden from other workers; extended while it runs
+        #[arg(long, value_name = "SECONDS", default_value_t = 300)]
+        visibility_timeout: u64,
+        /// Deliveries of a job failing with transient errors before it is given up on
+        #[arg(long, default_value_t = 5)]
+        max_attempts: u32,
+        /// Address to serve `GET /metrics` on, with job, latency, backend and cache metrics
+        /// for Prometheus
+        #[arg(long, value_name = "ADDRESS")]
+        metrics_listen: Option<std::net::SocketAddr>,
+    },
+    /// Print a presigned URL of an S3 archive, for reading it without AWS credentials
+    Presign {
+        /// S3 archive URI, optionally with ?versionId=
+        archive: ArchiveUri,
+        /// Lifetime of the URL in seconds; S3 allows up to seven days
+        #[arg(long, value_name = "SECONDS", default_value_t = 3600)]
+        expires_in: u64,
+    },
+    /// List the entries recorded in an index
+    List {
+        /// Archive URI; its index is found like `extract` does when --metadata is not given
+        archive: Option<ArchiveUri>,
+        /// Index file to read, without touching the archive
+        #[arg(short, long, required_unless_present = "archive")]
+        metadata: Option<PathBuf>,
+        /// DynamoDB table holding indexes by archive URI, used instead of the sidecar when it
+        /// has one for the archive
+        #[cfg(feature = "dynamodb")]
+        #[arg(long, env = "CLOUD_ZIP_DYNAMODB_TABLE")]
+        dynamodb_table: Option<String>,
+        /// How the names of entries without the UTF-8 flag are decoded when the archive is
+        /// indexed
+        #[arg(long, value_enum, default_value_t = NameCharset::Auto)]
+        name_encoding: NameCharset,
+        /// The archive is the `.zip` part of a split archive, read together with the `.z01`,
+        /// `.z02`, … parts next to it
+        #[arg(long, requires = "archive")]
+        split: bool,
+        #[command(flatten)]
+        select: SelectArgs,
+        /// Print a JSON array instead of a table
+        #[arg(long)]
+        json: bool,
+        /// Order entries by this key instead of their order in the archive
+        #[arg(long, value_enum)]
+        sort: Option<SortKey>,
+        /// Reverse the order
+        #[arg(short, long)]
+        reverse: bool,
+        /// Detect the content type of each entry from its first bytes, which are downloaded
+        /// and, for compressed entries, decoded
+        #[arg(long, requires = "archive")]
+        detect_type: bool,
+    },
 }
 
-async fn get_s3_client() -> Client {
-    let s3_endpoint: Option<String> = Some("http://127.0.0.1:9000".to_string());
-    let region_provider =
-        RegionProviderChain::default_provider().or_else(Region::new("asia-south-1"));
-    let shared_config = aws_config::defaults(BehaviorVersion::v2024_03_28())
-        .region(region_provider)
-        .load()
+impl Command {
+    /// Whether the command shuts down gracefully on Ctrl-C by itself, as servers do.
+    fn handles_signals(&self) -> bool {
+        match self {
+            #[cfg(feature = "mount")]
+            Command::Mount { .. } => true,
+            #[cfg(feature = "serve")]
+            Command::Serve { .. } => true,
+            #[cfg(feature = "grpc")]
+            Command::ServeGrpc { .. } => true,
+            #[cfg(feature = "worker")]
+            Command::Worker { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Entries added to a new or existing archive.
+#[derive(Args)]
+struct AddArgs {
+    /// Files and directories to add, directories with everything below them; entries are
+    /// named from the last component of each path on
+    #[arg(required_unless_present = "from")]
+    inputs: Vec<PathBuf>,
+    /// Also copy the entries of this archive, those picked by --prefix, --glob or --regex
+    /// when given, as they are stored without recompressing them
+    #[arg(long, value_name = "ARCHIVE_URI")]
+    from: Option<ArchiveUri>,
+    #[command(flatten)]
+    select: SelectArgs,
+    /// Deflate level of added files, from 1 to 9; 0 stores them uncompressed
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=9), default_value_t = 6)]
+    level: u32,
+    #[command(flatten)]
+    read: ReadArgs,
+}
+
+impl AddArgs {
+    /// Adds the entries and completes the archive, or discards it if any of them fails.
+    async fn write(&self, writer: ArchiveWriter, backends: &BackendOptions) -> Result<EntryIndex> {
+        let mut writer = writer.with_level(self.level);
+        let filled = async {
+            let source = match &self.from {
+                Some(from) => {
+                    let archive = CloudZip::discover(from.open(backends).await?).await?;
+                    let selector = match (self.select.selector()?, &from.entry) {
+                        (Some(selector), _) => selector,
+                        (None, Some(entry)) => entry_selector(&*archive.entries()?, entry),
+                        (None, None) => EntrySelector::All,
+                    };
+                    let archive = self
+                        .read
+                        .configure(archive, &selector)?
+                        .with_download_options(self.read.download.options());
+                    Some((archive, selector))
+                }
+                None if self.select.selector()?.is_some() => {
+                    return Err(CloudZipError::InvalidPattern(
+                        "entries can only be selected with --from".to_string(),
+                    ))
+                }
+                None => None,
+            };
+            for input in &self.inputs {
+                let name = input
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let added = writer::add_path(&mut writer, input, &name).await?;
+                info!(input = %input.display(), entries = added, "Added");
+            }
+            if let Some((archive, selector)) = &source {
+                let copied = writer.copy_entries(archive, selector).await?;
+                info!(entries = copied, "Copied the entries of the source archive");
+            }
+            Ok(())
+        }
         .await;
+        if let Err(err) = filled {
+            writer.abort().await;
+            return Err(err);
+        }
+        writer.finish().await
+    }
+}
+
+fn parse_rename(value: &str) -> std::result::Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => {
+            Ok((from.to_string(), to.to_string()))
+        }
+        _ => Err(format!("expected FROM=TO, not `{}`", value)),
+    }
+}
+
+/// The index `index` would update: the index file, the sidecar when storing one, or the
+/// DynamoDB table, whichever is found first. Empty when there is none, or it cannot be read.
+#[cfg_attr(not(feature = "dynamodb"), allow(unused_variables))]
+async fn previous_index(
+    args: &ArchiveArgs,
+    archive: &CloudZip,
+    sidecar: bool,
+    backends: &BackendArgs,
+) -> Arc<EntryIndex> {
+    let previous = async {
+        if args.metadata.as_deref().is_some_and(Path::exists) {
+            return archive.entries().map(Some);
+        }
+        if sidecar {
+            if let Some(bytes) = archive.reader().read_sidecar().await? {
+                return Ok(Some(Arc::new(metadata::decode_metadata(&bytes)?)));
+            }
+        }
+        #[cfg(feature = "dynamodb")]
+        if let Some(table) = args.dynamodb_table() {
+            let index = DynamoDbIndex::new(&backends.options()?.s3, table)
+                .await
+                .load(&args.archive.location)
+                .await?;
+            return Ok(index.map(Arc::new));
+        }
+        Ok(None)
+    };
+    match previous.await {
+        Ok(previous) => previous.unwrap_or_default(),
+        Err(err) => {
+            warn!(error = %err, "Could not read the existing index, indexing from scratch");
+            Arc::default()
+        }
+    }
+}
+
+/// `index` builds an index, or with a subcommand checks or repairs a stored one. Clap cannot
+/// derive arguments that only apply without a subcommand, hence the impls by hand.
+enum IndexCommand {
+    Build(IndexBuildArgs),
+    Action(IndexAction),
+}
+
+impl FromArgMatches for IndexCommand {
+    fn from_arg_matches(matches: &ArgMatches) -> std::result::Result<Self, clap::Error> {
+        Self::from_arg_matches_mut(&mut matches.clone())
+    }
+
+    fn from_arg_matches_mut(matches: &mut ArgMatches) -> std::result::Result<Self, clap::Error> {
+        if matches.subcommand_name().is_some() {
+            IndexAction::from_arg_matches_mut(matches).map(IndexCommand::Action)
+        } else {
+            IndexBuildArgs::from_arg_matches_mut(matches).map(IndexCommand::Build)
+        }
+    }
+
+    fn update_from_arg_matches(
+        &mut self,
+        matches: &ArgMatches,
+    ) -> std::result::Result<(), clap::Error> {
+        *self = Self::from_arg_matches(matches)?;
+        Ok(())
+    }
+}
+
+impl Args for IndexCommand {
+    fn augment_args(command: clap::Command) -> clap::Command {
+        IndexAction::augment_subcommands(IndexBuildArgs::augment_args(command))
+            .args_conflicts_with_subcommands(true)
+            .subcommand_negates_reqs(true)
+    }
+
+    fn augment_args_for_update(command: clap::Command) -> clap::Command {
+        Self::augment_args(command)
+    }
+}
+
+#[derive(Args)]
+struct IndexBuildArgs {
+    #[command(flatten)]
+    archive: ArchiveArgs,
+    /// Local copy of an S3 archive to read the central directory from
+    #[arg(long)]
+    local_copy: Option<PathBuf>,
+    /// Index the archive from scratch instead of updating the index already written, which
+    /// keeps its entries when the archive was only appended to
+    #[arg(long)]
+    rebuild: bool,
+    /// Recover what can be of a truncated or damaged archive by walking its local headers,
+    /// for when its central directory is missing
+    #[arg(long, conflicts_with_all = ["local_copy", "rebuild"])]
+    recover: bool,
+    /// Also store the index next to the archive as `<archive>.czidx`, where extraction
+    /// without --metadata picks it up
+    #[cfg_attr(
+        feature = "dynamodb",
+        arg(long, required_unless_present_any = ["metadata", "dynamodb_table"])
+    )]
+    #[cfg_attr(
+        not(feature = "dynamodb"),
+        arg(long, required_unless_present = "metadata")
+    )]
+    sidecar: bool,
+    #[command(flatten)]
+    encoding: IndexArgs,
+}
+
+#[derive(Subcommand)]
+enum IndexAction {
+    /// Check a stored index against the archive as it is now, reading only its central
+    /// directory and a few local headers
+    Check {
+        #[command(flatten)]
+        archive: ArchiveArgs,
+        /// Number of entries, spread over the archive, whose local header is read to confirm
+        /// where their data starts
+        #[arg(long, default_value_t = DEFAULT_HEADER_CHECKS)]
+        headers: usize,
+    },
+    /// Check a stored index and rebuild it where it is stored when it is stale, wrong or
+    /// unreadable
+    Repair {
+        #[command(flatten)]
+        archive: ArchiveArgs,
+        /// Number of entries, spread over the archive, whose local header is read to confirm
+        /// where their data starts
+        #[arg(long, default_value_t = DEFAULT_HEADER_CHECKS)]
+        headers: usize,
+        #[command(flatten)]
+        encoding: IndexArgs,
+    },
+}
+
+/// Logs what checking an index found.
+fn report_index_check(check: &IndexCheck) {
+    if let Some(change) = &check.changed {
+        error!("The archive changed since it was indexed: {}", change);
+    }
+    for problem in &check.problems {
+        error!(entry = problem.file_name, "{}", problem.reason);
+    }
+    if check.is_sound() {
+        info!(
+            entries = check.entries,
+            headers = check.headers_checked,
+            "The index matches the archive"
+        );
+    }
+}
+
+/// Saves the index `archive` holds to its index file, and stores it as the sidecar and in
+/// the DynamoDB table when asked to.
+#[cfg_attr(not(feature = "dynamodb"), allow(unused_variables))]
+async fn store_index(
+    args: &ArchiveArgs,
+    archive: &CloudZip,
+    sidecar: bool,
+    backends: &BackendArgs,
+) -> Result<()> {
+    let entries = archive.entries()?.len();
+    if let Some(path) = archive.metadata_path() {
+        info!(
+            entries,
+            path = %path.display(),
+            "Saved the central directory index"
+        );
+    }
+    if sidecar {
+        archive.upload_sidecar().await?;
+        info!(
+            entries,
+            archive = %args.archive.location,
+            "Stored the index next to the archive"
+        );
+    }
+    #[cfg(feature = "dynamodb")]
+    if let Some(table) = args.dynamodb_table() {
+        DynamoDbIndex::new(&backends.options()?.s3, table)
+            .await
+            .store(&args.archive.location, &*archive.entries()?)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Stores `index` as the sidecar of the archive at `location`.
+async fn store_sidecar(
+    location: &ArchiveLocation,
+    index: &EntryIndex,
+    encoding: &IndexArgs,
+    backends: &BackendOptions,
+) -> Result<()> {
+    location
+        .open(backends)
+        .await?
+        .write_sidecar(encoding.encode(index)?)
+        .await?;
+    info!(archive = %location, "Stored the index next to the archive");
+    Ok(())
+}
+
+#[derive(Args)]
+#[group(multiple = false)]
+struct SelectArgs {
+    /// Select every entry whose name starts with this prefix, e.g. `data/r/`
+    #[arg(long)]
+    prefix: Option<String>,
+    /// Select entries matching a glob, e.g. '**/*.JPG'
+    #[arg(long)]
+    glob: Option<String>,
+    /// Select entries whose name matches a regular expression
+    #[arg(long)]
+    regex: Option<String>,
+}
+
+impl SelectArgs {
+    fn selector(&self) -> Result<Option<EntrySelector>> {
+        if let Some(prefix) = &self.prefix {
+            return Ok(Some(EntrySelector::Prefix(prefix.clone())));
+        }
+        if let Some(glob) = &self.glob {
+            return EntrySelector::glob(glob).map(Some);
+        }
+        if let Some(regex) = &self.regex {
+            return EntrySelector::regex(regex).map(Some);
+        }
+        Ok(None)
+    }
+}
+
+#[derive(Args)]
+struct RangeArgs {
+    /// Only read bytes START to END of the entry's content, END included, or from START to
+    /// its end with `START-`: stored entries are read with one ranged read, others decoded
+    /// up to END
+    #[arg(long, value_name = "START-END", value_parser = parse_byte_range)]
+    bytes: Option<Range<u64>>,
+    /// Checkpoints in the content of a deflated entry, from which --bytes decodes instead of
+    /// from the start: read from this file when they are those of the entry as it is now,
+    /// and otherwise found by decoding the entry once and written to it; ignored for other
+    /// entries
+    #[arg(long, value_name = "FILE", requires = "bytes")]
+    checkpoints: Option<PathBuf>,
+}
+
+impl RangeArgs {
+    /// Loads or finds the checkpoints of `entry` when --checkpoints asks for them and it is
+    /// deflated; other entries have none.
+    async fn prepare(&self, archive: &CloudZip, entry: &str) -> Result<()> {
+        let Some(path) = &self.checkpoints else {
+            return Ok(());
+        };
+        if !archive.entries()?.find(entry)?.is_checkpointable() {
+            return Ok(());
+        }
+        if path.exists() {
+            let index = GzipIndex::decode(&std::fs::read(path)?)?;
+            if archive.use_entry_checkpoints(entry, index).await? {
+                return Ok(());
+            }
+            warn!(checkpoints = %path.display(), "The checkpoints are not those of the entry as it is now");
+        }
+        let index = archive.entry_checkpoints(entry).await?;
+        std::fs::write(path, index.encode())?;
+        info!(
+            entry,
+            checkpoints = index.checkpoints(),
+            path = %path.display(),
+            "Stored the checkpoints of the entry"
+        );
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+struct PreviewArgs {
+    /// Number of lines to print
+    #[arg(short = 'n', long, default_value_t = 10)]
+    lines: usize,
+    /// Print this many bytes instead of lines, e.g. 4K
+    #[arg(short = 'c', long, value_parser = parse_size, conflicts_with = "lines")]
+    bytes: Option<u64>,
+}
+
+/// First size of the end of a stored entry read to find its last lines, quadrupled until it
+/// holds them.
+const TAIL_WINDOW: u64 = 64 * 1024;
+
+/// Copies the first `lines` lines of `reader` to `out`, reading no further than their end.
+async fn write_head_lines(
+    reader: impl AsyncRead + Unpin,
+    lines: usize,
+    out: &mut (impl AsyncWrite + Unpin),
+) -> std::io::Result<()> {
+    let mut reader = tokio::io::BufReader::new(reader);
+    let mut left = lines;
+    while left > 0 {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            break;
+        }
+        let mut end = buf.len();
+        for (i, _) in buf.iter().enumerate().filter(|(_, &byte)| byte == b'\n') {
+            left -= 1;
+            if left == 0 {
+                end = i + 1;
+                break;
+            }
+        }
+        out.write_all(&buf[..end]).await?;
+        reader.consume(end);
+    }
+    Ok(())
+}
+
+/// Where the last `lines` lines of `bytes` start, a newline at its very end not counting as
+/// the start of another line; `None` when `bytes` holds fewer lines.
+fn last_lines_start(bytes: &[u8], lines: usize) -> Option<usize> {
+    if lines == 0 {
+        return Some(bytes.len());
+    }
+    let body = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+    body.iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, &byte)| byte == b'\n')
+        .nth(lines - 1)
+        .map(|(newline, _)| newline + 1)
+}
+
+/// The last `lines` lines of an entry: read from windows at the end of a stored entry, and
+/// otherwise decoded from the start, keeping only the last lines.
+async fn tail_lines(archive: &CloudZip, entry: &str, lines: usize) -> Result<Vec<u8>> {
+    let metadata = archive.entries()?.find(entry)?.clone();
+    let size = metadata.uncompressed_size;
+    if metadata.is_random_access() {
+        let mut window = TAIL_WINDOW;
+        loop {
+            let start = size.saturating_sub(window);
+            let mut bytes = Vec::new();
+            archive
+                .open_entry_range(entry, start..size)
+                .await?
+                .read_to_end(&mut bytes)
+                .await?;
+            match last_lines_start(&bytes, lines) {
+                Some(from) => return Ok(bytes.split_off(from)),
+                None if start == 0 => return Ok(bytes),
+                None => window = window.saturating_mul(4),
+            }
+        }
+    }
+    let mut reader = archive.open_entry(entry).await?;
+    let mut kept = Vec::new();
+    let mut chunk = vec![0u8; TAIL_WINDOW as usize];
+    // Trimming once what is kept has doubled scans every byte a bounded number of times.
+    let mut trim_at = TAIL_WINDOW as usize;
+    loop {
+        let read = reader.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        kept.extend_from_slice(&chunk[..read]);
+        if kept.len() >= trim_at {
+            if let Some(from) = last_lines_start(&kept, lines) {
+                kept.drain(..from);
+            }
+            trim_at = (kept.len() * 2).max(TAIL_WINDOW as usize);
+        }
+    }
+    if let Some(from) = last_lines_start(&kept, lines) {
+        kept.drain(..from);
+    }
+    Ok(kept)
+}
 
-    let s3_config = if let Some(s3_endpoint) = s3_endpoint {
-        aws_sdk_s3::config::Builder::from(&shared_config)
-            .endpoint_url(s3_endpoint)
-            .force_path_style(true)
-            .build()
+/// A line of sha256sum output: the hex digest, two spaces and the name, with a leading
+/// backslash and escaped backslashes and newlines when the name has either.
+fn checksum_line(digest: &[u8], name: &str) -> String {
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    if name.contains(['\\', '\n']) {
+        let name = name.replace('\\', "\\\\").replace('\n', "\\n");
+        format!("\\{}  {}", hex, name)
     } else {
-        aws_sdk_s3::config::Builder::from(&shared_config).build()
-    };
-    aws_sdk_s3::Client::from_conf(s3_config)
-}
-
-fn save_central_directory_with_offsets(zip_path: &str, metadata_path: &str) -> io::Result<()> {
-    let file = File::open(zip_path)?;
-    let mut archive = ZipArchive::new(file)?;
-
-    let file_metadata_list: Vec<FileMetadata> = (0..archive.len())
-        .filter_map(|i| {
-            let file = archive.by_index(i).ok()?;
-            let file_name = file.name().to_string();
-            let uncompressed_size = file.size();
-            let compressed_size = file.compressed_size();
-            let is_directory = file.is_dir();
-            let file_offset = file.data_start();
-            
-            Some(FileMetadata {
-                file_name,
-                uncompressed_size,
-                compressed_size,
-                is_directory,
-                file_offset,
-            })
+        format!("{}  {}", hex, name)
+    }
+}
+
+/// How `grep` prints what it found.
+struct GrepFormat {
+    line_number: bool,
+    files_with_matches: bool,
+    count: bool,
+    /// Whether `--` is printed between groups of lines that are not contiguous.
+    separators: bool,
+}
+
+impl GrepFormat {
+    /// Writes the matches of an entry as grep does with several files: `entry:line` for a
+    /// matching line and `entry-line` for a context line.
+    fn write(
+        &self,
+        out: &mut impl Write,
+        entry: &str,
+        matches: &GrepMatches,
+        first: bool,
+    ) -> std::io::Result<()> {
+        if self.files_with_matches {
+            return writeln!(out, "{}", entry);
+        }
+        if self.count {
+            return writeln!(out, "{}:{}", entry, matches.count);
+        }
+        if matches.binary {
+            return writeln!(out, "Binary entry {} matches", entry);
+        }
+        for (i, line) in matches.lines.iter().enumerate() {
+            if self.separators && ((i == 0 && !first) || line.after_gap) {
+                writeln!(out, "--")?;
+            }
+            let mark = if line.is_match { ':' } else { '-' };
+            write!(out, "{}{}", entry, mark)?;
+            if self.line_number {
+                write!(out, "{}{}", line.number, mark)?;
+            }
+            out.write_all(&line.text)?;
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses `START-END`, END included, or `START-`, into the range of bytes it covers.
+fn parse_byte_range(value: &str) -> std::result::Result<Range<u64>, String> {
+    let invalid = || format!("expected START-END or START-, not `{}`", value);
+    let (start, end) = value.split_once('-').ok_or_else(invalid)?;
+    let start: u64 = start.trim().parse().map_err(|_| invalid())?;
+    let end = match end.trim() {
+        "" => u64::MAX,
+        end => end
+            .parse::<u64>()
+            .ok()
+            .and_then(|end| end.checked_add(1))
+            .filter(|&end| end > start)
+            .ok_or_else(invalid)?,
+    };
+    Ok(start..end)
+}
+
+/// How written indexes are encoded.
+#[derive(Args)]
+struct IndexArgs {
+    /// Encoding of the written index; all of them are understood when reading
+    #[arg(long, value_enum, default_value_t = Format::Cbor)]
+    format: Format,
+    /// Compress the written index with zstd, shard by shard for a sharded one
+    #[cfg(feature = "zstd")]
+    #[arg(long)]
+    compress: bool,
+}
+
+impl IndexArgs {
+    fn configure(&self, archive: CloudZip) -> CloudZip {
+        let archive = archive.with_index_format(self.format.into());
+        #[cfg(feature = "zstd")]
+        let archive = archive.with_index_compression(self.compress);
+        archive
+    }
+
+    fn encode(&self, index: &EntryIndex) -> Result<Vec<u8>> {
+        #[cfg(feature = "zstd")]
+        if self.compress {
+            return metadata::encode_compressed_metadata(index, self.format.into());
+        }
+        metadata::encode_metadata(index, self.format.into())
+    }
+}
+
+/// How extracted entries are written to disk.
+#[derive(Args)]
+struct WriteArgs {
+    #[command(flatten)]
+    layout: LayoutArgs,
+    /// What to do when an extracted file already exists
+    #[arg(long, value_enum, default_value_t = OnConflict::Fail)]
+    on_conflict: OnConflict,
+    /// Leave modification times at the time of extraction
+    #[arg(long)]
+    no_times: bool,
+    /// Leave file permissions at the umask default instead of the archived Unix mode
+    #[arg(long)]
+    no_permissions: bool,
+    /// Recreate symlink entries as symlinks; links leaving the output directory are refused
+    #[arg(long)]
+    symlinks: bool,
+    /// Extract files with the CRC-32 and size of one extracted before them as hard links to
+    /// it, once their content is checked to be the same; applies to --prefix, --glob and
+    /// --regex selections
+    #[arg(long)]
+    link_duplicates: bool,
+    /// Rewrite names Windows cannot create, e.g. `CON` or `a:b`, as is always done on Windows
+    #[arg(long)]
+    windows_names: bool,
+    /// Number of entries downloaded and decompressed in parallel
+    #[arg(short = 'j', long, env = "CLOUD_ZIP_CONCURRENCY", default_value_t = DEFAULT_CONCURRENCY)]
+    concurrency: usize,
+}
+
+impl WriteArgs {
+    fn configure(&self, archive: CloudZip) -> CloudZip {
+        archive
+            .with_layout(self.layout.layout())
+            .with_conflict_policy(self.on_conflict.into())
+            .with_preserve_times(!self.no_times)
+            .with_preserve_permissions(!self.no_permissions)
+            .with_symlinks(self.symlinks)
+            .with_link_duplicates(self.link_duplicates)
+            .with_windows_names(cfg!(windows) || self.windows_names)
+            .with_concurrency(self.concurrency)
+    }
+}
+
+/// How entries are fetched and decoded.
+#[derive(Args)]
+struct ReadArgs {
+    #[command(flatten)]
+    download: DownloadArgs,
+    #[command(flatten)]
+    limits: LimitArgs,
+    /// Password for encrypted entries; asked for on the terminal when needed and not given
+    #[arg(long, env = "CLOUD_ZIP_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+    /// Check each entry's local header against the index before decompressing it
+    #[arg(long)]
+    check_headers: bool,
+    /// Refuse archives with duplicate entry names or entries whose data overlaps
+    #[arg(long)]
+    strict: bool,
+}
+
+impl ReadArgs {
+    /// Applies the options, prompting for a password if any of `entries` is encrypted.
+    fn configure(&self, archive: CloudZip, entries: &EntrySelector) -> Result<CloudZip> {
+        let archive = archive
+            .with_download_options(self.download.options())
+            .with_limits(self.limits.limits())
+            .with_header_checks(self.check_headers)
+            .with_strict(self.strict)
+            .with_cancellation(CANCELLATION.clone());
+        let archive = match METRICS.get() {
+            Some(metrics) => archive.with_metrics(metrics.clone()),
+            None => archive,
+        };
+
+        let password = match &self.password {
+            Some(password) => Some(password.clone()),
+            None if std::io::stdin().is_terminal() => {
+                let encrypted = archive
+                    .list_matching(entries)?
+                    .into_iter()
+                    .find(|meta| meta.encryption != Encryption::None);
+                match encrypted {
+                    Some(meta) => Some(rpassword::prompt_password(format!(
+                        "Password for {}: ",
+                        meta.file_name
+                    ))?),
+                    None => None,
+                }
+            }
+            None => None,
+        };
+        Ok(match password {
+            Some(password) => archive.with_password(password),
+            None => archive,
         })
+    }
+}
+
+#[derive(Args)]
+struct LayoutArgs {
+    /// Write every file directly into the output directory, dropping the archive's folders
+    #[arg(long, conflicts_with = "strip_components")]
+    flatten: bool,
+    /// Drop this many leading path components from entry names, like `tar`
+    #[arg(long, value_name = "N")]
+    strip_components: Option<usize>,
+}
+
+impl LayoutArgs {
+    fn layout(&self) -> PathLayout {
+        match self.strip_components {
+            _ if self.flatten => PathLayout::Flatten,
+            Some(n) => PathLayout::StripComponents(n),
+            None => PathLayout::Preserve,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SortKey {
+    Name,
+    /// Uncompressed size
+    Size,
+    /// Modification time
+    Mtime,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RequestPayerArg {
+    /// The caller is charged instead of the bucket owner
+    Requester,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum HttpVersionArg {
+    /// HTTP/2 where the endpoint offers it, HTTP/1.1 otherwise
+    Auto,
+    Http1,
+    /// HTTP/2 only, for endpoints known to support it
+    Http2,
+}
+
+impl From<HttpVersionArg> for HttpVersion {
+    fn from(version: HttpVersionArg) -> Self {
+        match version {
+            HttpVersionArg::Auto => HttpVersion::Auto,
+            HttpVersionArg::Http1 => HttpVersion::Http1,
+            HttpVersionArg::Http2 => HttpVersion::Http2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    /// One readable line per event
+    Text,
+    /// One JSON object per event, for log pipelines
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    /// Compact binary
+    Cbor,
+    /// Readable by people and other tools
+    Json,
+    /// Cut into shards by name, of which lookups in an index file read only those they need
+    Sharded,
+}
+
+impl From<Format> for IndexFormat {
+    fn from(value: Format) -> Self {
+        match value {
+            Format::Cbor => IndexFormat::Cbor,
+            Format::Json => IndexFormat::Json,
+            Format::Sharded => IndexFormat::Sharded,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ManifestKind {
+    /// An array of names or `{"name": ..., "destination": ...}` objects
+    Json,
+    /// `name[,destination]` rows
+    Csv,
+    /// One entry name per line
+    Lines,
+}
+
+impl From<ManifestKind> for ManifestFormat {
+    fn from(value: ManifestKind) -> Self {
+        match value {
+            ManifestKind::Json => ManifestFormat::Json,
+            ManifestKind::Csv => ManifestFormat::Csv,
+            ManifestKind::Lines => ManifestFormat::Lines,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OnConflict {
+    /// Stop without writing anything
+    Fail,
+    /// Keep the existing file
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Write to `name (1).ext` instead
+    Rename,
+}
+
+impl From<OnConflict> for ConflictPolicy {
+    fn from(value: OnConflict) -> Self {
+        match value {
+            OnConflict::Fail => ConflictPolicy::Fail,
+            OnConflict::Skip => ConflictPolicy::Skip,
+            OnConflict::Overwrite => ConflictPolicy::Overwrite,
+            OnConflict::Rename => ConflictPolicy::Rename,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum NameCharset {
+    /// UTF-8 when valid, as many tools write it without saying so, and CP437 otherwise
+    Auto,
+    /// IBM code page 437, as the zip format prescribes and Windows tools write
+    Cp437,
+    /// Windows-1252, as some Western European Windows tools write
+    #[value(name = "windows-1252")]
+    Windows1252,
+    /// UTF-8, replacing invalid sequences
+    #[value(name = "utf-8")]
+    Utf8,
+}
+
+impl From<NameCharset> for NameEncoding {
+    fn from(value: NameCharset) -> Self {
+        match value {
+            NameCharset::Auto => NameEncoding::Auto,
+            NameCharset::Cp437 => NameEncoding::Cp437,
+            NameCharset::Windows1252 => NameEncoding::Windows1252,
+            NameCharset::Utf8 => NameEncoding::Utf8,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DataFormat {
+    /// Values separated by commas, tabs, semicolons or pipes, under a header row
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+    Parquet,
+}
+
+impl From<DataFormat> for PreviewFormat {
+    fn from(value: DataFormat) -> Self {
+        match value {
+            DataFormat::Csv => PreviewFormat::Csv,
+            DataFormat::Jsonl => PreviewFormat::Jsonl,
+            DataFormat::Parquet => PreviewFormat::Parquet,
+        }
+    }
+}
+
+#[derive(Args)]
+struct LimitArgs {
+    /// Abort when an entry decompresses to more than this size, e.g. 512M or 2G
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_entry_size: Option<u64>,
+    /// Abort when all selected entries together decompress to more than this size
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_total_size: Option<u64>,
+    /// Abort when an entry expands more than this many times its compressed size
+    #[arg(long, value_name = "RATIO")]
+    max_ratio: Option<u64>,
+}
+
+impl LimitArgs {
+    fn limits(&self) -> ExtractLimits {
+        ExtractLimits {
+            max_entry_size: self.max_entry_size,
+            max_total_size: self.max_total_size,
+            max_ratio: self.max_ratio,
+        }
+    }
+}
+
+/// Parses a byte count with an optional binary K, M, G or T suffix.
+fn parse_size(value: &str) -> std::result::Result<u64, String> {
+    let value = value.trim();
+    let (digits, shift) = match value.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&value[..i], 10),
+        Some((i, 'm' | 'M')) => (&value[..i], 20),
+        Some((i, 'g' | 'G')) => (&value[..i], 30),
+        Some((i, 't' | 'T')) => (&value[..i], 40),
+        _ => (value, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size `{}`", value))
+}
+
+/// Parses a number of seconds such as `30` or `2.5`.
+fn parse_seconds(value: &str) -> std::result::Result<Duration, String> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| format!("invalid number of seconds `{}`", value.trim()))
+}
+
+/// Parses a rate such as `50MB/s`, `50M` or `512KiB/s` with [`parse_size`], so `MB` and
+/// `MiB` both mean 2^20 bytes.
+fn parse_bandwidth(value: &str) -> std::result::Result<u64, String> {
+    let trimmed = value.trim();
+    let size = trimmed.strip_suffix("/s").unwrap_or(trimmed);
+    let size = size
+        .strip_suffix("iB")
+        .or_else(|| size.strip_suffix('B'))
+        .unwrap_or(size);
+    match parse_size(size) {
+        Ok(0) | Err(_) => Err(format!("invalid bandwidth `{}`", value.trim())),
+        Ok(rate) => Ok(rate),
+    }
+}
+
+#[derive(Args)]
+struct DownloadArgs {
+    /// Fetch entries larger than this many MiB as several concurrent ranged reads
+    #[arg(long, value_name = "MIB", default_value_t = 8)]
+    part_size: u64,
+    /// Number of parts of a single entry downloaded in parallel
+    #[arg(long, default_value_t = 4)]
+    part_concurrency: usize,
+    /// Fetch neighbouring entries at most this far apart in the archive with one request; 0
+    /// fetches every entry on its own
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "64K")]
+    coalesce_gap: u64,
+}
+
+impl DownloadArgs {
+    fn options(&self) -> DownloadOptions {
+        DownloadOptions {
+            part_size: self.part_size.max(1) * 1024 * 1024,
+            concurrency: self.part_concurrency,
+            coalesce_gap: self.coalesce_gap,
+        }
+    }
+}
+
+#[derive(Args)]
+struct ArchiveArgs {
+    /// Archive URI: a local path, file://, s3://bucket/key[?versionId=...], http(s):// or
+    /// az://container/blob, optionally followed by `!entry/name`
+    archive: ArchiveUri,
+    /// Index file to write or read; without it, the `.czidx` sidecar next to the archive is
+    /// used, or else the central directory is read from the archive itself. A path ending
+    /// with `.sqlite` is written as a SQLite index, of which only the needed entries are read
+    #[arg(short, long)]
+    metadata: Option<PathBuf>,
+    /// DynamoDB table holding indexes by archive URI, used instead of the sidecar when it has
+    /// one for the archive; `index` stores the index there
+    #[cfg(feature = "dynamodb")]
+    #[arg(long, env = "CLOUD_ZIP_DYNAMODB_TABLE")]
+    dynamodb_table: Option<String>,
+    /// How the names of entries without the UTF-8 flag are decoded when the archive is
+    /// indexed
+    #[arg(long, value_enum, default_value_t = NameCharset::Auto)]
+    name_encoding: NameCharset,
+    /// The archive is the `.zip` part of a split archive, read together with the `.z01`,
+    /// `.z02`, … parts next to it
+    #[arg(long)]
+    split: bool,
+}
+
+impl ArchiveArgs {
+    async fn reader(&self, backends: &BackendArgs) -> Result<Arc<dyn RangeReader>> {
+        let options = backends.options()?;
+        let reader = if self.split {
+            self.archive.location.open_split(&options).await?
+        } else {
+            self.archive.location.open(&options).await?
+        };
+        self.archive.open_nested(reader).await
+    }
+
+    /// The DynamoDB table of the archive's index; nested archives have none, as the table
+    /// keeps indexes by the location they share with the archive holding them.
+    #[cfg(feature = "dynamodb")]
+    fn dynamodb_table(&self) -> Option<&String> {
+        self.dynamodb_table
+            .as_ref()
+            .filter(|_| self.archive.nested.is_empty())
+    }
+
+    async fn open(&self, backends: &BackendArgs) -> Result<CloudZip> {
+        self.open_selected(backends, &EntrySelector::All).await
+    }
+
+    /// Opens the archive like [`ArchiveArgs::open`], reading only the entries picked by
+    /// `selector` when the index is sharded, in SQLite or in DynamoDB.
+    async fn open_selected(
+        &self,
+        backends: &BackendArgs,
+        selector: &EntrySelector,
+    ) -> Result<CloudZip> {
+        let names = self.name_encoding.into();
+        Ok(self
+            .open_index(backends, selector, names)
+            .await?
+            .with_name_encoding(names))
+    }
+
+    async fn open_index(
+        &self,
+        backends: &BackendArgs,
+        selector: &EntrySelector,
+        names: NameEncoding,
+    ) -> Result<CloudZip> {
+        let reader = self.reader(backends).await?;
+        if let Some(path) = &self.metadata {
+            if metadata::is_sharded_index(path)? {
+                let index = ShardedIndex::open(Arc::new(LocalBackend::open(path)?)).await?;
+                return Ok(CloudZip::from_entry_index(
+                    reader,
+                    index.select(selector).await?,
+                ));
+            }
+            #[cfg(feature = "sqlite")]
+            if metadata::is_sqlite_index(path)? {
+                let entries = SqliteIndex::open(path)?.select(selector)?;
+                return Ok(CloudZip::from_entry_index(reader, entries));
+            }
+            return Ok(CloudZip::with_reader(reader, path));
+        }
+        #[cfg(feature = "dynamodb")]
+        if let Some(table) = self.dynamodb_table() {
+            let index = DynamoDbIndex::new(&backends.options()?.s3, table).await;
+            match index.select(&self.archive.location, selector).await? {
+                Some(entries) => return Ok(CloudZip::from_entry_index(reader, entries)),
+                None => info!(table, "The archive has no index in DynamoDB"),
+            }
+        }
+        CloudZip::discover_with_names(reader, names).await
+    }
+
+    /// Opens the archive with the index stored for it, failing instead of reading the central
+    /// directory when there is none.
+    async fn open_stored(&self, backends: &BackendArgs) -> Result<CloudZip> {
+        if self.metadata.is_some() {
+            return self.open(backends).await;
+        }
+        let reader = self.reader(backends).await?;
+        #[cfg(feature = "dynamodb")]
+        if let Some(table) = self.dynamodb_table() {
+            let index = DynamoDbIndex::new(&backends.options()?.s3, table).await;
+            return match index.load(&self.archive.location).await? {
+                Some(entries) => Ok(CloudZip::from_entry_index(reader, entries)
+                    .with_name_encoding(self.name_encoding.into())),
+                None => Err(CloudZipError::InvalidRequest(format!(
+                    "{} has no index in {}",
+                    self.archive.location, table
+                ))),
+            };
+        }
+        match reader.read_sidecar().await? {
+            Some(sidecar) => {
+                let entries = metadata::decode_metadata(&sidecar)?;
+                Ok(CloudZip::from_entry_index(reader, entries)
+                    .with_name_encoding(self.name_encoding.into()))
+            }
+            None => Err(CloudZipError::InvalidRequest(format!(
+                "{} has no index sidecar",
+                self.archive.location
+            ))),
+        }
+    }
+
+    /// Whether the index of the archive is its sidecar, rather than an index file or DynamoDB.
+    fn stored_in_sidecar(&self) -> bool {
+        #[cfg(feature = "dynamodb")]
+        if self.dynamodb_table().is_some() {
+            return false;
+        }
+        self.metadata.is_none()
+    }
+}
+
+/// Reads an index file; from a sharded or SQLite index, only the entries picked by `selector`.
+async fn read_index(path: &Path, selector: &EntrySelector) -> Result<EntryIndex> {
+    if metadata::is_sharded_index(path)? {
+        return ShardedIndex::open(Arc::new(LocalBackend::open(path)?))
+            .await?
+            .select(selector)
+            .await;
+    }
+    #[cfg(feature = "sqlite")]
+    if metadata::is_sqlite_index(path)? {
+        return SqliteIndex::open(path)?.select(selector);
+    }
+    metadata::read_metadata(path)
+}
+
+/// Names the config file to read instead of `cloud_zip/config.toml` in the config directory.
+const CONFIG_ENV: &str = "CLOUD_ZIP_CONFIG";
+
+/// The config file, and whether it was named explicitly and so has to exist.
+fn config_path() -> Option<(PathBuf, bool)> {
+    if let Some(path) = std::env::var_os(CONFIG_ENV).filter(|path| !path.is_empty()) {
+        return Some((PathBuf::from(path), true));
+    }
+    let dir = match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some((dir.join("cloud_zip").join("config.toml"), false))
+}
+
+/// Turns the settings of the config file into defaults of the options of `command`, which
+/// environment variables and flags still override.
+fn load_config(command: clap::Command) -> std::result::Result<clap::Command, String> {
+    let Some((path, required)) = config_path() else {
+        return Ok(command);
+    };
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound && !required => return Ok(command),
+        Err(err) => return Err(format!("{}: {}", path.display(), err)),
+    };
+    let settings: toml::Table = text
+        .parse()
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    apply_config(command, &settings).map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+/// Each key names a long option, with `_` or `-` between words, and sets its default in
+/// every command that has it; a table named after a command sets defaults for that command
+/// alone.
+fn apply_config(
+    mut command: clap::Command,
+    settings: &toml::Table,
+) -> std::result::Result<clap::Command, String> {
+    for (key, value) in settings {
+        if let toml::Value::Table(settings) = value {
+            if command.find_subcommand(key).is_none() {
+                return Err(format!("there is no `{}` command", key));
+            }
+            let mut failed = None;
+            command = command.mut_subcommand(key, |subcommand| {
+                apply_config(subcommand.clone(), settings).unwrap_or_else(|err| {
+                    failed = Some(err);
+                    subcommand
+                })
+            });
+            if let Some(err) = failed {
+                return Err(err);
+            }
+            continue;
+        }
+        let values =
+            config_values(value).ok_or_else(|| format!("`{}` cannot be set to {}", key, value))?;
+        let mut found = false;
+        command = set_default(command, &key.replace('-', "_"), &values, &mut found);
+        if !found {
+            return Err(format!("there is no --{} option", key.replace('_', "-")));
+        }
+    }
+    Ok(command)
+}
+
+fn config_values(value: &toml::Value) -> Option<Vec<String>> {
+    match value {
+        toml::Value::String(value) => Some(vec![value.clone()]),
+        toml::Value::Integer(value) => Some(vec![value.to_string()]),
+        toml::Value::Float(value) => Some(vec![value.to_string()]),
+        toml::Value::Boolean(value) => Some(vec![value.to_string()]),
+        toml::Value::Array(values) => values
+            .iter()
+            .map(|value| config_values(value)?.pop())
+            .collect(),
+        toml::Value::Datetime(_) | toml::Value::Table(_) => None,
+    }
+}
+
+/// Prefix of the ids of the `--no-<flag>` options that turn off flags the config file turns on.
+const TURN_OFF: &str = "turn_off:";
+
+/// Sets the default of the long option `id` in `command` and all its subcommands. A flag
+/// turned on also gets a `--no-<flag>` option turning it off again.
+fn set_default(
+    command: clap::Command,
+    id: &str,
+    values: &[String],
+    found: &mut bool,
+) -> clap::Command {
+    change_option(command, id, found, &|command: clap::Command| {
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == id)
+            .expect("the option is there");
+        let long = format!("no-{}", arg.get_long().unwrap_or(id));
+        let turn_off = (values == ["true"]
+            && matches!(arg.get_action(), ArgAction::SetTrue)
+            && !command
+                .get_arguments()
+                .any(|arg| arg.get_long() == Some(long.as_str())))
+        .then(|| {
+            clap::Arg::new(format!("{}{}", TURN_OFF, id))
+                .help(format!(
+                    "Turn off --{}, which the config file turns on",
+                    &long[3..]
+                ))
+                .long(long)
+                .action(ArgAction::SetTrue)
+                .global(arg.is_global_set())
+        });
+        let command = command.mut_arg(id, |arg| arg.default_values(values.to_vec()));
+        match turn_off {
+            Some(turn_off) => command.arg(turn_off),
+            None => command,
+        }
+    })
+}
+
+/// Turns the flag `id` back off in every command, whatever the config file and its
+/// environment variable say.
+fn turn_off(command: clap::Command, id: &str) -> clap::Command {
+    change_option(command, id, &mut false, &|command: clap::Command| {
+        command.mut_arg(id, |arg| arg.default_value("false").env(None))
+    })
+}
+
+/// Applies `change` to `command` and each of its subcommands having the long option `id`.
+fn change_option(
+    mut command: clap::Command,
+    id: &str,
+    found: &mut bool,
+    change: &impl Fn(clap::Command) -> clap::Command,
+) -> clap::Command {
+    if command
+        .get_arguments()
+        .any(|arg| arg.get_id() == id && arg.get_long().is_some())
+    {
+        *found = true;
+        command = change(command);
+    }
+    let subcommands: Vec<String> = command
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_string())
         .collect();
+    for name in subcommands {
+        command = command.mut_subcommand(name, |subcommand| {
+            change_option(subcommand, id, found, change)
+        });
+    }
+    command
+}
+
+/// Parses `args` with `command`, parsing them again when `--no-<flag>` turns off flags the
+/// config file turns on, with those flags off.
+fn parse_args(
+    command: clap::Command,
+    args: impl IntoIterator<Item = impl Into<std::ffi::OsString>>,
+) -> std::result::Result<ArgMatches, clap::Error> {
+    let args: Vec<std::ffi::OsString> = args.into_iter().map(Into::into).collect();
+    let matches = command.clone().try_get_matches_from(&args)?;
+    let mut turned_off = Vec::new();
+    let mut level = Some(&matches);
+    while let Some(matches) = level {
+        for id in matches.ids() {
+            if let Some(flag) = id.as_str().strip_prefix(TURN_OFF) {
+                if matches.get_flag(id.as_str()) && !turned_off.contains(&flag) {
+                    turned_off.push(flag);
+                }
+            }
+        }
+        level = matches.subcommand().map(|(_, matches)| matches);
+    }
+    if turned_off.is_empty() {
+        return Ok(matches);
+    }
+    turned_off
+        .into_iter()
+        .fold(command, turn_off)
+        .try_get_matches_from(args)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let command = match load_config(Cli::command()) {
+        Ok(command) => command,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            return ExitCode::from(2);
+        }
+    };
+    let cli = parse_args(command, std::env::args_os())
+        .and_then(|matches| Cli::from_arg_matches(&matches))
+        .unwrap_or_else(|err| err.exit());
+    let _log = match cli.log.init() {
+        Ok(log) => log,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    if cli.stats {
+        let _ = METRICS.set(Arc::new(MetricsRecorder::new()));
+    }
+
+    let result = if cli.command.handles_signals() {
+        run(cli).await
+    } else {
+        run_interruptible(cli).await
+    };
+    if let Some(metrics) = METRICS.get() {
+        let _ = print_stats(&metrics.metrics());
+    }
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(CloudZipError::Cancelled) => {
+            warn!("Interrupted");
+            ExitCode::from(130)
+        }
+        Err(err) => {
+            error!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Runs the command until Ctrl-C or `kill`, then cancels [`CANCELLATION`] so that entries being
+/// read stop and clean up, and gives the command [`CANCEL_GRACE`] or until another signal to
+/// return before dropping it.
+async fn run_interruptible(cli: Cli) -> Result<()> {
+    let run = run(cli);
+    tokio::pin!(run);
+    tokio::select! {
+        result = &mut run => return result,
+        signal = shutdown_signal() => signal?,
+    }
+    info!("Stopping; press Ctrl-C again to stop at once");
+    CANCELLATION.cancel();
+    tokio::select! {
+        result = &mut run => result.and(Err(CloudZipError::Cancelled)),
+        _ = tokio::time::sleep(CANCEL_GRACE) => Err(CloudZipError::Cancelled),
+        _ = shutdown_signal() => Err(CloudZipError::Cancelled),
+    }
+}
+
+/// Progress bar on stderr for extractions and verifications, shown only on a terminal and
+/// when info messages are logged.
+struct ProgressDisplay(Option<ProgressBar>);
+
+impl ProgressDisplay {
+    fn new() -> Self {
+        if !std::io::stderr().is_terminal() || !tracing::enabled!(Level::INFO) {
+            return ProgressDisplay(None);
+        }
+        let style = ProgressStyle::with_template(
+            "{spinner} [{elapsed_precise}] {wide_bar} {bytes}/{total_bytes} ({bytes_per_sec}, {eta}) {msg}",
+        )
+        .expect("the progress template is valid");
+        let bar = ProgressBar::new(0).with_style(style);
+        *PROGRESS_BAR.lock().unwrap_or_else(PoisonError::into_inner) = Some(bar.clone());
+        ProgressDisplay(Some(bar))
+    }
+
+    /// Makes the archive move the bar, which counts decompressed bytes.
+    fn attach(&self, archive: CloudZip) -> CloudZip {
+        let Some(bar) = self.0.clone() else {
+            return archive;
+        };
+        archive.with_progress(move |progress| {
+            bar.set_length(progress.write_total);
+            bar.set_position(progress.bytes_written);
+            bar.set_message(format!(
+                "{}/{} entries, {} downloaded",
+                progress.entries_done,
+                progress.entries_total,
+                HumanBytes(progress.bytes_downloaded)
+            ));
+        })
+    }
+
+    fn finish(&self) {
+        if let Some(bar) = &self.0 {
+            bar.finish_and_clear();
+            *PROGRESS_BAR.lock().unwrap_or_else(PoisonError::into_inner) = None;
+        }
+    }
+}
+
+/// One element of the `extract --report` array.
+#[derive(Serialize)]
+struct ReportedEntry<'a> {
+    name: &'a str,
+    status: &'static str,
+    destination: Option<String>,
+    size: Option<u64>,
+    error: Option<String>,
+}
 
-    let metadata_file = OpenOptions::new().create(true).write(true).open(metadata_path)?;
-    serde_cbor::to_writer(metadata_file, &file_metadata_list).unwrap();
+/// One element of `list --json`.
+#[derive(Serialize)]
+struct ListedEntry<'a> {
+    name: &'a str,
+    compressed_size: u64,
+    uncompressed_size: u64,
+    method: &'static str,
+    modified: Option<String>,
+    is_directory: bool,
+    crc32: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<&'static str>,
+}
 
-    println!("Central Directory with offsets saved to {}", metadata_path);
+/// The content types of `entries`, detected a few at a time; `None` for those whose first
+/// bytes could not be read.
+async fn detect_types(archive: &CloudZip, entries: &[&FileMetadata]) -> Vec<Option<&'static str>> {
+    stream::iter(entries)
+        .map(|meta| async move {
+            if meta.is_directory {
+                return Some("inode/directory");
+            }
+            if meta.uncompressed_size == 0 {
+                return Some("inode/x-empty");
+            }
+            match archive.detect_type(&meta.file_name).await {
+                Ok(detected) => Some(detected.unwrap_or("application/octet-stream")),
+                Err(err) => {
+                    warn!(entry = meta.file_name, error = %err, "Could not detect the content type");
+                    None
+                }
+            }
+        })
+        .buffered(DEFAULT_CONCURRENCY)
+        .collect()
+        .await
+}
+
+/// Prints `entries`, with the content type of each when `types` holds them.
+fn print_listing(
+    entries: &[&FileMetadata],
+    types: Option<&[Option<&'static str>]>,
+    json: bool,
+) -> std::io::Result<()> {
+    let mut out = std::io::stdout().lock();
+    let type_of = |i: usize| types.and_then(|types| types[i]);
+    if json {
+        let listed: Vec<_> = entries
+            .iter()
+            .enumerate()
+            .map(|(i, meta)| ListedEntry {
+                name: &meta.file_name,
+                compressed_size: meta.compressed_size,
+                uncompressed_size: meta.uncompressed_size,
+                method: compression::method_name(meta.compression_method),
+                modified: meta.modified.map(format_time),
+                is_directory: meta.is_directory,
+                crc32: meta.crc32,
+                content_type: type_of(i),
+            })
+            .collect();
+        serde_json::to_writer(&mut out, &listed)?;
+        return writeln!(out);
+    }
+    for (i, meta) in entries.iter().enumerate() {
+        write!(
+            out,
+            "{:>12} {:>12} {:<8} {:<20} ",
+            meta.compressed_size,
+            meta.uncompressed_size,
+            compression::method_name(meta.compression_method),
+            meta.modified.map(format_time).unwrap_or_default(),
+        )?;
+        if types.is_some() {
+            write!(out, "{:<30} ", type_of(i).unwrap_or("-"))?;
+        }
+        writeln!(out, "{}", meta.file_name)?;
+    }
     Ok(())
 }
 
-fn extract_file_from_local_zip(zip_path: &str, file_name: &str, metadata_path: &str) -> io::Result<()> {
-    let mut file = File::open(zip_path)?;
+/// The entries of one side of `diff` picked by `selector`: those of a local directory, read
+/// to take their CRC-32, or else those in the index of an archive.
+async fn diff_side(
+    uri: ArchiveUri,
+    name_encoding: NameCharset,
+    backends: &BackendArgs,
+    selector: &EntrySelector,
+) -> Result<Vec<FileMetadata>> {
+    let mut entries = match &uri.location {
+        ArchiveLocation::Local(path) if uri.nested.is_empty() && path.is_dir() => {
+            diff::index_directory(path).await?
+        }
+        _ => ArchiveArgs {
+            archive: uri,
+            metadata: None,
+            #[cfg(feature = "dynamodb")]
+            dynamodb_table: None,
+            name_encoding,
+            split: false,
+        }
+        .open_selected(backends, selector)
+        .await?
+        .entries()?
+        .as_slice()
+        .to_vec(),
+    };
+    entries.retain(|meta| selector.matches(&meta.file_name));
+    Ok(entries)
+}
 
-    let metadata_file = File::open(metadata_path)?;
-    let file_metadata_list: Vec<FileMetadata> = serde_cbor::from_reader(metadata_file).unwrap();
+fn print_changes(changes: &[EntryChange], json: bool) -> std::io::Result<()> {
+    let mut out = std::io::stdout().lock();
+    if json {
+        serde_json::to_writer(&mut out, changes)?;
+        return writeln!(out);
+    }
+    let crc = |crc32: Option<u32>| crc32.map_or("-".to_string(), |crc32| format!("{:08x}", crc32));
+    for change in changes {
+        match change.change {
+            Change::Added => writeln!(
+                out,
+                "+ {} ({} bytes)",
+                change.name,
+                change.new_size.unwrap_or(0)
+            )?,
+            Change::Removed => writeln!(
+                out,
+                "- {} ({} bytes)",
+                change.name,
+                change.old_size.unwrap_or(0)
+            )?,
+            Change::Modified => writeln!(
+                out,
+                "~ {} ({} -> {} bytes, CRC-32 {} -> {})",
+                change.name,
+                change.old_size.unwrap_or(0),
+                change.new_size.unwrap_or(0),
+                crc(change.old_crc32),
+                crc(change.new_crc32)
+            )?,
+        }
+    }
+    Ok(())
+}
 
-    let metadata = file_metadata_list
-        .iter()
-        .find(|&meta| meta.file_name == file_name)
-        .ok_or(io::Error::new(io::ErrorKind::NotFound, "File not found"))?;
+/// What the entries below a directory add up to; one element of `du --json`.
+#[derive(Serialize, Default)]
+struct DirectoryUsage {
+    path: String,
+    entries: u64,
+    compressed: u64,
+    uncompressed: u64,
+}
 
-    println!("Found metadata for file: {:?}", metadata);
+/// Sums the sizes of `entries` for each directory up to `depth` levels down, the directories
+/// in name order, then for all of them under `.`.
+fn directory_usage(entries: &[&FileMetadata], depth: usize) -> Vec<DirectoryUsage> {
+    let mut total = DirectoryUsage {
+        path: ".".to_string(),
+        ..DirectoryUsage::default()
+    };
+    let mut directories: BTreeMap<&str, DirectoryUsage> = BTreeMap::new();
+    let add = |usage: &mut DirectoryUsage, meta: &FileMetadata| {
+        usage.entries += 1;
+        usage.compressed += meta.compressed_size;
+        usage.uncompressed += meta.uncompressed_size;
+    };
+    for meta in entries.iter().filter(|meta| !meta.is_directory) {
+        add(&mut total, meta);
+        let name = meta.file_name.as_str();
+        for (level, (slash, _)) in name.match_indices('/').enumerate() {
+            if level == depth {
+                break;
+            }
+            let path = &name[..=slash];
+            add(
+                directories.entry(path).or_insert_with(|| DirectoryUsage {
+                    path: path.to_string(),
+                    ..DirectoryUsage::default()
+                }),
+                meta,
+            );
+        }
+    }
+    directories.into_values().chain([total]).collect()
+}
+
+fn print_usage(usage: &[DirectoryUsage], human: bool, json: bool) -> std::io::Result<()> {
+    let mut out = std::io::stdout().lock();
+    if json {
+        serde_json::to_writer(&mut out, usage)?;
+        return writeln!(out);
+    }
+    let size = |bytes: u64| {
+        if human {
+            HumanBytes(bytes).to_string()
+        } else {
+            bytes.to_string()
+        }
+    };
+    for usage in usage {
+        let ratio = match usage.uncompressed {
+            0 => 100.0,
+            uncompressed => usage.compressed as f64 * 100.0 / uncompressed as f64,
+        };
+        writeln!(
+            out,
+            "{:>12} {:>12} {:>5.1}% {:>9} {}",
+            size(usage.compressed),
+            size(usage.uncompressed),
+            ratio,
+            usage.entries,
+            usage.path
+        )?;
+    }
+    Ok(())
+}
 
-    let output_file_path = format!("extracted_{}", file_name);
-    let output_dir = std::path::Path::new(&output_file_path).parent().unwrap();
+/// One element of `find --json`.
+#[derive(Serialize)]
+struct FoundEntry<'a> {
+    archive: &'a str,
+    name: &'a str,
+    uncompressed_size: u64,
+    modified: Option<String>,
+}
 
-    if !output_dir.exists() {
-        create_dir_all(output_dir).map_err(|err| {
-            io::Error::new(io::ErrorKind::NotFound, format!("Failed to create output directory: {}", err))
-        })?;
+fn print_found(found: &[CatalogMatch<'_>], json: bool) -> std::io::Result<()> {
+    let mut out = std::io::stdout().lock();
+    if json {
+        let listed: Vec<_> = found
+            .iter()
+            .map(|found| FoundEntry {
+                archive: &found.archive.archive,
+                name: &found.entry.file_name,
+                uncompressed_size: found.entry.uncompressed_size,
+                modified: found.entry.modified.map(format_time),
+            })
+            .collect();
+        serde_json::to_writer(&mut out, &listed)?;
+        return writeln!(out);
+    }
+    for found in found {
+        writeln!(
+            out,
+            "{:>12} {:<20} {}!{}",
+            found.entry.uncompressed_size,
+            found.entry.modified.map(format_time).unwrap_or_default(),
+            found.archive.archive,
+            found.entry.file_name
+        )?;
     }
+    Ok(())
+}
 
-    let mut output_file = File::create(output_file_path).map_err(|err| {
-        io::Error::new(io::ErrorKind::NotFound, format!("Failed to create output file: {}", err))
-    })?;
+/// One element of `duplicates --json`.
+#[derive(Serialize)]
+struct ReportedDuplicates<'a> {
+    crc32: u32,
+    size: u64,
+    copies: Vec<FoundCopy<'a>>,
+}
 
-    file.seek(SeekFrom::Start(metadata.file_offset)).map_err(|err| {
-        io::Error::new(io::ErrorKind::InvalidInput, format!("Failed to seek to file offset: {}", err))
-    })?;
+#[derive(Serialize)]
+struct FoundCopy<'a> {
+    archive: &'a str,
+    name: &'a str,
+}
 
-    let mut compressed_data = file.take(metadata.compressed_size as u64);
-    let mut decoder = DeflateDecoder::new(&mut compressed_data);
+fn print_duplicates(groups: &[DuplicateGroup<'_>], json: bool) -> std::io::Result<()> {
+    let mut out = std::io::stdout().lock();
+    if json {
+        let reported: Vec<_> = groups
+            .iter()
+            .map(|group| ReportedDuplicates {
+                crc32: group.crc32,
+                size: group.size,
+                copies: group
+                    .entries
+                    .iter()
+                    .map(|found| FoundCopy {
+                        archive: &found.archive.archive,
+                        name: &found.entry.file_name,
+                    })
+                    .collect(),
+            })
+            .collect();
+        serde_json::to_writer(&mut out, &reported)?;
+        return writeln!(out);
+    }
+    for group in groups {
+        writeln!(
+            out,
+            "{:08x} {} bytes, {} copies, {} wasted",
+            group.crc32,
+            group.size,
+            group.entries.len(),
+            HumanBytes(group.wasted())
+        )?;
+        for found in &group.entries {
+            writeln!(out, "  {}!{}", found.archive.archive, found.entry.file_name)?;
+        }
+    }
+    Ok(())
+}
 
-    io::copy(&mut decoder, &mut output_file).map_err(|err| {
-        io::Error::new(io::ErrorKind::Other, format!("Failed to extract file: {}", err))
-    })?;
+/// Whether the name has the extension of a JPEG image.
+fn is_jpeg(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    [".jpg", ".jpeg", ".jpe"]
+        .iter()
+        .any(|extension| name.ends_with(extension))
+}
 
+fn print_sync_plan(plan: &SyncPlan, delete: bool) -> std::io::Result<()> {
+    let mut out = std::io::stdout().lock();
+    for name in &plan.missing {
+        writeln!(out, "+ {}", name)?;
+    }
+    for name in &plan.changed {
+        writeln!(out, "~ {}", name)?;
+    }
+    if delete {
+        for path in &plan.extra {
+            writeln!(out, "- {}", path.display())?;
+        }
+    }
     Ok(())
 }
 
-async fn extract_file_from_cloud_zip(client: &Client, zip_path: &str, file_name: &str, metadata_path: &str, bucket_name: &str) -> io::Result<()> {
-    let metadata_file = File::open(metadata_path)?;
-    let file_metadata_list: Vec<FileMetadata> = serde_cbor::from_reader(metadata_file).unwrap();
+/// Widest a value is printed in a preview table.
+const PREVIEW_COLUMN_WIDTH: usize = 32;
 
-    let metadata = file_metadata_list
+fn print_preview(preview: &Preview, json: bool) -> std::io::Result<()> {
+    let mut out = std::io::stdout().lock();
+    if json {
+        serde_json::to_writer(&mut out, preview)?;
+        return writeln!(out);
+    }
+    if let Some(rows) = preview.row_count {
+        match preview.row_groups {
+            Some(groups) => writeln!(out, "{} rows in {} row groups", rows, groups)?,
+            None => writeln!(out, "{} rows", rows)?,
+        }
+    }
+    let names: Vec<String> = preview
+        .columns
+        .iter()
+        .map(|column| format!("{}{}", "  ".repeat(column.depth), column.name))
+        .collect();
+    let name_width = names
+        .iter()
+        .map(|name| name.chars().count())
+        .max()
+        .unwrap_or(0);
+    for (column, name) in preview.columns.iter().zip(&names) {
+        let nullable = if column.nullable { " nullable" } else { "" };
+        writeln!(
+            out,
+            "{:<width$}  {}{}",
+            name,
+            column.data_type,
+            nullable,
+            width = name_width
+        )?;
+    }
+    let Some(rows) = preview.rows.as_ref().filter(|rows| !rows.is_empty()) else {
+        return Ok(());
+    };
+    // Values are cut short, and line breaks in them escaped, to keep to one line per row.
+    let cell = |value: &str| {
+        let value = value.replace('\n', "\\n").replace('\r', "\\r");
+        match value.char_indices().nth(PREVIEW_COLUMN_WIDTH - 1) {
+            Some((end, _)) => format!("{}…", &value[..end]),
+            None => value,
+        }
+    };
+    let header: Vec<String> = preview
+        .columns
         .iter()
-        .find(|&meta| meta.file_name == file_name)
-        .ok_or(io::Error::new(io::ErrorKind::NotFound, "File not found"))?;
+        .map(|column| cell(&column.name))
+        .collect();
+    let rows: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| row.iter().map(|value| cell(value)).collect())
+        .collect();
+    let mut widths: Vec<usize> = header.iter().map(|name| name.chars().count()).collect();
+    for row in &rows {
+        for (i, value) in row.iter().enumerate() {
+            match widths.get_mut(i) {
+                Some(width) => *width = (*width).max(value.chars().count()),
+                None => widths.push(value.chars().count()),
+            }
+        }
+    }
+    writeln!(out)?;
+    for row in std::iter::once(&header).chain(&rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(value, &width)| format!("{:<width$}", value, width = width))
+            .collect();
+        writeln!(out, "{}", line.join("  ").trim_end())?;
+    }
+    Ok(())
+}
 
-    println!("Found metadata for file: {:?}", metadata);
+/// One element of `exif --json`.
+#[derive(Serialize)]
+struct ImageExif<'a> {
+    name: &'a str,
+    #[serde(flatten)]
+    exif: Exif,
+    thumbnail_size: Option<usize>,
+    thumbnail_path: Option<String>,
+}
 
-    let output_file_path = format!("extracted_{}", file_name);
-    let output_dir = std::path::Path::new(&output_file_path).parent().unwrap();
+fn print_exif(images: &[ImageExif<'_>], json: bool) -> std::io::Result<()> {
+    let mut out = std::io::stdout().lock();
+    if json {
+        serde_json::to_writer(&mut out, images)?;
+        return writeln!(out);
+    }
+    for image in images {
+        let exif = &image.exif;
+        let size = match (exif.width, exif.height) {
+            (Some(width), Some(height)) => format!("{}x{}", width, height),
+            _ => "-".to_string(),
+        };
+        // Models often start with the make already.
+        let camera = match (&exif.make, &exif.model) {
+            (Some(make), Some(model)) if !model.starts_with(make.as_str()) => {
+                format!("{} {}", make, model)
+            }
+            (_, Some(camera)) | (Some(camera), None) => camera.clone(),
+            (None, None) => "-".to_string(),
+        };
+        writeln!(
+            out,
+            "{:<19} {:>11} {:>8} {:<24} {}",
+            exif.taken.as_deref().unwrap_or("-"),
+            size,
+            image
+                .thumbnail_size
+                .map_or("-".to_string(), |len| len.to_string()),
+            camera,
+            image.name
+        )?;
+    }
+    Ok(())
+}
+
+fn print_stat(meta: &FileMetadata) {
+    let kind = if meta.is_directory {
+        "directory"
+    } else if meta.is_symlink() {
+        "symlink"
+    } else {
+        "file"
+    };
+    let encryption = match meta.encryption {
+        Encryption::None => "none".to_string(),
+        Encryption::Aes {
+            strength,
+            vendor_version,
+        } => format!(
+            "WinZip AES-{} (AE-{})",
+            64 + 64 * strength as u32,
+            vendor_version
+        ),
+        Encryption::ZipCrypto { .. } => "ZipCrypto".to_string(),
+    };
+    let range = meta.byte_range();
+
+    println!("Name:          {}", meta.file_name);
+    println!("Type:          {}", kind);
+    println!(
+        "Method:        {} ({})",
+        compression::method_name(meta.compression_method),
+        meta.compression_method
+    );
+    println!("Compressed:    {} bytes", meta.compressed_size);
+    println!("Uncompressed:  {} bytes", meta.uncompressed_size);
+    match meta.crc32 {
+        Some(crc32) => println!("CRC-32:        {:08x}", crc32),
+        None => println!("CRC-32:        not recorded"),
+    }
+    match meta.modified {
+        Some(modified) => println!("Modified:      {}", format_time(modified)),
+        None => println!("Modified:      not recorded"),
+    }
+    if let Some(mode) = meta.unix_mode {
+        println!("Mode:          {:o}", mode);
+    }
+    println!("Encryption:    {}", encryption);
+    println!("Data offset:   {}", meta.file_offset);
+    if range.is_empty() {
+        println!("Byte range:    none, the entry has no data");
+    } else {
+        println!("Byte range:    bytes={}-{}", range.start, range.end - 1);
+    }
+}
 
-    if !output_dir.exists() {
-        create_dir_all(output_dir).map_err(|err| {
-            io::Error::new(io::ErrorKind::NotFound, format!("Failed to create output directory: {}", err))
-        })?;
+/// Formats Unix seconds as an RFC 3339 UTC timestamp.
+fn format_time(secs: i64) -> String {
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let days = secs.div_euclid(86_400);
+    let time = secs.rem_euclid(86_400);
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3_600,
+        time % 3_600 / 60,
+        time % 60
+    )
+}
+
+/// Waits for Ctrl-C or, on Unix, a plain `kill`, so that long-running commands can clean up.
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            interrupted = tokio::signal::ctrl_c() => interrupted,
+            _ = terminate.recv() => Ok(()),
+        }
     }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
 
-    let mut output_file = File::create(output_file_path).map_err(|err| {
-        io::Error::new(io::ErrorKind::NotFound, format!("Failed to create output file: {}", err))
-    })?;
+/// An archive given to `serve`, optionally named with `name=URI`.
+#[cfg(feature = "serve")]
+#[derive(Clone)]
+struct ServedArchive {
+    name: Option<String>,
+    uri: ArchiveUri,
+}
 
+#[cfg(feature = "serve")]
+impl std::str::FromStr for ServedArchive {
+    type Err = CloudZipError;
 
-    let byte_range = format!("bytes={}-{}", metadata.file_offset as usize, metadata.file_offset + metadata.compressed_size);
-    let file = download_bytes(client, bucket_name, zip_path, &byte_range).await.unwrap();
+    fn from_str(value: &str) -> Result<Self> {
+        // URIs may hold `=` themselves, e.g. in ?versionId=, but never before a `/` or `:`.
+        match value.split_once('=') {
+            Some((name, uri)) if !name.is_empty() && !name.contains(['/', ':', '?']) => {
+                Ok(ServedArchive {
+                    name: Some(name.to_string()),
+                    uri: uri.parse()?,
+                })
+            }
+            _ => Ok(ServedArchive {
+                name: None,
+                uri: value.parse()?,
+            }),
+        }
+    }
+}
 
-    let mut compressed_data = file.take(metadata.compressed_size as u64);
-    let mut decoder = DeflateDecoder::new(&mut compressed_data);
+#[cfg(feature = "serve")]
+impl ServedArchive {
+    /// The name to serve the archive under, which defaults to its file name.
+    fn name_and_uri(&self) -> (String, &ArchiveUri) {
+        let name = self.name.clone().unwrap_or_else(|| {
+            let location = self.uri.location.to_string();
+            let path = location.split('?').next().unwrap_or_default();
+            path.rsplit(['/', '\\']).next().unwrap_or(path).to_string()
+        });
+        (name, &self.uri)
+    }
+}
+
+/// Reads a manifest from a file, or from stdin for `-`.
+fn load_manifest(path: &Path, format: Option<ManifestFormat>) -> Result<Vec<ManifestEntry>> {
+    if path.as_os_str() == "-" {
+        let text = std::io::read_to_string(std::io::stdin())?;
+        return parse_manifest(&text, format.unwrap_or(ManifestFormat::Lines));
+    }
+    read_manifest(path, format)
+}
 
-    io::copy(&mut decoder, &mut output_file).map_err(|err| {
-        io::Error::new(io::ErrorKind::Other, format!("Failed to extract file: {}", err))
-    })?;
+/// Selects what `extract <entry>` writes: the entry itself, or everything below it when it
+/// is a directory.
+fn entry_selector(entries: &EntryIndex, entry: &str) -> EntrySelector {
+    match entries.find(entry) {
+        Ok(metadata) if !metadata.is_directory => EntrySelector::Name(entry.to_string()),
+        _ => EntrySelector::Prefix(format!("{}/", entry.trim_end_matches('/'))),
+    }
+}
 
+fn print_estimate(estimate: &TransferEstimate) {
+    println!("Entries:       {}", estimate.entries);
+    println!("GET requests:  {}", estimate.requests);
+    println!(
+        "Downloaded:    {} bytes ({})",
+        estimate.bytes_downloaded,
+        HumanBytes(estimate.bytes_downloaded)
+    );
+    println!(
+        "Written:       {} bytes ({})",
+        estimate.bytes_written,
+        HumanBytes(estimate.bytes_written)
+    );
+}
+
+fn print_stats(metrics: &Metrics) -> std::io::Result<()> {
+    let mut out = std::io::stderr().lock();
+    writeln!(
+        out,
+        "Requests:      {} ({} retried, {} failed)",
+        metrics.requests, metrics.retries, metrics.errors
+    )?;
+    if metrics.cache_hits + metrics.cache_misses > 0 {
+        writeln!(
+            out,
+            "Cache:         {} hits, {} misses",
+            metrics.cache_hits, metrics.cache_misses
+        )?;
+    }
+    writeln!(
+        out,
+        "Downloaded:    {} bytes ({})",
+        metrics.bytes_downloaded,
+        HumanBytes(metrics.bytes_downloaded)
+    )?;
+    writeln!(
+        out,
+        "Written:       {} bytes ({})",
+        metrics.bytes_written,
+        HumanBytes(metrics.bytes_written)
+    )?;
+    writeln!(out, "Elapsed:       {:.2}s", metrics.elapsed.as_secs_f64())?;
+    let mut slowest: Vec<_> = metrics.entries.iter().collect();
+    slowest.sort_by_key(|entry| std::cmp::Reverse(entry.elapsed));
+    for entry in slowest.into_iter().take(STATS_SLOWEST) {
+        writeln!(
+            out,
+            "{:>9.2}s {:>5} req {:>5} retries {:>11} {}",
+            entry.elapsed.as_secs_f64(),
+            entry.requests,
+            entry.retries,
+            HumanBytes(entry.bytes_downloaded).to_string(),
+            entry.name
+        )?;
+    }
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> io::Result<()> {
-    let metadata_path = "local_central_directory_with_offsets.cbor";
-    let zip_path = "pc.zip";
-    let file_name = "data/r/r2.bin";
+/// Picks the entry from the positional argument or the `archive!entry` fragment.
+fn entry_name(archive: &ArchiveUri, entry: Option<String>) -> Result<String> {
+    entry.or_else(|| archive.entry.clone()).ok_or_else(|| {
+        CloudZipError::InvalidLocation(format!(
+            "No entry given for {}; pass it as an argument or as archive!entry",
+            archive.location
+        ))
+    })
+}
 
-    save_central_directory_with_offsets(zip_path, metadata_path)?;
-    extract_file_from_local_zip(zip_path, file_name, metadata_path)?;
+/// The entries matching `glob`, or else the entry named by `archive!entry`, or else all.
+fn glob_or_entry(glob: Option<String>, archive: &ArchiveUri) -> Result<EntrySelector> {
+    match (glob, &archive.entry) {
+        (Some(glob), _) => EntrySelector::glob(&glob),
+        (None, Some(entry)) => Ok(EntrySelector::Name(entry.clone())),
+        (None, None) => Ok(EntrySelector::All),
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    match cli.command {
+        Command::Index(IndexCommand::Action(IndexAction::Check { archive, headers })) => {
+            let check = archive
+                .open_stored(&cli.backends)
+                .await?
+                .check_index(headers)
+                .await?;
+            report_index_check(&check);
+            if !check.is_sound() {
+                return Err(CloudZipError::InvalidArchive(format!(
+                    "The index does not match the archive: {} of {} entries are wrong",
+                    check.problems.len(),
+                    check.entries
+                )));
+            }
+        }
+        Command::Index(IndexCommand::Action(IndexAction::Repair {
+            archive: archive_args,
+            headers,
+            encoding,
+        })) => {
+            let check = match archive_args.open_stored(&cli.backends).await {
+                Ok(stored) => stored.check_index(headers).await,
+                Err(err) => Err(err),
+            };
+            match check {
+                Ok(check) => {
+                    report_index_check(&check);
+                    if check.is_sound() {
+                        return Ok(());
+                    }
+                }
+                Err(err) => warn!(error = %err, "Could not read the stored index"),
+            }
+            let reader = archive_args.reader(&cli.backends).await?;
+            let archive = encoding.configure(match &archive_args.metadata {
+                Some(path) => CloudZip::with_reader(reader, path),
+                None => CloudZip::from_index(reader, Vec::new()),
+            });
+            let archive = archive.with_name_encoding(archive_args.name_encoding.into());
+            let list = archive.index().await?;
+            info!(entries = list.len(), "Rebuilt the index");
+            let sidecar = archive_args.stored_in_sidecar();
+            store_index(&archive_args, &archive, sidecar, &cli.backends).await?;
+        }
+        Command::Index(IndexCommand::Build(IndexBuildArgs {
+            archive: archive_args,
+            local_copy,
+            rebuild,
+            recover,
+            sidecar,
+            encoding,
+        })) => {
+            let reader = archive_args.reader(&cli.backends).await?;
+            let archive = match &archive_args.metadata {
+                Some(path) => CloudZip::with_reader(reader, path),
+                None => CloudZip::from_index(reader, Vec::new()),
+            };
+            let archive = encoding
+                .configure(archive)
+                .with_name_encoding(archive_args.name_encoding.into());
+            match local_copy {
+                Some(local_copy) => {
+                    archive.index_from(local_copy).await?;
+                }
+                None if rebuild => {
+                    archive.index().await?;
+                }
+                None if recover => {
+                    let list = archive.recover_index().await?;
+                    info!(
+                        entries = list.len(),
+                        "Recovered entries from the local headers"
+                    );
+                }
+                None => {
+                    let previous =
+                        previous_index(&archive_args, &archive, sidecar, &cli.backends).await;
+                    let update = archive.update_index(&previous).await?;
+                    if !previous.is_empty() {
+                        info!(
+                            entries = update.entries,
+                            reused = update.reused,
+                            "Updated the existing index"
+                        );
+                    }
+                }
+            }
+            store_index(&archive_args, &archive, sidecar, &cli.backends).await?;
+        }
+        Command::Extract {
+            archive,
+            entry,
+            select,
+            manifest,
+            manifest_format,
+            report,
+            dry_run,
+            output_dir,
+            resume,
+            dest,
+            range,
+            write,
+            read,
+        } => {
+            let manifest = manifest
+                .map(|path| load_manifest(&path, manifest_format.map(ManifestFormat::from)))
+                .transpose()?;
+            if dry_run {
+                let uri = archive.archive.clone();
+                let archive = write
+                    .configure(archive.open(&cli.backends).await?)
+                    .with_download_options(read.download.options());
+                let selector = match (&manifest, select.selector()?) {
+                    (Some(manifest), _) => EntrySelector::Names(
+                        manifest.iter().map(|item| item.name.clone()).collect(),
+                    ),
+                    (None, Some(selector)) => selector,
+                    (None, None) => {
+                        let entry = entry_name(&uri, entry)?;
+                        entry_selector(&*archive.entries()?, &entry)
+                    }
+                };
+                print_estimate(&archive.estimate(&selector).await?);
+                return Ok(());
+            }
+
+            if let Some(dest) = dest {
+                let uri = archive.archive.clone();
+                let archive = write.configure(archive.open(&cli.backends).await?);
+                let selector = match select.selector()? {
+                    Some(selector) => selector,
+                    None => {
+                        let entry = entry_name(&uri, entry)?;
+                        entry_selector(&*archive.entries()?, &entry)
+                    }
+                };
+                let archive = read.configure(archive, &selector)?;
+                let client = get_s3_client(&cli.backends.options()?.s3).await;
+                let display = ProgressDisplay::new();
+                let uploaded = display
+                    .attach(archive)
+                    .extract_to_s3(&selector, &client, &dest)
+                    .await;
+                display.finish();
+                let uploaded = uploaded?;
+                info!(
+                    entries = uploaded.entries.len(),
+                    bytes = uploaded.bytes,
+                    destination = %dest,
+                    "Uploaded the extracted entries"
+                );
+                return Ok(());
+            }
 
+            if let Some(manifest) = manifest {
+                let selector =
+                    EntrySelector::Names(manifest.iter().map(|item| item.name.clone()).collect());
+                let archive =
+                    write.configure(archive.open_selected(&cli.backends, &selector).await?);
+                let archive = read.configure(archive, &selector)?;
+                let display = ProgressDisplay::new();
+                let archive = display.attach(archive);
 
-    let metadata_path = "cloud_central_directory_with_offsets.cbor";
-    let target_file_name = "test/RRIF0045_147-2023_F1_140723_062728.JPG";
-    let obj_key = "test.zip";
+                let total = manifest.len();
+                let mut done = 0;
+                let outcomes = archive
+                    .extract_manifest(&manifest, &output_dir, |outcome| {
+                        done += 1;
+                        let entry = &outcome.file_name;
+                        match &outcome.result {
+                            Ok(Some(path)) => {
+                                info!(done, total, entry, path = %path.display(), "Extracted")
+                            }
+                            Ok(None) => info!(
+                                done,
+                                total, entry, "Skipped, the output file already exists"
+                            ),
+                            Err(err) => {
+                                error!(done, total, entry, error = %err, "Extraction failed")
+                            }
+                        }
+                    })
+                    .await;
+                display.finish();
+                let outcomes = outcomes?;
 
-    let client = get_s3_client().await;
+                let entries = archive.entries()?;
+                let reported: Vec<_> = outcomes
+                    .iter()
+                    .map(|outcome| ReportedEntry {
+                        name: &outcome.file_name,
+                        status: match &outcome.result {
+                            Ok(Some(_)) => "extracted",
+                            Ok(None) => "skipped",
+                            Err(_) => "failed",
+                        },
+                        destination: outcome
+                            .result
+                            .as_ref()
+                            .ok()
+                            .and_then(Option::as_ref)
+                            .map(|path| path.display().to_string()),
+                        size: entries
+                            .get(&outcome.file_name)
+                            .map(|meta| meta.uncompressed_size),
+                        error: outcome.result.as_ref().err().map(|err| err.to_string()),
+                    })
+                    .collect();
+                let failed = reported
+                    .iter()
+                    .filter(|entry| entry.status == "failed")
+                    .count();
+                let skipped = reported
+                    .iter()
+                    .filter(|entry| entry.status == "skipped")
+                    .count();
+                if let Some(path) = report {
+                    let mut out: Box<dyn Write> = if path.as_os_str() == "-" {
+                        Box::new(std::io::stdout().lock())
+                    } else {
+                        Box::new(std::fs::File::create(path)?)
+                    };
+                    serde_json::to_writer_pretty(&mut out, &reported)?;
+                    writeln!(out)?;
+                }
+                info!(
+                    extracted = total - failed - skipped,
+                    skipped, failed, total, "Finished the manifest"
+                );
+                if failed > 0 {
+                    return Err(std::io::Error::other(format!(
+                        "{} of {} manifest entries could not be extracted",
+                        failed, total
+                    ))
+                    .into());
+                }
+                return Ok(());
+            }
 
-    save_central_directory_with_offsets("test.zip", metadata_path)?;
-    extract_file_from_cloud_zip(&client, obj_key, target_file_name, metadata_path, "my_bucket").await?;
+            if let Some(selector) = select.selector()? {
+                if range.bytes.is_some() {
+                    return Err(CloudZipError::InvalidRequest(
+                        "--bytes reads part of a single entry, not of a selection".to_string(),
+                    ));
+                }
+                let mut archive =
+                    write.configure(archive.open_selected(&cli.backends, &selector).await?);
+                if let Some(resume) = resume {
+                    archive = archive.with_resume_state(resume);
+                }
+                let archive = read.configure(archive, &selector)?;
+                let display = ProgressDisplay::new();
+                let written = display
+                    .attach(archive)
+                    .extract_matching(&selector, &output_dir)
+                    .await;
+                display.finish();
+                let written = written?;
+                info!(
+                    entries = written.len(),
+                    selector = %selector.describe(),
+                    output_dir = %output_dir.display(),
+                    "Extracted the matching entries"
+                );
+                return Ok(());
+            }
+
+            let entry = entry_name(&archive.archive, entry)?;
+            let selector = EntrySelector::Prefix(entry.clone());
+            let mut archive =
+                write.configure(archive.open_selected(&cli.backends, &selector).await?);
+            if let Some(resume) = resume {
+                archive = archive.with_resume_state(resume);
+            }
+            let archive = read.configure(archive, &selector)?;
+            if let Some(bytes) = range.bytes.clone() {
+                range.prepare(&archive, &entry).await?;
+                match archive.extract_range_to(&entry, bytes, &output_dir).await? {
+                    Some(output_path) => info!(entry, path = %output_path.display(), "Extracted"),
+                    None => info!(entry, "Skipped, the output file already exists"),
+                }
+                return Ok(());
+            }
+            let display = ProgressDisplay::new();
+            let extracted = display
+                .attach(archive)
+                .extract_to(&entry, &output_dir)
+                .await;
+            display.finish();
+            match extracted? {
+                Some(output_path) => info!(entry, path = %output_path.display(), "Extracted"),
+                None => info!(entry, "Skipped, the output file already exists"),
+            }
+        }
+        Command::Create {
+            destination,
+            add,
+            no_sidecar,
+            encoding,
+        } => {
+            let backends = cli.backends.options()?;
+            let writer = ArchiveWriter::create(&destination, &backends).await?;
+            let index = add.write(writer, &backends).await?;
+            info!(
+                entries = index.len(),
+                archive = %destination,
+                "Created the archive"
+            );
+            if !no_sidecar {
+                store_sidecar(&destination, &index, &encoding, &backends).await?;
+            }
+        }
+        Command::Append {
+            archive,
+            add,
+            to,
+            encoding,
+        } => {
+            let backends = cli.backends.options()?;
+            let destination = to.unwrap_or_else(|| archive.clone());
+            let writer = ArchiveWriter::append(&archive, &destination, &backends).await?;
+            let existing = writer.entries().len();
+            let index = add.write(writer, &backends).await?;
+            info!(
+                added = index.len() - existing,
+                entries = index.len(),
+                archive = %destination,
+                "Appended to the archive"
+            );
+            store_sidecar(&destination, &index, &encoding, &backends).await?;
+        }
+        Command::Edit {
+            archive,
+            delete,
+            rename,
+            to,
+            encoding,
+        } => {
+            let backends = cli.backends.options()?;
+            let destination = to.unwrap_or_else(|| archive.clone());
+            let edit = delete
+                .into_iter()
+                .fold(ArchiveEdit::new(), ArchiveEdit::delete);
+            let edit = rename
+                .into_iter()
+                .fold(edit, |edit, (from, to)| edit.rename(from, to));
+            let index = edit.apply(&archive, &destination, &backends).await?;
+            store_sidecar(&destination, &index, &encoding, &backends).await?;
+        }
+        Command::Catalog {
+            prefixes,
+            catalog: location,
+            concurrency,
+            format,
+        } => {
+            let backends = cli.backends.options()?;
+            let mut catalog = Catalog::load(&location, &backends)
+                .await?
+                .unwrap_or_default();
+            for prefix in &prefixes {
+                let update = catalog
+                    .update(&prefix.bucket, &prefix.prefix, &backends, concurrency)
+                    .await?;
+                if update.failed > 0 {
+                    error!(
+                        prefix = %prefix,
+                        failed = update.failed,
+                        "Some archives could not be indexed and are left out of the catalog"
+                    );
+                }
+            }
+            catalog.save(&location, &backends, format.into()).await?;
+            info!(
+                archives = catalog.archives.len(),
+                catalog = %location,
+                "Stored the catalog"
+            );
+        }
+        Command::Find {
+            pattern,
+            catalog: location,
+            json,
+            extract,
+            output_dir,
+            write,
+            read,
+        } => {
+            let backends = cli.backends.options()?;
+            let catalog = Catalog::load(&location, &backends).await?.ok_or_else(|| {
+                CloudZipError::InvalidLocation(format!(
+                    "there is no catalog at {}; write one with `cloud_zip catalog`",
+                    location
+                ))
+            })?;
+            let found = catalog.find(&pattern)?;
+            if !extract {
+                // A closed pipe just means the reader, e.g. `head`, has seen enough.
+                match print_found(&found, json) {
+                    Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => {
+                        return Err(err.into())
+                    }
+                    _ => {}
+                }
+                return Ok(());
+            }
+            if found.is_empty() {
+                return Err(CloudZipError::EntryNotFound(pattern));
+            }
+
+            // Matches come grouped by archive, in catalog order.
+            for matches in found.chunk_by(|a, b| std::ptr::eq(a.archive, b.archive)) {
+                let archive = matches[0].archive;
+                let selector = EntrySelector::Names(
+                    matches
+                        .iter()
+                        .map(|found| found.entry.file_name.clone())
+                        .collect(),
+                );
+                let location: ArchiveLocation = archive.archive.parse()?;
+                let reader = location.open(&backends).await?;
+                let opened = write.configure(CloudZip::from_entry_index(reader, archive.index()));
+                let opened = read.configure(opened, &selector)?;
+                let display = ProgressDisplay::new();
+                let written = display
+                    .attach(opened)
+                    .extract_matching(&selector, &output_dir)
+                    .await;
+                display.finish();
+                info!(
+                    entries = written?.len(),
+                    archive = %location,
+                    output_dir = %output_dir.display(),
+                    "Extracted the matching entries"
+                );
+            }
+        }
+        Command::Cat {
+            archive,
+            entry,
+            range,
+            read,
+        } => {
+            let entry = entry_name(&archive.archive, entry)?;
+            let selector = EntrySelector::Name(entry.clone());
+            let archive = archive.open_selected(&cli.backends, &selector).await?;
+            let archive = read.configure(archive, &selector)?;
+            let mut reader = match range.bytes.clone() {
+                Some(bytes) => {
+                    range.prepare(&archive, &entry).await?;
+                    archive.open_entry_range(&entry, bytes).await?
+                }
+                None => archive.open_entry(&entry).await?,
+            };
+            let mut stdout = tokio::io::stdout();
+            let copied = match tokio::io::copy(&mut reader, &mut stdout).await {
+                Ok(_) => stdout.flush().await,
+                Err(err) => Err(err),
+            };
+            // A closed pipe just means the reader, e.g. `head`, has seen enough.
+            match copied {
+                Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => return Err(err.into()),
+                _ => {}
+            }
+        }
+        Command::Head {
+            archive,
+            entry,
+            count,
+            read,
+        } => {
+            let entry = entry_name(&archive.archive, entry)?;
+            let selector = EntrySelector::Name(entry.clone());
+            let archive = archive.open_selected(&cli.backends, &selector).await?;
+            let archive = read.configure(archive, &selector)?;
+            let mut stdout = tokio::io::stdout();
+            let written = match count.bytes {
+                Some(bytes) => {
+                    let mut reader = archive.open_entry_range(&entry, 0..bytes).await?;
+                    tokio::io::copy(&mut reader, &mut stdout).await.map(drop)
+                }
+                None => {
+                    let reader = archive.open_entry(&entry).await?;
+                    write_head_lines(reader, count.lines, &mut stdout).await
+                }
+            };
+            // A closed pipe just means the reader has seen enough.
+            match written.and(stdout.flush().await) {
+                Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => return Err(err.into()),
+                _ => {}
+            }
+        }
+        Command::Tail {
+            archive,
+            entry,
+            count,
+            read,
+        } => {
+            let entry = entry_name(&archive.archive, entry)?;
+            let selector = EntrySelector::Name(entry.clone());
+            let archive = archive.open_selected(&cli.backends, &selector).await?;
+            let archive = read.configure(archive, &selector)?;
+            let mut stdout = tokio::io::stdout();
+            let written = match count.bytes {
+                Some(bytes) => {
+                    let size = archive.entries()?.find(&entry)?.uncompressed_size;
+                    let mut reader = archive
+                        .open_entry_range(&entry, size.saturating_sub(bytes)..size)
+                        .await?;
+                    tokio::io::copy(&mut reader, &mut stdout).await.map(drop)
+                }
+                None => {
+                    let lines = tail_lines(&archive, &entry, count.lines).await?;
+                    stdout.write_all(&lines).await
+                }
+            };
+            match written.and(stdout.flush().await) {
+                Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => return Err(err.into()),
+                _ => {}
+            }
+        }
+        Command::Sync {
+            archive,
+            output_dir,
+            select,
+            delete,
+            checksum,
+            dry_run,
+            write,
+            read,
+        } => {
+            let selector = select.selector()?.unwrap_or(EntrySelector::All);
+            let archive = write.configure(archive.open_selected(&cli.backends, &selector).await?);
+            let archive = read.configure(archive, &selector)?;
+            let options = SyncOptions { checksum, delete };
+            let plan = if dry_run {
+                archive.plan_sync(&selector, &output_dir, options).await?
+            } else {
+                let display = ProgressDisplay::new();
+                let plan = display
+                    .attach(archive)
+                    .sync_to(&selector, &output_dir, options)
+                    .await;
+                display.finish();
+                plan?
+            };
+            match print_sync_plan(&plan, delete) {
+                Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => return Err(err.into()),
+                _ => {}
+            }
+            info!(
+                extracted = plan.missing.len() + plan.changed.len(),
+                unchanged = plan.unchanged,
+                removed = if delete { plan.extra.len() } else { 0 },
+                dry_run,
+                "Synced the directory"
+            );
+        }
+        Command::Preview {
+            archive,
+            entry,
+            rows,
+            format,
+            json,
+            read,
+        } => {
+            let entry = entry_name(&archive.archive, entry)?;
+            let selector = EntrySelector::Name(entry.clone());
+            let archive = archive.open_selected(&cli.backends, &selector).await?;
+            let archive = read.configure(archive, &selector)?;
+            let preview = archive
+                .preview(&entry, format.map(PreviewFormat::from), rows)
+                .await?;
+            match print_preview(&preview, json) {
+                Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => return Err(err.into()),
+                _ => {}
+            }
+        }
+        Command::Grep {
+            pattern,
+            archive,
+            glob,
+            ignore_case,
+            line_number,
+            after_context,
+            before_context,
+            context,
+            files_with_matches,
+            count,
+            concurrency,
+            read,
+        } => {
+            let regex = regex::bytes::RegexBuilder::new(&pattern)
+                .case_insensitive(ignore_case)
+                .build()
+                .map_err(|err| CloudZipError::InvalidRequest(err.to_string()))?;
+            let selector = glob_or_entry(glob, &archive.archive)?;
+            let context = GrepContext {
+                before: before_context.or(context).unwrap_or(0),
+                after: after_context.or(context).unwrap_or(0),
+            };
+            let format = GrepFormat {
+                line_number,
+                files_with_matches,
+                count,
+                separators: context.before > 0 || context.after > 0,
+            };
+            let archive = archive
+                .open_selected(&cli.backends, &selector)
+                .await?
+                .with_concurrency(concurrency);
+            let archive = read.configure(archive, &selector)?;
+            let mut results = archive.grep(&selector, &regex, context).await?;
+            let mut stdout = std::io::stdout().lock();
+            let (mut searched, mut matched, mut failed) = (0, 0, 0);
+            while let Some(result) = results.next().await {
+                searched += 1;
+                let matches = match result.result {
+                    Ok(matches) => matches,
+                    Err(err) => {
+                        failed += 1;
+                        error!(entry = result.file_name, error = %err, "Search failed");
+                        continue;
+                    }
+                };
+                if matches.count == 0 {
+                    continue;
+                }
+                matched += 1;
+                match format.write(&mut stdout, &result.file_name, &matches, matched == 1) {
+                    // A closed pipe just means the reader has seen enough.
+                    Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => return Ok(()),
+                    written => written?,
+                }
+            }
+            if failed > 0 {
+                return Err(CloudZipError::InvalidArchive(format!(
+                    "{} of {} entries could not be searched",
+                    failed, searched
+                )));
+            }
+            if matched == 0 {
+                info!(entries = searched, "No entry matched");
+            }
+        }
+        Command::Hash {
+            archive,
+            glob,
+            sha256: _,
+            md5,
+            concurrency,
+            read,
+        } => {
+            let algorithm = if md5 {
+                HashAlgorithm::Md5
+            } else {
+                HashAlgorithm::Sha256
+            };
+            let selector = glob_or_entry(glob, &archive.archive)?;
+            let archive = archive
+                .open_selected(&cli.backends, &selector)
+                .await?
+                .with_concurrency(concurrency);
+            let archive = read.configure(archive, &selector)?;
+            let mut results = archive.digest(&selector, algorithm).await?;
+            let mut stdout = std::io::stdout().lock();
+            let (mut hashed, mut failed) = (0, 0);
+            while let Some(result) = results.next().await {
+                let digest = match result.result {
+                    Ok(digest) => digest,
+                    Err(err) => {
+                        failed += 1;
+                        error!(entry = result.file_name, error = %err, "Hashing failed");
+                        continue;
+                    }
+                };
+                hashed += 1;
+                match writeln!(stdout, "{}", checksum_line(&digest, &result.file_name)) {
+                    Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => return Ok(()),
+                    written => written?,
+                }
+            }
+            if failed > 0 {
+                return Err(CloudZipError::InvalidArchive(format!(
+                    "{} of {} entries could not be hashed",
+                    failed,
+                    hashed + failed
+                )));
+            }
+        }
+        Command::Diff {
+            old,
+            new,
+            select,
+            name_encoding,
+            json,
+        } => {
+            let selector = select.selector()?.unwrap_or(EntrySelector::All);
+            let old = diff_side(old, name_encoding, &cli.backends, &selector).await?;
+            let new = diff_side(new, name_encoding, &cli.backends, &selector).await?;
+            let changes = diff::diff(&old, &new);
+            match print_changes(&changes, json) {
+                Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => return Err(err.into()),
+                _ => {}
+            }
+            let count = |change| changes.iter().filter(|c| c.change == change).count();
+            info!(
+                added = count(Change::Added),
+                removed = count(Change::Removed),
+                modified = count(Change::Modified),
+                "Compared the archives"
+            );
+        }
+        Command::Du {
+            archive,
+            select,
+            depth,
+            by_size,
+            human,
+            json,
+        } => {
+            let selector = select.selector()?.unwrap_or(EntrySelector::All);
+            let entries = archive
+                .open_selected(&cli.backends, &selector)
+                .await?
+                .entries()?;
+            let mut usage = directory_usage(&entries.select(&selector), depth);
+            if by_size {
+                // The total stays last.
+                let directories = usage.len() - 1;
+                usage[..directories].sort_by_key(|usage| std::cmp::Reverse(usage.uncompressed));
+            }
+            match print_usage(&usage, human, json) {
+                Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => return Err(err.into()),
+                _ => {}
+            }
+        }
+        Command::Duplicates {
+            archives,
+            catalog: location,
+            name_encoding,
+            json,
+        } => {
+            let catalog = match location {
+                Some(location) => Catalog::load(&location, &cli.backends.options()?)
+                    .await?
+                    .ok_or_else(|| {
+                        CloudZipError::InvalidLocation(format!(
+                            "there is no catalog at {}; write one with `cloud_zip catalog`",
+                            location
+                        ))
+                    })?,
+                None => {
+                    let mut catalog = Catalog::new();
+                    for uri in archives {
+                        let archive = uri
+                            .nested
+                            .iter()
+                            .fold(uri.location.to_string(), |uri, inner| {
+                                format!("{}!{}", uri, inner)
+                            });
+                        let entries = ArchiveArgs {
+                            archive: uri,
+                            metadata: None,
+                            #[cfg(feature = "dynamodb")]
+                            dynamodb_table: None,
+                            name_encoding,
+                            split: false,
+                        }
+                        .open(&cli.backends)
+                        .await?
+                        .entries()?;
+                        catalog.archives.push(CatalogArchive {
+                            archive,
+                            fingerprint: None,
+                            entries: entries.as_slice().to_vec(),
+                        });
+                    }
+                    catalog.archives.sort_by(|a, b| a.archive.cmp(&b.archive));
+                    catalog
+                }
+            };
+            let groups = catalog.duplicates();
+            match print_duplicates(&groups, json) {
+                Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => return Err(err.into()),
+                _ => {}
+            }
+            info!(
+                groups = groups.len(),
+                wasted = groups.iter().map(DuplicateGroup::wasted).sum::<u64>(),
+                "Found the duplicates"
+            );
+        }
+        Command::Exif {
+            archive,
+            glob,
+            thumbnails,
+            json,
+            write,
+            read,
+        } => {
+            let selector = glob_or_entry(glob, &archive.archive)?;
+            let archive = write.configure(archive.open_selected(&cli.backends, &selector).await?);
+            let archive = read.configure(archive, &selector)?;
+            let entries = archive.entries()?;
+            let names: Vec<&str> = entries
+                .select(&selector)
+                .into_iter()
+                .filter(|meta| !meta.is_directory)
+                .map(|meta| meta.file_name.as_str())
+                .filter(|name| matches!(selector, EntrySelector::Name(_)) || is_jpeg(name))
+                .collect();
+            if names.is_empty() {
+                return Err(CloudZipError::EntryNotFound(selector.describe()));
+            }
+            let archive = &archive;
+            let thumbnails = thumbnails.as_deref();
+            let mut results = stream::iter(names)
+                .map(|name| async move {
+                    let result = async {
+                        let exif = archive.read_exif(name).await?;
+                        let thumbnail = match (&exif, thumbnails) {
+                            (Some(exif), Some(dir)) => {
+                                archive.extract_thumbnail_to(name, exif, dir).await?
+                            }
+                            _ => None,
+                        };
+                        Ok::<_, CloudZipError>((exif, thumbnail))
+                    };
+                    (name, result.await)
+                })
+                .buffered(write.concurrency);
+            let mut listed = Vec::new();
+            let mut failed = 0;
+            while let Some((name, result)) = results.next().await {
+                match result {
+                    Ok((Some(exif), thumbnail)) => listed.push(ImageExif {
+                        name,
+                        thumbnail_size: exif.thumbnail.as_ref().map(Vec::len),
+                        thumbnail_path: thumbnail.map(|path| path.display().to_string()),
+                        exif,
+                    }),
+                    Ok((None, _)) => debug!(entry = name, "No EXIF metadata"),
+                    Err(err) => {
+                        failed += 1;
+                        error!(entry = name, error = %err, "Could not read the EXIF metadata");
+                    }
+                }
+            }
+            match print_exif(&listed, json) {
+                Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => return Err(err.into()),
+                _ => {}
+            }
+            if failed > 0 {
+                return Err(CloudZipError::InvalidArchive(format!(
+                    "{} images could not be read",
+                    failed
+                )));
+            }
+        }
+        Command::Stat { archive, entry } => {
+            let entry = entry_name(&archive.archive, entry)?;
+            let entries = archive
+                .open_selected(&cli.backends, &EntrySelector::Name(entry.clone()))
+                .await?
+                .entries()?;
+            print_stat(entries.find(&entry)?);
+        }
+        Command::Verify {
+            archive,
+            select,
+            concurrency,
+            read,
+        } => {
+            let selector = select.selector()?.unwrap_or(EntrySelector::All);
+            let archive = archive
+                .open_selected(&cli.backends, &selector)
+                .await?
+                .with_concurrency(concurrency);
+            let archive = read.configure(archive, &selector)?;
+            let display = ProgressDisplay::new();
+            let checks = display.attach(archive).verify(&selector).await;
+            display.finish();
+            let checks = checks?;
+            let mut failed = 0;
+            for check in &checks {
+                match &check.result {
+                    Ok(size) => info!(entry = check.file_name, size, "Verified"),
+                    Err(err) => {
+                        failed += 1;
+                        error!(entry = check.file_name, error = %err, "Verification failed");
+                    }
+                }
+            }
+            if failed > 0 {
+                return Err(CloudZipError::InvalidArchive(format!(
+                    "{} of {} entries failed verification",
+                    failed,
+                    checks.len()
+                )));
+            }
+            info!(entries = checks.len(), "Verified every entry");
+        }
+        #[cfg(feature = "mount")]
+        Command::Mount {
+            archive,
+            mountpoint,
+            allow_other,
+            read,
+        } => {
+            let location = archive.archive.location.clone();
+            let archive =
+                read.configure(archive.open(&cli.backends).await?, &EntrySelector::All)?;
+            let session = cloud_zip::mount::mount(archive, &mountpoint, allow_other).await?;
+            info!(
+                archive = %location,
+                mountpoint = %mountpoint.display(),
+                "Mounted; press Ctrl-C to unmount"
+            );
+            shutdown_signal().await?;
+            drop(session);
+            info!(mountpoint = %mountpoint.display(), "Unmounted");
+        }
+        #[cfg(feature = "serve")]
+        Command::Serve {
+            archives,
+            listen,
+            metrics,
+            read,
+        } => {
+            let mut options = cli.backends.options()?;
+            let metrics = metrics.then(|| {
+                let recorder = options
+                    .metrics
+                    .get_or_insert_with(|| Arc::new(MetricsRecorder::new()))
+                    .clone();
+                Arc::new(PrometheusMetrics::new("http_request", "status", recorder))
+            });
+            let mut served = std::collections::HashMap::new();
+            for served_archive in &archives {
+                let (name, uri) = served_archive.name_and_uri();
+                let reader = uri.open(&options).await?;
+                let archive =
+                    read.configure(CloudZip::discover(reader).await?, &EntrySelector::All)?;
+                info!(name, archive = %uri.location, "Serving");
+                if served.insert(name.clone(), archive).is_some() {
+                    return Err(CloudZipError::InvalidLocation(format!(
+                        "Two archives are named {}; name them with name=URI",
+                        name
+                    )));
+                }
+            }
+            let listener = tokio::net::TcpListener::bind(listen).await?;
+            info!(address = %listener.local_addr()?, "Listening; press Ctrl-C to stop");
+            let mut app = cloud_zip::serve::router(served);
+            if let Some(metrics) = metrics {
+                app = cloud_zip::prometheus::instrument(app, metrics);
+            }
+            axum::serve(listener, app)
+                .with_graceful_shutdown(async {
+                    let _ = shutdown_signal().await;
+                })
+                .await?;
+        }
+        #[cfg(feature = "grpc")]
+        Command::ServeGrpc {
+            listen,
+            allow_local_files,
+        } => {
+            let service = cloud_zip::grpc::CloudZipService::new(cli.backends.options()?)
+                .allow_local_files(allow_local_files);
+            info!(address = %listen, "Listening; press Ctrl-C to stop");
+            tonic::transport::Server::builder()
+                .add_service(service.into_server())
+                .serve_with_shutdown(listen, async {
+                    let _ = shutdown_signal().await;
+                })
+                .await
+                .map_err(|err| CloudZipError::Io(std::io::Error::other(err)))?;
+        }
+        #[cfg(feature = "lambda")]
+        Command::Lambda => cloud_zip::lambda::run(cli.backends.options()?)
+            .await
+            .map_err(|err| CloudZipError::Io(std::io::Error::other(err)))?,
+        #[cfg(feature = "worker")]
+        Command::Worker {
+            queue_url,
+            results_queue_url,
+            concurrency,
+            visibility_timeout,
+            max_attempts,
+            metrics_listen,
+        } => {
+            let config = cloud_zip::worker::WorkerConfig {
+                results_queue_url,
+                concurrency,
+                visibility_timeout: Duration::from_secs(visibility_timeout),
+                max_attempts,
+                ..cloud_zip::worker::WorkerConfig::new(queue_url)
+            };
+            let worker = cloud_zip::worker::Worker::new(cli.backends.options()?, config).await;
+            if let Some(address) = metrics_listen {
+                let listener = tokio::net::TcpListener::bind(address).await?;
+                info!(address = %listener.local_addr()?, "Serving metrics");
+                let app = cloud_zip::prometheus::router(worker.metrics());
+                tokio::spawn(async move {
+                    if let Err(err) = axum::serve(listener, app).await {
+                        warn!(error = %err, "Metrics server failed");
+                    }
+                });
+            }
+            Arc::new(worker)
+                .run(async {
+                    let _ = shutdown_signal().await;
+                })
+                .await;
+        }
+        Command::Presign {
+            archive,
+            expires_in,
+        } => {
+            let ArchiveLocation::S3 {
+                bucket,
+                key,
+                version_id,
+            } = archive.location
+            else {
+                return Err(CloudZipError::InvalidLocation(format!(
+                    "only S3 archives can be presigned, not {}",
+                    archive.location
+                )));
+            };
+            let client = get_s3_client(&cli.backends.options()?.s3).await;
+            let url = S3Backend::new(client, bucket, key)
+                .with_version_id(version_id)
+                .presigned_url(Duration::from_secs(expires_in))
+                .await?;
+            println!("{}", url);
+        }
+        Command::List {
+            archive,
+            metadata,
+            #[cfg(feature = "dynamodb")]
+            dynamodb_table,
+            name_encoding,
+            split,
+            select,
+            json,
+            sort,
+            reverse,
+            detect_type,
+        } => {
+            let selector = select.selector()?.unwrap_or(EntrySelector::All);
+            let (entries, archive) = match (metadata, archive) {
+                (Some(metadata), _) if !detect_type => {
+                    (Arc::new(read_index(&metadata, &selector).await?), None)
+                }
+                (metadata, Some(archive)) => {
+                    let archive = ArchiveArgs {
+                        archive,
+                        metadata,
+                        #[cfg(feature = "dynamodb")]
+                        dynamodb_table,
+                        name_encoding,
+                        split,
+                    }
+                    .open_selected(&cli.backends, &selector)
+                    .await?;
+                    (archive.entries()?, Some(archive))
+                }
+                (_, None) => unreachable!("clap requires an archive or an index"),
+            };
+
+            let mut selected = entries.select(&selector);
+            match sort {
+                Some(SortKey::Name) => selected.sort_by(|a, b| a.file_name.cmp(&b.file_name)),
+                Some(SortKey::Size) => selected.sort_by_key(|meta| meta.uncompressed_size),
+                Some(SortKey::Mtime) => selected.sort_by_key(|meta| meta.modified),
+                None => {}
+            }
+            if reverse {
+                selected.reverse();
+            }
+            let types = match &archive {
+                Some(archive) if detect_type => Some(detect_types(archive, &selected).await),
+                _ => None,
+            };
+            // A closed pipe just means the reader, e.g. `head`, has seen enough.
+            match print_listing(&selected, types.as_deref(), json) {
+                Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => return Err(err.into()),
+                _ => {}
+            }
+        }
+    }
 
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extract_matches(command: clap::Command, flags: &[&str]) -> ArgMatches {
+        let args = ["cloud_zip", "extract", "archive.zip", "entry"];
+        let matches = parse_args(command, args.iter().chain(flags)).unwrap();
+        matches.subcommand_matches("extract").unwrap().clone()
+    }
+
+    fn configured(config: &str) -> clap::Command {
+        apply_config(Cli::command(), &config.parse::<toml::Table>().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn flags_beat_the_environment_which_beats_the_config_file() {
+        std::env::remove_var("CLOUD_ZIP_MAX_ATTEMPTS");
+        std::env::remove_var("CLOUD_ZIP_OUTPUT_DIR");
+        let config = "max_attempts = 7\n[extract]\noutput_dir = \"/config\"\n";
+        let settings = |matches: &ArgMatches| {
+            (
+                *matches.get_one::<u32>("max_attempts").unwrap(),
+                matches.get_one::<PathBuf>("output_dir").unwrap().clone(),
+            )
+        };
+
+        let defaults = settings(&extract_matches(Cli::command(), &[]));
+        assert_eq!(
+            defaults,
+            (RetryPolicy::default().max_attempts, PathBuf::from("."))
+        );
+        let from_config = settings(&extract_matches(configured(config), &[]));
+        assert_eq!(from_config, (7, PathBuf::from("/config")));
+
+        std::env::set_var("CLOUD_ZIP_MAX_ATTEMPTS", "5");
+        std::env::set_var("CLOUD_ZIP_OUTPUT_DIR", "/env");
+        let from_env = settings(&extract_matches(configured(config), &[]));
+        let from_flags = settings(&extract_matches(
+            configured(config),
+            &["--max-attempts", "3", "--output-dir", "/flag"],
+        ));
+        std::env::remove_var("CLOUD_ZIP_MAX_ATTEMPTS");
+        std::env::remove_var("CLOUD_ZIP_OUTPUT_DIR");
+        assert_eq!(from_env, (5, PathBuf::from("/env")));
+        assert_eq!(from_flags, (3, PathBuf::from("/flag")));
+    }
+
+    #[test]
+    fn flags_the_config_file_turns_on_can_be_turned_off() {
+        std::env::remove_var("CLOUD_ZIP_FORCE_PATH_STYLE");
+        let force_path_style =
+            |command, flags: &[&str]| extract_matches(command, flags).get_flag("force_path_style");
+        assert!(force_path_style(configured("force_path_style = true"), &[]));
+        assert!(!force_path_style(
+            configured("force_path_style = true"),
+            &["--no-force-path-style"]
+        ));
+        // Without the config file turning it on, there is nothing to turn off.
+        assert!(Cli::command()
+            .try_get_matches_from(["cloud_zip", "extract", "a.zip", "--no-force-path-style"])
+            .is_err());
+    }
+}
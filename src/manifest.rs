@@ -0,0 +1,155 @@
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+use crate::error::{CloudZipError, Result};
+
+/// One entry requested by a manifest, optionally with the file it should be written to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub name: String,
+    /// Output file, relative to the output directory unless absolute; `None` places the entry
+    /// according to the configured path layout.
+    pub destination: Option<PathBuf>,
+}
+
+/// How a manifest file is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestFormat {
+    /// An array of entry names or `{"name": ..., "destination": ...}` objects.
+    Json,
+    /// `name[,destination]` rows, with an optional `name,destination` header.
+    Csv,
+    /// One entry name per line; blank lines and lines starting with `#` are ignored.
+    Lines,
+}
+
+impl ManifestFormat {
+    /// Guesses the format from the file extension, falling back to a plain list of names.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => ManifestFormat::Json,
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => ManifestFormat::Csv,
+            _ => ManifestFormat::Lines,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonItem {
+    Name(String),
+    Entry {
+        name: String,
+        #[serde(default)]
+        destination: Option<PathBuf>,
+    },
+}
+
+/// Parses the requested entries out of a manifest.
+pub fn parse_manifest(text: &str, format: ManifestFormat) -> Result<Vec<ManifestEntry>> {
+    let entries = match format {
+        ManifestFormat::Json => serde_json::from_str::<Vec<JsonItem>>(text)
+            .map_err(|err| CloudZipError::InvalidManifest(err.to_string()))?
+            .into_iter()
+            .map(|item| match item {
+                JsonItem::Name(name) => ManifestEntry {
+                    name,
+                    destination: None,
+                },
+                JsonItem::Entry { name, destination } => ManifestEntry { name, destination },
+            })
+            .collect(),
+        ManifestFormat::Csv => parse_csv(text)?,
+        ManifestFormat::Lines => text
+            .lines()
+            .map(|line| line.trim_end_matches('\r'))
+            .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+            .map(|line| ManifestEntry {
+                name: line.to_string(),
+                destination: None,
+            })
+            .collect(),
+    };
+    if let Some(entry) = entries.iter().find(|entry| entry.name.is_empty()) {
+        return Err(CloudZipError::InvalidManifest(format!(
+            "empty entry name{}",
+            entry
+                .destination
+                .as_ref()
+                .map(|dest| format!(" for destination {}", dest.display()))
+                .unwrap_or_default()
+        )));
+    }
+    Ok(entries)
+}
+
+/// Reads a manifest file, in the format its extension suggests unless one is given.
+pub fn read_manifest(path: &Path, format: Option<ManifestFormat>) -> Result<Vec<ManifestEntry>> {
+    let text = std::fs::read_to_string(path)?;
+    parse_manifest(
+        &text,
+        format.unwrap_or_else(|| ManifestFormat::from_path(path)),
+    )
+}
+
+fn parse_csv(text: &str) -> Result<Vec<ManifestEntry>> {
+    let mut entries = Vec::new();
+    for (number, row) in csv_rows(text)?.into_iter().enumerate() {
+        if number == 0 && row.len() <= 2 && row[0] == "name" {
+            continue;
+        }
+        let mut fields = row.into_iter();
+        let name = fields.next().unwrap_or_default();
+        let destination = fields.next().filter(|dest| !dest.is_empty());
+        if fields.next().is_some() {
+            return Err(CloudZipError::InvalidManifest(format!(
+                "row {} has more than two fields",
+                number + 1
+            )));
+        }
+        entries.push(ManifestEntry {
+            name,
+            destination: destination.map(PathBuf::from),
+        });
+    }
+    Ok(entries)
+}
+
+/// Splits RFC 4180 CSV into rows of fields, skipping blank lines.
+fn csv_rows(text: &str) -> Result<Vec<Vec<String>>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => row.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                row.push(std::mem::take(&mut field));
+                if row.len() > 1 || !row[0].is_empty() {
+                    rows.push(std::mem::take(&mut row));
+                }
+                row.clear();
+            }
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(CloudZipError::InvalidManifest(
+            "unterminated quoted field".to_string(),
+        ));
+    }
+    row.push(field);
+    if row.len() > 1 || !row[0].is_empty() {
+        rows.push(row);
+    }
+    Ok(rows)
+}
@@ -0,0 +1,584 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::compression;
+use crate::error::{CloudZipError, Result};
+use crate::selection::EntrySelector;
+use crate::sharded_index::{self, SHARDED_MAGIC};
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub file_name: String,
+    pub uncompressed_size: u64,
+    pub compressed_size: u64,
+    pub is_directory: bool,
+    pub file_offset: u64,
+    /// Indexes written before this field existed only ever held deflate entries.
+    #[serde(default = "default_compression_method")]
+    pub compression_method: u16,
+    /// `None` for indexes written before checksums were recorded; such entries are not verified.
+    #[serde(default)]
+    pub crc32: Option<u32>,
+    /// Modification time in seconds since the Unix epoch, from the extended timestamp or NTFS
+    /// extra field when present and otherwise from the MS-DOS fields read as UTC.
+    #[serde(default)]
+    pub modified: Option<i64>,
+    /// Unix file mode, including the file type bits, for archives written on Unix.
+    #[serde(default)]
+    pub unix_mode: Option<u32>,
+    #[serde(default)]
+    pub encryption: Encryption,
+}
+
+/// How the stored bytes of an entry are encrypted.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encryption {
+    #[default]
+    None,
+    /// WinZip AES; `strength` 1, 2 and 3 stand for 128, 192 and 256-bit keys, and
+    /// `vendor_version` 2 (AE-2) means the CRC is not recorded.
+    Aes { strength: u8, vendor_version: u16 },
+    /// The traditional PKWARE stream cipher; the last byte of its 12-byte header must
+    /// equal `check_byte` when the password is right.
+    ZipCrypto { check_byte: u8 },
+}
+
+const UNIX_FILE_TYPE: u32 = 0o170000;
+const UNIX_SYMLINK: u32 = 0o120000;
+
+impl FileMetadata {
+    /// Whether the entry is a symbolic link whose content is the link target.
+    pub fn is_symlink(&self) -> bool {
+        self.unix_mode
+            .is_some_and(|mode| mode & UNIX_FILE_TYPE == UNIX_SYMLINK)
+    }
+
+    /// The permission bits to give the extracted file, without setuid, setgid or sticky bits.
+    ///
+    /// Symlinks have none, as their `0777` would otherwise end up on the plain file holding
+    /// the target when links are not recreated.
+    pub fn permissions(&self) -> Option<u32> {
+        self.unix_mode
+            .filter(|_| !self.is_symlink())
+            .map(|mode| mode & 0o777)
+            .filter(|&mode| mode != 0)
+    }
+
+    /// The bytes of the archive holding the stored data of the entry, which is what
+    /// extraction requests; sizes that run past the largest offset end there.
+    pub fn byte_range(&self) -> Range<u64> {
+        self.file_offset..self.file_offset.saturating_add(self.compressed_size)
+    }
+
+    /// Whether any byte of the content can be read straight from the archive, without
+    /// decoding the ones before it: the entry is stored uncompressed and unencrypted.
+    pub fn is_random_access(&self) -> bool {
+        self.compression_method == compression::STORED && self.encryption == Encryption::None
+    }
+
+    /// Whether any byte of the content can be decoded from a checkpoint in its deflate stream,
+    /// as [`crate::CloudZip::entry_checkpoints`] finds them: the entry is a deflated,
+    /// unencrypted file.
+    pub fn is_checkpointable(&self) -> bool {
+        self.compression_method == compression::DEFLATED
+            && self.encryption == Encryption::None
+            && !self.is_directory
+    }
+
+    /// The CRC-32 and size of a non-empty file's content, which files holding the same
+    /// content share; `None` for directories, empty files and entries without a CRC-32.
+    pub fn content_key(&self) -> Option<(u32, u64)> {
+        self.crc32
+            .filter(|_| !self.is_directory && self.uncompressed_size > 0)
+            .map(|crc32| (crc32, self.uncompressed_size))
+    }
+
+    /// The modification time as a [`SystemTime`], if the archive recorded one.
+    pub fn modified_time(&self) -> Option<SystemTime> {
+        let modified = self.modified?;
+        let magnitude = Duration::from_secs(modified.unsigned_abs());
+        if modified >= 0 {
+            UNIX_EPOCH.checked_add(magnitude)
+        } else {
+            UNIX_EPOCH.checked_sub(magnitude)
+        }
+    }
+}
+
+fn default_compression_method() -> u16 {
+    compression::DEFLATED
+}
+
+/// Serialization used for index files and sidecars. Reading detects the format by itself.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexFormat {
+    /// Compact binary encoding.
+    #[default]
+    Cbor,
+    /// Human-readable, for inspecting or post-processing the index with other tools.
+    Json,
+    /// Entries cut into shards by name behind a small root, for archives with millions of
+    /// entries: a [`ShardedIndex`](crate::sharded_index::ShardedIndex) reads only the shards a
+    /// lookup needs.
+    Sharded,
+}
+
+/// Version of the index layout written by this release.
+///
+/// 0. A bare list of entries, from before the envelope existed. Fields added over time
+///    (`compression_method`, `crc32`, `modified`, `unix_mode`, `encryption`) fall back to
+///    their serde defaults.
+/// 1. The list wrapped in an [`IndexEnvelope`].
+///
+/// Bump it whenever an entry field changes meaning, and migrate older versions in
+/// [`decode_metadata`]. Indexes newer than this are refused rather than misread.
+pub const INDEX_VERSION: u32 = 1;
+
+/// What index files and sidecars hold; `E` is the list of entries.
+#[derive(Deserialize, Serialize)]
+struct IndexEnvelope<E> {
+    version: u32,
+    /// The release that wrote the index, for troubleshooting.
+    #[serde(default)]
+    generator: String,
+    /// The archive the offsets were read from; absent in indexes written before it was recorded.
+    #[serde(default)]
+    archive: Option<ArchiveFingerprint>,
+    entries: E,
+}
+
+/// Reads only the version of an enveloped index, whatever the layout of the rest.
+#[derive(Deserialize)]
+struct VersionProbe {
+    version: u32,
+}
+
+/// Identifies the exact archive an index was built from, so that stale offsets are never used
+/// on an archive that has since been replaced.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveFingerprint {
+    pub size: u64,
+    #[serde(default)]
+    pub etag: Option<String>,
+    #[serde(default)]
+    pub version_id: Option<String>,
+}
+
+impl ArchiveFingerprint {
+    /// Fails when `current` describes a different archive. Tags missing on either side are
+    /// not compared, as not every backend has them.
+    pub fn check(&self, current: &ArchiveFingerprint) -> Result<()> {
+        fn differs(recorded: &Option<String>, current: &Option<String>) -> bool {
+            matches!((recorded, current), (Some(recorded), Some(current)) if recorded != current)
+        }
+
+        let change = if self.size != current.size {
+            format!("its size went from {} to {} bytes", self.size, current.size)
+        } else if differs(&self.etag, &current.etag) {
+            format!(
+                "its ETag went from {} to {}",
+                self.etag.as_deref().unwrap_or_default(),
+                current.etag.as_deref().unwrap_or_default()
+            )
+        } else if differs(&self.version_id, &current.version_id) {
+            format!(
+                "its version went from {} to {}",
+                self.version_id.as_deref().unwrap_or_default(),
+                current.version_id.as_deref().unwrap_or_default()
+            )
+        } else {
+            return Ok(());
+        };
+        Err(CloudZipError::ArchiveChanged(change))
+    }
+}
+
+/// First bytes of a zstd frame, which an index compressed on write starts with.
+const ZSTD_MAGIC: &[u8; 4] = &[0x28, 0xb5, 0x2f, 0xfd];
+/// Compression level of indexes; their names are repetitive enough that higher levels gain
+/// little for the time they take.
+#[cfg(feature = "zstd")]
+const INDEX_ZSTD_LEVEL: i32 = 9;
+
+/// Encodes an index the way it is stored in index files and sidecars.
+pub fn encode_metadata(index: &EntryIndex, format: IndexFormat) -> Result<Vec<u8>> {
+    let envelope = IndexEnvelope {
+        version: INDEX_VERSION,
+        generator: concat!("cloud_zip ", env!("CARGO_PKG_VERSION")).to_string(),
+        archive: index.fingerprint.clone(),
+        entries: index.as_slice(),
+    };
+    match format {
+        IndexFormat::Cbor => Ok(serde_cbor::to_vec(&envelope)?),
+        IndexFormat::Json => Ok(serde_json::to_vec_pretty(&envelope)?),
+        IndexFormat::Sharded => sharded_index::encode(index, false),
+    }
+}
+
+/// Encodes an index like [`encode_metadata`], compressed with zstd. A sharded index has each
+/// shard compressed instead of the whole file, so it can still be read a shard at a time.
+///
+/// [`decode_metadata`] and [`read_metadata`] decompress such indexes on their own.
+#[cfg(feature = "zstd")]
+pub fn encode_compressed_metadata(index: &EntryIndex, format: IndexFormat) -> Result<Vec<u8>> {
+    match format {
+        IndexFormat::Sharded => sharded_index::encode(index, true),
+        format => compress_index(&encode_metadata(index, format)?),
+    }
+}
+
+#[cfg(feature = "zstd")]
+pub(crate) fn compress_index(bytes: &[u8]) -> Result<Vec<u8>> {
+    Ok(zstd::bulk::compress(bytes, INDEX_ZSTD_LEVEL)?)
+}
+
+#[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+pub(crate) fn decompress_index(bytes: &[u8]) -> Result<Vec<u8>> {
+    #[cfg(feature = "zstd")]
+    return zstd::stream::decode_all(bytes).map_err(|source| CloudZipError::Decompression {
+        file_name: "the index".to_string(),
+        source,
+    });
+    #[cfg(not(feature = "zstd"))]
+    return Err(CloudZipError::UnsupportedCompression {
+        method: compression::ZSTD,
+        name: compression::method_name(compression::ZSTD),
+    });
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8], format: IndexFormat) -> Result<T> {
+    match format {
+        IndexFormat::Json => Ok(serde_json::from_slice(bytes)?),
+        IndexFormat::Cbor | IndexFormat::Sharded => Ok(serde_cbor::from_slice(bytes)?),
+    }
+}
+
+/// Decodes an index in any format and of any version up to [`INDEX_VERSION`].
+pub fn decode_metadata(bytes: &[u8]) -> Result<EntryIndex> {
+    if bytes.starts_with(ZSTD_MAGIC) {
+        return decode_metadata(&decompress_index(bytes)?);
+    }
+    if bytes.starts_with(SHARDED_MAGIC) {
+        return sharded_index::decode(bytes);
+    }
+    // A CBOR index starts with an array or map header byte, never the `[` or `{` of JSON.
+    let (format, legacy) = match bytes.iter().find(|byte| !byte.is_ascii_whitespace()) {
+        Some(b'[') => (IndexFormat::Json, true),
+        Some(b'{') => (IndexFormat::Json, false),
+        Some(0x80..=0x9f) => (IndexFormat::Cbor, true),
+        _ => (IndexFormat::Cbor, false),
+    };
+    if legacy {
+        return Ok(EntryIndex::new(decode(bytes, format)?));
+    }
+
+    let VersionProbe { version } = decode(bytes, format)?;
+    if version > INDEX_VERSION {
+        return Err(CloudZipError::UnsupportedIndexVersion {
+            found: version,
+            supported: INDEX_VERSION,
+        });
+    }
+    let envelope: IndexEnvelope<Vec<FileMetadata>> = decode(bytes, format)?;
+    Ok(EntryIndex::new(envelope.entries).with_fingerprint(envelope.archive))
+}
+
+/// Writes the index to a file; paths ending with `.sqlite` or `.sqlite3` get a SQLite database
+/// instead, whatever `format` says.
+pub fn write_metadata(
+    metadata_path: impl AsRef<Path>,
+    index: &EntryIndex,
+    format: IndexFormat,
+) -> Result<()> {
+    write_index(metadata_path.as_ref(), index, |index| {
+        encode_metadata(index, format)
+    })
+}
+
+/// Writes the index to a file like [`write_metadata`], compressed with
+/// [`encode_compressed_metadata`]; SQLite databases are written as they are.
+#[cfg(feature = "zstd")]
+pub fn write_compressed_metadata(
+    metadata_path: impl AsRef<Path>,
+    index: &EntryIndex,
+    format: IndexFormat,
+) -> Result<()> {
+    write_index(metadata_path.as_ref(), index, |index| {
+        encode_compressed_metadata(index, format)
+    })
+}
+
+fn write_index(
+    metadata_path: &Path,
+    index: &EntryIndex,
+    encode: impl FnOnce(&EntryIndex) -> Result<Vec<u8>>,
+) -> Result<()> {
+    if is_sqlite_path(metadata_path) {
+        #[cfg(feature = "sqlite")]
+        return crate::sqlite_index::SqliteIndex::create(metadata_path, index);
+        #[cfg(not(feature = "sqlite"))]
+        return Err(sqlite_unsupported(metadata_path));
+    }
+    fs::write(metadata_path, encode(index)?)?;
+    Ok(())
+}
+
+/// Reads an index file in any format, including a SQLite database, which is read whole.
+pub fn read_metadata(metadata_path: impl AsRef<Path>) -> Result<EntryIndex> {
+    let metadata_path = metadata_path.as_ref();
+    if is_sqlite_index(metadata_path)? {
+        #[cfg(feature = "sqlite")]
+        return crate::sqlite_index::SqliteIndex::open(metadata_path)?.load();
+        #[cfg(not(feature = "sqlite"))]
+        return Err(sqlite_unsupported(metadata_path));
+    }
+    decode_metadata(&fs::read(metadata_path)?)
+}
+
+fn is_sqlite_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            ["sqlite", "sqlite3"]
+                .iter()
+                .any(|sqlite| extension.eq_ignore_ascii_case(sqlite))
+        })
+}
+
+/// Whether the index file is a SQLite database, from its first bytes.
+pub fn is_sqlite_index(path: impl AsRef<Path>) -> Result<bool> {
+    starts_with(path.as_ref(), b"SQLite format 3\0")
+}
+
+/// Whether the index file is a sharded one, which [`ShardedIndex`] reads in place.
+///
+/// [`ShardedIndex`]: crate::sharded_index::ShardedIndex
+pub fn is_sharded_index(path: impl AsRef<Path>) -> Result<bool> {
+    starts_with(path.as_ref(), SHARDED_MAGIC)
+}
+
+fn starts_with(path: &Path, magic: &[u8]) -> Result<bool> {
+    let mut header = vec![0; magic.len()];
+    let mut file = fs::File::open(path)?;
+    match std::io::Read::read_exact(&mut file, &mut header) {
+        Ok(()) => Ok(header == magic),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn sqlite_unsupported(path: &Path) -> CloudZipError {
+    CloudZipError::Sqlite(format!(
+        "{} is a SQLite index, which needs cloud_zip built with the sqlite feature",
+        path.display()
+    ))
+}
+
+/// The entries of an archive, looked up by name without scanning the whole list.
+#[derive(Debug, Clone, Default)]
+pub struct EntryIndex {
+    entries: Vec<FileMetadata>,
+    by_name: HashMap<String, usize>,
+    fingerprint: Option<ArchiveFingerprint>,
+}
+
+impl EntryIndex {
+    /// Indexes `entries` by name; when a name occurs twice, the first entry wins.
+    pub fn new(entries: Vec<FileMetadata>) -> Self {
+        let mut by_name = HashMap::with_capacity(entries.len());
+        for (position, meta) in entries.iter().enumerate() {
+            by_name.entry(meta.file_name.clone()).or_insert(position);
+        }
+        EntryIndex {
+            entries,
+            by_name,
+            fingerprint: None,
+        }
+    }
+
+    /// Records which archive the entries were read from.
+    pub fn with_fingerprint(mut self, fingerprint: Option<ArchiveFingerprint>) -> Self {
+        self.fingerprint = fingerprint;
+        self
+    }
+
+    pub fn fingerprint(&self) -> Option<&ArchiveFingerprint> {
+        self.fingerprint.as_ref()
+    }
+
+    pub fn get(&self, file_name: &str) -> Option<&FileMetadata> {
+        self.by_name
+            .get(file_name)
+            .map(|&position| &self.entries[position])
+    }
+
+    pub fn find(&self, file_name: &str) -> Result<&FileMetadata> {
+        self.get(file_name)
+            .ok_or_else(|| CloudZipError::EntryNotFound(file_name.to_string()))
+    }
+
+    /// Returns the entries picked by `selector`, in archive order.
+    pub fn select(&self, selector: &EntrySelector) -> Vec<&FileMetadata> {
+        match selector {
+            EntrySelector::Name(name) => self.get(name).into_iter().collect(),
+            _ => selector.select(&self.entries),
+        }
+    }
+
+    pub fn as_slice(&self) -> &[FileMetadata] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The entries that should not share an archive: names recorded more than once, which
+    /// zip readers resolve differently, and entries whose data overlaps, so that the same
+    /// bytes decode as several files. Empty for any archive written by a zip tool.
+    pub fn conflicts(&self) -> Vec<EntryConflict> {
+        let mut conflicts = Vec::new();
+        if self.by_name.len() < self.entries.len() {
+            let mut counts: HashMap<&str, usize> = HashMap::with_capacity(self.by_name.len());
+            for meta in &self.entries {
+                *counts.entry(&meta.file_name).or_default() += 1;
+            }
+            // In archive order, each name once.
+            for meta in &self.entries {
+                if let Some(count) = counts.remove(meta.file_name.as_str()).filter(|&n| n > 1) {
+                    conflicts.push(EntryConflict::DuplicateName {
+                        file_name: meta.file_name.clone(),
+                        count,
+                    });
+                }
+            }
+        }
+
+        let mut stored: Vec<&FileMetadata> = self
+            .entries
+            .iter()
+            .filter(|meta| meta.compressed_size > 0)
+            .collect();
+        stored.sort_unstable_by_key(|meta| meta.file_offset);
+        // The entry reaching furthest so far, which any later one starting before its end
+        // overlaps.
+        let mut furthest: Option<&FileMetadata> = None;
+        for meta in stored {
+            match furthest {
+                Some(previous) if meta.file_offset < previous.byte_range().end => {
+                    conflicts.push(EntryConflict::Overlap {
+                        first: previous.file_name.clone(),
+                        second: meta.file_name.clone(),
+                    });
+                    if meta.byte_range().end > previous.byte_range().end {
+                        furthest = Some(meta);
+                    }
+                }
+                _ => furthest = Some(meta),
+            }
+        }
+        conflicts
+    }
+}
+
+/// Entries that should not share an archive, found by [`EntryIndex::conflicts`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryConflict {
+    /// `count` entries are named `file_name`.
+    DuplicateName { file_name: String, count: usize },
+    /// The data of `second` starts before the data of `first` ends.
+    Overlap { first: String, second: String },
+}
+
+impl fmt::Display for EntryConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryConflict::DuplicateName { file_name, count } => {
+                write!(f, "{} entries are named {}", count, file_name)
+            }
+            EntryConflict::Overlap { first, second } => {
+                write!(f, "the data of {} overlaps the data of {}", second, first)
+            }
+        }
+    }
+}
+
+pub fn find_entry<'a>(list: &'a [FileMetadata], file_name: &str) -> Result<&'a FileMetadata> {
+    list.iter()
+        .find(|meta| meta.file_name == file_name)
+        .ok_or_else(|| CloudZipError::EntryNotFound(file_name.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(file_name: &str, file_offset: u64, compressed_size: u64) -> FileMetadata {
+        FileMetadata {
+            file_name: file_name.to_string(),
+            uncompressed_size: compressed_size,
+            compressed_size,
+            is_directory: false,
+            file_offset,
+            compression_method: 0,
+            crc32: None,
+            modified: None,
+            unix_mode: None,
+            encryption: Encryption::None,
+        }
+    }
+
+    #[test]
+    fn finds_duplicate_names() {
+        let index = EntryIndex::new(vec![
+            entry("b.txt", 30, 5),
+            entry("a.txt", 65, 5),
+            entry("b.txt", 100, 5),
+            entry("b.txt", 135, 5),
+        ]);
+        assert_eq!(
+            index.conflicts(),
+            [EntryConflict::DuplicateName {
+                file_name: "b.txt".to_string(),
+                count: 3,
+            }]
+        );
+    }
+
+    #[test]
+    fn finds_overlapping_entries() {
+        let index = EntryIndex::new(vec![
+            entry("outer", 30, 100),
+            entry("inner", 60, 10),
+            entry("tail", 120, 20),
+            entry("apart", 200, 10),
+            entry("empty", 70, 0),
+        ]);
+        let overlap = |first: &str, second: &str| EntryConflict::Overlap {
+            first: first.to_string(),
+            second: second.to_string(),
+        };
+        assert_eq!(
+            index.conflicts(),
+            [overlap("outer", "inner"), overlap("outer", "tail")]
+        );
+    }
+
+    #[test]
+    fn sizes_past_the_largest_offset_still_overlap() {
+        let huge = entry("huge", 30, u64::MAX);
+        assert_eq!(huge.byte_range(), 30..u64::MAX);
+        let index = EntryIndex::new(vec![huge, entry("after", 1 << 40, u64::MAX)]);
+        assert_eq!(index.conflicts().len(), 1);
+    }
+}
@@ -0,0 +1,219 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+tokio::task_local! {
+    /// The entry the current task is extracting, which requests and writes are counted for.
+    static ENTRY: Arc<EntryRecord>;
+}
+
+/// The requests made, bytes moved and time spent so far, as collected by a
+/// [`MetricsRecorder`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Reads sent to the backends, retries included.
+    pub requests: u64,
+    /// Bytes received from the backends.
+    pub bytes_downloaded: u64,
+    /// Decompressed bytes written out, or only checked when verifying.
+    pub bytes_written: u64,
+    /// Reads sent again after a transient failure.
+    pub retries: u64,
+    /// Reads that failed, whether they were retried or not.
+    pub errors: u64,
+    /// Ranges served by a [`CachingReader`](crate::backend::CachingReader) from memory or disk.
+    pub cache_hits: u64,
+    /// Ranges a [`CachingReader`](crate::backend::CachingReader) had to read from its backend.
+    pub cache_misses: u64,
+    /// Time since the recorder was created.
+    pub elapsed: Duration,
+    /// One per entry extracted or checked, in the order they were started.
+    pub entries: Vec<EntryMetrics>,
+}
+
+/// What the extraction or check of one entry took. Ranges fetched for several entries at once
+/// are counted for the entry that asked first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EntryMetrics {
+    pub name: String,
+    pub requests: u64,
+    pub bytes_downloaded: u64,
+    pub bytes_written: u64,
+    pub retries: u64,
+    pub errors: u64,
+    /// From the start of the entry to its end, not counting the wait for a concurrency slot.
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    requests: AtomicU64,
+    bytes_downloaded: AtomicU64,
+    bytes_written: AtomicU64,
+    retries: AtomicU64,
+    errors: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+/// Picks one of the [`Counters`].
+type Counter = fn(&Counters) -> &AtomicU64;
+
+fn add(counters: &Counters, counter: Counter, n: u64) {
+    counter(counters).fetch_add(n, Ordering::Relaxed);
+}
+
+/// Collects [`Metrics`] from the backends of [`BackendOptions::metrics`] and the archives
+/// given it with [`CloudZip::with_metrics`], which may share one recorder for a whole job.
+///
+/// [`BackendOptions::metrics`]: crate::BackendOptions::metrics
+/// [`CloudZip::with_metrics`]: crate::CloudZip::with_metrics
+#[derive(Debug)]
+pub struct MetricsRecorder {
+    started: Instant,
+    totals: Arc<Counters>,
+    entries: Mutex<Vec<Arc<EntryRecord>>>,
+}
+
+impl Default for MetricsRecorder {
+    fn default() -> Self {
+        MetricsRecorder {
+            started: Instant::now(),
+            totals: Arc::default(),
+            entries: Mutex::default(),
+        }
+    }
+}
+
+impl MetricsRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        let entries = self
+            .entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|entry| EntryMetrics {
+                name: entry.name.clone(),
+                requests: load(&entry.counters.requests),
+                bytes_downloaded: load(&entry.counters.bytes_downloaded),
+                bytes_written: load(&entry.counters.bytes_written),
+                retries: load(&entry.counters.retries),
+                errors: load(&entry.counters.errors),
+                elapsed: Duration::from_nanos(load(&entry.elapsed_nanos)),
+            })
+            .collect();
+        Metrics {
+            requests: load(&self.totals.requests),
+            bytes_downloaded: load(&self.totals.bytes_downloaded),
+            bytes_written: load(&self.totals.bytes_written),
+            retries: load(&self.totals.retries),
+            errors: load(&self.totals.errors),
+            cache_hits: load(&self.totals.cache_hits),
+            cache_misses: load(&self.totals.cache_misses),
+            elapsed: self.started.elapsed(),
+            entries,
+        }
+    }
+
+    pub(crate) fn request(&self) {
+        self.record(|counters| &counters.requests, 1);
+    }
+
+    pub(crate) fn downloaded(&self, n: u64) {
+        self.record(|counters| &counters.bytes_downloaded, n);
+    }
+
+    pub(crate) fn retried(&self) {
+        self.record(|counters| &counters.retries, 1);
+    }
+
+    pub(crate) fn failed(&self) {
+        self.record(|counters| &counters.errors, 1);
+    }
+
+    pub(crate) fn cache_lookup(&self, hit: bool) {
+        match hit {
+            true => add(&self.totals, |counters| &counters.cache_hits, 1),
+            false => add(&self.totals, |counters| &counters.cache_misses, 1),
+        }
+    }
+
+    fn record(&self, counter: Counter, n: u64) {
+        add(&self.totals, counter, n);
+        let _ = ENTRY.try_with(|entry| add(&entry.counters, counter, n));
+    }
+
+    /// Starts counting for an entry, which [`measured`] then runs.
+    pub(crate) fn entry(&self, name: &str) -> Arc<EntryRecord> {
+        let entry = Arc::new(EntryRecord {
+            name: name.to_string(),
+            counters: Counters::default(),
+            totals: self.totals.clone(),
+            elapsed_nanos: AtomicU64::new(0),
+        });
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(entry.clone());
+        entry
+    }
+}
+
+fn load(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+#[derive(Debug)]
+pub(crate) struct EntryRecord {
+    name: String,
+    counters: Counters,
+    /// Those of the recorder, which bytes written are only counted in through their entry.
+    totals: Arc<Counters>,
+    elapsed_nanos: AtomicU64,
+}
+
+/// Counts `n` decompressed bytes written for the current entry, if it is measured.
+pub(crate) fn written(n: u64) {
+    let _ = ENTRY.try_with(|entry| {
+        add(&entry.counters, |counters| &counters.bytes_written, n);
+        add(&entry.totals, |counters| &counters.bytes_written, n);
+    });
+}
+
+/// Runs `work` as the work on `entry`, timing it; without an entry, it just runs.
+pub(crate) async fn measured<F: Future>(entry: Option<Arc<EntryRecord>>, work: F) -> F::Output {
+    let Some(entry) = entry else {
+        return work.await;
+    };
+    let started = Instant::now();
+    let output = ENTRY.scope(entry.clone(), work).await;
+    let elapsed = u64::try_from(started.elapsed().as_nanos()).unwrap_or(u64::MAX);
+    entry.elapsed_nanos.store(elapsed, Ordering::Relaxed);
+    output
+}
+
+/// The entry the current task works on, to carry over into the tasks it spawns.
+pub(crate) fn current_entry() -> Option<Arc<EntryRecord>> {
+    ENTRY.try_with(Arc::clone).ok()
+}
+
+/// Runs `work`, spawned by the task of `entry`, as part of it.
+pub(crate) async fn within<F: Future>(entry: Option<Arc<EntryRecord>>, work: F) -> F::Output {
+    match entry {
+        Some(entry) => ENTRY.scope(entry, work).await,
+        None => work.await,
+    }
+}
+
+/// Like [`within`], for work on a blocking thread.
+pub(crate) fn within_blocking<R>(entry: Option<Arc<EntryRecord>>, work: impl FnOnce() -> R) -> R {
+    match entry {
+        Some(entry) => ENTRY.sync_scope(entry, work),
+        None => work(),
+    }
+}
@@ -0,0 +1,408 @@
+use fuser::{
+    BackgroundSession, FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData,
+    ReplyDirectory, ReplyEntry, ReplyOpen, Request, FUSE_ROOT_ID,
+};
+use libc::{EBADF, EIO, EISDIR, ENOENT, ENOTDIR};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::runtime::Handle;
+use tracing::warn;
+
+use crate::archive::{CloudZip, CHECKPOINTED_ENTRY_LEN};
+use crate::error::Result;
+use crate::extract::{range_entry_reader, EntryReader};
+use crate::metadata::{EntryIndex, FileMetadata};
+
+/// How long the kernel may keep attributes and lookups; a mounted archive never changes.
+const TTL: Duration = Duration::from_secs(3600);
+/// Furthest an entry read from checkpoints is decoded ahead to reach a read, beyond which it
+/// is reopened from the checkpoint before the read instead.
+const CHECKPOINTED_SKIP: u64 = 4 * 1024 * 1024;
+
+/// Mounts `archive` as a read-only filesystem at `mountpoint`, until the returned session is
+/// dropped.
+///
+/// Directories come from the index, both the entries stored for them and those only implied
+/// by the names below them. File reads are served with ranged reads: stored entries are read
+/// at any offset directly, compressed ones are decoded from the start and read best in order.
+/// Going back in a deflated entry of at least [`CHECKPOINTED_ENTRY_LEN`] bytes decodes it
+/// whole once to find checkpoints, from which every later read resumes.
+///
+/// Must be called from within a Tokio runtime, which serves the reads.
+pub async fn mount(
+    archive: CloudZip,
+    mountpoint: impl AsRef<Path>,
+    allow_other: bool,
+) -> Result<BackgroundSession> {
+    let entries = archive.verified_entries().await?;
+    let filesystem = ArchiveFs::new(Arc::new(archive), entries, Handle::current());
+    let mut options = vec![
+        MountOption::RO,
+        MountOption::FSName("cloud_zip".to_string()),
+        MountOption::Subtype("cloud_zip".to_string()),
+        MountOption::DefaultPermissions,
+    ];
+    if allow_other {
+        options.push(MountOption::AllowOther);
+    }
+    Ok(fuser::spawn_mount2(filesystem, mountpoint, &options)?)
+}
+
+/// A file or directory of the mounted tree; its inode number is its index plus one.
+struct Node {
+    parent: u64,
+    /// Position of the entry in the index; `None` for implied directories.
+    entry: Option<usize>,
+    /// Names and inode numbers of the nodes inside a directory.
+    children: Option<BTreeMap<String, u64>>,
+}
+
+/// An open compressed entry, kept so that reads in order continue where the last one ended.
+struct OpenEntry {
+    reader: EntryReader,
+    position: u64,
+    /// Whether the reader started from a checkpoint, which reading elsewhere can too.
+    checkpointed: bool,
+}
+
+struct ArchiveFs {
+    archive: Arc<CloudZip>,
+    entries: Arc<EntryIndex>,
+    runtime: Handle,
+    nodes: Vec<Node>,
+    /// Open compressed entries by file handle; stored entries need none.
+    open: HashMap<u64, Option<OpenEntry>>,
+    next_handle: u64,
+    uid: u32,
+    gid: u32,
+    mounted_at: SystemTime,
+}
+
+impl ArchiveFs {
+    fn new(archive: Arc<CloudZip>, entries: Arc<EntryIndex>, runtime: Handle) -> Self {
+        let mut filesystem = ArchiveFs {
+            archive,
+            entries: entries.clone(),
+            runtime,
+            nodes: vec![Node {
+                parent: FUSE_ROOT_ID,
+                entry: None,
+                children: Some(BTreeMap::new()),
+            }],
+            open: HashMap::new(),
+            next_handle: 1,
+            // SAFETY: getuid and getgid cannot fail and touch no memory.
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            mounted_at: SystemTime::now(),
+        };
+        for (index, metadata) in entries.as_slice().iter().enumerate() {
+            filesystem.insert(index, metadata);
+        }
+        filesystem
+    }
+
+    /// Adds an entry and the directories leading to it. Names with `.` or `..` components are
+    /// left out, as is an entry whose name is already taken.
+    fn insert(&mut self, index: usize, metadata: &FileMetadata) {
+        let components: Vec<_> = metadata
+            .file_name
+            .split('/')
+            .filter(|component| !component.is_empty())
+            .collect();
+        if components.is_empty() || components.iter().any(|c| *c == "." || *c == "..") {
+            return;
+        }
+        let mut parent = FUSE_ROOT_ID;
+        for (depth, component) in components.iter().enumerate() {
+            let last = depth == components.len() - 1;
+            let existing = self
+                .node(parent)
+                .children
+                .as_ref()
+                .and_then(|children| children.get(*component).copied());
+            parent = match existing {
+                Some(ino) if last && metadata.is_directory => {
+                    let node = &mut self.nodes[ino as usize - 1];
+                    if node.children.is_some() && node.entry.is_none() {
+                        node.entry = Some(index);
+                    }
+                    return;
+                }
+                Some(_) if last => return,
+                Some(ino) if self.node(ino).children.is_some() => ino,
+                // A file is in the way of a directory.
+                Some(_) => return,
+                None => {
+                    let ino = self.nodes.len() as u64 + 1;
+                    let is_directory = !last || metadata.is_directory;
+                    self.nodes.push(Node {
+                        parent,
+                        entry: last.then_some(index),
+                        children: is_directory.then(BTreeMap::new),
+                    });
+                    if let Some(children) = &mut self.nodes[parent as usize - 1].children {
+                        children.insert(component.to_string(), ino);
+                    }
+                    ino
+                }
+            };
+        }
+    }
+
+    fn node(&self, ino: u64) -> &Node {
+        &self.nodes[ino as usize - 1]
+    }
+
+    fn get(&self, ino: u64) -> Option<&Node> {
+        ino.checked_sub(1)
+            .and_then(|index| self.nodes.get(index as usize))
+    }
+
+    fn metadata(&self, node: &Node) -> Option<&FileMetadata> {
+        node.entry.map(|index| &self.entries.as_slice()[index])
+    }
+
+    fn kind(&self, node: &Node) -> FileType {
+        match (&node.children, self.metadata(node)) {
+            (Some(_), _) => FileType::Directory,
+            (None, Some(metadata)) if metadata.is_symlink() => FileType::Symlink,
+            (None, _) => FileType::RegularFile,
+        }
+    }
+
+    fn attr(&self, ino: u64, node: &Node) -> FileAttr {
+        let kind = self.kind(node);
+        let metadata = self.metadata(node);
+        let modified = metadata
+            .and_then(FileMetadata::modified_time)
+            .unwrap_or(self.mounted_at);
+        let default_perm = match kind {
+            FileType::Directory => 0o555,
+            FileType::Symlink => 0o777,
+            _ => 0o444,
+        };
+        let perm = metadata
+            .and_then(FileMetadata::permissions)
+            .map_or(default_perm, |mode| mode & 0o555);
+        let size = match kind {
+            FileType::Directory => 0,
+            _ => metadata.map_or(0, |metadata| metadata.uncompressed_size),
+        };
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: modified,
+            mtime: modified,
+            ctime: modified,
+            crtime: modified,
+            kind,
+            perm: perm as u16,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: 512,
+            flags: 0,
+        }
+    }
+}
+
+/// Reads up to `len` bytes, stopping early only at the end of the input.
+async fn read_up_to(reader: &mut (impl AsyncRead + Unpin), len: usize) -> std::io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(len);
+    reader.take(len as u64).read_to_end(&mut data).await?;
+    Ok(data)
+}
+
+/// Reads `len` decompressed bytes at `offset`, reopening the entry when asked to go back:
+/// from the checkpoint before the offset for large deflated entries, which are also reopened
+/// there to skip far ahead once they have checkpoints, and from the start for others.
+async fn read_compressed(
+    archive: &CloudZip,
+    metadata: &FileMetadata,
+    open: &mut Option<OpenEntry>,
+    offset: u64,
+    len: usize,
+) -> Result<Vec<u8>> {
+    let far = |open: &OpenEntry| {
+        open.position > offset || (open.checkpointed && offset - open.position > CHECKPOINTED_SKIP)
+    };
+    match open.as_ref() {
+        Some(open) if !far(open) => {}
+        Some(_)
+            if metadata.is_checkpointable()
+                && metadata.uncompressed_size >= CHECKPOINTED_ENTRY_LEN =>
+        {
+            let content = archive.entry_reader(&metadata.file_name).await?;
+            *open = Some(OpenEntry {
+                reader: range_entry_reader(
+                    content,
+                    offset,
+                    metadata.uncompressed_size.saturating_sub(offset),
+                ),
+                position: offset,
+                checkpointed: true,
+            });
+        }
+        _ => {
+            *open = Some(OpenEntry {
+                reader: archive.open_entry(&metadata.file_name).await?,
+                position: 0,
+                checkpointed: false,
+            });
+        }
+    }
+    let open = open.as_mut().expect("the entry was just opened");
+    let skip = offset - open.position;
+    tokio::io::copy(&mut (&mut open.reader).take(skip), &mut tokio::io::sink()).await?;
+    open.position = offset;
+    let data = read_up_to(&mut open.reader, len).await?;
+    open.position += data.len() as u64;
+    Ok(data)
+}
+
+impl Filesystem for ArchiveFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let child = self
+            .get(parent)
+            .and_then(|node| node.children.as_ref())
+            .zip(name.to_str())
+            .and_then(|(children, name)| children.get(name).copied());
+        match child {
+            Some(ino) => reply.entry(&TTL, &self.attr(ino, self.node(ino)), 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.get(ino) {
+            Some(node) => reply.attr(&TTL, &self.attr(ino, node)),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        let Some(metadata) = self.get(ino).and_then(|node| self.metadata(node)) else {
+            return reply.error(ENOENT);
+        };
+        let target = self.runtime.block_on(async {
+            let mut reader = self.archive.open_entry(&metadata.file_name).await?;
+            Ok::<_, crate::error::CloudZipError>(read_up_to(&mut reader, 4096).await?)
+        });
+        match target {
+            Ok(target) => reply.data(&target),
+            Err(err) => {
+                warn!(entry = %metadata.file_name, error = %err, "Could not read a symlink");
+                reply.error(EIO);
+            }
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        match self.get(ino) {
+            None => reply.error(ENOENT),
+            Some(node) if node.children.is_some() => reply.error(EISDIR),
+            Some(_) => {
+                let handle = self.next_handle;
+                self.next_handle += 1;
+                self.open.insert(handle, None);
+                reply.opened(handle, 0);
+            }
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let Some(metadata) = self.get(ino).and_then(|node| self.metadata(node)).cloned() else {
+            return reply.error(ENOENT);
+        };
+        let Some(open) = self.open.get_mut(&fh) else {
+            return reply.error(EBADF);
+        };
+        let offset = offset.max(0) as u64;
+        let len = (size as u64).min(metadata.uncompressed_size.saturating_sub(offset));
+        if len == 0 {
+            return reply.data(&[]);
+        }
+        let archive = &self.archive;
+        let data = self.runtime.block_on(async {
+            if metadata.is_random_access() {
+                archive
+                    .reader()
+                    .read_range(metadata.file_offset + offset, len)
+                    .await
+            } else {
+                read_compressed(archive, &metadata, open, offset, len as usize).await
+            }
+        });
+        match data {
+            Ok(data) => reply.data(&data),
+            Err(err) => {
+                warn!(entry = %metadata.file_name, offset, error = %err, "Read failed");
+                reply.error(EIO);
+            }
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.open.remove(&fh);
+        reply.ok();
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(node) = self.get(ino) else {
+            return reply.error(ENOENT);
+        };
+        let Some(children) = &node.children else {
+            return reply.error(ENOTDIR);
+        };
+        let listing = [
+            (ino, FileType::Directory, "."),
+            (node.parent, FileType::Directory, ".."),
+        ]
+        .into_iter()
+        .chain(
+            children
+                .iter()
+                .map(|(name, &child)| (child, self.kind(self.node(child)), name.as_str())),
+        );
+        for (position, (child, kind, name)) in listing.enumerate().skip(offset.max(0) as usize) {
+            // The offset handed back is where the next call resumes.
+            if reply.add(child, position as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
@@ -0,0 +1,373 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::error::{CloudZipError, Result};
+
+/// How entry names are mapped to paths below the output directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PathLayout {
+    /// Recreate the directory tree stored in the archive.
+    #[default]
+    Preserve,
+    /// Write every file directly into the output directory and skip directory entries.
+    Flatten,
+    /// Drop this many leading path components, like `tar --strip-components`.
+    StripComponents(usize),
+}
+
+impl PathLayout {
+    /// Returns the path of an entry relative to the output directory, or `None` when nothing
+    /// is left of its name.
+    ///
+    /// Names that could escape the output directory are rejected rather than rewritten.
+    pub fn relative_path(&self, file_name: &str, is_directory: bool) -> Result<Option<PathBuf>> {
+        let mut components = safe_components(file_name)?.into_iter();
+        let path: PathBuf = match self {
+            PathLayout::Preserve => components.collect(),
+            PathLayout::Flatten if is_directory => return Ok(None),
+            PathLayout::Flatten => components.next_back().into_iter().collect(),
+            PathLayout::StripComponents(n) => components.skip(*n).collect(),
+        };
+        Ok((!path.as_os_str().is_empty()).then_some(path))
+    }
+}
+
+/// Characters Windows refuses in file names, besides separators and control characters.
+const WINDOWS_FORBIDDEN: [char; 7] = ['<', '>', ':', '"', '|', '?', '*'];
+/// Device names Windows reserves in every directory and whatever the extension.
+const WINDOWS_RESERVED: [&str; 24] = [
+    "CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$", "COM1", "COM2", "COM3", "COM4", "COM5",
+    "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8",
+    "LPT9",
+];
+
+/// Rewrites `path`, relative to the output directory, into names Windows can create, or
+/// `None` when it already is one.
+///
+/// Forbidden characters become `_`, trailing dots and spaces, which Windows would drop, are
+/// dropped, and reserved device names such as `CON` or `nul.txt` get a leading `_`. Paths
+/// longer than `MAX_PATH` need nothing: the standard library gives every file system call on
+/// Windows the `\\?\` prefix they need, and output directories given with it are kept.
+pub(crate) fn windows_safe(path: &Path) -> Option<PathBuf> {
+    let mut renamed = false;
+    let safe = path
+        .components()
+        .map(|component| {
+            let name = component.as_os_str().to_string_lossy();
+            let safe = windows_safe_name(&name);
+            renamed |= safe != name;
+            safe
+        })
+        .collect();
+    renamed.then_some(safe)
+}
+
+fn windows_safe_name(name: &str) -> String {
+    let mut safe: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_control() || WINDOWS_FORBIDDEN.contains(&c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    safe.truncate(safe.trim_end_matches(['.', ' ']).len());
+    if safe.is_empty() {
+        safe.push('_');
+    }
+    let stem = safe.split('.').next().unwrap_or_default().trim_end();
+    if WINDOWS_RESERVED
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        safe.insert(0, '_');
+    }
+    safe
+}
+
+/// Splits an entry name into its path components, rejecting absolute names and `..`.
+///
+/// Backslashes count as separators too, since some Windows tools write them into entry names.
+pub(crate) fn safe_components(file_name: &str) -> Result<Vec<&str>> {
+    let unsafe_path = |reason| CloudZipError::InvalidEntryPath {
+        file_name: file_name.to_string(),
+        reason,
+    };
+
+    if file_name.starts_with(['/', '\\']) {
+        return Err(unsafe_path("absolute paths are not allowed"));
+    }
+
+    let mut components = Vec::new();
+    for component in file_name.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." => return Err(unsafe_path("`..` components are not allowed")),
+            _ if components.is_empty() && is_drive_prefix(component) => {
+                return Err(unsafe_path("absolute paths are not allowed"))
+            }
+            _ if component.contains('\0') => return Err(unsafe_path("NUL bytes are not allowed")),
+            _ => components.push(component),
+        }
+    }
+    Ok(components)
+}
+
+/// Checks that a symlink at `link`, relative to the output directory, resolves to a path
+/// inside it. Only the names are looked at, so links are created last, after every file
+/// they might otherwise be used to redirect.
+pub(crate) fn target_stays_inside(link: &Path, target: &str) -> bool {
+    if target.starts_with(['/', '\\']) {
+        return false;
+    }
+
+    let mut depth = link.components().count().saturating_sub(1);
+    for (i, component) in target.split(['/', '\\']).enumerate() {
+        match component {
+            "" | "." => {}
+            ".." if depth == 0 => return false,
+            ".." => depth -= 1,
+            _ if i == 0 && is_drive_prefix(component) => return false,
+            _ => depth += 1,
+        }
+    }
+    true
+}
+
+/// Matches a Windows drive such as `C:`.
+fn is_drive_prefix(component: &str) -> bool {
+    let bytes = component.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+/// What to do when an extracted file would replace one that already exists.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Refuse to extract and report the existing file.
+    #[default]
+    Fail,
+    /// Leave the existing file alone and skip the entry.
+    Skip,
+    /// Replace the existing file.
+    Overwrite,
+    /// Write to the first free `name (n).ext` next to the existing file.
+    Rename,
+}
+
+impl ConflictPolicy {
+    /// Decides where an entry planned for `path` is written, or `None` to skip it.
+    ///
+    /// `claimed` holds the paths already given to other entries of the same extraction, so
+    /// entries that map to the same file (e.g. when flattening) conflict with each other too.
+    pub(crate) fn resolve(
+        &self,
+        path: PathBuf,
+        claimed: &mut HashSet<PathBuf>,
+    ) -> Result<Option<PathBuf>> {
+        let taken = |path: &Path, claimed: &HashSet<PathBuf>| {
+            claimed.contains(path) || path.symlink_metadata().is_ok()
+        };
+
+        let resolved = match self {
+            _ if !taken(&path, claimed) => path,
+            ConflictPolicy::Fail => return Err(CloudZipError::OutputExists(path)),
+            ConflictPolicy::Skip => return Ok(None),
+            ConflictPolicy::Overwrite if claimed.contains(&path) => {
+                return Err(CloudZipError::OutputExists(path))
+            }
+            ConflictPolicy::Overwrite => path,
+            ConflictPolicy::Rename => (1..)
+                .map(|n| numbered(&path, n))
+                .find(|candidate| !taken(candidate, claimed))
+                .expect("some numbered name is free"),
+        };
+        claimed.insert(resolved.clone());
+        Ok(Some(resolved))
+    }
+}
+
+/// Turns `dir/name.ext` into `dir/name (n).ext`.
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{} ({}).{}", stem, n, ext.to_string_lossy()),
+        None => format!("{} ({})", stem, n),
+    };
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reason(file_name: &str) -> &'static str {
+        match safe_components(file_name) {
+            Err(CloudZipError::InvalidEntryPath { reason, .. }) => reason,
+            other => panic!("{file_name:?} was accepted: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn splits_names_on_both_separators() {
+        assert_eq!(
+            safe_components("a/./b\\c//d.txt").unwrap(),
+            ["a", "b", "c", "d.txt"]
+        );
+        assert_eq!(safe_components("dir/").unwrap(), ["dir"]);
+        // Dots that are not a whole component are plain names.
+        assert_eq!(safe_components("..a/b..").unwrap(), ["..a", "b.."]);
+    }
+
+    #[test]
+    fn rejects_names_escaping_the_output_directory() {
+        for name in ["..", "../x", "a/../../x", "a/..", "a\\..\\x"] {
+            assert_eq!(reason(name), "`..` components are not allowed", "{name}");
+        }
+        for name in [
+            "/etc/passwd",
+            "\\x",
+            "C:",
+            "c:/x",
+            "C:\\Windows\\x",
+            "./D:x",
+        ] {
+            assert_eq!(reason(name), "absolute paths are not allowed", "{name}");
+        }
+        assert_eq!(reason("a/b\0c"), "NUL bytes are not allowed");
+        // A drive letter is only a prefix at the start.
+        assert_eq!(safe_components("a/C:x").unwrap(), ["a", "C:x"]);
+    }
+
+    #[test]
+    fn symlink_targets_must_stay_inside() {
+        let link = Path::new("dir/link");
+        assert!(target_stays_inside(link, "file"));
+        assert!(target_stays_inside(link, "../file"));
+        assert!(target_stays_inside(link, "./sub/../../other/file"));
+        assert!(!target_stays_inside(link, "../../file"));
+        assert!(!target_stays_inside(Path::new("link"), "a/../../x"));
+        assert!(!target_stays_inside(Path::new("link"), ".."));
+        assert!(!target_stays_inside(link, "..\\..\\file"));
+        assert!(!target_stays_inside(link, "/etc/passwd"));
+        assert!(!target_stays_inside(link, "\\share\\x"));
+        assert!(!target_stays_inside(link, "C:/x"));
+        assert!(!target_stays_inside(link, "c:x"));
+    }
+
+    #[test]
+    fn rewrites_names_windows_refuses() {
+        assert_eq!(windows_safe_name("report.txt"), "report.txt");
+        assert_eq!(windows_safe_name("a<b>c:d\"e|f?g*"), "a_b_c_d_e_f_g_");
+        assert_eq!(windows_safe_name("tab\tnew\nline"), "tab_new_line");
+        assert_eq!(windows_safe_name("trailing. . "), "trailing");
+        assert_eq!(windows_safe_name("..."), "_");
+        for reserved in [
+            "nul.txt",
+            "CON",
+            "con.tar.gz",
+            "Com1",
+            "lpt9.log",
+            "aux .txt",
+        ] {
+            assert_eq!(
+                windows_safe_name(reserved),
+                format!("_{}", reserved),
+                "{reserved}"
+            );
+        }
+        // Only the whole stem is reserved.
+        assert_eq!(windows_safe_name("nullable.txt"), "nullable.txt");
+        assert_eq!(windows_safe_name("COM10"), "COM10");
+        assert_eq!(windows_safe_name("x.nul"), "x.nul");
+    }
+
+    #[test]
+    fn rewrites_every_component_of_a_path() {
+        assert_eq!(windows_safe(Path::new("dir/file.txt")), None);
+        assert_eq!(
+            windows_safe(Path::new("aux/notes?.txt")),
+            Some(PathBuf::from("_aux/notes_.txt"))
+        );
+        assert_eq!(
+            windows_safe(Path::new("dir./nul")),
+            Some(PathBuf::from("dir/_nul"))
+        );
+    }
+
+    #[test]
+    fn conflicts_with_existing_files_follow_the_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let existing = dir.path().join("a.txt");
+        std::fs::write(&existing, "old").unwrap();
+        std::fs::write(dir.path().join("a (1).txt"), "older").unwrap();
+        let free = dir.path().join("b.txt");
+
+        for policy in [
+            ConflictPolicy::Fail,
+            ConflictPolicy::Skip,
+            ConflictPolicy::Overwrite,
+            ConflictPolicy::Rename,
+        ] {
+            let mut claimed = HashSet::new();
+            assert_eq!(
+                policy.resolve(free.clone(), &mut claimed).unwrap(),
+                Some(free.clone())
+            );
+            assert!(claimed.contains(&free));
+        }
+
+        let mut claimed = HashSet::new();
+        assert!(matches!(
+            ConflictPolicy::Fail.resolve(existing.clone(), &mut claimed),
+            Err(CloudZipError::OutputExists(path)) if path == existing
+        ));
+        assert_eq!(
+            ConflictPolicy::Skip
+                .resolve(existing.clone(), &mut claimed)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            ConflictPolicy::Overwrite
+                .resolve(existing.clone(), &mut claimed)
+                .unwrap(),
+            Some(existing.clone())
+        );
+        assert_eq!(
+            ConflictPolicy::Rename
+                .resolve(existing.clone(), &mut HashSet::new())
+                .unwrap(),
+            Some(dir.path().join("a (2).txt"))
+        );
+    }
+
+    #[test]
+    fn entries_claiming_the_same_path_conflict() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        let mut claimed = HashSet::new();
+        ConflictPolicy::Overwrite
+            .resolve(path.clone(), &mut claimed)
+            .unwrap();
+        // Overwriting the file another entry of the same extraction writes would lose it.
+        assert!(matches!(
+            ConflictPolicy::Overwrite.resolve(path.clone(), &mut claimed),
+            Err(CloudZipError::OutputExists(_))
+        ));
+        assert_eq!(
+            ConflictPolicy::Rename
+                .resolve(path.clone(), &mut claimed)
+                .unwrap(),
+            Some(dir.path().join("data (1)"))
+        );
+        assert_eq!(
+            ConflictPolicy::Rename
+                .resolve(path.clone(), &mut claimed)
+                .unwrap(),
+            Some(dir.path().join("data (2)"))
+        );
+        assert!(!path.exists());
+    }
+}
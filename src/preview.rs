@@ -0,0 +1,1815 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::future::Future;
+use std::io::Read;
+use std::ops::Range;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tracing::warn;
+
+use crate::error::{CloudZipError, Result};
+
+/// Trailing bytes of a Parquet file: the length of its metadata and the `PAR1` magic.
+pub(crate) const PARQUET_TAIL_LEN: u64 = 8;
+/// Longest Parquet footer read.
+const MAX_FOOTER_LEN: u64 = 64 * 1024 * 1024;
+/// Deepest nesting of Thrift structs, lists and maps skipped in a Parquet footer.
+const MAX_THRIFT_DEPTH: usize = 64;
+/// Bytes read at first for a Parquet page header, which is then read again whole up to
+/// [`MAX_PAGE_HEADER_LEN`] when longer.
+const PAGE_HEADER_PROBE: u64 = 64 * 1024;
+const MAX_PAGE_HEADER_LEN: u64 = 16 * 1024 * 1024;
+/// Largest Parquet page decoded, compressed or not.
+const MAX_PAGE_LEN: i32 = 64 * 1024 * 1024;
+/// Longest line read from a CSV or JSON Lines entry.
+const MAX_LINE_LEN: usize = 1024 * 1024;
+
+/// Formats of tabular entries [`crate::CloudZip::preview`] reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PreviewFormat {
+    /// Comma, tab, semicolon or pipe separated values with a header row.
+    Csv,
+    /// One JSON object per line.
+    Jsonl,
+    Parquet,
+}
+
+impl PreviewFormat {
+    /// The format the extension of `name` stands for.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        let extension = name.rsplit_once('.')?.1;
+        match extension {
+            "csv" | "tsv" | "psv" => Some(PreviewFormat::Csv),
+            "jsonl" | "ndjson" => Some(PreviewFormat::Jsonl),
+            "parquet" | "pq" => Some(PreviewFormat::Parquet),
+            _ => None,
+        }
+    }
+}
+
+/// A column of a previewed entry.
+#[derive(Debug, Clone, Serialize)]
+pub struct Column {
+    pub name: String,
+    /// For Parquet, the physical type and any logical one, or `group` for nested columns;
+    /// for CSV and JSON Lines, the type every previewed value fits.
+    pub data_type: String,
+    /// Whether values may be missing: optional or repeated in Parquet, empty or absent in
+    /// some previewed row otherwise.
+    pub nullable: bool,
+    /// Nesting level below the top of a Parquet schema; 0 for other formats.
+    pub depth: usize,
+}
+
+/// The schema and first rows of a tabular entry.
+#[derive(Debug, Clone, Serialize)]
+pub struct Preview {
+    pub format: PreviewFormat,
+    pub columns: Vec<Column>,
+    /// Values of the first rows, one per column. Parquet group columns are left empty and
+    /// repeated ones list their values in brackets; `None` for a Parquet file compressed or
+    /// encoded in a way that is not decoded, such as with LZ4 or delta encodings.
+    pub rows: Option<Vec<Vec<String>>>,
+    /// Number of rows, which a Parquet footer records.
+    pub row_count: Option<u64>,
+    pub row_groups: Option<usize>,
+}
+
+/// Reads the header and `rows` rows of CSV content, guessing the separator from the header.
+pub(crate) async fn preview_csv(content: impl AsyncRead + Unpin, rows: usize) -> Result<Preview> {
+    let mut lines = records(content, rows + 1).await?.into_iter();
+    let header = lines.next().unwrap_or_default();
+    let separator = [',', '\t', ';', '|']
+        .into_iter()
+        .max_by_key(|&separator| header.matches(separator).count())
+        .unwrap_or(',');
+    let names = split_csv(&header, separator);
+    let rows: Vec<Vec<String>> = lines.map(|line| split_csv(&line, separator)).collect();
+    let columns = names
+        .into_iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let values: Vec<&str> = rows
+                .iter()
+                .map(|row| row.get(i).map_or("", String::as_str))
+                .collect();
+            Column {
+                name,
+                data_type: infer_type(values.iter().copied().filter(|value| !value.is_empty()))
+                    .to_string(),
+                nullable: values.iter().any(|value| value.is_empty()),
+                depth: 0,
+            }
+        })
+        .collect();
+    Ok(Preview {
+        format: PreviewFormat::Csv,
+        columns,
+        rows: Some(rows),
+        row_count: None,
+        row_groups: None,
+    })
+}
+
+/// Reads `rows` lines of JSON Lines content, with a column for every key of their objects in
+/// the order they first appear.
+pub(crate) async fn preview_jsonl(content: impl AsyncRead + Unpin, rows: usize) -> Result<Preview> {
+    let objects = records(content, rows)
+        .await?
+        .into_iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| match serde_json::from_str(&line) {
+            Ok(Value::Object(object)) => Ok(object),
+            _ => Err(CloudZipError::InvalidRequest(format!(
+                "not a JSON object: {}",
+                line.chars().take(80).collect::<String>()
+            ))),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut names: Vec<&String> = Vec::new();
+    for object in &objects {
+        for key in object.keys() {
+            if !names.contains(&key) {
+                names.push(key);
+            }
+        }
+    }
+    let columns = names
+        .iter()
+        .map(|&name| {
+            let values: Vec<Option<&Value>> =
+                objects.iter().map(|object| object.get(name)).collect();
+            let mut kinds: Vec<&str> = Vec::new();
+            for kind in values.iter().flatten().filter_map(|value| json_type(value)) {
+                if !kinds.contains(&kind) {
+                    kinds.push(kind);
+                }
+            }
+            let data_type = match kinds.as_slice() {
+                [] => "null".to_string(),
+                ["integer", "number"] | ["number", "integer"] => "number".to_string(),
+                kinds => kinds.join("|"),
+            };
+            Column {
+                name: name.clone(),
+                data_type,
+                nullable: values.iter().any(|value| value.is_none_or(Value::is_null)),
+                depth: 0,
+            }
+        })
+        .collect();
+    let rows = objects
+        .iter()
+        .map(|object| {
+            names
+                .iter()
+                .map(|&name| match object.get(name) {
+                    None | Some(Value::Null) => String::new(),
+                    Some(Value::String(text)) => text.clone(),
+                    Some(value) => value.to_string(),
+                })
+                .collect()
+        })
+        .collect();
+    Ok(Preview {
+        format: PreviewFormat::Jsonl,
+        columns,
+        rows: Some(rows),
+        row_count: None,
+        row_groups: None,
+    })
+}
+
+/// Where the Parquet metadata before `tail`, the last [`PARQUET_TAIL_LEN`] bytes of the
+/// file, starting at `tail_start`, begins.
+pub(crate) fn parquet_footer_start(tail: &[u8], tail_start: u64) -> Result<u64> {
+    match tail {
+        [a, b, c, d, b'P', b'A', b'R', b'1'] => {
+            let len = u64::from(u32::from_le_bytes([*a, *b, *c, *d]));
+            if len > MAX_FOOTER_LEN {
+                return Err(invalid_parquet("its footer is too large"));
+            }
+            tail_start
+                .checked_sub(len)
+                .ok_or_else(|| invalid_parquet("its footer is truncated"))
+        }
+        _ => Err(invalid_parquet("it does not end with PAR1")),
+    }
+}
+
+/// The schema of a Parquet file and where its first rows are, read from its footer.
+pub(crate) struct ParquetFooter {
+    /// The schema and row counts, without rows.
+    pub preview: Preview,
+    /// The primitive columns, in the order of the column chunks of each row group.
+    leaves: Vec<Leaf>,
+    row_groups: Vec<RowGroupLayout>,
+}
+
+/// Reads the schema and row groups from Parquet metadata, a Thrift `FileMetaData`.
+pub(crate) fn read_parquet_footer(footer: &[u8]) -> Result<ParquetFooter> {
+    let mut thrift = Compact::new(footer);
+    let mut elements = Vec::new();
+    let mut row_count = None;
+    let mut row_groups = Vec::new();
+    let mut groups = None;
+    let mut last = 0;
+    while let Some((id, kind)) = thrift.field(&mut last)? {
+        match (id, kind) {
+            (2, LIST) => {
+                let (len, _) = thrift.list()?;
+                for _ in 0..len {
+                    elements.push(SchemaElement::read(&mut thrift)?);
+                }
+            }
+            (3, I64) => row_count = Some(thrift.zigzag()? as u64),
+            (4, LIST) => {
+                let (len, element) = thrift.list()?;
+                for _ in 0..len {
+                    if element == STRUCT {
+                        row_groups.push(RowGroupLayout::read(&mut thrift)?);
+                    } else {
+                        thrift.skip_element(element, 1)?;
+                    }
+                }
+                groups = Some(len);
+            }
+            _ => thrift.skip(kind)?,
+        }
+    }
+
+    // The elements are the schema tree in depth-first order, its root first. Each group
+    // on the way down is kept with its children left and the levels of its values.
+    let mut columns = Vec::new();
+    let mut leaves = Vec::new();
+    let mut parents: Vec<(i32, Levels)> = Vec::new();
+    for (i, element) in elements.into_iter().enumerate() {
+        while parents.last().is_some_and(|(left, _)| *left == 0) {
+            parents.pop();
+        }
+        let mut levels = parents
+            .last()
+            .map_or(Levels::default(), |(_, levels)| *levels);
+        if let Some((left, _)) = parents.last_mut() {
+            *left -= 1;
+        }
+        let depth = parents.len();
+        if i > 0 {
+            levels = levels.below(element.repetition);
+        }
+        if element.num_children > 0 {
+            parents.push((element.num_children, levels));
+        }
+        if i == 0 {
+            continue;
+        }
+        let depth = depth
+            .checked_sub(1)
+            .ok_or_else(|| invalid_parquet("its schema has more elements than its root holds"))?;
+        if let (0, Some(physical)) = (element.num_children, element.physical) {
+            leaves.push(Leaf {
+                column: columns.len(),
+                physical,
+                type_length: usize::try_from(element.type_length.unwrap_or(0)).unwrap_or(0),
+                unsigned: matches!(element.converted, Some(11..=14)),
+                levels,
+            });
+        }
+        columns.push(Column {
+            data_type: element.data_type(),
+            name: element.name,
+            nullable: element.repetition != Some(0),
+            depth,
+        });
+    }
+    Ok(ParquetFooter {
+        preview: Preview {
+            format: PreviewFormat::Parquet,
+            columns,
+            rows: None,
+            row_count,
+            row_groups: groups,
+        },
+        leaves,
+        row_groups,
+    })
+}
+
+fn invalid_parquet(reason: &str) -> CloudZipError {
+    CloudZipError::InvalidRequest(format!("not a Parquet file: {}", reason))
+}
+
+/// The highest definition and repetition levels of the values of a Parquet column.
+#[derive(Debug, Clone, Copy, Default)]
+struct Levels {
+    definition: u32,
+    repetition: u32,
+}
+
+impl Levels {
+    /// The levels below a schema element that is required (0), optional (1) or repeated (2).
+    fn below(self, repetition: Option<i32>) -> Self {
+        match repetition {
+            Some(1) => Levels {
+                definition: self.definition.saturating_add(1),
+                ..self
+            },
+            Some(2) => Levels {
+                definition: self.definition.saturating_add(1),
+                repetition: self.repetition.saturating_add(1),
+            },
+            _ => self,
+        }
+    }
+}
+
+/// A primitive column of a Parquet schema, which each row group holds a column chunk of.
+struct Leaf {
+    /// Its position among the columns of the preview.
+    column: usize,
+    physical: i32,
+    type_length: usize,
+    unsigned: bool,
+    levels: Levels,
+}
+
+#[derive(Default)]
+struct RowGroupLayout {
+    columns: Vec<ChunkLayout>,
+    num_rows: i64,
+}
+
+impl RowGroupLayout {
+    fn read(thrift: &mut Compact<'_>) -> Result<Self> {
+        let mut group = RowGroupLayout::default();
+        let mut last = 0;
+        while let Some((id, kind)) = thrift.field(&mut last)? {
+            match (id, kind) {
+                (1, LIST) => {
+                    let (len, element) = thrift.list()?;
+                    for _ in 0..len {
+                        if element == STRUCT {
+                            group.columns.push(ChunkLayout::read(thrift)?);
+                        } else {
+                            thrift.skip_element(element, 1)?;
+                        }
+                    }
+                }
+                (3, I64) => group.num_rows = thrift.zigzag()?,
+                _ => thrift.skip(kind)?,
+            }
+        }
+        Ok(group)
+    }
+}
+
+/// Where the pages of a column chunk are, from its `ColumnChunk` and `ColumnMetaData`.
+#[derive(Default)]
+struct ChunkLayout {
+    /// Set when the chunk is in another file.
+    external: bool,
+    codec: i32,
+    num_values: i64,
+    compressed_size: i64,
+    data_page_offset: i64,
+    dictionary_page_offset: Option<i64>,
+}
+
+impl ChunkLayout {
+    fn read(thrift: &mut Compact<'_>) -> Result<Self> {
+        let mut chunk = ChunkLayout::default();
+        let mut last = 0;
+        while let Some((id, kind)) = thrift.field(&mut last)? {
+            match (id, kind) {
+                (1, BINARY) => chunk.external = !thrift.binary()?.is_empty(),
+                (3, STRUCT) => {
+                    let mut last = 0;
+                    while let Some((id, kind)) = thrift.field(&mut last)? {
+                        match (id, kind) {
+                            (4, I32) => chunk.codec = thrift.zigzag()? as i32,
+                            (5, I64) => chunk.num_values = thrift.zigzag()?,
+                            (7, I64) => chunk.compressed_size = thrift.zigzag()?,
+                            (9, I64) => chunk.data_page_offset = thrift.zigzag()?,
+                            (11, I64) => chunk.dictionary_page_offset = Some(thrift.zigzag()?),
+                            _ => thrift.skip(kind)?,
+                        }
+                    }
+                }
+                _ => thrift.skip(kind)?,
+            }
+        }
+        Ok(chunk)
+    }
+
+    /// The bytes of the file holding the pages, the dictionary page first when there is one.
+    /// Some writers record a dictionary page offset of 0 for chunks without one.
+    fn range(&self) -> Result<Range<u64>> {
+        let start = match self.dictionary_page_offset {
+            Some(offset) if offset > 0 && offset < self.data_page_offset => offset,
+            _ => self.data_page_offset,
+        };
+        u64::try_from(start)
+            .ok()
+            .zip(u64::try_from(self.compressed_size).ok())
+            .and_then(|(start, len)| Some(start..start.checked_add(len)?))
+            .ok_or_else(|| invalid_parquet("a column chunk lies outside of the file"))
+    }
+}
+
+/// Why the rows of a Parquet file are not decoded.
+enum RowError {
+    /// A compression, encoding or layout that is not decoded; the rows are left out.
+    Unsupported(String),
+    Invalid(CloudZipError),
+}
+
+impl From<CloudZipError> for RowError {
+    fn from(err: CloudZipError) -> Self {
+        RowError::Invalid(err)
+    }
+}
+
+type RowResult<T> = std::result::Result<T, RowError>;
+
+const CODECS: &[&str] = &[
+    "uncompressed",
+    "Snappy",
+    "gzip",
+    "LZO",
+    "Brotli",
+    "LZ4",
+    "zstd",
+    "LZ4 raw",
+];
+
+const ENCODINGS: &[&str] = &[
+    "PLAIN",
+    "GROUP_VAR_INT",
+    "PLAIN_DICTIONARY",
+    "RLE",
+    "BIT_PACKED",
+    "DELTA_BINARY_PACKED",
+    "DELTA_LENGTH_BYTE_ARRAY",
+    "DELTA_BYTE_ARRAY",
+    "RLE_DICTIONARY",
+    "BYTE_STREAM_SPLIT",
+];
+
+const PLAIN: i32 = 0;
+const PLAIN_DICTIONARY: i32 = 2;
+const RLE: i32 = 3;
+const RLE_DICTIONARY: i32 = 8;
+
+const DATA_PAGE: i32 = 0;
+const DICTIONARY_PAGE: i32 = 2;
+const DATA_PAGE_V2: i32 = 3;
+
+fn unsupported_encoding(encoding: i32) -> RowError {
+    let name = usize::try_from(encoding)
+        .ok()
+        .and_then(|encoding| ENCODINGS.get(encoding))
+        .copied()
+        .unwrap_or("unknown");
+    RowError::Unsupported(format!("the {} encoding", name))
+}
+
+impl ParquetFooter {
+    /// Decodes the first `count` rows, one value per column, reading the pages that hold
+    /// them with `read`, which gives bytes of the file; `None` when the file is compressed
+    /// or encoded in a way that is not decoded.
+    pub(crate) async fn rows<F, Fut>(
+        &self,
+        count: usize,
+        read: F,
+    ) -> Result<Option<Vec<Vec<String>>>>
+    where
+        F: Fn(Range<u64>) -> Fut,
+        Fut: Future<Output = Result<Vec<u8>>>,
+    {
+        let mut rows: Vec<Vec<String>> = Vec::new();
+        for group in &self.row_groups {
+            let wanted = usize::try_from(group.num_rows)
+                .unwrap_or(0)
+                .min(count - rows.len());
+            if wanted == 0 {
+                break;
+            }
+            if group.columns.len() != self.leaves.len() {
+                return Err(invalid_parquet(
+                    "a row group holds another number of columns than its schema",
+                ));
+            }
+            let mut group_rows = vec![vec![String::new(); self.preview.columns.len()]; wanted];
+            for (leaf, chunk) in self.leaves.iter().zip(&group.columns) {
+                let values = match read_column(leaf, chunk, wanted, &read).await {
+                    Ok(values) => values,
+                    Err(RowError::Unsupported(reason)) => {
+                        warn!(
+                            column = self.preview.columns[leaf.column].name,
+                            "The rows of the Parquet file are not decoded, as it uses {}", reason
+                        );
+                        return Ok(None);
+                    }
+                    Err(RowError::Invalid(err)) => return Err(err),
+                };
+                for (row, value) in group_rows.iter_mut().zip(values) {
+                    row[leaf.column] = value;
+                }
+            }
+            rows.extend(group_rows);
+        }
+        Ok(Some(rows))
+    }
+}
+
+/// The values of `leaf` in the first `wanted` rows of a column chunk, read page by page.
+async fn read_column<F, Fut>(
+    leaf: &Leaf,
+    chunk: &ChunkLayout,
+    wanted: usize,
+    read: &F,
+) -> RowResult<Vec<String>>
+where
+    F: Fn(Range<u64>) -> Fut,
+    Fut: Future<Output = Result<Vec<u8>>>,
+{
+    if chunk.external {
+        return Err(RowError::Unsupported(
+            "column chunks in other files".to_string(),
+        ));
+    }
+    let Range {
+        start: mut pos,
+        end,
+    } = chunk.range()?;
+    let mut cells = Cells::new(leaf, wanted);
+    let mut dictionary = None;
+    let mut values_left = chunk.num_values;
+    while pos < end && values_left > 0 && !cells.done() {
+        let probe = read(pos..end.min(pos.saturating_add(PAGE_HEADER_PROBE))).await?;
+        let (header, header_len, probe) = match PageHeader::read(&probe) {
+            Ok((header, len)) => (header, len, probe),
+            Err(_) if (probe.len() as u64) < end - pos => {
+                let whole = read(pos..end.min(pos.saturating_add(MAX_PAGE_HEADER_LEN))).await?;
+                let (header, len) = PageHeader::read(&whole)?;
+                (header, len, whole)
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if !(0..=MAX_PAGE_LEN).contains(&header.compressed_size)
+            || !(0..=MAX_PAGE_LEN).contains(&header.uncompressed_size)
+        {
+            return Err(invalid_parquet("a page is too large").into());
+        }
+        let data_start = pos + header_len as u64;
+        let data_end = data_start + header.compressed_size as u64;
+        if data_end > end {
+            return Err(invalid_parquet("a page runs past its column chunk").into());
+        }
+        let data = match probe.get(header_len..header_len + header.compressed_size as usize) {
+            Some(data) => data.to_vec(),
+            None => read(data_start..data_end).await?,
+        };
+        if data.len() != header.compressed_size as usize {
+            return Err(invalid_parquet("a page is truncated").into());
+        }
+        pos = data_end;
+
+        let uncompressed_size = header.uncompressed_size as usize;
+        match header.kind {
+            DICTIONARY_PAGE => {
+                if header.encoding != PLAIN && header.encoding != PLAIN_DICTIONARY {
+                    return Err(unsupported_encoding(header.encoding));
+                }
+                let page = decompress(chunk.codec, data, uncompressed_size)?;
+                let len = usize::try_from(header.num_values)
+                    .ok()
+                    .filter(|&len| len <= page.len())
+                    .ok_or_else(|| invalid_parquet("a dictionary page is truncated"))?;
+                let mut plain = Plain::new(leaf, &page);
+                dictionary = Some((0..len).map(|_| plain.next()).collect::<Result<Vec<_>>>()?);
+            }
+            DATA_PAGE => {
+                let page = decompress(chunk.codec, data, uncompressed_size)?;
+                let mut at = 0;
+                let repetition =
+                    levels_v1(&page, &mut at, leaf.levels.repetition, header.rep_encoding)?;
+                let definition =
+                    levels_v1(&page, &mut at, leaf.levels.definition, header.def_encoding)?;
+                let values =
+                    Values::new(leaf, header.encoding, &page[at..], dictionary.as_deref())?;
+                cells.read_page(header.num_values, repetition, definition, values)?;
+            }
+            DATA_PAGE_V2 => {
+                let (rep_len, def_len) = usize::try_from(header.rep_len)
+                    .ok()
+                    .zip(usize::try_from(header.def_len).ok())
+                    .filter(|&(rep, def)| {
+                        rep.saturating_add(def) <= data.len().min(uncompressed_size)
+                    })
+                    .ok_or_else(|| invalid_parquet("the levels of a page are truncated"))?;
+                let repetition = levels_v2(&data[..rep_len], leaf.levels.repetition);
+                let definition =
+                    levels_v2(&data[rep_len..rep_len + def_len], leaf.levels.definition);
+                let rest = data[rep_len + def_len..].to_vec();
+                let rest_len = uncompressed_size - rep_len - def_len;
+                let page = if header.compressed {
+                    decompress(chunk.codec, rest, rest_len)?
+                } else {
+                    rest
+                };
+                let values = Values::new(leaf, header.encoding, &page, dictionary.as_deref())?;
+                cells.read_page(header.num_values, repetition, definition, values)?;
+            }
+            _ => {}
+        }
+        if matches!(header.kind, DATA_PAGE | DATA_PAGE_V2) {
+            values_left -= i64::from(header.num_values.max(0));
+        }
+    }
+    Ok(cells.finish())
+}
+
+/// What a Thrift `PageHeader` records of a page, with the fields of its data or dictionary
+/// page header.
+struct PageHeader {
+    kind: i32,
+    uncompressed_size: i32,
+    compressed_size: i32,
+    num_values: i32,
+    encoding: i32,
+    def_encoding: i32,
+    rep_encoding: i32,
+    /// The lengths of the levels before the values of a version 2 data page, which are
+    /// never compressed.
+    def_len: i32,
+    rep_len: i32,
+    /// Whether the values of a version 2 data page are compressed.
+    compressed: bool,
+}
+
+impl PageHeader {
+    /// The header at the start of `bytes` and its length.
+    fn read(bytes: &[u8]) -> Result<(Self, usize)> {
+        let corrupt = |_| invalid_parquet("a page header is corrupt or truncated");
+        let mut thrift = Compact::new(bytes);
+        let mut header = PageHeader {
+            kind: -1,
+            uncompressed_size: 0,
+            compressed_size: 0,
+            num_values: 0,
+            encoding: PLAIN,
+            def_encoding: RLE,
+            rep_encoding: RLE,
+            def_len: 0,
+            rep_len: 0,
+            compressed: true,
+        };
+        let mut last = 0;
+        while let Some((id, kind)) = thrift.field(&mut last).map_err(corrupt)? {
+            match (id, kind) {
+                (1, I32) => header.kind = thrift.zigzag().map_err(corrupt)? as i32,
+                (2, I32) => header.uncompressed_size = thrift.zigzag().map_err(corrupt)? as i32,
+                (3, I32) => header.compressed_size = thrift.zigzag().map_err(corrupt)? as i32,
+                (5 | 7 | 8, STRUCT) => {
+                    let mut last = 0;
+                    while let Some((field, kind)) = thrift.field(&mut last).map_err(corrupt)? {
+                        // The data page, dictionary page and version 2 data page headers
+                        // share their first field, the number of values.
+                        match (id, field, kind) {
+                            (_, 1, I32) => {
+                                header.num_values = thrift.zigzag().map_err(corrupt)? as i32
+                            }
+                            (5 | 7, 2, I32) | (8, 4, I32) => {
+                                header.encoding = thrift.zigzag().map_err(corrupt)? as i32
+                            }
+                            (5, 3, I32) => {
+                                header.def_encoding = thrift.zigzag().map_err(corrupt)? as i32
+                            }
+                            (5, 4, I32) => {
+                                header.rep_encoding = thrift.zigzag().map_err(corrupt)? as i32
+                            }
+                            (8, 5, I32) => {
+                                header.def_len = thrift.zigzag().map_err(corrupt)? as i32
+                            }
+                            (8, 6, I32) => {
+                                header.rep_len = thrift.zigzag().map_err(corrupt)? as i32
+                            }
+                            (8, 7, 1 | 2) => header.compressed = kind == 1,
+                            _ => thrift.skip(kind).map_err(corrupt)?,
+                        }
+                    }
+                }
+                _ => thrift.skip(kind).map_err(corrupt)?,
+            }
+        }
+        Ok((header, thrift.pos))
+    }
+}
+
+/// Decompresses a page compressed with `codec` into its `len` bytes.
+fn decompress(codec: i32, data: Vec<u8>, len: usize) -> RowResult<Vec<u8>> {
+    let corrupt = || RowError::from(invalid_parquet("a compressed page is corrupt"));
+    let page = match codec {
+        0 => data,
+        1 => unsnappy(&data, len).ok_or_else(corrupt)?,
+        2 => {
+            let mut page = Vec::with_capacity(len);
+            flate2::read::MultiGzDecoder::new(data.as_slice())
+                .take(len as u64 + 1)
+                .read_to_end(&mut page)
+                .map_err(|_| corrupt())?;
+            page
+        }
+        #[cfg(feature = "zstd")]
+        6 => zstd::bulk::decompress(&data, len).map_err(|_| corrupt())?,
+        codec => {
+            let name = usize::try_from(codec)
+                .ok()
+                .and_then(|codec| CODECS.get(codec))
+                .copied()
+                .unwrap_or("unknown");
+            return Err(RowError::Unsupported(format!("{} compression", name)));
+        }
+    };
+    if page.len() != len {
+        return Err(corrupt());
+    }
+    Ok(page)
+}
+
+/// Decompresses a raw Snappy block of `len` bytes: literals and copies of earlier output.
+fn unsnappy(data: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut hybrid = Hybrid::new(data, 0);
+    if usize::try_from(hybrid.varint()?).ok()? != len {
+        return None;
+    }
+    let mut pos = hybrid.pos;
+    let mut page = Vec::with_capacity(len);
+    let le = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .rev()
+            .fold(0usize, |value, &byte| value << 8 | byte as usize)
+    };
+    while pos < data.len() {
+        let tag = data[pos];
+        pos += 1;
+        let (copy_len, offset_len) = match tag & 3 {
+            0 => {
+                let mut literal_len = (tag >> 2) as usize;
+                if literal_len >= 60 {
+                    let extra = literal_len - 59;
+                    literal_len = le(data.get(pos..pos + extra)?);
+                    pos += extra;
+                }
+                let literal = data.get(pos..pos.checked_add(literal_len + 1)?)?;
+                page.extend_from_slice(literal);
+                pos += literal.len();
+                if page.len() > len {
+                    return None;
+                }
+                continue;
+            }
+            1 => (4 + ((tag >> 2) & 7) as usize, 1),
+            2 => ((tag >> 2) as usize + 1, 2),
+            _ => ((tag >> 2) as usize + 1, 4),
+        };
+        let mut offset = le(data.get(pos..pos + offset_len)?);
+        if offset_len == 1 {
+            offset |= ((tag >> 5) as usize) << 8;
+        }
+        pos += offset_len;
+        if offset == 0 || offset > page.len() || page.len() + copy_len > len {
+            return None;
+        }
+        for _ in 0..copy_len {
+            page.push(page[page.len() - offset]);
+        }
+    }
+    Some(page)
+}
+
+/// The number of bits levels up to `max` are packed in.
+fn bit_width(max: u32) -> u32 {
+    u32::BITS - max.leading_zeros()
+}
+
+/// The levels of a version 1 data page starting at `at`, each up to `max`: their length as
+/// 4 little-endian bytes, then the levels in the RLE and bit-packed hybrid encoding. `None`
+/// when they are all 0 and left out.
+fn levels_v1<'a>(
+    page: &'a [u8],
+    at: &mut usize,
+    max: u32,
+    encoding: i32,
+) -> RowResult<Option<Hybrid<'a>>> {
+    if max == 0 {
+        return Ok(None);
+    }
+    if encoding != RLE {
+        return Err(unsupported_encoding(encoding));
+    }
+    let truncated = || invalid_parquet("the levels of a page are truncated");
+    let len = page
+        .get(*at..*at + 4)
+        .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+        .ok_or_else(truncated)?;
+    let levels = page
+        .get(*at + 4..)
+        .and_then(|rest| rest.get(..len))
+        .ok_or_else(truncated)?;
+    *at += 4 + len;
+    Ok(Some(Hybrid::new(levels, bit_width(max))))
+}
+
+/// The levels of a version 2 data page, each up to `max`, which come without their length.
+fn levels_v2(levels: &[u8], max: u32) -> Option<Hybrid<'_>> {
+    (max > 0).then(|| Hybrid::new(levels, bit_width(max)))
+}
+
+/// Reads the RLE and bit-packed hybrid encoding of levels and dictionary indices: runs of
+/// one repeated value, and groups of 8 values packed in `bit_width` bits each.
+struct Hybrid<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit_width: u32,
+    repeated: u64,
+    value: u32,
+    packed: u64,
+    /// The bit the next packed value starts at.
+    bit: usize,
+}
+
+impl<'a> Hybrid<'a> {
+    fn new(data: &'a [u8], bit_width: u32) -> Self {
+        Hybrid {
+            data,
+            pos: 0,
+            bit_width,
+            repeated: 0,
+            value: 0,
+            packed: 0,
+            bit: 0,
+        }
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.data.get(self.pos)?;
+            self.pos += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn next(&mut self) -> Result<u32> {
+        let truncated = || invalid_parquet("the levels or indices of a page are truncated");
+        loop {
+            if self.repeated > 0 {
+                self.repeated -= 1;
+                return Ok(self.value);
+            }
+            if self.packed > 0 {
+                self.packed -= 1;
+                let mut value = 0;
+                for i in 0..self.bit_width as usize {
+                    let bit = self.bit + i;
+                    let byte = self.data.get(bit / 8).ok_or_else(truncated)?;
+                    value |= u32::from(byte >> (bit % 8) & 1) << i;
+                }
+                self.bit += self.bit_width as usize;
+                return Ok(value);
+            }
+            let header = self.varint().ok_or_else(truncated)?;
+            if header & 1 == 1 {
+                // Groups of 8 values, whose bytes follow.
+                let groups = header >> 1;
+                self.packed = groups.saturating_mul(8);
+                self.bit = self.pos * 8;
+                let len = usize::try_from(groups.saturating_mul(u64::from(self.bit_width)))
+                    .unwrap_or(usize::MAX);
+                self.pos = self.pos.saturating_add(len).min(self.data.len());
+            } else {
+                let width = self.bit_width.div_ceil(8) as usize;
+                let value = self
+                    .data
+                    .get(self.pos..self.pos + width)
+                    .ok_or_else(truncated)?;
+                self.value = value
+                    .iter()
+                    .rev()
+                    .fold(0, |value, &byte| value << 8 | u32::from(byte));
+                self.pos += width;
+                self.repeated = header >> 1;
+            }
+        }
+    }
+}
+
+/// Reads values in the plain encoding, each formatted for the preview.
+struct Plain<'a> {
+    leaf: &'a Leaf,
+    data: &'a [u8],
+    pos: usize,
+    /// The next bit, for booleans, which take one each.
+    bit: usize,
+}
+
+impl<'a> Plain<'a> {
+    fn new(leaf: &'a Leaf, data: &'a [u8]) -> Self {
+        Plain {
+            leaf,
+            data,
+            pos: 0,
+            bit: 0,
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or_else(|| invalid_parquet("the values of a page are truncated"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn next(&mut self) -> Result<String> {
+        let hex = |bytes: &[u8]| {
+            let digits: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+            format!("0x{}", digits)
+        };
+        Ok(match self.leaf.physical {
+            0 => {
+                let byte = self
+                    .data
+                    .get(self.bit / 8)
+                    .ok_or_else(|| invalid_parquet("the values of a page are truncated"))?;
+                let value = byte >> (self.bit % 8) & 1 == 1;
+                self.bit += 1;
+                value.to_string()
+            }
+            1 => {
+                let value = i32::from_le_bytes(self.take(4)?.try_into().unwrap());
+                if self.leaf.unsigned {
+                    (value as u32).to_string()
+                } else {
+                    value.to_string()
+                }
+            }
+            2 => {
+                let value = i64::from_le_bytes(self.take(8)?.try_into().unwrap());
+                if self.leaf.unsigned {
+                    (value as u64).to_string()
+                } else {
+                    value.to_string()
+                }
+            }
+            3 => hex(self.take(12)?),
+            4 => f32::from_le_bytes(self.take(4)?.try_into().unwrap()).to_string(),
+            5 => f64::from_le_bytes(self.take(8)?.try_into().unwrap()).to_string(),
+            6 => {
+                let len = u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize;
+                let bytes = self.take(len)?;
+                match std::str::from_utf8(bytes) {
+                    Ok(text) => text.to_string(),
+                    Err(_) => hex(bytes),
+                }
+            }
+            7 => hex(self.take(self.leaf.type_length)?),
+            _ => return Err(invalid_parquet("a column has an unknown physical type")),
+        })
+    }
+}
+
+/// The values of a data page, in the order of the levels that say they are there.
+enum Values<'a> {
+    Plain(Plain<'a>),
+    /// Booleans in the RLE and bit-packed hybrid encoding, after their length.
+    Booleans(Hybrid<'a>),
+    Dictionary {
+        indices: Hybrid<'a>,
+        dictionary: &'a [String],
+    },
+}
+
+impl<'a> Values<'a> {
+    fn new(
+        leaf: &'a Leaf,
+        encoding: i32,
+        data: &'a [u8],
+        dictionary: Option<&'a [String]>,
+    ) -> RowResult<Self> {
+        match encoding {
+            PLAIN => Ok(Values::Plain(Plain::new(leaf, data))),
+            RLE if leaf.physical == 0 => Ok(Values::Booleans(Hybrid::new(
+                data.get(4..).unwrap_or_default(),
+                1,
+            ))),
+            PLAIN_DICTIONARY | RLE_DICTIONARY => {
+                let dictionary = dictionary
+                    .ok_or_else(|| invalid_parquet("a page refers to a missing dictionary"))?;
+                let (&bit_width, indices) = data.split_first().unwrap_or((&0, &[]));
+                Ok(Values::Dictionary {
+                    indices: Hybrid::new(indices, u32::from(bit_width.min(32))),
+                    dictionary,
+                })
+            }
+            encoding => Err(unsupported_encoding(encoding)),
+        }
+    }
+
+    fn next(&mut self) -> Result<String> {
+        match self {
+            Values::Plain(plain) => plain.next(),
+            Values::Booleans(bits) => Ok((bits.next()? == 1).to_string()),
+            Values::Dictionary {
+                indices,
+                dictionary,
+            } => {
+                let index = indices.next()? as usize;
+                dictionary
+                    .get(index)
+                    .cloned()
+                    .ok_or_else(|| invalid_parquet("a page refers past its dictionary"))
+            }
+        }
+    }
+}
+
+/// The values of a column in the first rows, gathered page by page.
+struct Cells<'a> {
+    leaf: &'a Leaf,
+    wanted: usize,
+    rows: Vec<Vec<String>>,
+    /// Set once the row after the last one wanted starts, which ends a repeated value.
+    complete: bool,
+}
+
+impl<'a> Cells<'a> {
+    fn new(leaf: &'a Leaf, wanted: usize) -> Self {
+        Cells {
+            leaf,
+            wanted,
+            rows: Vec::with_capacity(wanted),
+            complete: false,
+        }
+    }
+
+    fn done(&self) -> bool {
+        self.complete || (self.leaf.levels.repetition == 0 && self.rows.len() == self.wanted)
+    }
+
+    /// Reads the `count` levels of a data page and the values they say are there. A value is
+    /// there where the definition level is the highest, and starts a row where the
+    /// repetition level is 0.
+    fn read_page(
+        &mut self,
+        count: i32,
+        mut repetition: Option<Hybrid<'_>>,
+        mut definition: Option<Hybrid<'_>>,
+        mut values: Values<'_>,
+    ) -> Result<()> {
+        for _ in 0..count.max(0) {
+            let repeated = match &mut repetition {
+                Some(levels) => levels.next()?,
+                None => 0,
+            };
+            let defined = match &mut definition {
+                Some(levels) => levels.next()?,
+                None => 0,
+            };
+            if repeated == 0 {
+                if self.rows.len() == self.wanted {
+                    self.complete = true;
+                    return Ok(());
+                }
+                self.rows.push(Vec::new());
+            }
+            if defined == self.leaf.levels.definition {
+                let value = values.next()?;
+                if let Some(row) = self.rows.last_mut() {
+                    row.push(value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Vec<String> {
+        let repeated = self.leaf.levels.repetition > 0;
+        self.rows
+            .into_iter()
+            .map(|mut row| {
+                if repeated {
+                    format!("[{}]", row.join(", "))
+                } else {
+                    row.pop().unwrap_or_default()
+                }
+            })
+            .collect()
+    }
+}
+
+/// Reads the first `count` records of text content, a record ending at a newline outside
+/// double quotes.
+async fn records(content: impl AsyncRead + Unpin, count: usize) -> Result<Vec<String>> {
+    let mut content = BufReader::new(content);
+    let mut records = Vec::with_capacity(count);
+    let mut record = Vec::new();
+    while records.len() < count {
+        let read = content.read_until(b'\n', &mut record).await?;
+        let quotes = record.iter().filter(|&&byte| byte == b'"').count();
+        if read > 0 && quotes % 2 == 1 && record.len() < MAX_LINE_LEN {
+            continue;
+        }
+        if record.is_empty() {
+            break;
+        }
+        let text = String::from_utf8_lossy(&record);
+        records.push(text.trim_end_matches(['\r', '\n']).to_string());
+        record.clear();
+        if read == 0 {
+            break;
+        }
+    }
+    Ok(records)
+}
+
+/// Splits a CSV record into its fields, unquoting quoted ones.
+fn split_csv(record: &str, separator: char) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = record.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// The narrowest type all `values` fit: `boolean`, `integer`, `float`, `date`, `timestamp`
+/// or `string`; `null` without any value.
+fn infer_type<'a>(values: impl Iterator<Item = &'a str>) -> &'static str {
+    let mut inferred = None;
+    for value in values {
+        let kind = if value.eq_ignore_ascii_case("true") || value.eq_ignore_ascii_case("false") {
+            "boolean"
+        } else if value.parse::<i64>().is_ok() {
+            "integer"
+        } else if value.parse::<f64>().is_ok() {
+            "float"
+        } else if is_date(value) && value.len() == 10 {
+            "date"
+        } else if is_date(value) && matches!(value.as_bytes().get(10), Some(b'T' | b' ')) {
+            "timestamp"
+        } else {
+            "string"
+        };
+        inferred = Some(match (inferred, kind) {
+            (None, kind) => kind,
+            (Some(seen), kind) if seen == kind => kind,
+            (Some("integer" | "float"), "integer" | "float") => "float",
+            (Some("date" | "timestamp"), "date" | "timestamp") => "timestamp",
+            _ => "string",
+        });
+    }
+    inferred.unwrap_or("null")
+}
+
+/// Whether `value` starts with a `YYYY-MM-DD` date.
+fn is_date(value: &str) -> bool {
+    let bytes = value.as_bytes();
+    bytes.len() >= 10
+        && bytes[..10].iter().enumerate().all(|(i, byte)| match i {
+            4 | 7 => *byte == b'-',
+            _ => byte.is_ascii_digit(),
+        })
+}
+
+fn json_type(value: &Value) -> Option<&'static str> {
+    Some(match value {
+        Value::Null => return None,
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    })
+}
+
+/// A node of a Parquet schema.
+#[derive(Default)]
+struct SchemaElement {
+    physical: Option<i32>,
+    type_length: Option<i32>,
+    repetition: Option<i32>,
+    name: String,
+    num_children: i32,
+    converted: Option<i32>,
+    logical: Option<&'static str>,
+}
+
+const PHYSICAL_TYPES: &[&str] = &[
+    "boolean",
+    "int32",
+    "int64",
+    "int96",
+    "float",
+    "double",
+    "binary",
+    "fixed_len_byte_array",
+];
+
+const CONVERTED_TYPES: &[&str] = &[
+    "UTF8",
+    "MAP",
+    "MAP_KEY_VALUE",
+    "LIST",
+    "ENUM",
+    "DECIMAL",
+    "DATE",
+    "TIME_MILLIS",
+    "TIME_MICROS",
+    "TIMESTAMP_MILLIS",
+    "TIMESTAMP_MICROS",
+    "UINT_8",
+    "UINT_16",
+    "UINT_32",
+    "UINT_64",
+    "INT_8",
+    "INT_16",
+    "INT_32",
+    "INT_64",
+    "JSON",
+    "BSON",
+    "INTERVAL",
+];
+
+/// Names of the members of the `LogicalType` union, by field id.
+const LOGICAL_TYPES: &[&str] = &[
+    "",
+    "STRING",
+    "MAP",
+    "LIST",
+    "ENUM",
+    "DECIMAL",
+    "DATE",
+    "TIME",
+    "TIMESTAMP",
+    "",
+    "INTEGER",
+    "UNKNOWN",
+    "JSON",
+    "BSON",
+    "UUID",
+    "FLOAT16",
+    "VARIANT",
+    "GEOMETRY",
+    "GEOGRAPHY",
+];
+
+impl SchemaElement {
+    fn read(thrift: &mut Compact<'_>) -> Result<Self> {
+        let mut element = SchemaElement::default();
+        let mut last = 0;
+        while let Some((id, kind)) = thrift.field(&mut last)? {
+            match (id, kind) {
+                (1, I32) => element.physical = Some(thrift.zigzag()? as i32),
+                (2, I32) => element.type_length = Some(thrift.zigzag()? as i32),
+                (3, I32) => element.repetition = Some(thrift.zigzag()? as i32),
+                (4, BINARY) => {
+                    element.name = String::from_utf8_lossy(thrift.binary()?).into_owned()
+                }
+                (5, I32) => element.num_children = thrift.zigzag()? as i32,
+                (6, I32) => element.converted = Some(thrift.zigzag()? as i32),
+                (10, STRUCT) => {
+                    // A union: one field, whose id names the logical type.
+                    let mut last = 0;
+                    while let Some((id, kind)) = thrift.field(&mut last)? {
+                        element.logical = LOGICAL_TYPES.get(id as usize).copied();
+                        thrift.skip(kind)?;
+                    }
+                }
+                _ => thrift.skip(kind)?,
+            }
+        }
+        Ok(element)
+    }
+
+    fn data_type(&self) -> String {
+        let physical = match self.physical {
+            Some(physical) => PHYSICAL_TYPES
+                .get(physical as usize)
+                .copied()
+                .unwrap_or("?"),
+            None => "group",
+        };
+        let logical = self
+            .logical
+            .filter(|logical| !logical.is_empty())
+            .or_else(|| {
+                self.converted
+                    .and_then(|converted| CONVERTED_TYPES.get(converted as usize).copied())
+            });
+        match logical {
+            Some(logical) => format!("{} ({})", physical, logical),
+            None => physical.to_string(),
+        }
+    }
+}
+
+const I32: u8 = 5;
+const I64: u8 = 6;
+const BINARY: u8 = 8;
+const LIST: u8 = 9;
+const STRUCT: u8 = 12;
+
+/// Reads the Thrift compact protocol Parquet metadata is written in.
+struct Compact<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Compact<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Compact { data, pos: 0 }
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let byte = *self
+            .data
+            .get(self.pos)
+            .ok_or_else(|| invalid_parquet("its footer is truncated"))?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid_parquet("its footer has an overlong integer"))
+    }
+
+    fn zigzag(&mut self) -> Result<i64> {
+        let value = self.varint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    fn binary(&mut self) -> Result<&'a [u8]> {
+        let len = self.varint()? as usize;
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or_else(|| invalid_parquet("its footer is truncated"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// The id and type of the next field of a struct, after the one with id `last`; `None`
+    /// at its end.
+    fn field(&mut self, last: &mut i16) -> Result<Option<(i16, u8)>> {
+        let header = self.byte()?;
+        if header == 0 {
+            return Ok(None);
+        }
+        let delta = (header >> 4) as i16;
+        *last = match delta {
+            0 => self.zigzag()? as i16,
+            delta => last
+                .checked_add(delta)
+                .ok_or_else(|| invalid_parquet("its footer has an invalid field id"))?,
+        };
+        Ok(Some((*last, header & 0x0f)))
+    }
+
+    /// The length and element type of a list or set.
+    fn list(&mut self) -> Result<(usize, u8)> {
+        let header = self.byte()?;
+        let len = match header >> 4 {
+            15 => self.varint()?,
+            len => u64::from(len),
+        };
+        Ok((self.count(len)?, header & 0x0f))
+    }
+
+    /// `len` elements of a list, set or map, each of which takes at least a byte.
+    fn count(&self, len: u64) -> Result<usize> {
+        match usize::try_from(len) {
+            Ok(len) if len <= self.data.len() - self.pos.min(self.data.len()) => Ok(len),
+            _ => Err(invalid_parquet("its footer is truncated")),
+        }
+    }
+
+    fn skip(&mut self, kind: u8) -> Result<()> {
+        self.skip_nested(kind, 0)
+    }
+
+    /// Skips a value of type `kind`, `depth` structs, lists or maps down.
+    fn skip_nested(&mut self, kind: u8, depth: usize) -> Result<()> {
+        if depth > MAX_THRIFT_DEPTH {
+            return Err(invalid_parquet("its footer is nested too deeply"));
+        }
+        match kind {
+            // Booleans in a field header carry their value; in a list, they take a byte.
+            1 | 2 => {}
+            3 => self.pos += 1,
+            4..=6 => {
+                self.varint()?;
+            }
+            7 => self.pos += 8,
+            BINARY => {
+                self.binary()?;
+            }
+            LIST | 10 => {
+                let (len, element) = self.list()?;
+                for _ in 0..len {
+                    self.skip_element(element, depth + 1)?;
+                }
+            }
+            11 => {
+                let len = self.varint()?;
+                if len > 0 {
+                    let kinds = self.byte()?;
+                    for _ in 0..self.count(len)? {
+                        self.skip_element(kinds >> 4, depth + 1)?;
+                        self.skip_element(kinds & 0x0f, depth + 1)?;
+                    }
+                }
+            }
+            STRUCT => {
+                let mut last = 0;
+                while let Some((_, kind)) = self.field(&mut last)? {
+                    self.skip_nested(kind, depth + 1)?;
+                }
+            }
+            _ => return Err(invalid_parquet("its footer has an unknown Thrift type")),
+        }
+        if self.pos > self.data.len() {
+            return Err(invalid_parquet("its footer is truncated"));
+        }
+        Ok(())
+    }
+
+    /// Skips an element of a list, set or map, where booleans take a byte of their own.
+    fn skip_element(&mut self, kind: u8, depth: usize) -> Result<()> {
+        match kind {
+            1 | 2 => {
+                self.byte()?;
+                Ok(())
+            }
+            kind => self.skip_nested(kind, depth),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    /// Writes the Thrift compact protocol, for Parquet metadata.
+    #[derive(Default)]
+    struct Thrift {
+        bytes: Vec<u8>,
+        last: Vec<i16>,
+    }
+
+    impl Thrift {
+        fn begin(&mut self) -> &mut Self {
+            self.last.push(0);
+            self
+        }
+
+        fn end(&mut self) -> &mut Self {
+            self.bytes.push(0);
+            self.last.pop();
+            self
+        }
+
+        fn field(&mut self, id: i16, kind: u8) -> &mut Self {
+            let last = self.last.last_mut().unwrap();
+            self.bytes.push(((id - *last) as u8) << 4 | kind);
+            *last = id;
+            self
+        }
+
+        fn varint(&mut self, mut value: u64) -> &mut Self {
+            while value >= 0x80 {
+                self.bytes.push(value as u8 | 0x80);
+                value >>= 7;
+            }
+            self.bytes.push(value as u8);
+            self
+        }
+
+        fn int(&mut self, value: i64) -> &mut Self {
+            self.varint(((value << 1) ^ (value >> 63)) as u64)
+        }
+
+        fn binary(&mut self, bytes: &[u8]) -> &mut Self {
+            self.varint(bytes.len() as u64);
+            self.bytes.extend(bytes);
+            self
+        }
+
+        fn list(&mut self, len: usize, kind: u8) -> &mut Self {
+            self.bytes.push((len as u8) << 4 | kind);
+            self
+        }
+    }
+
+    /// A page of `kind` holding `data`, `uncompressed` bytes once decompressed, whose header
+    /// has the fields `details` writes in its struct of field `id`.
+    fn page(
+        kind: i64,
+        uncompressed: usize,
+        data: &[u8],
+        id: i16,
+        details: impl FnOnce(&mut Thrift),
+    ) -> Vec<u8> {
+        let mut thrift = Thrift::default();
+        thrift
+            .begin()
+            .field(1, I32)
+            .int(kind)
+            .field(2, I32)
+            .int(uncompressed as i64)
+            .field(3, I32)
+            .int(data.len() as i64)
+            .field(id, STRUCT)
+            .begin();
+        details(&mut thrift);
+        thrift.end().end();
+        let mut page = thrift.bytes;
+        page.extend(data);
+        page
+    }
+
+    /// `data` as a Snappy block of one literal.
+    fn snappy(data: &[u8]) -> Vec<u8> {
+        let mut block = vec![data.len() as u8, ((data.len() - 1) as u8) << 2];
+        block.extend(data);
+        block
+    }
+
+    /// Version 1 data page fields: the number of values and their encodings.
+    fn data_page(thrift: &mut Thrift, values: i64) {
+        thrift
+            .field(1, I32)
+            .int(values)
+            .field(2, I32)
+            .int(0)
+            .field(3, I32)
+            .int(3)
+            .field(4, I32)
+            .int(3);
+    }
+
+    /// A Parquet file of 3 rows: an optional int64 `a` compressed with `codec_a`, a required
+    /// string `s` in a Snappy dictionary page and version 2 data page, and a repeated int32
+    /// `t` compressed with gzip.
+    fn parquet_file(codec_a: i64) -> Vec<u8> {
+        let mut file = b"PAR1".to_vec();
+
+        // Definition levels 1, 0, 1, then the values there are.
+        let mut data = vec![2, 0, 0, 0, 0x03, 0b101];
+        data.extend(1i64.to_le_bytes());
+        data.extend(3i64.to_le_bytes());
+        let a_offset = file.len() as i64;
+        file.extend(page(0, data.len(), &data, 5, |thrift| data_page(thrift, 3)));
+        let a_len = file.len() as i64 - a_offset;
+
+        let dictionary = [1, 0, 0, 0, b'x', 2, 0, 0, 0, b'y', b'y'];
+        let s_offset = file.len() as i64;
+        file.extend(page(
+            2,
+            dictionary.len(),
+            &snappy(&dictionary),
+            7,
+            |thrift| {
+                thrift.field(1, I32).int(2).field(2, I32).int(0);
+            },
+        ));
+        // Indices 0, 1, 0 of one bit each.
+        let indices = [1, 0x03, 0b010];
+        let s_data_offset = file.len() as i64;
+        file.extend(page(3, indices.len(), &snappy(&indices), 8, |thrift| {
+            thrift
+                .field(1, I32)
+                .int(3)
+                .field(2, I32)
+                .int(0)
+                .field(3, I32)
+                .int(3)
+                .field(4, I32)
+                .int(8)
+                .field(5, I32)
+                .int(0)
+                .field(6, I32)
+                .int(0);
+        }));
+        let s_len = file.len() as i64 - s_offset;
+
+        // Repetition levels 0, 1, 0, 0 and definition levels 1, 1, 0, 1: [1, 2], [], [3].
+        let mut data = vec![2, 0, 0, 0, 0x03, 0b0010, 2, 0, 0, 0, 0x03, 0b1011];
+        for value in [1i32, 2, 3] {
+            data.extend(value.to_le_bytes());
+        }
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&data).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let t_offset = file.len() as i64;
+        file.extend(page(0, data.len(), &gzip, 5, |thrift| data_page(thrift, 4)));
+        let t_len = file.len() as i64 - t_offset;
+
+        let mut thrift = Thrift::default();
+        thrift
+            .begin()
+            .field(1, I32)
+            .int(2)
+            .field(2, LIST)
+            .list(4, STRUCT);
+        thrift
+            .begin()
+            .field(4, BINARY)
+            .binary(b"schema")
+            .field(5, I32)
+            .int(3)
+            .end();
+        for (name, physical, repetition) in [("a", 2, 1), ("s", 6, 0), ("t", 1, 2)] {
+            thrift
+                .begin()
+                .field(1, I32)
+                .int(physical)
+                .field(3, I32)
+                .int(repetition)
+                .field(4, BINARY)
+                .binary(name.as_bytes());
+            if name == "s" {
+                thrift.field(6, I32).int(0);
+            }
+            thrift.end();
+        }
+        thrift.field(3, I64).int(3).field(4, LIST).list(1, STRUCT);
+        thrift.begin().field(1, LIST).list(3, STRUCT);
+        let chunks = [
+            (codec_a, 3, a_offset, a_len, a_offset, None),
+            (1, 3, s_offset, s_len, s_data_offset, Some(s_offset)),
+            (2, 4, t_offset, t_len, t_offset, None),
+        ];
+        for (codec, values, offset, len, data_offset, dictionary_offset) in chunks {
+            thrift
+                .begin()
+                .field(2, I64)
+                .int(offset)
+                .field(3, STRUCT)
+                .begin()
+                .field(4, I32)
+                .int(codec)
+                .field(5, I64)
+                .int(values)
+                .field(7, I64)
+                .int(len)
+                .field(9, I64)
+                .int(data_offset);
+            if let Some(dictionary_offset) = dictionary_offset {
+                thrift.field(11, I64).int(dictionary_offset);
+            }
+            thrift.end().end();
+        }
+        thrift.field(3, I64).int(3).end().end();
+
+        file.extend(&thrift.bytes);
+        file.extend((thrift.bytes.len() as u32).to_le_bytes());
+        file.extend(b"PAR1");
+        file
+    }
+
+    /// Reads the footer of a Parquet `file` and its first `count` rows.
+    async fn parquet_rows(file: &[u8], count: usize) -> Result<Option<Vec<Vec<String>>>> {
+        let tail_start = file.len() as u64 - PARQUET_TAIL_LEN;
+        let start = parquet_footer_start(&file[tail_start as usize..], tail_start)?;
+        let footer = read_parquet_footer(&file[start as usize..tail_start as usize])?;
+        footer
+            .rows(count, |range| {
+                let end = (range.end as usize).min(file.len());
+                let bytes = file[(range.start as usize).min(end)..end].to_vec();
+                async move { Ok(bytes) }
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn decodes_the_first_rows_of_a_parquet_file() {
+        let file = parquet_file(0);
+        let rows = parquet_rows(&file, 10).await.unwrap().unwrap();
+        assert_eq!(
+            rows,
+            [["1", "x", "[1, 2]"], ["", "yy", "[]"], ["3", "x", "[3]"]]
+        );
+        let rows = parquet_rows(&file, 2).await.unwrap().unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1], ["", "yy", "[]"]);
+    }
+
+    #[tokio::test]
+    async fn leaves_out_rows_compressed_in_other_ways() {
+        // Brotli.
+        let file = parquet_file(4);
+        assert!(parquet_rows(&file, 10).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_footers_longer_than_the_file() {
+        let mut file = parquet_file(0);
+        let len = file.len();
+        file[len - 8..len - 4].copy_from_slice(&(len as u32).to_le_bytes());
+        let err = parquet_rows(&file, 10).await.unwrap_err();
+        assert!(err.to_string().contains("its footer is truncated"), "{err}");
+    }
+
+    #[test]
+    fn decompresses_snappy_copies() {
+        // The literal `abc`, then 6 bytes copied from 3 back.
+        let block = [9, 0x08, b'a', b'b', b'c', 0x09, 0x03];
+        assert_eq!(unsnappy(&block, 9).unwrap(), b"abcabcabc");
+        assert!(unsnappy(&block, 10).is_none());
+        let from_before_the_start = [9, 0x08, b'a', b'b', b'c', 0x09, 0x04];
+        assert!(unsnappy(&from_before_the_start, 9).is_none());
+    }
+
+    #[test]
+    fn reads_a_parquet_schema() {
+        // A root holding one optional int64 column named `a`, and 3 rows.
+        let footer = [
+            0x29, 0x2c, 0x48, 0x01, b'r', 0x15, 0x02, 0x00, 0x15, 0x04, 0x25, 0x02, 0x18, 0x01,
+            b'a', 0x00, 0x16, 0x06, 0x00,
+        ];
+        let preview = read_parquet_footer(&footer).unwrap().preview;
+        assert_eq!(preview.row_count, Some(3));
+        assert_eq!(preview.columns.len(), 1);
+        assert_eq!(preview.columns[0].name, "a");
+        assert_eq!(preview.columns[0].data_type, "int64");
+        assert!(preview.columns[0].nullable);
+        assert!(preview.rows.is_none());
+    }
+
+    #[test]
+    fn rejects_more_schema_elements_than_the_root_holds() {
+        let footer = [
+            0x29, 0x2c, 0x48, 0x01, b'r', 0x00, 0x48, 0x01, b'a', 0x00, 0x00,
+        ];
+        assert!(read_parquet_footer(&footer).is_err());
+    }
+
+    #[test]
+    fn rejects_lists_longer_than_the_footer() {
+        let mut footer = vec![0x19, 0xf1];
+        footer.extend([0xff; 9]);
+        footer.push(0x01);
+        assert!(read_parquet_footer(&footer).is_err());
+
+        let mut footer = vec![0x1b];
+        footer.extend([0xff; 9]);
+        footer.extend([0x01, 0x11]);
+        assert!(read_parquet_footer(&footer).is_err());
+    }
+
+    #[test]
+    fn rejects_deeply_nested_structs() {
+        let footer = vec![0x1c; 100_000];
+        assert!(read_parquet_footer(&footer).is_err());
+    }
+
+    #[test]
+    fn rejects_overflowing_field_ids() {
+        // Field 32767, then one 15 ids further.
+        let footer = [0x05, 0xfe, 0xff, 0x03, 0x00, 0xf5, 0x00, 0x00];
+        assert!(read_parquet_footer(&footer).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::metadata::FileMetadata;
+use crate::metrics;
+
+/// Where an extraction or verification stands, as passed to a [`ProgressCallback`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub entries_done: usize,
+    pub entries_total: usize,
+    /// Compressed bytes received from the backend.
+    pub bytes_downloaded: u64,
+    pub download_total: u64,
+    /// Decompressed bytes written out, or only checked when verifying.
+    pub bytes_written: u64,
+    pub write_total: u64,
+}
+
+/// Called from the download and decompression tasks every time a counter moves, so it should
+/// return quickly.
+pub type ProgressCallback = Arc<dyn Fn(&Progress) + Send + Sync>;
+
+/// Counts the progress of one batch and reports it to the callback, if there is one.
+pub(crate) struct ProgressTracker {
+    callback: Option<ProgressCallback>,
+    entries_total: usize,
+    download_total: u64,
+    write_total: u64,
+    entries_done: AtomicUsize,
+    bytes_downloaded: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+impl ProgressTracker {
+    pub fn new<'a>(
+        callback: Option<ProgressCallback>,
+        planned: impl IntoIterator<Item = &'a FileMetadata>,
+    ) -> Self {
+        let mut tracker = ProgressTracker {
+            callback,
+            entries_total: 0,
+            download_total: 0,
+            write_total: 0,
+            entries_done: AtomicUsize::new(0),
+            bytes_downloaded: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+        };
+        for metadata in planned.into_iter().filter(|meta| !meta.is_directory) {
+            tracker.entries_total += 1;
+            tracker.download_total += metadata.compressed_size;
+            tracker.write_total += metadata.uncompressed_size;
+        }
+        tracker
+    }
+
+    pub fn downloaded(&self, n: u64) {
+        self.bytes_downloaded.fetch_add(n, Ordering::Relaxed);
+        self.report();
+    }
+
+    pub fn written(&self, n: u64) {
+        self.bytes_written.fetch_add(n, Ordering::Relaxed);
+        metrics::written(n);
+        self.report();
+    }
+
+    pub fn entry_done(&self) {
+        self.entries_done.fetch_add(1, Ordering::Relaxed);
+        self.report();
+    }
+
+    fn report(&self) {
+        if let Some(callback) = &self.callback {
+            callback(&Progress {
+                entries_done: self.entries_done.load(Ordering::Relaxed),
+                entries_total: self.entries_total,
+                bytes_downloaded: self.bytes_downloaded.load(Ordering::Relaxed),
+                download_total: self.download_total,
+                bytes_written: self.bytes_written.load(Ordering::Relaxed),
+                write_total: self.write_total,
+            });
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use bytes::Bytes;
+use http_body::{Frame, SizeHint};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::metrics::MetricsRecorder;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 15] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+/// Counts of the requests or jobs a long-running process handles, by outcome, their
+/// latencies and the reads of its backends, rendered in the Prometheus text format.
+pub struct PrometheusMetrics {
+    /// What is handled, in the singular, e.g. `http_request` or `job`.
+    subject: &'static str,
+    /// Name of the label the outcome is given in.
+    label: &'static str,
+    backends: Arc<MetricsRecorder>,
+    handled: Mutex<Handled>,
+}
+
+#[derive(Default)]
+struct Handled {
+    by_outcome: BTreeMap<String, u64>,
+    /// Observations at or below each of [`LATENCY_BUCKETS`].
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    seconds: f64,
+    count: u64,
+}
+
+impl PrometheusMetrics {
+    /// Metrics for `cloud_zip_{subject}s_total{label="..."}` and
+    /// `cloud_zip_{subject}_duration_seconds`, plus the totals of `backends`, which should
+    /// be the [`BackendOptions::metrics`](crate::BackendOptions::metrics) of the process.
+    pub fn new(subject: &'static str, label: &'static str, backends: Arc<MetricsRecorder>) -> Self {
+        PrometheusMetrics {
+            subject,
+            label,
+            backends,
+            handled: Mutex::default(),
+        }
+    }
+
+    /// Counts one request or job that ended with `outcome` after `elapsed`.
+    pub fn observe(&self, outcome: &str, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let mut handled = self.handled.lock().unwrap_or_else(PoisonError::into_inner);
+        *handled.by_outcome.entry(outcome.to_string()).or_default() += 1;
+        for (bucket, bound) in handled.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        handled.seconds += seconds;
+        handled.count += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let name = format!("cloud_zip_{}s_total", self.subject);
+        header(&mut out, &name, "counter", "Handled so far, by outcome.");
+        {
+            let handled = self.handled.lock().unwrap_or_else(PoisonError::into_inner);
+            for (outcome, count) in &handled.by_outcome {
+                let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, self.label, outcome, count);
+            }
+            let name = format!("cloud_zip_{}_duration_seconds", self.subject);
+            header(
+                &mut out,
+                &name,
+                "histogram",
+                "Time taken to handle, whatever the outcome.",
+            );
+            for (bucket, bound) in handled.buckets.iter().zip(LATENCY_BUCKETS) {
+                let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, bucket);
+            }
+            let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, handled.count);
+            let _ = writeln!(out, "{}_sum {}", name, handled.seconds);
+            let _ = writeln!(out, "{}_count {}", name, handled.count);
+        }
+        let backends = self.backends.metrics();
+        for (name, help, value) in [
+            (
+                "cloud_zip_backend_requests_total",
+                "Reads sent to the storage backends, retries included.",
+                backends.requests,
+            ),
+            (
+                "cloud_zip_backend_errors_total",
+                "Reads of the storage backends that failed, retried or not.",
+                backends.errors,
+            ),
+            (
+                "cloud_zip_backend_retries_total",
+                "Reads sent again after a transient failure.",
+                backends.retries,
+            ),
+            (
+                "cloud_zip_downloaded_bytes_total",
+                "Bytes received from the storage backends.",
+                backends.bytes_downloaded,
+            ),
+            (
+                "cloud_zip_cache_hits_total",
+                "Ranges served from the range cache.",
+                backends.cache_hits,
+            ),
+            (
+                "cloud_zip_cache_misses_total",
+                "Ranges the range cache had to read from its backend.",
+                backends.cache_misses,
+            ),
+        ] {
+            header(&mut out, name, "counter", help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// A router answering `GET /metrics` with `metrics`.
+pub fn router(metrics: Arc<PrometheusMetrics>) -> Router {
+    Router::new()
+        .route("/metrics", get(render))
+        .with_state(metrics)
+}
+
+async fn render(State(metrics): State<Arc<PrometheusMetrics>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.render(),
+    )
+}
+
+/// Adds `GET /metrics` to `app`, and counts every other request it answers into `metrics`
+/// by status, timed until its body has been sent or the client went away.
+pub fn instrument(app: Router, metrics: Arc<PrometheusMetrics>) -> Router {
+    app.layer(middleware::from_fn_with_state(metrics.clone(), track))
+        .merge(router(metrics))
+}
+
+async fn track(
+    State(metrics): State<Arc<PrometheusMetrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let status = response.status();
+    response.map(|body| {
+        Body::new(TimedBody {
+            inner: body,
+            finished: Some(Finished {
+                metrics,
+                status,
+                started,
+            }),
+        })
+    })
+}
+
+/// Observes a request when dropped.
+struct Finished {
+    metrics: Arc<PrometheusMetrics>,
+    status: StatusCode,
+    started: Instant,
+}
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        self.metrics
+            .observe(self.status.as_str(), self.started.elapsed());
+    }
+}
+
+struct TimedBody {
+    inner: Body,
+    finished: Option<Finished>,
+}
+
+impl http_body::Body for TimedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(None | Some(Err(_))) = frame {
+            self.finished.take();
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+use crate::metadata::FileMetadata;
+
+/// One line of a job state file: an entry written completely, as it was in the index.
+#[derive(Serialize, Deserialize)]
+struct Finished {
+    entry: String,
+    size: u64,
+    crc32: Option<u32>,
+}
+
+/// The entries a batch extraction has finished, kept in a JSON Lines file appended to after
+/// each one, so that running the extraction again skips them.
+pub(crate) struct JobState {
+    path: PathBuf,
+    finished: HashMap<String, (u64, Option<u32>)>,
+    file: Mutex<File>,
+}
+
+impl JobState {
+    /// Reads what an earlier run recorded at `path`, if anything, and opens it for appending.
+    /// A line cut short by a crash is ignored.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut finished = HashMap::new();
+        match File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    if let Ok(line) = serde_json::from_str::<Finished>(&line?) {
+                        finished.insert(line.entry, (line.size, line.crc32));
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JobState {
+            path: path.to_path_buf(),
+            finished,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Whether an earlier run finished the entry as the index now describes it, and its
+    /// output is still at `output_path`.
+    pub fn is_finished(&self, metadata: &FileMetadata, output_path: &Path) -> bool {
+        let recorded = self.finished.get(&metadata.file_name);
+        if recorded != Some(&(metadata.uncompressed_size, metadata.crc32)) {
+            return false;
+        }
+        match output_path.symlink_metadata() {
+            Ok(local) if local.is_symlink() => true,
+            Ok(local) => local.is_file() && local.len() == metadata.uncompressed_size,
+            Err(_) => false,
+        }
+    }
+
+    pub fn finished_len(&self) -> usize {
+        self.finished.len()
+    }
+
+    pub fn record(&self, metadata: &FileMetadata) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&Finished {
+            entry: metadata.file_name.clone(),
+            size: metadata.uncompressed_size,
+            crc32: metadata.crc32,
+        })?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        file.write_all(&line)?;
+        file.flush()
+    }
+
+    /// Removes the file once the job is done, so the next run starts afresh.
+    pub fn finish(self) -> io::Result<()> {
+        drop(self.file);
+        std::fs::remove_file(&self.path)
+    }
+}
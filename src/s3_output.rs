@@ -0,0 +1,359 @@
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::Client;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Serialize;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::field::Empty;
+use tracing::{debug_span, warn, Instrument};
+
+use crate::backend::s3::request_error;
+use crate::error::{CloudZipError, Result};
+use crate::metadata::ArchiveFingerprint;
+
+/// Entries are uploaded in parts of this size; S3 wants at least 5 MiB for all but the last.
+const UPLOAD_PART_LEN: usize = 8 * 1024 * 1024;
+/// Size of the reads feeding an upload.
+const COPY_CHUNK_LEN: usize = 256 * 1024;
+/// Smallest part S3 accepts for all but the last.
+pub(crate) const MIN_PART_LEN: u64 = 5 * 1024 * 1024;
+/// Parts copied from another object may be up to 5 GiB; smaller ones are copied in parallel.
+const COPY_PART_LEN: u64 = 512 * 1024 * 1024;
+const COPY_CONCURRENCY: usize = 8;
+/// Keys in `x-amz-copy-source` are URL-encoded, except for their slashes.
+const COPY_SOURCE_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'/');
+
+/// Where extracted entries are written, parsed from `s3://bucket/prefix/`: each entry becomes
+/// the object named by the prefix followed by the entry name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Destination {
+    pub bucket: String,
+    /// Empty or ending with `/`.
+    pub prefix: String,
+}
+
+impl S3Destination {
+    /// The key of the object written for an entry at `path`, relative to the destination.
+    pub fn key(&self, path: &Path) -> String {
+        let components: Vec<_> = path
+            .iter()
+            .map(|component| component.to_string_lossy())
+            .collect();
+        format!("{}{}", self.prefix, components.join("/"))
+    }
+}
+
+impl FromStr for S3Destination {
+    type Err = CloudZipError;
+
+    fn from_str(destination: &str) -> Result<Self> {
+        let rest = destination.strip_prefix("s3://").ok_or_else(|| {
+            CloudZipError::InvalidLocation(format!(
+                "the destination must be an s3:// URI, not {}",
+                destination
+            ))
+        })?;
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            return Err(CloudZipError::InvalidLocation(format!(
+                "{} names no bucket",
+                destination
+            )));
+        }
+        let prefix = match prefix {
+            "" => String::new(),
+            prefix if prefix.ends_with('/') => prefix.to_string(),
+            prefix => format!("{}/", prefix),
+        };
+        Ok(S3Destination {
+            bucket: bucket.to_string(),
+            prefix,
+        })
+    }
+}
+
+impl fmt::Display for S3Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.prefix)
+    }
+}
+
+/// The objects written by [`CloudZip::extract_to_s3`](crate::CloudZip::extract_to_s3).
+#[derive(Debug, Default, Serialize)]
+pub struct ExtractedObjects {
+    /// `s3://` URIs, in archive order.
+    pub entries: Vec<String>,
+    /// Decompressed bytes uploaded.
+    pub bytes: u64,
+}
+
+/// Uploads everything `reader` yields as the object `key`, returning its size.
+pub(crate) async fn upload(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+    reader: impl AsyncRead + Unpin,
+) -> Result<u64> {
+    let span = debug_span!("write", bucket, key, bytes = Empty);
+    let size = upload_all(client, bucket, key, reader)
+        .instrument(span.clone())
+        .await?;
+    span.record("bytes", size);
+    Ok(size)
+}
+
+async fn upload_all(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+    mut reader: impl AsyncRead + Unpin,
+) -> Result<u64> {
+    let mut upload = S3Upload::new(client.clone(), bucket, key);
+    let mut chunk = vec![0; COPY_CHUNK_LEN];
+    loop {
+        let read = match reader.read(&mut chunk).await {
+            Ok(read) => read,
+            Err(err) => {
+                upload.abort().await;
+                return Err(err.into());
+            }
+        };
+        if read == 0 {
+            break;
+        }
+        if let Err(err) = upload.write(&chunk[..read]).await {
+            upload.abort().await;
+            return Err(err);
+        }
+    }
+    Ok(upload.finish().await?.size)
+}
+
+/// An object written piece by piece: with a single PUT when it ends up fitting in one part,
+/// and as a multipart upload otherwise, so memory holds one part whatever the size.
+///
+/// Dropping an upload without finishing or aborting it leaves its parts behind, billed until
+/// a lifecycle rule removes them.
+pub struct S3Upload {
+    client: Client,
+    bucket: String,
+    key: String,
+    buffer: Vec<u8>,
+    upload_id: Option<String>,
+    parts: Vec<CompletedPart>,
+    size: u64,
+}
+
+impl S3Upload {
+    pub fn new(client: Client, bucket: impl Into<String>, key: impl Into<String>) -> Self {
+        S3Upload {
+            client,
+            bucket: bucket.into(),
+            key: key.into(),
+            buffer: Vec::new(),
+            upload_id: None,
+            parts: Vec::new(),
+            size: 0,
+        }
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.size += data.len() as u64;
+        self.buffer.extend_from_slice(data);
+        while self.buffer.len() >= UPLOAD_PART_LEN {
+            let rest = self.buffer.split_off(UPLOAD_PART_LEN);
+            let part = std::mem::replace(&mut self.buffer, rest);
+            self.upload_part(part).await?;
+        }
+        Ok(())
+    }
+
+    /// Makes the first `len` bytes of another object the start of this one, copied inside S3
+    /// instead of downloaded and uploaded again. Only allowed before anything is written, and
+    /// for at least [`MIN_PART_LEN`] bytes.
+    ///
+    /// With `etag`, the copy fails if the source object no longer has it.
+    pub(crate) async fn copy_from(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        etag: Option<&str>,
+        len: u64,
+    ) -> Result<()> {
+        debug_assert!(self.size == 0 && len >= MIN_PART_LEN);
+        let mut copy_source = format!(
+            "{}/{}",
+            bucket,
+            utf8_percent_encode(key, COPY_SOURCE_ENCODE_SET)
+        );
+        if let Some(version_id) = version_id {
+            copy_source.push_str("?versionId=");
+            copy_source.extend(utf8_percent_encode(version_id, COPY_SOURCE_ENCODE_SET));
+        }
+        let upload_id = self.upload_id().await?;
+        let part_count = len.div_ceil(COPY_PART_LEN);
+        let part_len = len.div_ceil(part_count);
+        let first_part = self.parts.len() as i32 + 1;
+        let parts: Vec<CompletedPart> = stream::iter(0..part_count)
+            .map(|i| {
+                let start = i * part_len;
+                let end = len.min(start + part_len) - 1;
+                let part_number = first_part + i as i32;
+                let copied = self
+                    .client
+                    .upload_part_copy()
+                    .bucket(&self.bucket)
+                    .key(&self.key)
+                    .upload_id(&upload_id)
+                    .part_number(part_number)
+                    .copy_source(&copy_source)
+                    .copy_source_range(format!("bytes={}-{}", start, end))
+                    .set_copy_source_if_match(etag.map(str::to_string))
+                    .send();
+                async move {
+                    let copied = copied.await.map_err(request_error)?;
+                    Ok::<_, CloudZipError>(
+                        CompletedPart::builder()
+                            .part_number(part_number)
+                            .set_e_tag(copied.copy_part_result.and_then(|result| result.e_tag))
+                            .build(),
+                    )
+                }
+            })
+            .buffered(COPY_CONCURRENCY)
+            .try_collect()
+            .await?;
+        self.parts.extend(parts);
+        self.size += len;
+        Ok(())
+    }
+
+    /// The ID of the multipart upload, which is started on first use.
+    async fn upload_id(&mut self) -> Result<String> {
+        if let Some(upload_id) = &self.upload_id {
+            return Ok(upload_id.clone());
+        }
+        let upload_id = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .send()
+            .await
+            .map_err(request_error)?
+            .upload_id
+            .ok_or_else(|| {
+                CloudZipError::s3(std::io::Error::other("S3 returned no upload ID"), false)
+            })?;
+        Ok(self.upload_id.insert(upload_id).clone())
+    }
+
+    async fn upload_part(&mut self, data: Vec<u8>) -> Result<()> {
+        let upload_id = self.upload_id().await?;
+        let part_number = self.parts.len() as i32 + 1;
+        let part = self
+            .client
+            .upload_part()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .body(ByteStream::from(data))
+            .send()
+            .instrument(debug_span!(
+                "s3.put",
+                otel.kind = "client",
+                part = part_number
+            ))
+            .await
+            .map_err(request_error)?;
+        self.parts.push(
+            CompletedPart::builder()
+                .part_number(part_number)
+                .set_e_tag(part.e_tag)
+                .build(),
+        );
+        Ok(())
+    }
+
+    /// Stores the object and describes it as a later HEAD would.
+    pub async fn finish(mut self) -> Result<ArchiveFingerprint> {
+        let buffer = std::mem::take(&mut self.buffer);
+        let Some(upload_id) = self.upload_id.clone() else {
+            let put = self
+                .client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(&self.key)
+                .body(ByteStream::from(buffer))
+                .send()
+                .instrument(debug_span!("s3.put", otel.kind = "client"))
+                .await
+                .map_err(request_error)?;
+            return Ok(ArchiveFingerprint {
+                size: self.size,
+                etag: put.e_tag,
+                version_id: put.version_id,
+            });
+        };
+        if !buffer.is_empty() {
+            if let Err(err) = self.upload_part(buffer).await {
+                self.abort().await;
+                return Err(err);
+            }
+        }
+        let completed = self
+            .client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(&upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(std::mem::take(&mut self.parts)))
+                    .build(),
+            )
+            .send()
+            .await;
+        match completed {
+            Ok(completed) => Ok(ArchiveFingerprint {
+                size: self.size,
+                etag: completed.e_tag,
+                version_id: completed.version_id,
+            }),
+            Err(err) => {
+                self.abort().await;
+                Err(request_error(err))
+            }
+        }
+    }
+
+    /// Discards the parts uploaded so far.
+    pub async fn abort(self) {
+        let Some(upload_id) = &self.upload_id else {
+            return;
+        };
+        // Parts of an abandoned upload are billed until it is aborted.
+        if let Err(err) = self
+            .client
+            .abort_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(upload_id)
+            .send()
+            .await
+        {
+            warn!(key = %self.key, error = %err, "Could not abort the upload");
+        }
+    }
+}
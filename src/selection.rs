@@ -0,0 +1,117 @@
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+use crate::error::{CloudZipError, Result};
+use crate::metadata::FileMetadata;
+
+/// Picks entries out of an index by name; matching never touches the archive itself.
+#[derive(Debug, Clone)]
+pub enum EntrySelector {
+    /// Every entry in the archive.
+    All,
+    /// Exactly one entry name.
+    Name(String),
+    /// Entries whose name starts with the prefix, e.g. `data/r/`.
+    Prefix(String),
+    /// A shell glob such as `**/*.JPG`; `*` does not cross `/`.
+    Glob(GlobMatcher),
+    Regex(Regex),
+    /// Any of a set of exact names, e.g. the entries listed in a manifest.
+    Names(HashSet<String>),
+}
+
+impl EntrySelector {
+    pub fn glob(pattern: &str) -> Result<Self> {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|err| CloudZipError::InvalidPattern(err.to_string()))?;
+        Ok(EntrySelector::Glob(glob.compile_matcher()))
+    }
+
+    pub fn regex(pattern: &str) -> Result<Self> {
+        Regex::new(pattern)
+            .map(EntrySelector::Regex)
+            .map_err(|err| CloudZipError::InvalidPattern(err.to_string()))
+    }
+
+    pub fn matches(&self, file_name: &str) -> bool {
+        match self {
+            EntrySelector::All => true,
+            EntrySelector::Name(name) => file_name == name,
+            EntrySelector::Prefix(prefix) => file_name.starts_with(prefix.as_str()),
+            EntrySelector::Glob(glob) => glob.is_match(file_name),
+            EntrySelector::Regex(regex) => regex.is_match(file_name),
+            EntrySelector::Names(names) => names.contains(file_name),
+        }
+    }
+
+    pub fn select<'a>(&self, list: &'a [FileMetadata]) -> Vec<&'a FileMetadata> {
+        list.iter()
+            .filter(|meta| self.matches(&meta.file_name))
+            .collect()
+    }
+
+    /// Describes the selector in error messages.
+    pub fn describe(&self) -> String {
+        match self {
+            EntrySelector::All => "*".to_string(),
+            EntrySelector::Name(name) => name.clone(),
+            EntrySelector::Prefix(prefix) => format!("{}*", prefix),
+            EntrySelector::Glob(glob) => glob.glob().to_string(),
+            EntrySelector::Regex(regex) => format!("/{}/", regex),
+            EntrySelector::Names(names) => format!("{} listed entries", names.len()),
+        }
+    }
+}
+
+/// Picks entries as given in a JSON request, where at most one of the fields is set; with
+/// none, every entry is picked.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Selection {
+    pub name: Option<String>,
+    pub prefix: Option<String>,
+    pub glob: Option<String>,
+    pub regex: Option<String>,
+    /// Exact entry names.
+    pub entries: Option<Vec<String>>,
+}
+
+impl Selection {
+    pub fn selector(&self) -> Result<EntrySelector> {
+        match (
+            &self.name,
+            &self.prefix,
+            &self.glob,
+            &self.regex,
+            &self.entries,
+        ) {
+            (None, None, None, None, None) => Ok(EntrySelector::All),
+            (Some(name), None, None, None, None) => Ok(EntrySelector::Name(name.clone())),
+            (None, Some(prefix), None, None, None) => Ok(EntrySelector::Prefix(prefix.clone())),
+            (None, None, Some(glob), None, None) => EntrySelector::glob(glob),
+            (None, None, None, Some(regex), None) => EntrySelector::regex(regex),
+            (None, None, None, None, Some(entries)) => {
+                Ok(EntrySelector::Names(entries.iter().cloned().collect()))
+            }
+            _ => Err(CloudZipError::InvalidPattern(
+                "give only one of name, prefix, glob, regex and entries".to_string(),
+            )),
+        }
+    }
+}
+
+/// The smallest string greater than every string starting with `prefix`, if there is one;
+/// names compare as UTF-8 bytes, which order like their characters.
+pub(crate) fn prefix_end(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
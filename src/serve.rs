@@ -0,0 +1,341 @@
+use axum::body::Body;
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio_util::io::ReaderStream;
+use tracing::warn;
+
+use crate::archive::CloudZip;
+use crate::backend::split_range;
+use crate::content_type::GENERIC_TYPES;
+use crate::error::CloudZipError;
+use crate::extract::range_entry_reader;
+use crate::metadata::FileMetadata;
+
+/// Range requests are fetched in parts of this size, so memory use stays bounded whatever
+/// the client asks for.
+const RANGE_PART_LEN: u64 = 8 * 1024 * 1024;
+
+type Archives = Arc<HashMap<String, CloudZip>>;
+
+/// Routes serving each archive under `/{name}/`:
+///
+/// - `GET /` lists the archive names as a JSON array.
+/// - `GET /{name}/` lists the entries of an archive as JSON, and `GET /{name}/{dir}/` those
+///   below a directory.
+/// - `GET /{name}/{entry}` streams the decompressed entry, with its `Content-Length` and a
+///   `Content-Type` guessed from its name. Stored and deflated entries also answer
+///   single-range `Range` requests; the first one for a large deflated entry decodes it
+///   whole to find checkpoints in it.
+pub fn router(archives: HashMap<String, CloudZip>) -> Router {
+    Router::new()
+        .route("/", get(list_archives))
+        .route("/{archive}/", get(list_root))
+        .route("/{archive}/{*entry}", get(get_entry))
+        .with_state(Arc::new(archives))
+}
+
+/// One element of a listing.
+#[derive(Serialize)]
+struct ListedEntry<'a> {
+    name: &'a str,
+    size: u64,
+    compressed_size: u64,
+    /// Seconds since the Unix epoch.
+    modified: Option<i64>,
+    is_directory: bool,
+}
+
+/// A failure turned into a plain-text response.
+struct ServeError(StatusCode, String);
+
+impl From<CloudZipError> for ServeError {
+    fn from(err: CloudZipError) -> Self {
+        let status = match &err {
+            CloudZipError::EntryNotFound(_) => StatusCode::NOT_FOUND,
+            CloudZipError::PasswordRequired(_) | CloudZipError::WrongPassword(_) => {
+                StatusCode::FORBIDDEN
+            }
+            CloudZipError::S3 { .. }
+            | CloudZipError::DynamoDb { .. }
+            | CloudZipError::Http { .. } => StatusCode::BAD_GATEWAY,
+            CloudZipError::ArchiveChanged(_) => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        if status.is_server_error() {
+            warn!(error = %err, "Request failed");
+        }
+        ServeError(status, err.to_string())
+    }
+}
+
+impl IntoResponse for ServeError {
+    fn into_response(self) -> Response {
+        (self.0, self.1).into_response()
+    }
+}
+
+fn archive<'a>(archives: &'a Archives, name: &str) -> Result<&'a CloudZip, ServeError> {
+    archives
+        .get(name)
+        .ok_or_else(|| ServeError(StatusCode::NOT_FOUND, format!("No archive named {}", name)))
+}
+
+async fn list_archives(State(archives): State<Archives>) -> Json<Vec<String>> {
+    let mut names: Vec<_> = archives.keys().cloned().collect();
+    names.sort();
+    Json(names)
+}
+
+async fn list_root(
+    State(archives): State<Archives>,
+    Path(name): Path<String>,
+) -> Result<Response, ServeError> {
+    list(archive(&archives, &name)?, "").await
+}
+
+/// Lists the entries whose names start with `prefix`, or fails when there are none.
+async fn list(archive: &CloudZip, prefix: &str) -> Result<Response, ServeError> {
+    let entries = archive.verified_entries().await?;
+    let listed: Vec<_> = entries
+        .as_slice()
+        .iter()
+        .filter(|meta| meta.file_name.starts_with(prefix))
+        .map(|meta| ListedEntry {
+            name: &meta.file_name,
+            size: meta.uncompressed_size,
+            compressed_size: meta.compressed_size,
+            modified: meta.modified,
+            is_directory: meta.is_directory,
+        })
+        .collect();
+    if listed.is_empty() && !prefix.is_empty() {
+        return Err(CloudZipError::EntryNotFound(prefix.to_string()).into());
+    }
+    Ok(Json(listed).into_response())
+}
+
+async fn get_entry(
+    State(archives): State<Archives>,
+    Path((name, entry)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, ServeError> {
+    let archive = archive(&archives, &name)?;
+    if entry.ends_with('/') {
+        return list(archive, &entry).await;
+    }
+    let entries = archive.verified_entries().await?;
+    let metadata = match entries.get(&entry) {
+        Some(metadata) if !metadata.is_directory => metadata.clone(),
+        _ => {
+            let directory = format!("{}/", entry);
+            if !entries
+                .as_slice()
+                .iter()
+                .any(|meta| meta.file_name.starts_with(&directory))
+            {
+                return Err(CloudZipError::EntryNotFound(entry).into());
+            }
+            return list(archive, &directory).await;
+        }
+    };
+
+    let size = metadata.uncompressed_size;
+    let content_type = content_type(&entry, archive.detect_type(&entry).await?);
+    let response = Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(
+            header::ACCEPT_RANGES,
+            if ranges(&metadata) { "bytes" } else { "none" },
+        );
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .filter(|_| ranges(&metadata))
+        .and_then(|value| parse_range(value, size));
+    let response = match range {
+        None => response
+            .header(header::CONTENT_LENGTH, size)
+            .body(Body::from_stream(ReaderStream::new(
+                archive.open_entry(&entry).await?,
+            ))),
+        Some(None) => response
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+            .body(Body::empty()),
+        Some(Some((start, end))) => response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_LENGTH, end - start)
+            .header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end - 1, size),
+            )
+            .body(range_body(archive, &metadata, start, end).await?),
+    };
+    response.map_err(|err| ServeError(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+/// The `Content-Type` of an entry: the type its first bytes show, unless they only show a
+/// generic one, such as the zip under a `.docx`, and its name tells a more precise one.
+fn content_type(entry: &str, detected: Option<&'static str>) -> String {
+    let guessed = mime_guess::from_path(entry).first();
+    match (detected, guessed) {
+        (Some(detected), Some(guessed)) if GENERIC_TYPES.contains(&detected) => guessed.to_string(),
+        (Some(detected), _) => detected.to_string(),
+        (None, Some(guessed)) => guessed.to_string(),
+        (None, None) => mime_guess::mime::APPLICATION_OCTET_STREAM.to_string(),
+    }
+}
+
+/// Whether `Range` requests for the entry are answered.
+fn ranges(metadata: &FileMetadata) -> bool {
+    metadata.is_random_access() || metadata.is_checkpointable()
+}
+
+/// Streams bytes `start..end` of an entry: straight from the archive when it is stored, and
+/// through [`CloudZip::entry_reader`] when it is deflated.
+async fn range_body(
+    archive: &CloudZip,
+    metadata: &FileMetadata,
+    start: u64,
+    end: u64,
+) -> Result<Body, ServeError> {
+    if !metadata.is_random_access() {
+        let content = archive.entry_reader(&metadata.file_name).await?;
+        return Ok(Body::from_stream(ReaderStream::new(range_entry_reader(
+            content,
+            start,
+            end - start,
+        ))));
+    }
+    let reader = archive.reader().clone();
+    let parts = split_range(metadata.file_offset + start, end - start, RANGE_PART_LEN);
+    Ok(Body::from_stream(stream::iter(parts).then(
+        move |(offset, len)| {
+            let reader = reader.clone();
+            async move { reader.read_range(offset, len).await.map(Bytes::from) }
+        },
+    )))
+}
+
+/// Parses a single-range `Range` header into `start..end` of a body of `size` bytes.
+///
+/// Returns `None` for headers to ignore, which includes multiple ranges, and `Some(None)`
+/// when the range lies beyond the end.
+fn parse_range(value: &str, size: u64) -> Option<Option<(u64, u64)>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+    let (start, end) = if first.is_empty() {
+        let suffix: u64 = last.parse().ok()?;
+        (size.saturating_sub(suffix), size)
+    } else {
+        let start: u64 = first.parse().ok()?;
+        let end = match last {
+            "" => size,
+            last => last.parse::<u64>().ok()?.checked_add(1)?.min(size),
+        };
+        if end <= start && start < size {
+            return None;
+        }
+        (start, end)
+    };
+    Some((start < end).then_some((start, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range("bytes=0-9", 100), Some(Some((0, 10))));
+        assert_eq!(parse_range(" bytes= 10 - 19 ", 100), Some(Some((10, 20))));
+        // Open-ended, and reaching past the end.
+        assert_eq!(parse_range("bytes=90-", 100), Some(Some((90, 100))));
+        assert_eq!(parse_range("bytes=90-1000", 100), Some(Some((90, 100))));
+        // The last bytes.
+        assert_eq!(parse_range("bytes=-10", 100), Some(Some((90, 100))));
+        assert_eq!(parse_range("bytes=-1000", 100), Some(Some((0, 100))));
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_not_satisfiable() {
+        assert_eq!(parse_range("bytes=100-", 100), Some(None));
+        assert_eq!(parse_range("bytes=200-300", 100), Some(None));
+        assert_eq!(parse_range("bytes=-0", 100), Some(None));
+        assert_eq!(parse_range("bytes=0-", 0), Some(None));
+    }
+
+    #[test]
+    fn ignores_other_ranges() {
+        for value in [
+            "bytes=0-1,5-6",
+            "bytes=-5, 10-",
+            "bytes=9-5",
+            "bytes=-",
+            "bytes=a-b",
+            "bytes=0-18446744073709551615",
+            "items=0-1",
+            "0-1",
+        ] {
+            assert_eq!(parse_range(value, 100), None, "{value}");
+        }
+    }
+
+    async fn get(range: &str) -> Response {
+        let content: Vec<u8> = (0..100u8).collect();
+        let backend = MemoryBackend::stored_zip([("data.bin", content)])
+            .await
+            .unwrap();
+        let archive = CloudZip::discover(Arc::new(backend)).await.unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, HeaderValue::from_str(range).unwrap());
+        let archives = Arc::new(HashMap::from([("a".to_string(), archive)]));
+        match get_entry(
+            State(archives),
+            Path(("a".to_string(), "data.bin".to_string())),
+            headers,
+        )
+        .await
+        {
+            Ok(response) => response,
+            Err(err) => panic!("{}", err.1),
+        }
+    }
+
+    async fn body(response: Response) -> Vec<u8> {
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    #[tokio::test]
+    async fn answers_range_requests() {
+        let response = get("bytes=-3").await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 97-99/100");
+        assert_eq!(body(response).await, [97, 98, 99]);
+
+        let response = get("bytes=100-").await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */100");
+
+        let response = get("bytes=0-1,5-6").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await.len(), 100);
+    }
+}
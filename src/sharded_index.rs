@@ -0,0 +1,357 @@
+use futures::stream::{self, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use std::sync::Arc;
+use tracing::debug;
+
+use crate::backend::RangeReader;
+use crate::error::{CloudZipError, Result};
+use crate::metadata::{
+    decompress_index, ArchiveFingerprint, EntryIndex, FileMetadata, INDEX_VERSION,
+};
+use crate::selection::{prefix_end, EntrySelector};
+
+/// First bytes of a sharded index, which neither CBOR nor JSON indexes start with.
+pub const SHARDED_MAGIC: &[u8; 8] = b"CZSHARD1";
+/// Entries per shard; a 5M-entry archive gets about 1200 shards of a few hundred KiB.
+const SHARD_ENTRIES: usize = 4096;
+/// Bytes read at first when opening, enough for the root of most indexes.
+const ROOT_PROBE_LEN: u64 = 256 * 1024;
+const SHARD_CONCURRENCY: usize = 8;
+
+/// The small part read first: where each shard is and which names it covers.
+#[derive(Debug, Serialize, Deserialize)]
+struct ShardRoot {
+    version: u32,
+    #[serde(default)]
+    generator: String,
+    #[serde(default)]
+    archive: Option<ArchiveFingerprint>,
+    entries: usize,
+    /// Whether each shard is compressed with zstd on its own.
+    #[serde(default)]
+    compressed: bool,
+    shards: Vec<ShardInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ShardInfo {
+    /// The smallest and largest names in the shard.
+    first: String,
+    last: String,
+    /// Where the shard is, counting from the end of the root.
+    offset: u64,
+    len: u64,
+}
+
+impl ShardInfo {
+    /// The bytes of the shard in an index whose shards start at `data_start`.
+    fn range(&self, data_start: u64) -> Result<Range<u64>> {
+        data_start
+            .checked_add(self.offset)
+            .and_then(|start| Some(start..start.checked_add(self.len)?))
+            .ok_or_else(|| CloudZipError::invalid_archive("A shard lies outside of the index"))
+    }
+}
+
+/// A shard: entries sorted by name, each with its position in the archive.
+type Shard = Vec<(u64, FileMetadata)>;
+
+/// Encodes `index` as a sharded index: the magic, the length of the root as a little-endian
+/// `u32`, the CBOR root, then the CBOR shards. Entries are sorted by name before being cut into
+/// shards, so a name or a prefix is found in one shard or a few neighbouring ones. With
+/// `compress`, each shard is compressed with zstd on its own.
+pub(crate) fn encode(index: &EntryIndex, compress: bool) -> Result<Vec<u8>> {
+    let mut sorted: Vec<(u64, &FileMetadata)> = index
+        .as_slice()
+        .iter()
+        .enumerate()
+        .map(|(position, meta)| (position as u64, meta))
+        .collect();
+    sorted.sort_by(|a, b| a.1.file_name.cmp(&b.1.file_name).then(a.0.cmp(&b.0)));
+
+    let mut shards = Vec::new();
+    let mut infos = Vec::new();
+    let mut offset = 0;
+    for chunk in sorted.chunks(SHARD_ENTRIES) {
+        let shard = serde_cbor::to_vec(&chunk)?;
+        #[cfg(feature = "zstd")]
+        let shard = if compress {
+            crate::metadata::compress_index(&shard)?
+        } else {
+            shard
+        };
+        infos.push(ShardInfo {
+            first: chunk[0].1.file_name.clone(),
+            last: chunk[chunk.len() - 1].1.file_name.clone(),
+            offset,
+            len: shard.len() as u64,
+        });
+        offset += shard.len() as u64;
+        shards.push(shard);
+    }
+    let root = ShardRoot {
+        version: INDEX_VERSION,
+        generator: concat!("cloud_zip ", env!("CARGO_PKG_VERSION")).to_string(),
+        archive: index.fingerprint().cloned(),
+        entries: index.len(),
+        compressed: compress,
+        shards: infos,
+    };
+    let encoded_root = serde_cbor::to_vec(&root)?;
+
+    let mut bytes =
+        Vec::with_capacity(SHARDED_MAGIC.len() + 4 + encoded_root.len() + offset as usize);
+    bytes.extend_from_slice(SHARDED_MAGIC);
+    bytes.extend_from_slice(&(encoded_root.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&encoded_root);
+    for shard in shards {
+        bytes.extend_from_slice(&shard);
+    }
+    Ok(bytes)
+}
+
+/// Decodes a whole sharded index held in memory, e.g. a sidecar.
+pub(crate) fn decode(bytes: &[u8]) -> Result<EntryIndex> {
+    let (root, data_start) = decode_root(bytes)?
+        .ok_or_else(|| CloudZipError::invalid_archive("The sharded index is truncated"))?;
+    // The count is only trusted as far as the index has bytes to hold that many entries.
+    let mut entries = Vec::with_capacity(root.entries.min(bytes.len()));
+    for info in &root.shards {
+        let range = info.range(data_start as u64)?;
+        if range.end > bytes.len() as u64 {
+            return Err(CloudZipError::invalid_archive(
+                "The sharded index is truncated",
+            ));
+        }
+        let shard = &bytes[range.start as usize..range.end as usize];
+        entries.extend(decode_shard(&root, shard)?);
+    }
+    Ok(in_archive_order(entries, root.archive))
+}
+
+/// The root at the start of `bytes` and the offset of the first shard, or `None` when `bytes`
+/// stops before the end of the root.
+fn decode_root(bytes: &[u8]) -> Result<Option<(ShardRoot, usize)>> {
+    let header_len = SHARDED_MAGIC.len() + 4;
+    if bytes.len() < header_len || !bytes.starts_with(SHARDED_MAGIC) {
+        return Err(CloudZipError::invalid_archive("Not a sharded index"));
+    }
+    let root_len =
+        u32::from_le_bytes(bytes[SHARDED_MAGIC.len()..header_len].try_into().unwrap()) as usize;
+    let Some(root) = bytes.get(header_len..header_len + root_len) else {
+        return Ok(None);
+    };
+    let root: ShardRoot = serde_cbor::from_slice(root)?;
+    if root.version > INDEX_VERSION {
+        return Err(CloudZipError::UnsupportedIndexVersion {
+            found: root.version,
+            supported: INDEX_VERSION,
+        });
+    }
+    Ok(Some((root, header_len + root_len)))
+}
+
+fn decode_shard(root: &ShardRoot, bytes: &[u8]) -> Result<Shard> {
+    if root.compressed {
+        Ok(serde_cbor::from_slice(&decompress_index(bytes)?)?)
+    } else {
+        Ok(serde_cbor::from_slice(bytes)?)
+    }
+}
+
+fn in_archive_order(mut entries: Shard, fingerprint: Option<ArchiveFingerprint>) -> EntryIndex {
+    entries.sort_unstable_by_key(|(position, _)| *position);
+    EntryIndex::new(entries.into_iter().map(|(_, meta)| meta).collect())
+        .with_fingerprint(fingerprint)
+}
+
+/// A sharded index read in place: opening it reads only the root, and selecting entries only
+/// the shards whose names can match.
+///
+/// Names, sets of names and prefixes touch the shards covering them; globs and regexes read
+/// every shard.
+pub struct ShardedIndex {
+    reader: Arc<dyn RangeReader>,
+    root: ShardRoot,
+    data_start: u64,
+}
+
+impl ShardedIndex {
+    /// Reads the root of the sharded index that `reader` holds, such as a
+    /// [`LocalBackend`](crate::backend::LocalBackend) on an index file.
+    pub async fn open(reader: Arc<dyn RangeReader>) -> Result<Self> {
+        let probe = reader.read_range(0, ROOT_PROBE_LEN).await?;
+        let (root, data_start) = match decode_root(&probe)? {
+            Some(root) => root,
+            None => {
+                let root_len =
+                    u32::from_le_bytes(probe[SHARDED_MAGIC.len()..][..4].try_into().unwrap());
+                let whole = reader
+                    .read_range(0, (SHARDED_MAGIC.len() + 4) as u64 + u64::from(root_len))
+                    .await?;
+                decode_root(&whole)?.ok_or_else(|| {
+                    CloudZipError::invalid_archive("The sharded index is truncated")
+                })?
+            }
+        };
+        debug!(
+            entries = root.entries,
+            shards = root.shards.len(),
+            "Read the root of the sharded index"
+        );
+        Ok(ShardedIndex {
+            reader,
+            root,
+            data_start: data_start as u64,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.root.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.entries == 0
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.root.shards.len()
+    }
+
+    pub fn fingerprint(&self) -> Option<&ArchiveFingerprint> {
+        self.root.archive.as_ref()
+    }
+
+    /// Every entry, reading every shard.
+    pub async fn load(&self) -> Result<EntryIndex> {
+        self.select(&EntrySelector::All).await
+    }
+
+    /// The entries picked by `selector`, in archive order, as an index holding only them and
+    /// the fingerprint of the archive.
+    pub async fn select(&self, selector: &EntrySelector) -> Result<EntryIndex> {
+        let wanted: Vec<&ShardInfo> = self
+            .root
+            .shards
+            .iter()
+            .filter(|info| covers(info, selector))
+            .collect();
+        let shards: Vec<Shard> = stream::iter(wanted.iter())
+            .map(|info| async move {
+                let range = info.range(self.data_start)?;
+                let bytes = self
+                    .reader
+                    .read_range(range.start, range.end - range.start)
+                    .await?;
+                decode_shard(&self.root, &bytes)
+            })
+            .buffered(SHARD_CONCURRENCY)
+            .try_collect()
+            .await?;
+        let entries: Shard = shards
+            .into_iter()
+            .flatten()
+            .filter(|(_, meta)| selector.matches(&meta.file_name))
+            .collect();
+        debug!(
+            selector = %selector.describe(),
+            entries = entries.len(),
+            shards = wanted.len(),
+            of = self.root.shards.len(),
+            "Read entries from the sharded index"
+        );
+        Ok(in_archive_order(entries, self.root.archive.clone()))
+    }
+}
+
+/// Whether the shard may hold entries picked by `selector`.
+fn covers(info: &ShardInfo, selector: &EntrySelector) -> bool {
+    let holds = |name: &str| info.first.as_str() <= name && name <= info.last.as_str();
+    match selector {
+        EntrySelector::Name(name) => holds(name),
+        EntrySelector::Names(names) => names.iter().any(|name| holds(name)),
+        EntrySelector::Prefix(prefix) => {
+            info.last.as_str() >= prefix.as_str()
+                && prefix_end(prefix).is_none_or(|end| info.first < end)
+        }
+        EntrySelector::All | EntrySelector::Glob(_) | EntrySelector::Regex(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+    use crate::metadata::Encryption;
+
+    fn index(entries: usize) -> EntryIndex {
+        EntryIndex::new(
+            (0..entries)
+                .map(|i| FileMetadata {
+                    file_name: format!("dir/{i:05}.txt"),
+                    uncompressed_size: 5,
+                    compressed_size: 5,
+                    is_directory: false,
+                    file_offset: 40 * i as u64 + 35,
+                    compression_method: 0,
+                    crc32: Some(i as u32),
+                    modified: None,
+                    unix_mode: None,
+                    encryption: Encryption::None,
+                })
+                .collect(),
+        )
+    }
+
+    /// Encodes `index` with its root rewritten by `edit`.
+    fn with_root(index: &EntryIndex, edit: impl FnOnce(&mut ShardRoot)) -> Vec<u8> {
+        let bytes = encode(index, false).unwrap();
+        let (mut root, data_start) = decode_root(&bytes).unwrap().unwrap();
+        edit(&mut root);
+        let root = serde_cbor::to_vec(&root).unwrap();
+        let mut edited = SHARDED_MAGIC.to_vec();
+        edited.extend((root.len() as u32).to_le_bytes());
+        edited.extend(root);
+        edited.extend(&bytes[data_start..]);
+        edited
+    }
+
+    #[tokio::test]
+    async fn selects_entries_across_shards() {
+        let index = index(SHARD_ENTRIES + 10);
+        let bytes = encode(&index, false).unwrap();
+        assert_eq!(decode(&bytes).unwrap().as_slice(), index.as_slice());
+
+        let sharded = ShardedIndex::open(Arc::new(MemoryBackend::new(bytes)))
+            .await
+            .unwrap();
+        assert_eq!(sharded.shard_count(), 2);
+        let found = sharded
+            .select(&EntrySelector::Prefix("dir/0409".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 10);
+        assert_eq!(found.as_slice()[0].file_name, "dir/04090.txt");
+    }
+
+    #[tokio::test]
+    async fn rejects_roots_with_impossible_counts_and_offsets() {
+        let index = index(3);
+        let huge_count = with_root(&index, |root| root.entries = usize::MAX);
+        assert_eq!(decode(&huge_count).unwrap().len(), 3);
+
+        for (offset, len) in [(u64::MAX, 1), (1, u64::MAX), (1 << 40, 8)] {
+            let bytes = with_root(&index, |root| {
+                root.shards[0].offset = offset;
+                root.shards[0].len = len;
+            });
+            assert!(decode(&bytes).is_err());
+
+            let sharded = ShardedIndex::open(Arc::new(MemoryBackend::new(bytes)))
+                .await
+                .unwrap();
+            assert!(sharded.select(&EntrySelector::All).await.is_err());
+        }
+    }
+}
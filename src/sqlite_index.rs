@@ -0,0 +1,227 @@
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, OptionalExtension};
+use std::fs;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use tracing::debug;
+
+use crate::error::{CloudZipError, Result};
+use crate::metadata::{ArchiveFingerprint, EntryIndex, FileMetadata, INDEX_VERSION};
+use crate::selection::{prefix_end, EntrySelector};
+
+const SCHEMA: &str = "
+    CREATE TABLE archive (
+        version INTEGER NOT NULL,
+        generator TEXT NOT NULL,
+        entries INTEGER NOT NULL,
+        fingerprint BLOB
+    );
+    CREATE TABLE entries (
+        position INTEGER PRIMARY KEY,
+        file_name TEXT NOT NULL,
+        metadata BLOB NOT NULL
+    );
+";
+
+/// An index stored as a SQLite database, for archives with so many entries that decoding the
+/// whole list is most of the work of a run.
+///
+/// Entries are kept one row each, CBOR-encoded, with their names indexed: looking up a name or
+/// a prefix reads only the matching rows. Globs and regexes still go through every name, but
+/// only decode the entries they pick.
+pub struct SqliteIndex {
+    connection: Mutex<Connection>,
+    len: usize,
+    fingerprint: Option<ArchiveFingerprint>,
+}
+
+impl SqliteIndex {
+    /// Writes `index` as a new database at `path`, replacing any file there once it is complete.
+    pub fn create(path: impl AsRef<Path>, index: &EntryIndex) -> Result<()> {
+        let path = path.as_ref();
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        match fs::remove_file(&partial) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+
+        let mut connection = Connection::open(&partial)?;
+        // The file is renamed into place only once complete, so a crash loses nothing.
+        connection.execute_batch("PRAGMA journal_mode = OFF; PRAGMA synchronous = OFF;")?;
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(SCHEMA)?;
+        let fingerprint = index.fingerprint().map(serde_cbor::to_vec).transpose()?;
+        transaction.execute(
+            "INSERT INTO archive (version, generator, entries, fingerprint) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                INDEX_VERSION,
+                concat!("cloud_zip ", env!("CARGO_PKG_VERSION")),
+                index.len(),
+                fingerprint
+            ],
+        )?;
+        {
+            let mut insert = transaction.prepare(
+                "INSERT INTO entries (position, file_name, metadata) VALUES (?1, ?2, ?3)",
+            )?;
+            for (position, meta) in index.as_slice().iter().enumerate() {
+                insert.execute(params![position, meta.file_name, serde_cbor::to_vec(meta)?])?;
+            }
+        }
+        // Building the index once the rows are in is much faster than keeping it up to date.
+        transaction.execute_batch("CREATE INDEX entries_by_name ON entries (file_name);")?;
+        transaction.commit()?;
+        connection.close().map_err(|(_, err)| err)?;
+
+        fs::rename(&partial, path)?;
+        debug!(entries = index.len(), path = %path.display(), "Wrote the SQLite index");
+        Ok(())
+    }
+
+    /// Opens a database written by [`SqliteIndex::create`], reading none of its entries yet.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let connection = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        let (version, len, fingerprint) = connection.query_row(
+            "SELECT version, entries, fingerprint FROM archive",
+            [],
+            |row| {
+                Ok((
+                    row.get::<_, u32>(0)?,
+                    row.get::<_, usize>(1)?,
+                    row.get::<_, Option<Vec<u8>>>(2)?,
+                ))
+            },
+        )?;
+        if version > INDEX_VERSION {
+            return Err(CloudZipError::UnsupportedIndexVersion {
+                found: version,
+                supported: INDEX_VERSION,
+            });
+        }
+        let fingerprint = fingerprint
+            .map(|bytes| serde_cbor::from_slice(&bytes))
+            .transpose()?;
+        Ok(SqliteIndex {
+            connection: Mutex::new(connection),
+            len,
+            fingerprint,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn fingerprint(&self) -> Option<&ArchiveFingerprint> {
+        self.fingerprint.as_ref()
+    }
+
+    /// The entry named `file_name`; when a name occurs twice, the first entry wins.
+    pub fn get(&self, file_name: &str) -> Result<Option<FileMetadata>> {
+        let metadata = self
+            .lock()
+            .prepare_cached(
+                "SELECT metadata FROM entries WHERE file_name = ?1 ORDER BY position LIMIT 1",
+            )?
+            .query_row([file_name], |row| row.get::<_, Vec<u8>>(0))
+            .optional()?;
+        Ok(metadata
+            .map(|bytes| serde_cbor::from_slice(&bytes))
+            .transpose()?)
+    }
+
+    /// Every entry, as an index like the other formats load.
+    pub fn load(&self) -> Result<EntryIndex> {
+        self.select(&EntrySelector::All)
+    }
+
+    /// The entries picked by `selector`, in archive order, as an index holding only them and
+    /// the fingerprint of the archive.
+    pub fn select(&self, selector: &EntrySelector) -> Result<EntryIndex> {
+        let connection = self.lock();
+        let mut rows: Vec<(u64, Vec<u8>)> = Vec::new();
+        match selector {
+            EntrySelector::Name(name) => named_rows(&connection, [name], &mut rows)?,
+            EntrySelector::Names(names) => {
+                named_rows(&connection, names, &mut rows)?;
+                rows.sort_unstable_by_key(|(position, _)| *position);
+            }
+            EntrySelector::Prefix(prefix) => {
+                // A range over the index on names, which LIKE cannot use for case-sensitive
+                // matches.
+                let end = prefix_end(prefix);
+                let mut statement = connection.prepare_cached(match end {
+                    Some(_) => {
+                        "SELECT position, metadata FROM entries
+                         WHERE file_name >= ?1 AND file_name < ?2 ORDER BY position"
+                    }
+                    None => {
+                        "SELECT position, metadata FROM entries
+                         WHERE file_name >= ?1 ORDER BY position"
+                    }
+                })?;
+                let bounds = params_from_iter(std::iter::once(prefix).chain(&end));
+                for row in statement.query_map(bounds, |row| Ok((row.get(0)?, row.get(1)?)))? {
+                    rows.push(row?);
+                }
+            }
+            EntrySelector::All | EntrySelector::Glob(_) | EntrySelector::Regex(_) => {
+                let mut statement = connection.prepare_cached(
+                    "SELECT position, file_name, metadata FROM entries ORDER BY position",
+                )?;
+                let mut found = statement.query([])?;
+                while let Some(row) = found.next()? {
+                    let name = row
+                        .get_ref(1)?
+                        .as_str()
+                        .map_err(|err| CloudZipError::Sqlite(err.to_string()))?;
+                    if selector.matches(name) {
+                        rows.push((row.get(0)?, row.get(2)?));
+                    }
+                }
+            }
+        }
+        drop(connection);
+
+        let entries = rows
+            .iter()
+            .map(|(_, bytes)| serde_cbor::from_slice(bytes))
+            .collect::<Result<Vec<FileMetadata>, _>>()?;
+        debug!(
+            selector = %selector.describe(),
+            entries = entries.len(),
+            of = self.len,
+            "Read entries from the SQLite index"
+        );
+        Ok(EntryIndex::new(entries).with_fingerprint(self.fingerprint.clone()))
+    }
+}
+
+/// Adds the position and encoded entry of every row with one of `names`.
+fn named_rows<'a>(
+    connection: &Connection,
+    names: impl IntoIterator<Item = &'a String>,
+    rows: &mut Vec<(u64, Vec<u8>)>,
+) -> rusqlite::Result<()> {
+    let mut statement =
+        connection.prepare_cached("SELECT position, metadata FROM entries WHERE file_name = ?1")?;
+    for name in names {
+        for row in statement.query_map([name], |row| Ok((row.get(0)?, row.get(1)?)))? {
+            rows.push(row?);
+        }
+    }
+    Ok(())
+}
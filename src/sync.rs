@@ -0,0 +1,160 @@
+use serde::Serialize;
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::io::AsyncReadExt;
+
+use crate::metadata::FileMetadata;
+
+/// How [`crate::CloudZip::sync_to`] compares and cleans up local files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncOptions {
+    /// Compare the CRC-32 of local files of the right size even when their modification time
+    /// matches the entry's.
+    pub checksum: bool,
+    /// Remove local files and directories no selected entry accounts for.
+    pub delete: bool,
+}
+
+/// What bringing a directory in line with the entries of an archive takes.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SyncPlan {
+    /// Entries with nothing at their local path yet.
+    pub missing: Vec<String>,
+    /// Entries whose local file differs in kind, size or content.
+    pub changed: Vec<String>,
+    /// Entries whose local file is up to date.
+    pub unchanged: usize,
+    /// Local files and directories no selected entry accounts for; a directory stands for
+    /// everything inside it.
+    pub extra: Vec<PathBuf>,
+}
+
+/// How a local file compares with the entry extracted to its path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LocalFile {
+    Missing,
+    Differs,
+    /// A directory where a file belongs, or the other way around.
+    OtherKind,
+    Current,
+    /// Same content, but another modification time than the entry's.
+    Touched,
+}
+
+/// Compares the file at `path` with `metadata`, written there as a symlink when `as_symlink`.
+/// A file of the right size is current when its modification time is `modified`, unless
+/// `checksum` is set or no time is expected; otherwise its CRC-32 decides, and without one in
+/// the index, it differs.
+pub(crate) async fn compare(
+    metadata: &FileMetadata,
+    path: &Path,
+    as_symlink: bool,
+    modified: Option<SystemTime>,
+    checksum: bool,
+) -> io::Result<LocalFile> {
+    let local = match tokio::fs::symlink_metadata(path).await {
+        Ok(local) => local,
+        // A file may be where one of its parent directories belongs.
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+            ) =>
+        {
+            return Ok(LocalFile::Missing)
+        }
+        Err(err) => return Err(err),
+    };
+    if local.is_dir() != metadata.is_directory {
+        return Ok(LocalFile::OtherKind);
+    }
+    if metadata.is_directory {
+        return Ok(LocalFile::Current);
+    }
+    let crc32 = if as_symlink {
+        if !local.is_symlink() {
+            return Ok(LocalFile::Differs);
+        }
+        let target = tokio::fs::read_link(path).await?;
+        let target = target.to_string_lossy();
+        if target.len() as u64 != metadata.uncompressed_size {
+            return Ok(LocalFile::Differs);
+        }
+        crc32fast::hash(target.as_bytes())
+    } else {
+        if !local.is_file() || local.len() != metadata.uncompressed_size {
+            return Ok(LocalFile::Differs);
+        }
+        let same_time = modified.is_some() && local.modified().ok() == modified;
+        if same_time && !checksum {
+            return Ok(LocalFile::Current);
+        }
+        if metadata.crc32.is_none() {
+            return Ok(LocalFile::Differs);
+        }
+        file_crc32(path).await?
+    };
+    Ok(match metadata.crc32 {
+        Some(expected) if expected != crc32 => LocalFile::Differs,
+        // Symlinks are not given times.
+        _ if as_symlink || modified.is_none() || local.modified().ok() == modified => {
+            LocalFile::Current
+        }
+        _ => LocalFile::Touched,
+    })
+}
+
+async fn file_crc32(path: &Path) -> io::Result<u32> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(hasher.finalize());
+        }
+        hasher.update(&buf[..read]);
+    }
+}
+
+/// Lists what is below `root` but not in `expected`, which holds the paths entries are
+/// written to; the directories above them are expected too. Extra directories are listed
+/// without their content, and symlinks are not followed.
+pub(crate) async fn extra_paths(
+    root: &Path,
+    expected: &HashSet<PathBuf>,
+) -> io::Result<Vec<PathBuf>> {
+    let mut kept: HashSet<&Path> = HashSet::new();
+    for path in expected {
+        kept.extend(path.ancestors().take_while(|ancestor| *ancestor != root));
+    }
+    let mut extra = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let mut listing = match tokio::fs::read_dir(&dir).await {
+            Ok(listing) => listing,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        while let Some(child) = listing.next_entry().await? {
+            let path = child.path();
+            if !kept.contains(path.as_path()) {
+                extra.push(path);
+            } else if child.file_type().await?.is_dir() {
+                pending.push(path);
+            }
+        }
+    }
+    extra.sort_unstable();
+    Ok(extra)
+}
+
+/// Removes a path [`extra_paths`] listed, with everything inside it.
+pub(crate) async fn remove_path(path: &Path) -> io::Result<()> {
+    match tokio::fs::symlink_metadata(path).await?.is_dir() {
+        true => tokio::fs::remove_dir_all(path).await,
+        false => tokio::fs::remove_file(path).await,
+    }
+}
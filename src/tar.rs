@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+use tracing::debug;
+
+use crate::archive::IndexProblem;
+use crate::backend::RangeReader;
+use crate::codepage::NameEncoding;
+use crate::compression;
+use crate::error::{CloudZipError, Result};
+use crate::metadata::{Encryption, FileMetadata};
+
+const BLOCK_LEN: u64 = 512;
+/// Bytes read at a time while walking the headers; members smaller than this are stepped
+/// over without another request.
+const READ_CHUNK: u64 = 1024 * 1024;
+/// Longest GNU long name or pax header accepted.
+const MAX_META_LEN: u64 = 1024 * 1024;
+
+const UNIX_REGULAR: u32 = 0o100000;
+const UNIX_DIRECTORY: u32 = 0o040000;
+const UNIX_SYMLINK: u32 = 0o120000;
+
+fn invalid(msg: impl Into<String>) -> CloudZipError {
+    CloudZipError::invalid_archive(msg)
+}
+
+/// The bytes of a field up to its first NUL.
+fn text(field: &[u8]) -> &[u8] {
+    field
+        .iter()
+        .position(|&byte| byte == 0)
+        .map_or(field, |end| &field[..end])
+}
+
+/// A numeric field: octal digits, or big-endian base-256 when the high bit of its first byte
+/// is set, as GNU tar writes values too large for octal.
+fn number(field: &[u8]) -> Option<u64> {
+    if field.first().is_some_and(|&first| first & 0x80 != 0) {
+        return field[1..]
+            .iter()
+            .try_fold(u64::from(field[0] & 0x7f), |value, &byte| {
+                value.checked_mul(256)?.checked_add(u64::from(byte))
+            });
+    }
+    let digits = text(field).trim_ascii();
+    if digits.is_empty() {
+        return Some(0);
+    }
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| u64::from_str_radix(digits, 8).ok())
+}
+
+/// Whether `header` is a tar header, which its checksum tells: the sum of its bytes with the
+/// checksum field counted as spaces.
+fn is_header(header: &[u8]) -> bool {
+    if header.len() < BLOCK_LEN as usize {
+        return false;
+    }
+    let sum: u64 = header[..BLOCK_LEN as usize]
+        .iter()
+        .enumerate()
+        .map(|(i, &byte)| match i {
+            148..156 => u64::from(b' '),
+            _ => u64::from(byte),
+        })
+        .sum();
+    number(&header[148..156]) == Some(sum)
+}
+
+/// Whether the archive starts with a tar header.
+pub(crate) async fn is_tar(reader: &dyn RangeReader) -> Result<bool> {
+    Ok(is_header(&reader.read_range(0, BLOCK_LEN).await?))
+}
+
+/// The bytes of the archive last read, so that neighbouring headers take one request.
+struct Window {
+    start: u64,
+    bytes: Vec<u8>,
+}
+
+impl Window {
+    async fn read(
+        &mut self,
+        reader: &dyn RangeReader,
+        size: u64,
+        offset: u64,
+        len: u64,
+    ) -> Result<&[u8]> {
+        let end = offset + len;
+        if offset < self.start || end > self.start + self.bytes.len() as u64 {
+            self.bytes = reader
+                .read_range(offset, len.max(READ_CHUNK).min(size - offset))
+                .await?;
+            self.start = offset;
+            if (self.bytes.len() as u64) < len {
+                return Err(invalid("The tar archive is truncated"));
+            }
+        }
+        let start = (offset - self.start) as usize;
+        Ok(&self.bytes[start..start + len as usize])
+    }
+}
+
+/// Where the target of a link is stored, which a symlink entry points its data at.
+struct Link {
+    offset: u64,
+    len: u64,
+    target: String,
+}
+
+/// What pax extended headers say about the next member.
+#[derive(Default)]
+struct Pax {
+    path: Option<String>,
+    link: Option<Link>,
+    size: Option<u64>,
+    mtime: Option<i64>,
+}
+
+impl Pax {
+    /// Parses the `LEN KEY=VALUE\n` records of a pax header whose data is at `offset`.
+    fn parse(data: &[u8], offset: u64) -> Result<Self> {
+        let mut pax = Pax::default();
+        let mut pos = 0;
+        while pos < data.len() && data[pos] != 0 {
+            let record = &data[pos..];
+            let space = record
+                .iter()
+                .position(|&byte| byte == b' ')
+                .ok_or_else(|| invalid("Invalid pax header record"))?;
+            let len = std::str::from_utf8(&record[..space])
+                .ok()
+                .and_then(|len| len.parse::<usize>().ok())
+                .filter(|&len| len > space + 1 && len <= record.len())
+                .ok_or_else(|| invalid("Invalid pax header record"))?;
+            let entry = &record[space + 1..len - 1];
+            if let Some(equals) = entry.iter().position(|&byte| byte == b'=') {
+                let value = &entry[equals + 1..];
+                let text = String::from_utf8_lossy(value);
+                match &entry[..equals] {
+                    b"path" => pax.path = Some(text.into_owned()),
+                    b"linkpath" => {
+                        pax.link = Some(Link {
+                            offset: offset + (pos + space + 1 + equals + 1) as u64,
+                            len: value.len() as u64,
+                            target: text.into_owned(),
+                        })
+                    }
+                    b"size" => pax.size = text.parse().ok(),
+                    // Fractional seconds are dropped.
+                    b"mtime" => pax.mtime = text.split('.').next().and_then(|s| s.parse().ok()),
+                    _ => {}
+                }
+            }
+            pos += len;
+        }
+        Ok(pax)
+    }
+}
+
+/// Builds the index of a tar archive by walking its headers, reading none of the member data.
+///
+/// Files are indexed as stored entries without a CRC, symlinks point their data at the link
+/// target held in the header, and hard links share the data of the file they name. Devices,
+/// FIFOs and sparse files are left out.
+pub(crate) async fn build_index(
+    reader: &dyn RangeReader,
+    names: NameEncoding,
+) -> Result<Vec<FileMetadata>> {
+    let size = reader.size().await?;
+    let mut window = Window {
+        start: 0,
+        bytes: Vec::new(),
+    };
+    let mut list: Vec<FileMetadata> = Vec::new();
+    let mut by_name: HashMap<String, usize> = HashMap::new();
+    let mut long_name = None;
+    let mut long_link = None;
+    let mut pax = Pax::default();
+    let mut offset = 0;
+    while offset + BLOCK_LEN <= size {
+        let header = window.read(reader, size, offset, BLOCK_LEN).await?.to_vec();
+        if header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+        if !is_header(&header) {
+            return Err(invalid(format!("Invalid tar header at offset {}", offset)));
+        }
+        let data = offset + BLOCK_LEN;
+        let data_len = match pax.size.take() {
+            Some(len) => len,
+            None => number(&header[124..136])
+                .ok_or_else(|| invalid(format!("Invalid member size at offset {}", offset)))?,
+        };
+        let next = data_len
+            .div_ceil(BLOCK_LEN)
+            .checked_mul(BLOCK_LEN)
+            .and_then(|len| len.checked_add(data))
+            .ok_or_else(|| invalid(format!("Invalid member size at offset {}", offset)))?;
+        let typeflag = header[156];
+
+        if matches!(typeflag, b'L' | b'K' | b'x' | b'g') {
+            if data_len > MAX_META_LEN {
+                return Err(invalid(format!(
+                    "The tar header at offset {} is {} bytes long",
+                    offset, data_len
+                )));
+            }
+            let meta = window.read(reader, size, data, data_len).await?;
+            match typeflag {
+                b'L' => long_name = Some(names.decode(text(meta), false)),
+                b'K' => {
+                    let target = text(meta);
+                    long_link = Some(Link {
+                        offset: data,
+                        len: target.len() as u64,
+                        target: names.decode(target, false),
+                    });
+                }
+                b'x' => pax = Pax::parse(meta, data)?,
+                // Global pax headers hold defaults for the whole archive, none of which
+                // matter to the index.
+                _ => {}
+            }
+            offset = next;
+            continue;
+        }
+
+        let name = match (pax.path.take(), long_name.take()) {
+            (Some(path), _) | (None, Some(path)) => path,
+            (None, None) => {
+                let name = text(&header[..100]);
+                // ustar splits long names into a prefix and a name.
+                let prefix = text(&header[345..500]);
+                if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                    let mut full = prefix.to_vec();
+                    full.push(b'/');
+                    full.extend_from_slice(name);
+                    names.decode(&full, false)
+                } else {
+                    names.decode(name, false)
+                }
+            }
+        };
+        let link = pax.link.take().or(long_link.take()).unwrap_or_else(|| {
+            let target = text(&header[157..257]);
+            Link {
+                offset: offset + 157,
+                len: target.len() as u64,
+                target: names.decode(target, false),
+            }
+        });
+        let modified = pax
+            .mtime
+            .take()
+            .or_else(|| number(&header[136..148]).map(|mtime| mtime as i64));
+        let mode = number(&header[100..108]).unwrap_or(0) as u32 & 0o7777;
+        let name = name.trim_start_matches("./").to_string();
+        offset = next;
+        if name.is_empty() || name == "." {
+            continue;
+        }
+
+        let entry = |file_name: String, file_offset, len, is_directory, unix_mode| FileMetadata {
+            file_name,
+            uncompressed_size: len,
+            compressed_size: len,
+            is_directory,
+            file_offset,
+            compression_method: compression::STORED,
+            crc32: None,
+            modified,
+            unix_mode: Some(unix_mode),
+            encryption: Encryption::None,
+        };
+        let meta = match typeflag {
+            b'0' | 0 | b'7' => entry(name, data, data_len, false, UNIX_REGULAR | mode),
+            b'5' => {
+                let name = format!("{}/", name.trim_end_matches('/'));
+                entry(name, data, 0, true, UNIX_DIRECTORY | mode)
+            }
+            b'2' => entry(name, link.offset, link.len, false, UNIX_SYMLINK | 0o777),
+            b'1' => match by_name.get(link.target.trim_start_matches("./")) {
+                Some(&target) => FileMetadata {
+                    file_name: name,
+                    ..list[target].clone()
+                },
+                None => {
+                    debug!(entry = %name, target = %link.target, "Skipped a hard link to a file not in the archive");
+                    continue;
+                }
+            },
+            _ => {
+                debug!(entry = %name, typeflag = %char::from(typeflag), "Skipped a tar member that is not a file, directory or link");
+                continue;
+            }
+        };
+        by_name.insert(meta.file_name.clone(), list.len());
+        list.push(meta);
+    }
+    debug!(entries = list.len(), size, "Read the tar headers");
+    Ok(list)
+}
+
+/// Compares `indexed`, the entries of an index, with the headers of the tar archive.
+pub(crate) async fn check_entries(
+    reader: &dyn RangeReader,
+    indexed: &[FileMetadata],
+    names: NameEncoding,
+) -> Result<Vec<IndexProblem>> {
+    let entries = build_index(reader, names).await?;
+    let mut problems = Vec::new();
+    for position in 0..entries.len().max(indexed.len()) {
+        let (file_name, reason) = match (entries.get(position), indexed.get(position)) {
+            (Some(entry), Some(meta)) if entry.file_name != meta.file_name => (
+                meta.file_name.clone(),
+                format!("the archive has {} in its place", entry.file_name),
+            ),
+            (Some(entry), Some(meta)) if entry.byte_range() != meta.byte_range() => (
+                meta.file_name.clone(),
+                format!(
+                    "its data is {} bytes at {}, not {} bytes at {}",
+                    entry.compressed_size,
+                    entry.file_offset,
+                    meta.compressed_size,
+                    meta.file_offset
+                ),
+            ),
+            (Some(_), Some(_)) => continue,
+            (Some(entry), None) => (
+                entry.file_name.clone(),
+                "missing from the index".to_string(),
+            ),
+            (None, Some(meta)) => (meta.file_name.clone(), "not in the archive".to_string()),
+            (None, None) => unreachable!(),
+        };
+        problems.push(IndexProblem { file_name, reason });
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+
+    /// A header for a regular file `name` whose size field is `size`, with its checksum.
+    fn header(name: &str, size: [u8; 12]) -> Vec<u8> {
+        let mut header = vec![0; BLOCK_LEN as usize];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[124..136].copy_from_slice(&size);
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        let sum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &byte)| match i {
+                148..156 => u64::from(b' '),
+                _ => u64::from(byte),
+            })
+            .sum();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+        header
+    }
+
+    #[tokio::test]
+    async fn indexes_a_file() {
+        let mut tar = header("a.txt", *b"00000000005\0");
+        tar.extend(b"hello");
+        tar.resize(4 * BLOCK_LEN as usize, 0);
+        let list = build_index(&MemoryBackend::new(tar), NameEncoding::default())
+            .await
+            .unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].file_name, "a.txt");
+        assert_eq!(list[0].uncompressed_size, 5);
+    }
+
+    #[tokio::test]
+    async fn rejects_member_sizes_that_overflow() {
+        let mut size = [0xff; 12];
+        size[..4].copy_from_slice(&[0x80, 0, 0, 0]);
+        let mut tar = header("a.txt", size);
+        tar.resize(4 * BLOCK_LEN as usize, 0);
+        assert!(
+            build_index(&MemoryBackend::new(tar), NameEncoding::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_pax_records_without_room_for_a_newline() {
+        assert!(Pax::parse(b"2 x", 0).is_err());
+        let pax = Pax::parse(b"11 path=ab\n", 0).unwrap();
+        assert_eq!(pax.path.as_deref(), Some("ab"));
+    }
+}
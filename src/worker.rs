@@ -0,0 +1,346 @@
+use aws_config::{meta::region::RegionProviderChain, BehaviorVersion};
+use aws_sdk_s3::config::Region;
+use aws_sdk_sqs::error::DisplayErrorContext;
+use aws_sdk_sqs::types::{Message, MessageSystemAttributeName};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+
+use crate::archive::CloudZip;
+use crate::backend::s3::{get_s3_client, S3Config};
+use crate::error::{CloudZipError, Result};
+use crate::location::{ArchiveLocation, BackendOptions};
+use crate::metrics::MetricsRecorder;
+use crate::prometheus::PrometheusMetrics;
+use crate::s3_output::{ExtractedObjects, S3Destination};
+use crate::selection::Selection;
+
+/// SQS returns at most this many messages per receive.
+const MAX_BATCH: usize = 10;
+/// Long polling keeps an idle worker down to a few requests a minute.
+const WAIT_TIME_SECONDS: i32 = 20;
+/// Pause after a failed receive, so that an unreachable queue is not hammered.
+const RECEIVE_ERROR_PAUSE: Duration = Duration::from_secs(5);
+/// Upper bound of the delay before a job that failed transiently is delivered again.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(15 * 60);
+
+/// How a [`Worker`] consumes its queue.
+#[derive(Debug, Clone)]
+pub struct WorkerConfig {
+    /// Queue the jobs are received from.
+    pub queue_url: String,
+    /// Queue sent a [`JobResult`] for every finished job, successful or not.
+    pub results_queue_url: Option<String>,
+    /// Jobs processed at the same time.
+    pub concurrency: usize,
+    /// How long a received job stays hidden from other workers; extended for as long as the
+    /// job runs, so it only matters when a worker dies.
+    pub visibility_timeout: Duration,
+    /// Deliveries of a job failing with transient errors before it is given up on.
+    pub max_attempts: u32,
+}
+
+impl WorkerConfig {
+    pub fn new(queue_url: impl Into<String>) -> Self {
+        WorkerConfig {
+            queue_url: queue_url.into(),
+            results_queue_url: None,
+            concurrency: 4,
+            visibility_timeout: Duration::from_secs(300),
+            max_attempts: 5,
+        }
+    }
+}
+
+/// An extraction job, the JSON body of a queue message, e.g.
+/// `{"archive": "s3://b/a.zip", "entries": ["x.csv"], "destination": "s3://c/out/"}`.
+#[derive(Debug, Deserialize)]
+pub struct Job {
+    /// Copied to the result; the message ID when not given.
+    pub id: Option<String>,
+    pub archive: String,
+    #[serde(flatten)]
+    pub select: Selection,
+    /// `s3://bucket/prefix/` the selected files are written under.
+    pub destination: String,
+    pub password: Option<String>,
+}
+
+/// What happened to a job, sent to the results queue as JSON.
+#[derive(Debug, Serialize)]
+pub struct JobResult {
+    pub id: String,
+    pub archive: Option<String>,
+    pub succeeded: bool,
+    /// Uploaded objects, as `s3://` URIs.
+    pub entries: Vec<String>,
+    pub bytes: u64,
+    pub error: Option<String>,
+    /// Deliveries of the job, this one included.
+    pub attempts: u32,
+}
+
+/// Processes extraction jobs from an SQS queue, writing the extracted entries to S3.
+///
+/// A job is deleted from the queue once it succeeded or failed for good. After a transient
+/// failure it is left to come back after a growing delay, until `max_attempts` deliveries;
+/// a redrive policy on the queue still applies to workers that die halfway.
+pub struct Worker {
+    backends: BackendOptions,
+    config: WorkerConfig,
+    sqs: aws_sdk_sqs::Client,
+    s3: aws_sdk_s3::Client,
+    metrics: Arc<PrometheusMetrics>,
+}
+
+impl Worker {
+    pub async fn new(mut backends: BackendOptions, config: WorkerConfig) -> Self {
+        let sqs = get_sqs_client(&backends.s3).await;
+        let s3 = get_s3_client(&backends.s3).await;
+        let recorder = backends
+            .metrics
+            .get_or_insert_with(|| Arc::new(MetricsRecorder::new()))
+            .clone();
+        Worker {
+            backends,
+            config,
+            sqs,
+            s3,
+            metrics: Arc::new(PrometheusMetrics::new("job", "outcome", recorder)),
+        }
+    }
+
+    /// Jobs processed so far by outcome (`succeeded`, `failed` or `retried`), their
+    /// durations and the reads of the archives, for [`prometheus::router`].
+    ///
+    /// [`prometheus::router`]: crate::prometheus::router
+    pub fn metrics(&self) -> Arc<PrometheusMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Receives and processes jobs until `shutdown` completes, then finishes the jobs in
+    /// progress; jobs received but not started are left for another worker.
+    pub async fn run(self: Arc<Self>, shutdown: impl Future<Output = ()>) {
+        let permits = Arc::new(Semaphore::new(self.config.concurrency.max(1)));
+        let mut running = JoinSet::new();
+        tokio::pin!(shutdown);
+        info!(queue = %self.config.queue_url, "Waiting for jobs");
+        loop {
+            while running.try_join_next().is_some() {}
+            // Only ask for as many jobs as can start right away.
+            let free = tokio::select! {
+                _ = &mut shutdown => break,
+                permit = permits.clone().acquire_owned() => {
+                    drop(permit.expect("the semaphore is never closed"));
+                    permits.available_permits()
+                }
+            };
+            let messages = tokio::select! {
+                _ = &mut shutdown => break,
+                received = self.receive(free.min(MAX_BATCH)) => received,
+            };
+            for message in messages {
+                let permit = permits
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("the semaphore is never closed");
+                let worker = self.clone();
+                running.spawn(async move {
+                    worker.process(message).await;
+                    drop(permit);
+                });
+            }
+        }
+        info!(jobs = running.len(), "Finishing the jobs in progress");
+        while running.join_next().await.is_some() {}
+    }
+
+    async fn receive(&self, max: usize) -> Vec<Message> {
+        let received = self
+            .sqs
+            .receive_message()
+            .queue_url(&self.config.queue_url)
+            .max_number_of_messages(max as i32)
+            .wait_time_seconds(WAIT_TIME_SECONDS)
+            .visibility_timeout(self.visibility_seconds())
+            .message_system_attribute_names(MessageSystemAttributeName::ApproximateReceiveCount)
+            .send()
+            .await;
+        match received {
+            Ok(output) => output.messages.unwrap_or_default(),
+            Err(err) => {
+                warn!(error = %DisplayErrorContext(&err), "Could not receive jobs");
+                tokio::time::sleep(RECEIVE_ERROR_PAUSE).await;
+                Vec::new()
+            }
+        }
+    }
+
+    fn visibility_seconds(&self) -> i32 {
+        self.config.visibility_timeout.as_secs().clamp(1, 12 * 3600) as i32
+    }
+
+    async fn process(&self, message: Message) {
+        let (Some(receipt), Some(message_id)) = (message.receipt_handle, message.message_id) else {
+            return;
+        };
+        let started = Instant::now();
+        let attempts = message
+            .attributes
+            .as_ref()
+            .and_then(|attributes| {
+                attributes.get(&MessageSystemAttributeName::ApproximateReceiveCount)
+            })
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(1);
+        let job: Result<Job> = serde_json::from_str(message.body.as_deref().unwrap_or_default())
+            .map_err(|err| CloudZipError::InvalidRequest(err.to_string()));
+        let mut result = JobResult {
+            id: message_id,
+            archive: None,
+            succeeded: false,
+            entries: Vec::new(),
+            bytes: 0,
+            error: None,
+            attempts,
+        };
+        let outcome = match job {
+            Ok(job) => {
+                result.id = job.id.clone().unwrap_or(result.id);
+                result.archive = Some(job.archive.clone());
+                info!(job = %result.id, archive = %job.archive, attempts, "Starting a job");
+                self.keep_hidden(&receipt, self.run_job(job)).await
+            }
+            Err(err) => Err(err),
+        };
+        match outcome {
+            Ok(extracted) => {
+                info!(job = %result.id, entries = extracted.entries.len(), "Job done");
+                result.succeeded = true;
+                result.entries = extracted.entries;
+                result.bytes = extracted.bytes;
+            }
+            Err(err) if err.is_transient() && attempts < self.config.max_attempts => {
+                let delay = retry_delay(attempts);
+                warn!(job = %result.id, attempts, error = %err, ?delay, "Job failed, will retry");
+                self.set_visibility(&receipt, delay).await;
+                self.metrics.observe("retried", started.elapsed());
+                return;
+            }
+            Err(err) => {
+                warn!(job = %result.id, attempts, error = %err, "Job failed");
+                result.error = Some(err.to_string());
+            }
+        }
+        let outcome = if result.succeeded {
+            "succeeded"
+        } else {
+            "failed"
+        };
+        self.metrics.observe(outcome, started.elapsed());
+        if let Err(err) = self.send_result(&result).await {
+            // Leaving the job in the queue repeats it, which beats losing its result.
+            warn!(job = %result.id, error = %err, "Could not send the result");
+            return;
+        }
+        if let Err(err) = self
+            .sqs
+            .delete_message()
+            .queue_url(&self.config.queue_url)
+            .receipt_handle(&receipt)
+            .send()
+            .await
+        {
+            warn!(job = %result.id, error = %DisplayErrorContext(&err), "Could not delete the job");
+        }
+    }
+
+    async fn run_job(&self, job: Job) -> Result<ExtractedObjects> {
+        let selector = job.select.selector()?;
+        let destination: S3Destination = job.destination.parse()?;
+        let location: ArchiveLocation = job.archive.parse()?;
+        if let ArchiveLocation::Local(_) = location {
+            return Err(CloudZipError::InvalidLocation(format!(
+                "jobs cannot read local archives such as {}",
+                job.archive
+            )));
+        }
+        let mut archive = CloudZip::discover(location.open(&self.backends).await?).await?;
+        if let Some(password) = job.password {
+            archive = archive.with_password(password);
+        }
+        archive
+            .extract_to_s3(&selector, &self.s3, &destination)
+            .await
+    }
+
+    /// Runs `job`, extending the visibility timeout of its message until it completes.
+    async fn keep_hidden<T>(&self, receipt: &str, job: impl Future<Output = T>) -> T {
+        tokio::pin!(job);
+        let period = self.config.visibility_timeout / 2;
+        loop {
+            tokio::select! {
+                output = &mut job => return output,
+                _ = tokio::time::sleep(period) => {
+                    self.set_visibility(receipt, self.config.visibility_timeout).await;
+                }
+            }
+        }
+    }
+
+    async fn set_visibility(&self, receipt: &str, timeout: Duration) {
+        let changed = self
+            .sqs
+            .change_message_visibility()
+            .queue_url(&self.config.queue_url)
+            .receipt_handle(receipt)
+            .visibility_timeout(timeout.as_secs().min(12 * 3600) as i32)
+            .send()
+            .await;
+        match changed {
+            Ok(_) => debug!(?timeout, "Extended the visibility timeout"),
+            Err(err) => {
+                warn!(error = %DisplayErrorContext(&err), "Could not change the visibility timeout")
+            }
+        }
+    }
+
+    async fn send_result(&self, result: &JobResult) -> std::result::Result<(), String> {
+        let Some(queue_url) = &self.config.results_queue_url else {
+            return Ok(());
+        };
+        let body = serde_json::to_string(result).map_err(|err| err.to_string())?;
+        self.sqs
+            .send_message()
+            .queue_url(queue_url)
+            .message_body(body)
+            .send()
+            .await
+            .map_err(|err| DisplayErrorContext(err).to_string())?;
+        Ok(())
+    }
+}
+
+/// Doubles from 30 seconds with every delivery.
+fn retry_delay(attempts: u32) -> Duration {
+    Duration::from_secs(30)
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// An SQS client for the region and profile of the S3 settings; the role to assume is only
+/// used for the archives.
+async fn get_sqs_client(config: &S3Config) -> aws_sdk_sqs::Client {
+    let region_provider = RegionProviderChain::first_try(config.region.clone().map(Region::new))
+        .or_default_provider();
+    let mut loader = aws_config::defaults(BehaviorVersion::v2024_03_28()).region(region_provider);
+    if let Some(profile) = &config.profile {
+        loader = loader.profile_name(profile);
+    }
+    aws_sdk_sqs::Client::new(&loader.load().await)
+}
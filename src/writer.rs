@@ -0,0 +1,997 @@
+use async_trait::async_trait;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use futures::stream::TryStreamExt;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tracing::debug;
+
+use crate::archive::CloudZip;
+use crate::backend::s3::{get_s3_client, S3Encryption};
+use crate::central_directory::read_central_directory;
+use crate::error::{CloudZipError, Result};
+use crate::location::{ArchiveLocation, BackendOptions};
+use crate::metadata::{ArchiveFingerprint, Encryption, EntryIndex, FileMetadata};
+use crate::output::safe_components;
+use crate::s3_output::{S3Upload, MIN_PART_LEN};
+use crate::selection::EntrySelector;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x0807_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const ZIP64_EOCD_SIGNATURE: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x0706_4b50;
+const EOCD_SIGNATURE: u32 = 0x0605_4b50;
+
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+const EXTENDED_TIMESTAMP_EXTRA_ID: u16 = 0x5455;
+const AES_EXTRA_ID: u16 = 0x9901;
+const UNICODE_PATH_EXTRA_ID: u16 = 0x7075;
+
+const FLAG_ENCRYPTED: u16 = 0x0001;
+const FLAG_DATA_DESCRIPTOR: u16 = 0x0008;
+const FLAG_UTF8: u16 = 0x0800;
+
+const CENTRAL_HEADER_LEN: usize = 46;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+const AES_METHOD: u16 = 99;
+
+/// Version 2.0 handles deflate and directories, 4.5 ZIP64.
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+/// Unix host, so that the high half of the external attributes holds the mode.
+const VERSION_MADE_BY: u16 = (3 << 8) | 63;
+
+const DOS_DIRECTORY: u32 = 0x10;
+const UNIX_REGULAR: u32 = 0o100000;
+const UNIX_DIRECTORY: u32 = 0o040000;
+const UNIX_SYMLINK: u32 = 0o120000;
+
+/// Size of the reads feeding the encoder.
+const CHUNK_LEN: usize = 256 * 1024;
+
+/// Where the bytes of a new archive go, in order.
+#[async_trait]
+pub trait ArchiveSink: Send {
+    async fn write(&mut self, data: &[u8]) -> Result<()>;
+
+    /// Makes the written bytes the archive and describes it as reading it back would.
+    async fn finish(self: Box<Self>) -> Result<ArchiveFingerprint>;
+
+    /// Discards whatever was written.
+    async fn abort(self: Box<Self>);
+}
+
+#[async_trait]
+impl ArchiveSink for S3Upload {
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        S3Upload::write(self, data).await
+    }
+
+    async fn finish(self: Box<Self>) -> Result<ArchiveFingerprint> {
+        S3Upload::finish(*self).await
+    }
+
+    async fn abort(self: Box<Self>) {
+        S3Upload::abort(*self).await
+    }
+}
+
+/// A local archive, written next to its final path and renamed into place when finished.
+pub struct FileSink {
+    file: File,
+    path: PathBuf,
+    partial: PathBuf,
+    size: u64,
+}
+
+impl FileSink {
+    pub async fn create(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let mut partial = path.clone().into_os_string();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        Ok(FileSink {
+            file: File::create(&partial).await?,
+            path,
+            partial,
+            size: 0,
+        })
+    }
+}
+
+#[async_trait]
+impl ArchiveSink for FileSink {
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.file.write_all(data).await?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    async fn finish(mut self: Box<Self>) -> Result<ArchiveFingerprint> {
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+        tokio::fs::rename(&self.partial, &self.path).await?;
+        Ok(ArchiveFingerprint {
+            size: self.size,
+            etag: None,
+            version_id: None,
+        })
+    }
+
+    async fn abort(self: Box<Self>) {
+        drop(self.file);
+        let _ = tokio::fs::remove_file(&self.partial).await;
+    }
+}
+
+/// What [`ArchiveWriter::edit_existing`] does with an entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Retained {
+    Keep,
+    Drop,
+    Rename(String),
+}
+
+/// Attributes recorded for a new entry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EntryAttributes {
+    /// Seconds since the Unix epoch.
+    pub modified: Option<i64>,
+    /// Unix mode including the file type bits; regular files get `0644` and directories
+    /// `0755` when unset.
+    pub unix_mode: Option<u32>,
+}
+
+impl EntryAttributes {
+    /// The modification time and mode of a local file, without following symlinks.
+    pub fn of(metadata: &std::fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let unix_mode = {
+            use std::os::unix::fs::MetadataExt;
+            Some(metadata.mode())
+        };
+        #[cfg(not(unix))]
+        let unix_mode = None;
+        EntryAttributes {
+            modified: metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|since| since.as_secs() as i64),
+            unix_mode,
+        }
+    }
+}
+
+/// Writes a zip archive front to back into an [`ArchiveSink`], building its index on the way
+/// so that it can be stored as the sidecar as soon as the archive exists.
+///
+/// Files are deflated as they are read, with the CRC-32 and sizes in a data descriptor after
+/// the data; entries copied from another archive keep their compressed bytes. ZIP64 records
+/// are only written when sizes, offsets or the entry count need them.
+///
+/// After a failed call the archive is unusable and should be [`abort`](Self::abort)ed.
+pub struct ArchiveWriter {
+    sink: Box<dyn ArchiveSink>,
+    offset: u64,
+    /// Deflate level; `None` stores entries as they are.
+    level: Option<u32>,
+    entries: Vec<FileMetadata>,
+    names: HashSet<String>,
+    central_directory: Vec<u8>,
+}
+
+impl ArchiveWriter {
+    pub fn new(sink: impl ArchiveSink + 'static) -> Self {
+        Self::with_sink(Box::new(sink))
+    }
+
+    fn with_sink(sink: Box<dyn ArchiveSink>) -> Self {
+        ArchiveWriter {
+            sink,
+            offset: 0,
+            level: Some(6),
+            entries: Vec::new(),
+            names: HashSet::new(),
+            central_directory: Vec::new(),
+        }
+    }
+
+    /// Writes a new archive at `location`, a local path or an S3 object; nothing appears there
+    /// before [`finish`](Self::finish).
+    pub async fn create(location: &ArchiveLocation, options: &BackendOptions) -> Result<Self> {
+        Ok(Self::with_sink(open_sink(location, options).await?))
+    }
+
+    /// Writes the archive at `source` again at `destination`, which may be the same location,
+    /// ready for entries to be added after the existing ones.
+    ///
+    /// The stored entries are copied as they are, inside S3 when both archives are S3 objects,
+    /// and the central directory is kept and extended; the comment of the archive is dropped.
+    pub async fn append(
+        source: &ArchiveLocation,
+        destination: &ArchiveLocation,
+        options: &BackendOptions,
+    ) -> Result<Self> {
+        let reader = source.open(options).await?;
+        let existing = CloudZip::discover(reader.clone())
+            .await?
+            .verified_entries()
+            .await?;
+        let (eocd, central_directory) = read_central_directory(&*reader).await?;
+        if eocd.base > 0 {
+            return Err(CloudZipError::InvalidRequest(format!(
+                "{} starts with {} bytes of other data, such as a self-extractor, whose offsets \
+                 new entries would not match",
+                source, eocd.base
+            )));
+        }
+        if eocd.entries != existing.len() as u64 {
+            return Err(CloudZipError::ArchiveChanged(format!(
+                "the index lists {} entries but the central directory {}",
+                existing.len(),
+                eocd.entries
+            )));
+        }
+        let data_len = eocd.cd_offset;
+
+        let sink: Box<dyn ArchiveSink> = match (source, destination) {
+            (
+                ArchiveLocation::S3 {
+                    bucket: source_bucket,
+                    key: source_key,
+                    version_id,
+                },
+                ArchiveLocation::S3 {
+                    bucket,
+                    key,
+                    version_id: None,
+                },
+            ) if data_len >= MIN_PART_LEN
+                && !matches!(options.s3.encryption, S3Encryption::CustomerKey(_)) =>
+            {
+                let version_id = match version_id {
+                    Some(version_id) => Some(version_id.clone()),
+                    None => reader.version_id().await?,
+                };
+                let etag = reader.etag().await?;
+                let mut upload = S3Upload::new(get_s3_client(&options.s3).await, bucket, key);
+                let copied = upload
+                    .copy_from(
+                        source_bucket,
+                        source_key,
+                        version_id.as_deref(),
+                        etag.as_deref(),
+                        data_len,
+                    )
+                    .await;
+                if let Err(err) = copied {
+                    upload.abort().await;
+                    return Err(err);
+                }
+                Box::new(upload)
+            }
+            _ => {
+                let mut sink = open_sink(destination, options).await?;
+                let copied = async {
+                    if data_len > 0 {
+                        let mut chunks = reader.stream_range(0, data_len);
+                        while let Some(chunk) = chunks.try_next().await? {
+                            sink.write(&chunk).await?;
+                        }
+                    }
+                    Ok::<_, CloudZipError>(())
+                }
+                .await;
+                if let Err(err) = copied {
+                    sink.abort().await;
+                    return Err(err);
+                }
+                sink
+            }
+        };
+        debug!(
+            entries = existing.len(),
+            bytes = data_len,
+            "Copied the existing entries"
+        );
+
+        let mut writer = Self::with_sink(sink);
+        writer.offset = data_len;
+        writer.entries = existing.as_slice().to_vec();
+        writer.names = writer
+            .entries
+            .iter()
+            .map(|metadata| metadata.file_name.clone())
+            .collect();
+        writer.central_directory = central_directory;
+        Ok(writer)
+    }
+
+    /// Drops or renames the entries kept by [`append`](Self::append), as `decide` says for
+    /// each; must come before anything is added. Only the central directory changes: the data
+    /// of dropped entries stays in the archive, and renamed entries keep their old name in
+    /// their local header, which readers of the central directory ignore and header checks
+    /// accept while the sizes and CRC still match.
+    pub(crate) fn edit_existing(
+        &mut self,
+        mut decide: impl FnMut(&FileMetadata) -> Retained,
+    ) -> Result<()> {
+        let old_directory = std::mem::take(&mut self.central_directory);
+        let old_entries = std::mem::take(&mut self.entries);
+        self.names.clear();
+        let mut pos = 0;
+        for mut metadata in old_entries {
+            let record = old_directory
+                .get(pos..)
+                .filter(|rest| rest.len() >= CENTRAL_HEADER_LEN)
+                .ok_or_else(|| CloudZipError::invalid_archive("Central directory is truncated"))?;
+            let name_len = u16_at(record, 28) as usize;
+            let extra_len = u16_at(record, 30) as usize;
+            let comment_len = u16_at(record, 32) as usize;
+            let record_len = CENTRAL_HEADER_LEN + name_len + extra_len + comment_len;
+            let record = record
+                .get(..record_len)
+                .ok_or_else(|| CloudZipError::invalid_archive("Central directory is truncated"))?;
+            pos += record_len;
+
+            match decide(&metadata) {
+                Retained::Drop => continue,
+                Retained::Keep => {
+                    self.claim_existing(&metadata.file_name)?;
+                    self.central_directory.extend_from_slice(record);
+                }
+                Retained::Rename(name) => {
+                    let name = self.claim(&name, metadata.is_directory)?;
+                    let extra = &record[CENTRAL_HEADER_LEN + name_len..][..extra_len];
+                    // Readers prefer a Unicode path field over the name it would contradict.
+                    let extra = without_extra_field(extra, UNICODE_PATH_EXTRA_ID);
+                    let flags = (u16_at(record, 8) & !FLAG_UTF8) | name_flags(&name);
+                    let mut renamed = Vec::with_capacity(record_len);
+                    renamed.extend_from_slice(&record[..CENTRAL_HEADER_LEN]);
+                    renamed[8..10].copy_from_slice(&flags.to_le_bytes());
+                    renamed[28..30].copy_from_slice(&(name.len() as u16).to_le_bytes());
+                    renamed[30..32].copy_from_slice(&(extra.len() as u16).to_le_bytes());
+                    renamed.extend_from_slice(name.as_bytes());
+                    renamed.extend_from_slice(&extra);
+                    renamed.extend_from_slice(&record[record_len - comment_len..]);
+                    self.central_directory.extend_from_slice(&renamed);
+                    metadata.file_name = name;
+                }
+            }
+            self.entries.push(metadata);
+        }
+        Ok(())
+    }
+
+    /// Reserves the name of an entry kept from an existing archive, as it is.
+    fn claim_existing(&mut self, name: &str) -> Result<()> {
+        if !self.names.insert(name.to_string()) {
+            return Err(CloudZipError::InvalidNewEntry {
+                file_name: name.to_string(),
+                reason: "an entry of that name was already added",
+            });
+        }
+        Ok(())
+    }
+
+    /// Deflate level from 1 to 9 for the files added from now on; 0 stores them uncompressed.
+    pub fn with_level(mut self, level: u32) -> Self {
+        self.level = (level > 0).then_some(level.min(9));
+        self
+    }
+
+    /// The entries written so far.
+    pub fn entries(&self) -> &[FileMetadata] {
+        &self.entries
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.sink.write(data).await?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    /// Normalizes an entry name, refusing names that extraction would refuse and duplicates.
+    fn claim(&mut self, name: &str, is_directory: bool) -> Result<String> {
+        let invalid = |reason| CloudZipError::InvalidNewEntry {
+            file_name: name.to_string(),
+            reason,
+        };
+        let components = safe_components(name).map_err(|err| match err {
+            CloudZipError::InvalidEntryPath { reason, .. } => invalid(reason),
+            err => err,
+        })?;
+        if components.is_empty() {
+            return Err(invalid("the name is empty"));
+        }
+        let mut name = components.join("/");
+        if is_directory {
+            name.push('/');
+        }
+        if !self.names.insert(name.clone()) {
+            return Err(CloudZipError::InvalidNewEntry {
+                file_name: name,
+                reason: "an entry of that name was already added",
+            });
+        }
+        Ok(name)
+    }
+
+    pub async fn add_directory(&mut self, name: &str, attributes: EntryAttributes) -> Result<()> {
+        let name = self.claim(name, true)?;
+        let mut metadata = FileMetadata {
+            file_name: name,
+            uncompressed_size: 0,
+            compressed_size: 0,
+            is_directory: true,
+            file_offset: 0,
+            compression_method: STORED,
+            crc32: Some(0),
+            modified: attributes.modified,
+            unix_mode: Some(attributes.unix_mode.unwrap_or(UNIX_DIRECTORY | 0o755)),
+            encryption: Encryption::None,
+        };
+        let header_offset = self.start_known(&mut metadata, 0).await?;
+        self.end_known(metadata, header_offset, 0).await
+    }
+
+    /// Adds a file holding everything `reader` yields.
+    pub async fn add_file(
+        &mut self,
+        name: &str,
+        mut reader: impl AsyncRead + Unpin,
+        attributes: EntryAttributes,
+    ) -> Result<()> {
+        let name = self.claim(name, false)?;
+        let method = if self.level.is_some() {
+            DEFLATED
+        } else {
+            STORED
+        };
+        let mut metadata = FileMetadata {
+            file_name: name,
+            uncompressed_size: 0,
+            compressed_size: 0,
+            is_directory: false,
+            file_offset: 0,
+            compression_method: method,
+            crc32: None,
+            modified: attributes.modified,
+            unix_mode: Some(attributes.unix_mode.unwrap_or(UNIX_REGULAR | 0o644)),
+            encryption: Encryption::None,
+        };
+        let header_offset = self.offset;
+        self.write(&local_header(&metadata, FLAG_DATA_DESCRIPTOR))
+            .await?;
+        let data_offset = self.offset;
+
+        let mut crc = crc32fast::Hasher::new();
+        let mut uncompressed_size = 0;
+        let mut encoder = self
+            .level
+            .map(|level| DeflateEncoder::new(Vec::new(), Compression::new(level)));
+        let mut chunk = vec![0; CHUNK_LEN];
+        loop {
+            let read = reader.read(&mut chunk).await?;
+            if read == 0 {
+                break;
+            }
+            let data = &chunk[..read];
+            crc.update(data);
+            uncompressed_size += read as u64;
+            match &mut encoder {
+                Some(encoder) => {
+                    encoder.write_all(data)?;
+                    let compressed = std::mem::take(encoder.get_mut());
+                    self.write(&compressed).await?;
+                }
+                None => self.write(data).await?,
+            }
+        }
+        if let Some(encoder) = encoder {
+            let compressed = encoder.finish()?;
+            self.write(&compressed).await?;
+        }
+        let compressed_size = self.offset - data_offset;
+        let crc32 = crc.finalize();
+
+        metadata.uncompressed_size = uncompressed_size;
+        metadata.compressed_size = compressed_size;
+        metadata.file_offset = data_offset;
+        metadata.crc32 = Some(crc32);
+        self.write(&data_descriptor(&metadata)).await?;
+        self.record(metadata, header_offset, FLAG_DATA_DESCRIPTOR);
+        Ok(())
+    }
+
+    /// Adds a symlink pointing at `target`.
+    pub async fn add_symlink(
+        &mut self,
+        name: &str,
+        target: &str,
+        attributes: EntryAttributes,
+    ) -> Result<()> {
+        let name = self.claim(name, false)?;
+        let target = target.as_bytes().to_vec();
+        let mut metadata = FileMetadata {
+            file_name: name,
+            uncompressed_size: target.len() as u64,
+            compressed_size: target.len() as u64,
+            is_directory: false,
+            file_offset: 0,
+            compression_method: STORED,
+            crc32: Some(crc32fast::hash(&target)),
+            modified: attributes.modified,
+            unix_mode: Some(UNIX_SYMLINK | 0o777),
+            encryption: Encryption::None,
+        };
+        let header_offset = self.start_known(&mut metadata, 0).await?;
+        self.write(&target).await?;
+        self.end_known(metadata, header_offset, 0).await
+    }
+
+    /// Copies the entries of `source` picked by `selector`, keeping their names and
+    /// attributes.
+    ///
+    /// The stored bytes are copied as they are, without decompressing or decrypting them, and
+    /// neighbouring entries are fetched together as extraction does. Only ZipCrypto entries
+    /// whose password check relies on the time of the original header are decrypted with the
+    /// password of `source` and compressed again, without encryption.
+    pub async fn copy_entries(
+        &mut self,
+        source: &CloudZip,
+        selector: &EntrySelector,
+    ) -> Result<usize> {
+        let entries = source.verified_entries().await?;
+        let selected = entries.select(selector);
+        if selected.is_empty() {
+            return Err(CloudZipError::EntryNotFound(selector.describe()));
+        }
+        let reader = source.raw_reader(selected.iter().copied());
+        let mut recompressed = 0;
+        for metadata in &selected {
+            let attributes = EntryAttributes {
+                modified: metadata.modified,
+                unix_mode: metadata.unix_mode,
+            };
+            if metadata.is_directory {
+                self.add_directory(&metadata.file_name, attributes).await?;
+                continue;
+            }
+            let Some(flags) = raw_copy_flags(metadata) else {
+                let entry = source.open_entry(&metadata.file_name).await?;
+                self.add_file(&metadata.file_name, entry, attributes)
+                    .await?;
+                recompressed += 1;
+                continue;
+            };
+            let name = self.claim(&metadata.file_name, false)?;
+            let mut copied = FileMetadata {
+                file_name: name,
+                is_directory: false,
+                file_offset: 0,
+                ..(*metadata).clone()
+            };
+            let header_offset = self.start_known(&mut copied, flags).await?;
+            let mut chunks = reader.stream_range(metadata.file_offset, metadata.compressed_size);
+            while let Some(chunk) = chunks.try_next().await? {
+                self.write(&chunk).await?;
+            }
+            self.end_known(copied, header_offset, flags).await?;
+        }
+        debug!(entries = selected.len(), recompressed, "Copied entries");
+        Ok(selected.len())
+    }
+
+    /// Writes the local header of an entry whose CRC-32 and sizes are known up front, setting
+    /// its data offset; its data follows, then [`end_known`](Self::end_known) with the same
+    /// `flags`.
+    async fn start_known(&mut self, metadata: &mut FileMetadata, flags: u16) -> Result<u64> {
+        let header_offset = self.offset;
+        self.write(&local_header(metadata, flags)).await?;
+        metadata.file_offset = self.offset;
+        Ok(header_offset)
+    }
+
+    async fn end_known(
+        &mut self,
+        metadata: FileMetadata,
+        header_offset: u64,
+        flags: u16,
+    ) -> Result<()> {
+        let written = self.offset - metadata.file_offset;
+        if written != metadata.compressed_size {
+            return Err(CloudZipError::invalid_archive(format!(
+                "{} should be {} bytes but {} were written",
+                metadata.file_name, metadata.compressed_size, written
+            )));
+        }
+        if flags & FLAG_DATA_DESCRIPTOR != 0 {
+            self.write(&data_descriptor(&metadata)).await?;
+        }
+        self.record(metadata, header_offset, flags);
+        Ok(())
+    }
+
+    /// Adds the central directory record of an entry just written.
+    fn record(&mut self, metadata: FileMetadata, header_offset: u64, flags: u16) {
+        let mut zip64 = Vec::new();
+        let saturate = |value: u64, zip64: &mut Vec<u8>| {
+            if value >= u32::MAX as u64 {
+                put_u64(zip64, value);
+                u32::MAX
+            } else {
+                value as u32
+            }
+        };
+        // The ZIP64 extra lists the saturated fields in this order.
+        let uncompressed_size = saturate(metadata.uncompressed_size, &mut zip64);
+        let compressed_size = saturate(metadata.compressed_size, &mut zip64);
+        let offset = saturate(header_offset, &mut zip64);
+
+        let (encryption_flags, method, encryption_extra) = encryption_fields(&metadata);
+        let mut extra = Vec::new();
+        if !zip64.is_empty() {
+            put_u16(&mut extra, ZIP64_EXTRA_ID);
+            put_u16(&mut extra, zip64.len() as u16);
+            extra.extend_from_slice(&zip64);
+        }
+        extra.extend_from_slice(&timestamp_extra(metadata.modified));
+        extra.extend_from_slice(&encryption_extra);
+
+        let (dos_time, dos_date) = dos_date_time(metadata.modified);
+        let mut external_attributes = metadata.unix_mode.unwrap_or(0) << 16;
+        if metadata.is_directory {
+            external_attributes |= DOS_DIRECTORY;
+        }
+        let record = &mut self.central_directory;
+        put_u32(record, CENTRAL_HEADER_SIGNATURE);
+        put_u16(record, VERSION_MADE_BY);
+        put_u16(
+            record,
+            if zip64.is_empty() {
+                VERSION_DEFAULT
+            } else {
+                VERSION_ZIP64
+            },
+        );
+        put_u16(
+            record,
+            flags | encryption_flags | name_flags(&metadata.file_name),
+        );
+        put_u16(record, method);
+        put_u16(record, dos_time);
+        put_u16(record, dos_date);
+        put_u32(record, metadata.crc32.unwrap_or(0));
+        put_u32(record, compressed_size);
+        put_u32(record, uncompressed_size);
+        put_u16(record, metadata.file_name.len() as u16);
+        put_u16(record, extra.len() as u16);
+        put_u16(record, 0); // comment length
+        put_u16(record, 0); // disk number
+        put_u16(record, 0); // internal attributes
+        put_u32(record, external_attributes);
+        put_u32(record, offset);
+        record.extend_from_slice(metadata.file_name.as_bytes());
+        record.extend_from_slice(&extra);
+        self.entries.push(metadata);
+    }
+
+    /// Writes the central directory and completes the archive, returning its index.
+    pub async fn finish(mut self) -> Result<EntryIndex> {
+        let cd_offset = self.offset;
+        let central_directory = std::mem::take(&mut self.central_directory);
+        self.write(&central_directory).await?;
+        let cd_size = central_directory.len() as u64;
+        let count = self.entries.len() as u64;
+
+        let mut end = Vec::new();
+        let needs_zip64 =
+            count >= u16::MAX as u64 || cd_size >= u32::MAX as u64 || cd_offset >= u32::MAX as u64;
+        if needs_zip64 {
+            let zip64_offset = self.offset;
+            put_u32(&mut end, ZIP64_EOCD_SIGNATURE);
+            put_u64(&mut end, 44); // size of the rest of the record
+            put_u16(&mut end, VERSION_MADE_BY);
+            put_u16(&mut end, VERSION_ZIP64);
+            put_u32(&mut end, 0); // this disk
+            put_u32(&mut end, 0); // disk with the central directory
+            put_u64(&mut end, count);
+            put_u64(&mut end, count);
+            put_u64(&mut end, cd_size);
+            put_u64(&mut end, cd_offset);
+            put_u32(&mut end, ZIP64_LOCATOR_SIGNATURE);
+            put_u32(&mut end, 0);
+            put_u64(&mut end, zip64_offset);
+            put_u32(&mut end, 1); // total disks
+        }
+        put_u32(&mut end, EOCD_SIGNATURE);
+        put_u16(&mut end, 0);
+        put_u16(&mut end, 0);
+        put_u16(&mut end, count.min(u16::MAX as u64) as u16);
+        put_u16(&mut end, count.min(u16::MAX as u64) as u16);
+        put_u32(&mut end, cd_size.min(u32::MAX as u64) as u32);
+        put_u32(&mut end, cd_offset.min(u32::MAX as u64) as u32);
+        put_u16(&mut end, 0); // comment length
+        self.write(&end).await?;
+
+        let fingerprint = self.sink.finish().await?;
+        debug!(
+            entries = count,
+            size = fingerprint.size,
+            "Finished the archive"
+        );
+        Ok(EntryIndex::new(self.entries).with_fingerprint(Some(fingerprint)))
+    }
+
+    /// Discards the archive.
+    pub async fn abort(self) {
+        self.sink.abort().await
+    }
+}
+
+/// The sink writing a new archive at `location`, a local path or an S3 object.
+pub(crate) async fn open_sink(
+    location: &ArchiveLocation,
+    options: &BackendOptions,
+) -> Result<Box<dyn ArchiveSink>> {
+    match location {
+        ArchiveLocation::Local(path) => Ok(Box::new(FileSink::create(path).await?)),
+        ArchiveLocation::S3 {
+            bucket,
+            key,
+            version_id: None,
+        } => Ok(Box::new(S3Upload::new(
+            get_s3_client(&options.s3).await,
+            bucket,
+            key,
+        ))),
+        location => Err(CloudZipError::InvalidLocation(format!(
+            "archives can only be written on disk or in S3, not at {}",
+            location
+        ))),
+    }
+}
+
+/// Adds `path` and, for a directory, everything below it, named by `name` and the paths
+/// below it. Symlinks are stored as links, not followed.
+pub async fn add_path(writer: &mut ArchiveWriter, path: &Path, name: &str) -> Result<usize> {
+    let mut added = 0;
+    let mut pending = vec![(path.to_path_buf(), name.to_string())];
+    while let Some((path, name)) = pending.pop() {
+        let metadata = tokio::fs::symlink_metadata(&path).await?;
+        let attributes = EntryAttributes::of(&metadata);
+        if metadata.is_dir() {
+            if !name.is_empty() {
+                writer.add_directory(&name, attributes).await?;
+                added += 1;
+            }
+            let mut children = Vec::new();
+            let mut listing = tokio::fs::read_dir(&path).await?;
+            while let Some(child) = listing.next_entry().await? {
+                children.push(child.file_name());
+            }
+            // Popped in name order.
+            children.sort_unstable_by(|a, b| b.cmp(a));
+            for child in children {
+                let child_name = match name.as_str() {
+                    "" => child.to_string_lossy().into_owned(),
+                    name => format!("{}/{}", name, child.to_string_lossy()),
+                };
+                pending.push((path.join(&child), child_name));
+            }
+        } else if metadata.is_symlink() {
+            let target = tokio::fs::read_link(&path).await?;
+            writer
+                .add_symlink(&name, &target.to_string_lossy(), attributes)
+                .await?;
+            added += 1;
+        } else {
+            let file = File::open(&path).await?;
+            writer.add_file(&name, file, attributes).await?;
+            added += 1;
+        }
+    }
+    Ok(added)
+}
+
+/// The local header of `metadata`, whose CRC-32 and sizes are left zero when `flags` announce a
+/// data descriptor.
+fn local_header(metadata: &FileMetadata, flags: u16) -> Vec<u8> {
+    let (crc32, compressed_size, uncompressed_size) = if flags & FLAG_DATA_DESCRIPTOR != 0 {
+        (0, 0, 0)
+    } else {
+        (
+            metadata.crc32.unwrap_or(0),
+            metadata.compressed_size,
+            metadata.uncompressed_size,
+        )
+    };
+    let (encryption_flags, method, encryption_extra) = encryption_fields(metadata);
+    let name = &metadata.file_name;
+    let mut extra = Vec::new();
+    let zip64 = compressed_size >= u32::MAX as u64 || uncompressed_size >= u32::MAX as u64;
+    if zip64 {
+        put_u16(&mut extra, ZIP64_EXTRA_ID);
+        put_u16(&mut extra, 16);
+        put_u64(&mut extra, uncompressed_size);
+        put_u64(&mut extra, compressed_size);
+    }
+    extra.extend_from_slice(&timestamp_extra(metadata.modified));
+    extra.extend_from_slice(&encryption_extra);
+    let (dos_time, dos_date) = dos_date_time(metadata.modified);
+    let mut header = Vec::with_capacity(30 + name.len() + extra.len());
+    put_u32(&mut header, LOCAL_HEADER_SIGNATURE);
+    put_u16(
+        &mut header,
+        if zip64 {
+            VERSION_ZIP64
+        } else {
+            VERSION_DEFAULT
+        },
+    );
+    put_u16(&mut header, flags | encryption_flags | name_flags(name));
+    put_u16(&mut header, method);
+    put_u16(&mut header, dos_time);
+    put_u16(&mut header, dos_date);
+    put_u32(&mut header, crc32);
+    put_u32(
+        &mut header,
+        if zip64 {
+            u32::MAX
+        } else {
+            compressed_size as u32
+        },
+    );
+    put_u32(
+        &mut header,
+        if zip64 {
+            u32::MAX
+        } else {
+            uncompressed_size as u32
+        },
+    );
+    put_u16(&mut header, name.len() as u16);
+    put_u16(&mut header, extra.len() as u16);
+    header.extend_from_slice(name.as_bytes());
+    header.extend_from_slice(&extra);
+    header
+}
+
+/// The flags to copy the stored bytes of an entry with, or `None` when they would not be valid
+/// in the new archive. The ZipCrypto password check compares against the high byte of the
+/// CRC-32, or of the MS-DOS time for entries written with a data descriptor, and the original
+/// time is only kept when it was UTC.
+fn raw_copy_flags(metadata: &FileMetadata) -> Option<u16> {
+    let Encryption::ZipCrypto { check_byte } = metadata.encryption else {
+        return Some(0);
+    };
+    if metadata.crc32.map(|crc32| (crc32 >> 24) as u8) == Some(check_byte) {
+        Some(0)
+    } else if (dos_date_time(metadata.modified).0 >> 8) as u8 == check_byte {
+        Some(FLAG_DATA_DESCRIPTOR)
+    } else {
+        None
+    }
+}
+
+/// The record after the data of an entry written with [`FLAG_DATA_DESCRIPTOR`].
+fn data_descriptor(metadata: &FileMetadata) -> Vec<u8> {
+    let mut descriptor = Vec::with_capacity(24);
+    put_u32(&mut descriptor, DATA_DESCRIPTOR_SIGNATURE);
+    put_u32(&mut descriptor, metadata.crc32.unwrap_or(0));
+    if metadata.compressed_size >= u32::MAX as u64 || metadata.uncompressed_size >= u32::MAX as u64
+    {
+        put_u64(&mut descriptor, metadata.compressed_size);
+        put_u64(&mut descriptor, metadata.uncompressed_size);
+    } else {
+        put_u32(&mut descriptor, metadata.compressed_size as u32);
+        put_u32(&mut descriptor, metadata.uncompressed_size as u32);
+    }
+    descriptor
+}
+
+/// The general purpose flags, compression method and extra field that declare how an entry
+/// is encrypted.
+fn encryption_fields(metadata: &FileMetadata) -> (u16, u16, Vec<u8>) {
+    match metadata.encryption {
+        Encryption::None => (0, metadata.compression_method, Vec::new()),
+        Encryption::ZipCrypto { .. } => (FLAG_ENCRYPTED, metadata.compression_method, Vec::new()),
+        Encryption::Aes {
+            strength,
+            vendor_version,
+        } => {
+            let mut extra = Vec::with_capacity(11);
+            put_u16(&mut extra, AES_EXTRA_ID);
+            put_u16(&mut extra, 7);
+            put_u16(&mut extra, vendor_version);
+            extra.extend_from_slice(b"AE");
+            extra.push(strength);
+            put_u16(&mut extra, metadata.compression_method);
+            (FLAG_ENCRYPTED, AES_METHOD, extra)
+        }
+    }
+}
+
+/// Marks names that are not plain ASCII as UTF-8.
+fn name_flags(name: &str) -> u16 {
+    if name.is_ascii() {
+        0
+    } else {
+        FLAG_UTF8
+    }
+}
+
+/// The extended timestamp extra field carrying the exact modification time, when it fits.
+fn timestamp_extra(modified: Option<i64>) -> Vec<u8> {
+    let mut extra = Vec::new();
+    if let Some(modified) = modified.and_then(|modified| i32::try_from(modified).ok()) {
+        put_u16(&mut extra, EXTENDED_TIMESTAMP_EXTRA_ID);
+        put_u16(&mut extra, 5);
+        extra.push(1); // modification time present
+        extra.extend_from_slice(&modified.to_le_bytes());
+    }
+    extra
+}
+
+/// MS-DOS time and date of a Unix time, read back as UTC; 1980-01-01 when there is none or it
+/// is out of range.
+fn dos_date_time(modified: Option<i64>) -> (u16, u16) {
+    const DOS_EPOCH: i64 = 315_532_800;
+    let Some(secs) = modified.filter(|secs| (DOS_EPOCH..4_354_819_200).contains(secs)) else {
+        return (0, 0x21);
+    };
+    let (days, time) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // Civil date from days since the epoch, after Howard Hinnant's algorithm.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let dos_time = ((time / 3_600) << 11) | ((time % 3_600 / 60) << 5) | ((time % 60) / 2);
+    let dos_date = ((year - 1980) << 9) | (month << 5) | day;
+    (dos_time as u16, dos_date as u16)
+}
+
+/// `extra` without the fields with this ID.
+fn without_extra_field(extra: &[u8], id: u16) -> Vec<u8> {
+    let mut kept = Vec::with_capacity(extra.len());
+    let mut pos = 0;
+    while pos + 4 <= extra.len() {
+        let len = 4 + u16_at(extra, pos + 2) as usize;
+        let field = &extra[pos..(pos + len).min(extra.len())];
+        if u16_at(extra, pos) != id {
+            kept.extend_from_slice(field);
+        }
+        pos += len;
+    }
+    kept
+}
+
+fn u16_at(buf: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([buf[pos], buf[pos + 1]])
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}